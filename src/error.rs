@@ -0,0 +1,93 @@
+use std::fmt;
+
+/// Errors for malformed or unsupported input files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpmError {
+    /// The data ended before `expected` bytes could be read at `offset`
+    UnexpectedEof {
+        offset: u64,
+        expected: usize,
+    },
+    InvalidUtf8 {
+        offset: u64,
+    },
+    InvalidUtf16 {
+        offset: u64,
+    },
+    BadMagic {
+        expected: String,
+        found: String,
+    },
+    UnsupportedVersion {
+        format: &'static str,
+        version: i64,
+    },
+    /// The checksum over the headers is `checksum` instead of zero
+    BadChecksum {
+        format: &'static str,
+        checksum: i64,
+    },
+    /// The header at `offset` has an impossible `value` for `field`, e.g. a negative size
+    InvalidHeader {
+        format: &'static str,
+        offset: u64,
+        field: &'static str,
+        value: i64,
+    },
+    /// The date of the header is not a valid date, e.g. month 13
+    InvalidDate {
+        format: &'static str,
+        date: String,
+    },
+    /// The data described by the headers ends at `end` in a file of `len` bytes
+    LengthMismatch {
+        format: &'static str,
+        end: u64,
+        len: u64,
+    },
+}
+
+impl fmt::Display for SpmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof { offset, expected } => write!(
+                f,
+                "unexpected end of data reading {} bytes at offset {}",
+                expected, offset
+            ),
+            Self::InvalidUtf8 { offset } => write!(f, "invalid UTF-8 string at offset {}", offset),
+            Self::InvalidUtf16 { offset } => {
+                write!(f, "invalid UTF-16 string at offset {}", offset)
+            }
+            Self::BadMagic { expected, found } => {
+                write!(f, "bad magic: expected {:?}, found {:?}", expected, found)
+            }
+            Self::UnsupportedVersion { format, version } => {
+                write!(f, "unsupported {} version {}", format, version)
+            }
+            Self::BadChecksum { format, checksum } => {
+                write!(f, "bad {} header checksum {}", format, checksum)
+            }
+            Self::InvalidHeader {
+                format,
+                offset,
+                field,
+                value,
+            } => write!(
+                f,
+                "invalid {} header at offset {}: {} {}",
+                format, offset, field, value
+            ),
+            Self::InvalidDate { format, date } => write!(f, "invalid {} date {}", format, date),
+            Self::LengthMismatch { format, end, len } => write!(
+                f,
+                "{} data ends at {}, but the file length is {}",
+                format, end, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SpmError {}
+
+pub type SpmResult<T> = std::result::Result<T, SpmError>;
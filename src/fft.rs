@@ -0,0 +1,191 @@
+use std::f64::consts::PI;
+
+use ndarray::{Array2, Axis};
+use num_complex::Complex;
+use rustfft::FftPlanner;
+
+/// Window functions applied before a Fourier transform to reduce leakage
+/// from the image edges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Blackman,
+}
+
+impl Window {
+    /// Coefficients of the window for `n` points
+    pub fn coefficients(&self, n: usize) -> Vec<f64> {
+        let phase = |i: usize| {
+            if n > 1 {
+                2.0 * PI * i as f64 / (n - 1) as f64
+            } else {
+                0.0
+            }
+        };
+        (0..n)
+            .map(|i| match self {
+                Window::Rectangular => 1.0,
+                Window::Hann => 0.5 - 0.5 * phase(i).cos(),
+                Window::Blackman => 0.42 - 0.5 * phase(i).cos() + 0.08 * (2.0 * phase(i)).cos(),
+            })
+            .collect()
+    }
+}
+
+/// Filters applied to the Fourier transform of an image, frequencies are
+/// given in 1/nm
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FourierFilter {
+    /// Keep frequencies up to the cutoff
+    LowPass(f64),
+    /// Keep frequencies between the lower and upper bound, an infinite upper
+    /// bound gives a high-pass filter
+    BandPass(f64, f64),
+    /// Remove frequencies within `radius` of (`fx`, `fy`) and (-`fx`, -`fy`),
+    /// e.g. periodic electrical noise
+    Notch { fx: f64, fy: f64, radius: f64 },
+}
+
+impl FourierFilter {
+    /// Whether the component with the frequencies `fx` and `fy` passes
+    pub fn passes(&self, fx: f64, fy: f64) -> bool {
+        let f = fx.hypot(fy);
+        match *self {
+            FourierFilter::LowPass(cutoff) => f <= cutoff,
+            FourierFilter::BandPass(low, high) => low <= f && f <= high,
+            FourierFilter::Notch {
+                fx: x,
+                fy: y,
+                radius,
+            } => (fx - x).hypot(fy - y) > radius && (fx + x).hypot(fy + y) > radius,
+        }
+    }
+}
+
+/// Radially averaged power spectral density
+#[derive(Debug, Clone)]
+pub struct RadialPsd {
+    /// Spatial frequencies in 1/nm
+    pub frequency: Vec<f64>,
+    /// Power spectral density in z units^2 nm^2, its integral over the
+    /// frequency plane is the variance of the image
+    pub psd: Vec<f64>,
+}
+
+/// In-place discrete Fourier transform of arbitrary length. The inverse
+/// transform is normalized by 1/n.
+pub fn fft(data: &mut [Complex<f64>], inverse: bool) {
+    plan(data.len(), inverse)(data);
+}
+
+/// In-place Fourier transform over both axes
+pub fn fft2(data: &mut Array2<Complex<f64>>, inverse: bool) {
+    for axis in [Axis(0), Axis(1)] {
+        let transform = plan(data.len_of(axis), inverse);
+        for mut lane in data.lanes_mut(axis) {
+            let mut buffer = lane.to_vec();
+            transform(&mut buffer);
+            lane.iter_mut().zip(buffer).for_each(|(z, b)| *z = b);
+        }
+    }
+}
+
+// Transform of length `n`, planned once for all lanes of an axis
+fn plan(n: usize, inverse: bool) -> impl Fn(&mut [Complex<f64>]) {
+    let mut planner = FftPlanner::new();
+    let fft = if inverse {
+        planner.plan_fft_inverse(n)
+    } else {
+        planner.plan_fft_forward(n)
+    };
+    move |data: &mut [Complex<f64>]| {
+        fft.process(data);
+        if inverse {
+            data.iter_mut().for_each(|z| *z /= n as f64);
+        }
+    }
+}
+
+/// Spatial frequencies of the `n` Fourier components of a length `size`,
+/// in the order of `fft`, i.e. negative frequencies in the upper half
+pub fn frequencies(n: usize, size: f64) -> Vec<f64> {
+    (0..n)
+        .map(|k| {
+            let k = if k < n.div_ceil(2) {
+                k as f64
+            } else {
+                k as f64 - n as f64
+            };
+            k / size
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn dft(data: &[Complex<f64>]) -> Vec<Complex<f64>> {
+        let n = data.len();
+        (0..n)
+            .map(|k| {
+                data.iter()
+                    .enumerate()
+                    .map(|(j, x)| {
+                        x * Complex::from_polar(1.0, -2.0 * PI * (j * k) as f64 / n as f64)
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn signal(n: usize) -> Vec<Complex<f64>> {
+        (0..n)
+            .map(|i| Complex::new((i as f64 * 0.7).sin() + 0.1 * i as f64, (i as f64).cos()))
+            .collect()
+    }
+
+    #[test]
+    fn test_fft_matches_dft() {
+        for n in [1, 2, 6, 8, 13, 16, 30] {
+            let mut data = signal(n);
+            let expected = dft(&data);
+            fft(&mut data, false);
+            for (a, b) in data.iter().zip(&expected) {
+                assert!((a - b).norm() < 1e-9, "length {}", n);
+            }
+        }
+    }
+
+    #[test]
+    fn test_inverse_fft() {
+        for n in [7, 12, 32] {
+            let original = signal(n);
+            let mut data = original.clone();
+            fft(&mut data, false);
+            fft(&mut data, true);
+            for (a, b) in data.iter().zip(&original) {
+                assert!((a - b).norm() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_frequencies() {
+        assert_eq!(frequencies(4, 2.0), [0.0, 0.5, -1.0, -0.5]);
+        assert_eq!(frequencies(5, 1.0), [0.0, 1.0, 2.0, -2.0, -1.0]);
+    }
+
+    #[test]
+    fn test_windows() {
+        let hann = Window::Hann.coefficients(5);
+        assert!((hann[0]).abs() < 1e-12);
+        assert!((hann[2] - 1.0).abs() < 1e-12);
+        let blackman = Window::Blackman.coefficients(5);
+        assert!((blackman[4]).abs() < 1e-12);
+        assert!((blackman[2] - 1.0).abs() < 1e-12);
+        assert_eq!(Window::Rectangular.coefficients(3), [1.0; 3]);
+    }
+}
@@ -1,9 +1,16 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use ndarray::{ArrayD, IxDyn, ShapeBuilder};
+use num_complex::Complex;
 use std::{
+    collections::BTreeMap,
     fs::read,
     io::{Cursor, Read},
+    path::Path,
 };
 
+use crate::error::{SpmError, SpmResult};
+use crate::spm_file::{SpmChannel, SpmFile, SpmReader};
+use crate::spm_image::SpmImage;
 use crate::utils::Bytereading;
 
 #[derive(Debug)]
@@ -16,8 +23,12 @@ pub struct Ibw {
     pub x_step: [f64; 4],
     pub x_start: [f64; 4],
     pub data_units: String,
+    /// Units of the dimensions from the wave header, at most 3 characters
+    pub dim_units: [String; 4],
     pub data: NumericData,
     pub note: String,
+    /// Dependency formula of the wave, versions 3 and 5 only
+    pub formula: Option<String>,
     pub extended_data_units: Option<String>,
     pub dim_e_units: Option<Vec<String>>,
     pub dim_labels: Option<Vec<String>>,
@@ -27,7 +38,7 @@ pub struct Ibw {
 pub enum BinHeader {
     V1(BinHeader1),
     V2(BinHeader2),
-    V4(BinHeader3),
+    V3(BinHeader3),
     V5(BinHeader5),
 }
 
@@ -54,7 +65,7 @@ pub struct BinHeader3 {
     pub note_size: i32, // The size of the note text.
     pub formula_size: i32, // The size of the dependency formula, if any.
     pub pict_size: i32, // Reserved. Write zero. Ignore on read.
-    pub checksum: i32, // Checksum over this header and the wave header.
+    pub checksum: i16, // Checksum over this header and the wave header.
 }
 
 #[derive(Debug)]
@@ -161,6 +172,77 @@ pub struct WaveHeader5 {
     pub s_indeces: i32,
 }
 
+/// A dimension of a wave with its coordinates
+#[derive(Debug, Clone)]
+pub struct IbwAxis {
+    pub label: String,
+    pub unit: String,
+    pub values: Vec<f64>,
+}
+
+/// A value of the wave note, numbers are parsed
+#[derive(Debug, Clone, PartialEq)]
+pub enum NoteValue {
+    Number(f64),
+    Text(String),
+}
+
+impl NoteValue {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Number(x) => Some(*x),
+            Self::Text(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for NoteValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(x) => write!(f, "{}", x),
+            Self::Text(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// `Key: Value` lines as written by Asylum Research (MFP-3D, Cypher) with the
+/// text as in the note, other lines are skipped
+pub fn note_entries(note: &str) -> impl Iterator<Item = (&str, &str)> {
+    note.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .filter(|(key, _)| !key.is_empty())
+}
+
+/// Parses the `Key: Value` lines of the note, see `note_entries`
+pub fn parse_note(note: &str) -> BTreeMap<String, NoteValue> {
+    note_entries(note)
+        .map(|(key, value)| {
+            let value = match value.parse() {
+                Ok(x) => NoteValue::Number(x),
+                Err(_) => NoteValue::Text(value.to_string()),
+            };
+            (key.to_string(), value)
+        })
+        .collect()
+}
+
+// Unit of an Asylum channel like "HeightRetrace" or "Phase"
+fn asylum_unit(channel: &str) -> &'static str {
+    let name = channel
+        .strip_suffix("Retrace")
+        .or_else(|| channel.strip_suffix("Trace"))
+        .unwrap_or(channel);
+    match name {
+        "Height" | "ZSensor" | "Amplitude" | "Deflection" | "Amplitude1" | "Amplitude2" => "m",
+        "Phase" | "Phase1" | "Phase2" => "deg",
+        "Current" => "A",
+        "Potential" | "UserIn0" | "UserIn1" | "UserIn2" | "Bias" => "V",
+        "Frequency" => "Hz",
+        _ => "",
+    }
+}
+
 // TODO use generics instead
 #[derive(Debug)]
 pub enum NumericData {
@@ -174,26 +256,219 @@ pub enum NumericData {
 
     Float32(Vec<f32>),
     Float64(Vec<f64>),
+
+    ComplexInt8(Vec<Complex<i8>>),
+    ComplexInt16(Vec<Complex<i16>>),
+    ComplexInt32(Vec<Complex<i32>>),
+
+    ComplexUint8(Vec<Complex<u8>>),
+    ComplexUint16(Vec<Complex<u16>>),
+    ComplexUint32(Vec<Complex<u32>>),
+
+    Complex64(Vec<Complex<f32>>),
+    Complex128(Vec<Complex<f64>>),
+
+    Text(Vec<String>),
+}
+
+impl NumericData {
+    /// The values as f64, `None` for complex and text waves
+    pub fn to_f64(&self) -> Option<Vec<f64>> {
+        let data = match self {
+            Self::Int8(v) => v.iter().map(|x| *x as f64).collect(),
+            Self::Int16(v) => v.iter().map(|x| *x as f64).collect(),
+            Self::Int32(v) => v.iter().map(|x| *x as f64).collect(),
+            Self::Uint8(v) => v.iter().map(|x| *x as f64).collect(),
+            Self::Uint16(v) => v.iter().map(|x| *x as f64).collect(),
+            Self::Uint32(v) => v.iter().map(|x| *x as f64).collect(),
+            Self::Float32(v) => v.iter().map(|x| *x as f64).collect(),
+            Self::Float64(v) => v.clone(),
+            _ => return None,
+        };
+        Some(data)
+    }
 }
 
 pub fn read_ibw(filename: &str) -> Result<Ibw> {
-    let bytes = read(filename)?;
+    Ibw::from_bytes(&read(filename)?)
+}
+
+impl Ibw {
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Ibw> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ibw::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Ibw> {
+        read_ibw_bytes(bytes)
+    }
+
+    /// Number of points of every used dimension
+    pub fn shape(&self) -> Vec<usize> {
+        self.n_dim
+            .iter()
+            .take_while(|n| **n > 0)
+            .map(|n| *n as usize)
+            .collect()
+    }
+
+    /// The data indexed like in Igor by row, column, layer and chunk.
+    /// Igor stores the rows contiguously, so the array is in column-major order.
+    /// `None` for complex and text waves.
+    pub fn to_array(&self) -> Option<ArrayD<f64>> {
+        let data = self.data.to_f64()?;
+        ArrayD::from_shape_vec(IxDyn(&self.shape()).f(), data).ok()
+    }
+
+    /// Label of the dimension `dim` followed by the labels of its elements
+    pub fn dim_labels(&self, dim: usize) -> Vec<String> {
+        let Some(labels) = self.dim_labels.as_ref().and_then(|l| l.get(dim)) else {
+            return Vec::new();
+        };
+        // every label takes 32 bytes including the trailing null
+        labels
+            .as_bytes()
+            .chunks(32)
+            .map(|l| {
+                String::from_utf8_lossy(l)
+                    .trim_end_matches(char::from(0))
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// Coordinates and units of every used dimension
+    pub fn axes(&self) -> Vec<IbwAxis> {
+        self.shape()
+            .into_iter()
+            .enumerate()
+            .map(|(dim, n)| {
+                let unit = match self.dim_e_units.as_ref().map(|u| u[dim].as_str()) {
+                    Some(unit) if !unit.is_empty() => unit.to_string(),
+                    _ => self.dim_units[dim].clone(),
+                };
+                IbwAxis {
+                    label: self.dim_labels(dim).into_iter().next().unwrap_or_default(),
+                    unit,
+                    values: (0..n)
+                        .map(|i| self.x_start[dim] + i as f64 * self.x_step[dim])
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// The wave note as key value pairs
+    pub fn note_params(&self) -> BTreeMap<String, NoteValue> {
+        parse_note(&self.note)
+    }
+
+    /// 2D waves as image with the rows along x, sizes in m are converted to nm
+    pub fn to_spm_image(&self) -> Option<SpmImage> {
+        if self.shape().len() != 2 {
+            return None;
+        }
+        self.layer_image(0, self.bname.clone())
+    }
+
+    /// 2D waves as single channel, every layer of 3D waves as channel named by
+    /// its dimension label, e.g. the HeightRetrace and PhaseRetrace of Asylum images
+    pub fn to_spm_channels(&self) -> Vec<SpmChannel> {
+        let shape = self.shape();
+        let num_layers = match shape.len() {
+            2 => 1,
+            3 => shape[2],
+            _ => return Vec::new(),
+        };
+        // the first label is the one of the dimension itself
+        let labels = self.dim_labels(2);
+        (0..num_layers)
+            .filter_map(|layer| {
+                let name = match labels.get(layer + 1) {
+                    Some(label) if !label.is_empty() => label.clone(),
+                    _ if num_layers == 1 => self.bname.clone(),
+                    _ => format!("{}_{}", self.bname, layer),
+                };
+                let unit = match asylum_unit(&name) {
+                    "" => self.data_units.clone(),
+                    unit => unit.to_string(),
+                };
+                let image = self.layer_image(layer, name)?;
+                let metadata = BTreeMap::from([("unit".to_string(), unit)]);
+                Some(SpmChannel { metadata, image })
+            })
+            .collect()
+    }
+
+    fn layer_image(&self, layer: usize, img_id: String) -> Option<SpmImage> {
+        let shape = self.shape();
+        let num_points = shape[0] * shape[1];
+        let data = self.data.to_f64()?;
+        let axes = self.axes();
+        let size = |dim: usize| {
+            let size = (shape[dim] as f64 * self.x_step[dim]).abs();
+            if axes[dim].unit == "m" {
+                size * 1e9
+            } else {
+                size
+            }
+        };
+        Some(SpmImage {
+            img_id,
+            xsize: size(0),
+            ysize: size(1),
+            xres: shape[0],
+            yres: shape[1],
+            img_data: data
+                .get(layer * num_points..(layer + 1) * num_points)?
+                .to_vec(),
+        })
+    }
+}
+
+fn read_ibw_bytes(bytes: &[u8]) -> Result<Ibw> {
     // let file_len = bytes.len();
-    let mut cursor = Cursor::new(bytes.as_slice());
-    let version = cursor.read_i16_le();
+    let mut cursor = Cursor::new(bytes);
+    let version = cursor.read_i16_le()?;
     cursor.set_position(0);
 
-    let bin_header = match version {
-        2 => read_bin_header_2(&mut cursor),
-        5 => read_bin_header_5(&mut cursor),
-        _ => unreachable!("Not a version 2 or version 5 bin header"),
+    // versions 1 to 3 share the wave header
+    let (bin_header, wave_header) = match version {
+        1 => (
+            read_bin_header_1(&mut cursor)?,
+            read_wave_header_2(&mut cursor)?,
+        ),
+        2 => (
+            read_bin_header_2(&mut cursor)?,
+            read_wave_header_2(&mut cursor)?,
+        ),
+        3 => (
+            read_bin_header_3(&mut cursor)?,
+            read_wave_header_2(&mut cursor)?,
+        ),
+        5 => (
+            read_bin_header_5(&mut cursor)?,
+            read_wave_header_5(&mut cursor)?,
+        ),
+        _ => {
+            return Err(SpmError::UnsupportedVersion {
+                format: "ibw",
+                version: version.into(),
+            }
+            .into())
+        }
     };
 
-    let wave_header = match version {
-        2 => read_wave_header_2(&mut cursor),
-        5 => read_wave_header_5(&mut cursor),
-        _ => unreachable!("Not a version 2 or version 5 wave header"),
-    };
+    let size = header_size(version).unwrap_or_default();
+    let sum = checksum(&bytes[..size]);
+    if sum != 0 {
+        return Err(SpmError::BadChecksum {
+            format: "ibw",
+            checksum: sum.into(),
+        }
+        .into());
+    }
 
     let npnts = match &wave_header {
         WaveHeader::V2(wh) => wh.npnts,
@@ -205,13 +480,19 @@ pub fn read_ibw(filename: &str) -> Result<Ibw> {
         WaveHeader::V5(wh) => wh.type_,
     };
 
-    // TODO reshape data maybe
-    let data = read_numeric_data(&mut cursor, type_, npnts);
+    // text waves are split into strings after the string indices are read
+    let (mut data, text) = if type_ == 0 {
+        (
+            NumericData::Text(Vec::new()),
+            Some(read_text_data(&mut cursor, &bin_header)?),
+        )
+    } else {
+        (read_numeric_data(&mut cursor, type_, npnts)?, None)
+    };
 
     // version 1,2,3 have 16 bytes of padding after numeric wave data
     if version == 1 || version == 2 || version == 3 {
-        let pos = cursor.position();
-        cursor.set_position(pos + 16);
+        cursor.skip(16)?;
     }
 
     // Optional Data
@@ -220,10 +501,21 @@ pub fn read_ibw(filename: &str) -> Result<Ibw> {
     // v3: wave note data, wave dependency formula
     // v5: wave dependency formula, wave note data, extended data units data, extended dimension units data, dimension label data, String indices used for text waves only
 
-    let note = read_note(&mut cursor, &bin_header);
-    let extended_data_units = read_extended_data_units(&mut cursor, &bin_header);
-    let dim_e_units = read_dim_e_units(&mut cursor, &bin_header);
-    let dim_labels = read_dim_labels(&mut cursor, &bin_header);
+    // v5 stores the formula before the note, v3 after it
+    let (formula, note) = if let BinHeader::V5(_) = bin_header {
+        let formula = read_formula(&mut cursor, &bin_header)?;
+        (formula, read_note(&mut cursor, &bin_header)?)
+    } else {
+        let note = read_note(&mut cursor, &bin_header)?;
+        (read_formula(&mut cursor, &bin_header)?, note)
+    };
+    let extended_data_units = read_extended_data_units(&mut cursor, &bin_header)?;
+    let dim_e_units = read_dim_e_units(&mut cursor, &bin_header)?;
+    let dim_labels = read_dim_labels(&mut cursor, &bin_header)?;
+    if let Some(text) = text {
+        let s_indices = read_s_indices(&mut cursor, &bin_header)?;
+        data = NumericData::Text(split_text(&text, &s_indices));
+    }
     let bname = match &wave_header {
         WaveHeader::V2(wh) => wh.bname.trim_matches(char::from(0)).to_string(),
         WaveHeader::V5(wh) => wh.bname.trim_matches(char::from(0)).to_string(),
@@ -240,6 +532,19 @@ pub fn read_ibw(filename: &str) -> Result<Ibw> {
         WaveHeader::V2(wh) => [wh.hs_b, 0_f64, 0_f64, 0_f64],
         WaveHeader::V5(wh) => wh.sf_b,
     };
+    let dim_units = match &wave_header {
+        WaveHeader::V2(wh) => [
+            wh.x_units.trim_matches(char::from(0)).to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+        ],
+        WaveHeader::V5(wh) => wh.dim_units.map(|u| {
+            String::from_utf8_lossy(&u)
+                .trim_matches(char::from(0))
+                .to_string()
+        }),
+    };
     let data_units = match &wave_header {
         WaveHeader::V2(wh) => wh.data_units.trim_matches(char::from(0)).to_string(),
         WaveHeader::V5(wh) => wh.data_units.trim_matches(char::from(0)).to_string(),
@@ -252,127 +557,280 @@ pub fn read_ibw(filename: &str) -> Result<Ibw> {
         x_step,
         x_start,
         data_units,
+        dim_units,
         data,
         note,
+        formula,
         extended_data_units,
         dim_e_units,
         dim_labels,
     })
 }
 
-fn read_note(cursor: &mut Cursor<&[u8]>, bin_header: &BinHeader) -> String {
+pub struct IbwReader;
+
+impl SpmReader for IbwReader {
+    fn name(&self) -> &'static str {
+        "ibw"
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &[".ibw"]
+    }
+
+    // The version word alone is weak, so a valid header checksum is required
+    fn detect(&self, head: &[u8], _file_len: u64) -> f32 {
+        if head.len() < 2 {
+            return 0.0;
+        }
+        let version = i16::from_le_bytes([head[0], head[1]]);
+        match header_size(version) {
+            Some(size) if head.len() >= size && checksum(&head[..size]) == 0 => 0.9,
+            Some(_) => 0.1,
+            None => 0.0,
+        }
+    }
+
+    // 2D waves and the layers of 3D waves become channels, the note entries
+    // metadata prefixed with "note." and with the text as in the note
+    fn read(&self, filename: &str) -> Result<SpmFile> {
+        self.read_bytes(filename, &read(filename)?)
+    }
+
+    fn read_bytes(&self, filename: &str, bytes: &[u8]) -> Result<SpmFile> {
+        let ibw = Ibw::from_bytes(bytes)?;
+        let mut spm_file = SpmFile::new(Path::new(filename), self.name());
+        spm_file.channels = ibw.to_spm_channels();
+        for (key, value) in note_entries(&ibw.note) {
+            spm_file
+                .metadata
+                .insert(format!("note.{}", key), value.to_string());
+        }
+        spm_file.metadata.insert("bname".to_string(), ibw.bname);
+        spm_file
+            .metadata
+            .insert("npnts".to_string(), ibw.npnts.to_string());
+        spm_file
+            .metadata
+            .insert("n_dim".to_string(), format!("{:?}", ibw.n_dim));
+        spm_file
+            .metadata
+            .insert("data_units".to_string(), ibw.data_units);
+        spm_file.metadata.insert("note".to_string(), ibw.note);
+        Ok(spm_file)
+    }
+}
+
+// Size of bin header and wave header without the wave data
+fn header_size(version: i16) -> Option<usize> {
+    match version {
+        1 => Some(8 + 110),
+        2 => Some(16 + 110),
+        3 => Some(20 + 110),
+        5 => Some(64 + 320),
+        _ => None,
+    }
+}
+
+/// Sum of all 16 bit words of the headers, zero for a valid file
+pub fn checksum(headers: &[u8]) -> i16 {
+    headers.chunks_exact(2).fold(0_i16, |acc, b| {
+        acc.wrapping_add(i16::from_le_bytes([b[0], b[1]]))
+    })
+}
+
+fn read_note(cursor: &mut Cursor<&[u8]>, bin_header: &BinHeader) -> SpmResult<String> {
     let note_size = match bin_header {
         BinHeader::V2(bh) => bh.note_size,
+        BinHeader::V3(bh) => bh.note_size,
         BinHeader::V5(bh) => bh.note_size,
-        _ => unreachable!("Only version 2 and version 5 bin headers implemented"),
+        BinHeader::V1(_) => 0,
     };
 
-    if note_size != 0 {
-        cursor.read_string(note_size as usize).replace("\r", "\n")
+    if note_size > 0 {
+        Ok(cursor.read_string(note_size as usize)?.replace('\r', "\n"))
     } else {
-        "".to_string()
+        Ok("".to_string())
     }
 }
 
-fn read_extended_data_units(cursor: &mut Cursor<&[u8]>, bin_header: &BinHeader) -> Option<String> {
+fn read_formula(cursor: &mut Cursor<&[u8]>, bin_header: &BinHeader) -> SpmResult<Option<String>> {
+    let formula_size = match bin_header {
+        BinHeader::V3(bh) => bh.formula_size,
+        BinHeader::V5(bh) => bh.formula_size,
+        _ => 0,
+    };
+
+    if formula_size > 0 {
+        let formula = cursor.read_string(formula_size as usize)?;
+        Ok(Some(formula.trim_end_matches(char::from(0)).to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+fn read_extended_data_units(
+    cursor: &mut Cursor<&[u8]>,
+    bin_header: &BinHeader,
+) -> SpmResult<Option<String>> {
     // extended data units
     match bin_header {
-        BinHeader::V5(bh) => {
-            if bh.data_e_units_size != 0 {
-                Some(cursor.read_string(bh.data_e_units_size as usize))
-            } else {
-                None
-            }
+        BinHeader::V5(bh) if bh.data_e_units_size > 0 => {
+            Ok(Some(cursor.read_string(bh.data_e_units_size as usize)?))
         }
-        _ => None,
+        _ => Ok(None),
     }
 }
 
-fn read_dim_e_units(cursor: &mut Cursor<&[u8]>, bin_header: &BinHeader) -> Option<Vec<String>> {
+fn read_dim_e_units(
+    cursor: &mut Cursor<&[u8]>,
+    bin_header: &BinHeader,
+) -> SpmResult<Option<Vec<String>>> {
     match bin_header {
-        BinHeader::V5(bh) => Some(
-            bh.dim_e_units_size
-                .iter()
-                .map(|i| {
-                    if *i != 0 {
-                        cursor.read_string(*i as usize)
-                    } else {
-                        "".to_string()
-                    }
-                })
-                .collect::<Vec<String>>(),
-        ),
-        _ => None,
+        BinHeader::V5(bh) => bh
+            .dim_e_units_size
+            .iter()
+            .map(|i| {
+                if *i > 0 {
+                    cursor.read_string(*i as usize)
+                } else {
+                    Ok("".to_string())
+                }
+            })
+            .collect::<SpmResult<Vec<String>>>()
+            .map(Some),
+        _ => Ok(None),
     }
 }
 
-fn read_dim_labels(cursor: &mut Cursor<&[u8]>, bin_header: &BinHeader) -> Option<Vec<String>> {
+fn read_dim_labels(
+    cursor: &mut Cursor<&[u8]>,
+    bin_header: &BinHeader,
+) -> SpmResult<Option<Vec<String>>> {
     match bin_header {
-        BinHeader::V5(bh) => Some(
-            bh.dim_labels_size
-                .iter()
-                .map(|i| {
-                    if *i != 0 {
-                        cursor.read_string(*i as usize)
-                    } else {
-                        "".to_string()
-                    }
-                })
-                .collect::<Vec<String>>(),
-        ),
-        _ => None,
+        BinHeader::V5(bh) => bh
+            .dim_labels_size
+            .iter()
+            .map(|i| {
+                if *i > 0 {
+                    cursor.read_string(*i as usize)
+                } else {
+                    Ok("".to_string())
+                }
+            })
+            .collect::<SpmResult<Vec<String>>>()
+            .map(Some),
+        _ => Ok(None),
     }
 }
 
-fn read_bin_header_2(cursor: &mut Cursor<&[u8]>) -> BinHeader {
-    let version = cursor.read_i16_le();
-    let wfm_size = cursor.read_i32_le();
-    let note_size = cursor.read_i32_le();
-    let pict_size = cursor.read_i32_le();
-    let checksum = cursor.read_i16_le();
+// The text of all strings, its size is only known from the wave size
+fn read_text_data(cursor: &mut Cursor<&[u8]>, bin_header: &BinHeader) -> Result<Vec<u8>> {
+    match bin_header {
+        BinHeader::V5(bh) => Ok(cursor.read_bytes((bh.wfm_size - 320).max(0) as usize)?),
+        _ => bail!("Text waves are only supported in version 5"),
+    }
+}
+
+// Offsets of the end of every string of a text wave
+fn read_s_indices(cursor: &mut Cursor<&[u8]>, bin_header: &BinHeader) -> SpmResult<Vec<i32>> {
+    match bin_header {
+        BinHeader::V5(bh) => (0..bh.s_indices_size / 4)
+            .map(|_| cursor.read_i32_le())
+            .collect(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn split_text(text: &[u8], s_indices: &[i32]) -> Vec<String> {
+    let mut start = 0;
+    s_indices
+        .iter()
+        .map(|end| {
+            let end = (*end as usize).clamp(start, text.len());
+            let s = String::from_utf8_lossy(&text[start..end]).to_string();
+            start = end;
+            s
+        })
+        .collect()
+}
+
+fn read_bin_header_1(cursor: &mut Cursor<&[u8]>) -> SpmResult<BinHeader> {
+    let version = cursor.read_i16_le()?;
+    let wfm_size = cursor.read_i32_le()?;
+    let checksum = cursor.read_i16_le()?;
+
+    Ok(BinHeader::V1(BinHeader1 {
+        version,
+        wfm_size,
+        checksum,
+    }))
+}
+
+fn read_bin_header_2(cursor: &mut Cursor<&[u8]>) -> SpmResult<BinHeader> {
+    let version = cursor.read_i16_le()?;
+    let wfm_size = cursor.read_i32_le()?;
+    let note_size = cursor.read_i32_le()?;
+    let pict_size = cursor.read_i32_le()?;
+    let checksum = cursor.read_i16_le()?;
 
-    BinHeader::V2(BinHeader2 {
+    Ok(BinHeader::V2(BinHeader2 {
         version,
         wfm_size,
         note_size,
         pict_size,
         checksum,
-    })
+    }))
+}
+
+fn read_bin_header_3(cursor: &mut Cursor<&[u8]>) -> SpmResult<BinHeader> {
+    let version = cursor.read_i16_le()?;
+    let wfm_size = cursor.read_i32_le()?;
+    let note_size = cursor.read_i32_le()?;
+    let formula_size = cursor.read_i32_le()?;
+    let pict_size = cursor.read_i32_le()?;
+    let checksum = cursor.read_i16_le()?;
+
+    Ok(BinHeader::V3(BinHeader3 {
+        version,
+        wfm_size,
+        note_size,
+        formula_size,
+        pict_size,
+        checksum,
+    }))
 }
 
-fn read_wave_header_2(cursor: &mut Cursor<&[u8]>) -> WaveHeader {
-    let type_ = cursor.read_i16_le();
-    let next = cursor.read_u32_le();
-    let bname = cursor.read_string(20);
-    let wh_version = cursor.read_i16_le();
-    let src_fldr = cursor.read_i16_le();
-    let file_name = cursor.read_u32_le();
-    let data_units = cursor.read_string(4);
-    let x_units = cursor.read_string(4);
-    let npnts = cursor.read_i32_le();
-    let a_modified = cursor.read_i16_le();
-    let hs_a = cursor.read_f64_le();
-    let hs_b = cursor.read_f64_le();
-    let w_modified = cursor.read_i16_le();
-    let sw_modified = cursor.read_i16_le();
-    let fs_valid = cursor.read_i16_le();
-    let top_full_scale = cursor.read_f64_le();
-    let bot_full_scale = cursor.read_f64_le();
-
-    let mut use_bits = [0_u8; 1];
-    cursor.read_exact(&mut use_bits).unwrap();
-
-    let mut kind_bits = [0_u8; 1];
-    cursor.read_exact(&mut kind_bits).unwrap();
-
-    let formula = cursor.read_u32_le();
-    let dep_id = cursor.read_i32_le();
-    let creation_date = cursor.read_u32_le();
-    let w_unused = cursor.read_string(2);
-    let mod_date = cursor.read_u32_le();
-    let wave_note_h = cursor.read_u32_le();
-
-    WaveHeader::V2(WaveHeader2 {
+fn read_wave_header_2(cursor: &mut Cursor<&[u8]>) -> SpmResult<WaveHeader> {
+    let type_ = cursor.read_i16_le()?;
+    let next = cursor.read_u32_le()?;
+    let bname = cursor.read_string(20)?;
+    let wh_version = cursor.read_i16_le()?;
+    let src_fldr = cursor.read_i16_le()?;
+    let file_name = cursor.read_u32_le()?;
+    let data_units = cursor.read_string(4)?;
+    let x_units = cursor.read_string(4)?;
+    let npnts = cursor.read_i32_le()?;
+    let a_modified = cursor.read_i16_le()?;
+    let hs_a = cursor.read_f64_le()?;
+    let hs_b = cursor.read_f64_le()?;
+    let w_modified = cursor.read_i16_le()?;
+    let sw_modified = cursor.read_i16_le()?;
+    let fs_valid = cursor.read_i16_le()?;
+    let top_full_scale = cursor.read_f64_le()?;
+    let bot_full_scale = cursor.read_f64_le()?;
+
+    let use_bits = cursor.read_u8_le()?;
+    let kind_bits = cursor.read_u8_le()?;
+
+    let formula = cursor.read_u32_le()?;
+    let dep_id = cursor.read_i32_le()?;
+    let creation_date = cursor.read_u32_le()?;
+    let w_unused = cursor.read_string(2)?;
+    let mod_date = cursor.read_u32_le()?;
+    let wave_note_h = cursor.read_u32_le()?;
+
+    Ok(WaveHeader::V2(WaveHeader2 {
         type_,
         next,
         bname,
@@ -390,40 +848,40 @@ fn read_wave_header_2(cursor: &mut Cursor<&[u8]>) -> WaveHeader {
         fs_valid,
         top_full_scale,
         bot_full_scale,
-        use_bits: use_bits[0] as char,
-        kind_bits: kind_bits[0] as char,
+        use_bits: use_bits as char,
+        kind_bits: kind_bits as char,
         formula,
         dep_id,
         creation_date,
         w_unused,
         mod_date,
         wave_note_h,
-    })
+    }))
 }
 
-fn read_bin_header_5(cursor: &mut Cursor<&[u8]>) -> BinHeader {
-    let version = cursor.read_i16_le();
-    let checksum = cursor.read_i16_le();
-    let wfm_size = cursor.read_i32_le();
-    let formula_size = cursor.read_i32_le();
-    let note_size = cursor.read_i32_le();
-    let data_e_units_size = cursor.read_i32_le();
+fn read_bin_header_5(cursor: &mut Cursor<&[u8]>) -> SpmResult<BinHeader> {
+    let version = cursor.read_i16_le()?;
+    let checksum = cursor.read_i16_le()?;
+    let wfm_size = cursor.read_i32_le()?;
+    let formula_size = cursor.read_i32_le()?;
+    let note_size = cursor.read_i32_le()?;
+    let data_e_units_size = cursor.read_i32_le()?;
 
     let mut dim_e_units_size = [0; 4];
     for i in dim_e_units_size.iter_mut() {
-        *i = cursor.read_i32_le();
+        *i = cursor.read_i32_le()?;
     }
 
     let mut dim_labels_size = [0; 4];
     for i in dim_labels_size.iter_mut() {
-        *i = cursor.read_i32_le();
+        *i = cursor.read_i32_le()?;
     }
 
-    let s_indices_size = cursor.read_i32_le();
-    let options_size_1 = cursor.read_i32_le();
-    let options_size_2 = cursor.read_i32_le();
+    let s_indices_size = cursor.read_i32_le()?;
+    let options_size_1 = cursor.read_i32_le()?;
+    let options_size_2 = cursor.read_i32_le()?;
 
-    BinHeader::V5(BinHeader5 {
+    Ok(BinHeader::V5(BinHeader5 {
         version,
         checksum,
         wfm_size,
@@ -435,82 +893,79 @@ fn read_bin_header_5(cursor: &mut Cursor<&[u8]>) -> BinHeader {
         s_indices_size,
         options_size_1,
         options_size_2,
-    })
+    }))
 }
 
-fn read_wave_header_5(cursor: &mut Cursor<&[u8]>) -> WaveHeader {
-    let next = cursor.read_u32_le();
-    let creation_date = cursor.read_u32_le();
-    let mod_date = cursor.read_u32_le();
-    let npnts = cursor.read_i32_le();
-    let type_ = cursor.read_i16_le();
-    let d_lock = cursor.read_i16_le();
-    let whpad1 = cursor.read_string(6);
-    let wh_version = cursor.read_i16_le();
-    let bname = cursor.read_string(32);
-    let whpad2 = cursor.read_i32_le();
-    let data_folder = cursor.read_u32_le();
+fn read_wave_header_5(cursor: &mut Cursor<&[u8]>) -> SpmResult<WaveHeader> {
+    let next = cursor.read_u32_le()?;
+    let creation_date = cursor.read_u32_le()?;
+    let mod_date = cursor.read_u32_le()?;
+    let npnts = cursor.read_i32_le()?;
+    let type_ = cursor.read_i16_le()?;
+    let d_lock = cursor.read_i16_le()?;
+    let whpad1 = cursor.read_string(6)?;
+    let wh_version = cursor.read_i16_le()?;
+    let bname = cursor.read_string(32)?;
+    let whpad2 = cursor.read_i32_le()?;
+    let data_folder = cursor.read_u32_le()?;
 
     let mut n_dim = [0; 4];
     for i in n_dim.iter_mut() {
-        *i = cursor.read_i32_le();
+        *i = cursor.read_i32_le()?;
     }
 
     let mut sf_a = [0_f64; 4];
     for i in sf_a.iter_mut() {
-        *i = cursor.read_f64_le();
+        *i = cursor.read_f64_le()?;
     }
 
     let mut sf_b = [0_f64; 4];
     for i in sf_b.iter_mut() {
-        *i = cursor.read_f64_le();
+        *i = cursor.read_f64_le()?;
     }
 
-    let data_units = cursor.read_string(4);
+    let data_units = cursor.read_string(4)?;
 
     let mut dim_units = [[0_u8; 4]; 4];
     for i in dim_units.iter_mut() {
-        cursor.read_exact(i).unwrap();
+        i.copy_from_slice(&cursor.read_bytes(4)?);
     }
-    let fs_valid = cursor.read_i16_le();
-    let whpad3 = cursor.read_i16_le();
-    let top_full_scale = cursor.read_f64_le();
-    let bot_full_scale = cursor.read_f64_le();
-    let data_e_units = cursor.read_u32_le();
+    let fs_valid = cursor.read_i16_le()?;
+    let whpad3 = cursor.read_i16_le()?;
+    let top_full_scale = cursor.read_f64_le()?;
+    let bot_full_scale = cursor.read_f64_le()?;
+    let data_e_units = cursor.read_u32_le()?;
 
     let mut dim_e_units = [0_u32; 4];
     for i in dim_e_units.iter_mut() {
-        *i = cursor.read_u32_le();
+        *i = cursor.read_u32_le()?;
     }
 
     let mut dim_labels = [0_u32; 4];
     for i in dim_labels.iter_mut() {
-        *i = cursor.read_u32_le();
+        *i = cursor.read_u32_le()?;
     }
 
-    let wave_note_h = cursor.read_u32_le();
+    let wave_note_h = cursor.read_u32_le()?;
 
     let mut wh_unused = [0_i32; 16];
     for i in wh_unused.iter_mut() {
-        *i = cursor.read_i32_le();
+        *i = cursor.read_i32_le()?;
     }
-    let a_modified = cursor.read_i16_le();
-    let w_modified = cursor.read_i16_le();
-    let sw_modified = cursor.read_i16_le();
-
-    let mut use_bits = [0_u8; 1];
-    cursor.read_exact(&mut use_bits).unwrap();
+    let a_modified = cursor.read_i16_le()?;
+    let w_modified = cursor.read_i16_le()?;
+    let sw_modified = cursor.read_i16_le()?;
 
-    let mut kind_bits = [0_u8; 1];
-    cursor.read_exact(&mut kind_bits).unwrap();
-    let formula = cursor.read_u32_le();
-    let dep_id = cursor.read_i32_le();
-    let whpad4 = cursor.read_i16_le();
-    let src_fldr = cursor.read_i16_le();
-    let file_name = cursor.read_u32_le();
-    let s_indeces = cursor.read_i32_le();
+    let use_bits = cursor.read_u8_le()?;
+    let kind_bits = cursor.read_u8_le()?;
+    let formula = cursor.read_u32_le()?;
+    let dep_id = cursor.read_i32_le()?;
+    let whpad4 = cursor.read_i16_le()?;
+    let src_fldr = cursor.read_i16_le()?;
+    let file_name = cursor.read_u32_le()?;
+    let s_indeces = cursor.read_i32_le()?;
 
-    WaveHeader::V5(WaveHeader5 {
+    Ok(WaveHeader::V5(WaveHeader5 {
         next,
         creation_date,
         mod_date,
@@ -539,93 +994,128 @@ fn read_wave_header_5(cursor: &mut Cursor<&[u8]>) -> WaveHeader {
         a_modified,
         w_modified,
         sw_modified,
-        use_bits: use_bits[0] as char,
-        kind_bits: kind_bits[0] as char,
+        use_bits: use_bits as char,
+        kind_bits: kind_bits as char,
         formula,
         dep_id,
         whpad4,
         src_fldr,
         file_name,
         s_indeces,
-    })
+    }))
 }
 
 fn read_numeric_data(
     cursor: &mut Cursor<&[u8]>,
     data_type: i16,
     num_data_points: i32,
-) -> NumericData {
-    match data_type {
-        0 => todo!("Text Waves"),
-        1 => todo!("Complex"),
+) -> Result<NumericData> {
+    // a corrupt header must not lead to a huge allocation
+    let capacity = (num_data_points.max(0) as usize).min(cursor.get_ref().len());
+    let data = match data_type {
+        0 => bail!("Text waves are read with read_text_data"),
+        1 => bail!("Unknown complex wave data type"),
         2 => {
-            let mut v = Vec::with_capacity((num_data_points / 4) as usize);
+            let mut v = Vec::with_capacity(capacity);
             for _ in 0..num_data_points {
-                v.push(cursor.read_f32_le());
+                v.push(cursor.read_f32_le()?);
             }
             NumericData::Float32(v)
         }
-        3 => todo!("Complex 64"),
+        3 => NumericData::Complex64(read_complex(cursor, num_data_points, capacity, |c| {
+            c.read_f32_le()
+        })?),
         4 => {
-            let mut v = Vec::with_capacity((num_data_points / 8) as usize);
+            let mut v = Vec::with_capacity(capacity);
             for _ in 0..num_data_points {
-                v.push(cursor.read_f64_le());
+                v.push(cursor.read_f64_le()?);
             }
             NumericData::Float64(v)
         }
-        5 => todo!("Complex 128"),
+        5 => NumericData::Complex128(read_complex(cursor, num_data_points, capacity, |c| {
+            c.read_f64_le()
+        })?),
         8 => {
-            let mut v = Vec::with_capacity((num_data_points) as usize);
+            let mut v = Vec::with_capacity(capacity);
             for _ in 0..num_data_points {
-                v.push(cursor.read_i8_le());
+                v.push(cursor.read_i8_le()?);
             }
             NumericData::Int8(v)
         }
-        9 => todo!("Complex Int8"),
+        9 => NumericData::ComplexInt8(read_complex(cursor, num_data_points, capacity, |c| {
+            c.read_i8_le()
+        })?),
         0x10 => {
-            let mut v = Vec::with_capacity((num_data_points / 2) as usize);
+            let mut v = Vec::with_capacity(capacity);
             for _ in 0..num_data_points {
-                v.push(cursor.read_i16_le());
+                v.push(cursor.read_i16_le()?);
             }
             NumericData::Int16(v)
         }
-        0x11 => todo!("Complex Int16"),
+        0x11 => NumericData::ComplexInt16(read_complex(cursor, num_data_points, capacity, |c| {
+            c.read_i16_le()
+        })?),
 
         0x20 => {
-            let mut v = Vec::with_capacity((num_data_points / 4) as usize);
+            let mut v = Vec::with_capacity(capacity);
             for _ in 0..num_data_points {
-                v.push(cursor.read_i32_le());
+                v.push(cursor.read_i32_le()?);
             }
             NumericData::Int32(v)
         }
-        0x21 => todo!("Complex Int32"),
+        0x21 => NumericData::ComplexInt32(read_complex(cursor, num_data_points, capacity, |c| {
+            c.read_i32_le()
+        })?),
 
         0x48 => {
-            let mut v = Vec::with_capacity((num_data_points) as usize);
+            let mut v = Vec::with_capacity(capacity);
             for _ in 0..num_data_points {
-                v.push(cursor.read_u8_le());
+                v.push(cursor.read_u8_le()?);
             }
             NumericData::Uint8(v)
         }
-        0x49 => todo!("Complex UInt8"),
+        0x49 => NumericData::ComplexUint8(read_complex(cursor, num_data_points, capacity, |c| {
+            c.read_u8_le()
+        })?),
 
         0x50 => {
-            let mut v = Vec::with_capacity((num_data_points / 2) as usize);
+            let mut v = Vec::with_capacity(capacity);
             for _ in 0..num_data_points {
-                v.push(cursor.read_u16_le());
+                v.push(cursor.read_u16_le()?);
             }
             NumericData::Uint16(v)
         }
-        0x51 => todo!("Complex UInt16 Data"),
+        0x51 => NumericData::ComplexUint16(read_complex(cursor, num_data_points, capacity, |c| {
+            c.read_u16_le()
+        })?),
 
         0x60 => {
-            let mut v = Vec::with_capacity((num_data_points / 4) as usize);
+            let mut v = Vec::with_capacity(capacity);
             for _ in 0..num_data_points {
-                v.push(cursor.read_u32_le());
+                v.push(cursor.read_u32_le()?);
             }
             NumericData::Uint32(v)
         }
-        0x61 => todo!("Complex UInt32 Data"),
-        _ => unreachable!(),
+        0x61 => NumericData::ComplexUint32(read_complex(cursor, num_data_points, capacity, |c| {
+            c.read_u32_le()
+        })?),
+        _ => bail!("Unknown wave data type {}", data_type),
+    };
+    Ok(data)
+}
+
+// Complex values are stored as real and imaginary part
+fn read_complex<T>(
+    cursor: &mut Cursor<&[u8]>,
+    num_data_points: i32,
+    capacity: usize,
+    read: impl Fn(&mut Cursor<&[u8]>) -> SpmResult<T>,
+) -> SpmResult<Vec<Complex<T>>> {
+    let mut v = Vec::with_capacity(capacity);
+    for _ in 0..num_data_points {
+        let re = read(cursor)?;
+        let im = read(cursor)?;
+        v.push(Complex::new(re, im));
     }
+    Ok(v)
 }
@@ -0,0 +1,181 @@
+/// Nearest neighbour distance of the Au(111) surface in nm
+pub const AU_111: f64 = 0.2884;
+/// Lattice constant of the graphite (HOPG) surface in nm
+pub const HOPG: f64 = 0.246;
+
+/// 2x2 matrix acting on (x, y) coordinates, row-major
+pub type Matrix2 = [[f64; 2]; 2];
+
+/// Local maximum of the power spectrum with frequencies in 1/nm
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BraggPeak {
+    pub fx: f64,
+    pub fy: f64,
+    pub intensity: f64,
+}
+
+/// Two dimensional lattice given by two real space vectors in nm, with x
+/// along the scan lines and y along the rows of the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lattice {
+    pub a: [f64; 2],
+    pub b: [f64; 2],
+}
+
+impl Lattice {
+    /// Hexagonal lattice with vectors at 120 degrees, e.g. `AU_111` or `HOPG`
+    pub fn hexagonal(constant: f64) -> Self {
+        Lattice {
+            a: [constant, 0.0],
+            b: [-0.5 * constant, 0.75f64.sqrt() * constant],
+        }
+    }
+
+    pub fn square(constant: f64) -> Self {
+        Lattice {
+            a: [constant, 0.0],
+            b: [0.0, constant],
+        }
+    }
+
+    /// Real space lattice of the reciprocal vectors `g1` and `g2` in 1/nm,
+    /// None if they are parallel
+    pub fn from_reciprocal(g1: [f64; 2], g2: [f64; 2]) -> Option<Self> {
+        // The rows of the reciprocal basis are the columns of the inverse
+        let inv = invert([g1, g2])?;
+        Some(Lattice {
+            a: [inv[0][0], inv[1][0]],
+            b: [inv[0][1], inv[1][1]],
+        })
+    }
+
+    /// Lengths of both vectors in nm
+    pub fn lengths(&self) -> (f64, f64) {
+        (self.a[0].hypot(self.a[1]), self.b[0].hypot(self.b[1]))
+    }
+
+    /// Angle between the vectors in degrees
+    pub fn angle(&self) -> f64 {
+        let dot = self.a[0] * self.b[0] + self.a[1] * self.b[1];
+        cross(self.a, self.b).abs().atan2(dot).to_degrees()
+    }
+
+    /// Affine correction that maps this lattice onto `reference`. The
+    /// reference is rotated to the orientation of `a` and mirrored to the
+    /// handedness of this lattice, so the correction contains no rotation of
+    /// the image, only scaling and shear. None for degenerate lattices.
+    pub fn correction_to(&self, reference: &Lattice) -> Option<Matrix2> {
+        let rotation = self.a[1].atan2(self.a[0]) - reference.a[1].atan2(reference.a[0]);
+        let (sin, cos) = rotation.sin_cos();
+        let rotate = |v: [f64; 2]| [cos * v[0] - sin * v[1], sin * v[0] + cos * v[1]];
+        let (ra, mut rb) = (rotate(reference.a), rotate(reference.b));
+        if cross(self.a, self.b).signum() != cross(ra, rb).signum() {
+            // Mirror b at the axis of a
+            let (ax, ay) = (ra[0] / reference.lengths().0, ra[1] / reference.lengths().0);
+            let dot = rb[0] * ax + rb[1] * ay;
+            rb = [2.0 * dot * ax - rb[0], 2.0 * dot * ay - rb[1]];
+        }
+        // M [a b] = [ra rb]
+        let observed = invert([[self.a[0], self.b[0]], [self.a[1], self.b[1]]])?;
+        let target = [[ra[0], rb[0]], [ra[1], rb[1]]];
+        Some(multiply(target, observed))
+    }
+}
+
+/// z component of the cross product
+pub fn cross(u: [f64; 2], v: [f64; 2]) -> f64 {
+    u[0] * v[1] - u[1] * v[0]
+}
+
+/// Inverse of `m`, None if it is singular
+pub fn invert(m: Matrix2) -> Option<Matrix2> {
+    let det = m[0][0] * m[1][1] - m[0][1] * m[1][0];
+    let scale = m[0][0].hypot(m[0][1]) * m[1][0].hypot(m[1][1]);
+    if det.abs() <= 1e-12 * scale || !det.is_finite() {
+        return None;
+    }
+    Some([
+        [m[1][1] / det, -m[0][1] / det],
+        [-m[1][0] / det, m[0][0] / det],
+    ])
+}
+
+fn multiply(a: Matrix2, b: Matrix2) -> Matrix2 {
+    let mut m = [[0.0; 2]; 2];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = a[i][0] * b[0][j] + a[i][1] * b[1][j];
+        }
+    }
+    m
+}
+
+/// Applies `m` to the vector `v`
+pub fn transform(m: Matrix2, v: [f64; 2]) -> [f64; 2] {
+    [
+        m[0][0] * v[0] + m[0][1] * v[1],
+        m[1][0] * v[0] + m[1][1] * v[1],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn assert_close(u: [f64; 2], v: [f64; 2]) {
+        assert!(
+            (u[0] - v[0]).abs() < 1e-12 && (u[1] - v[1]).abs() < 1e-12,
+            "{:?} {:?}",
+            u,
+            v
+        );
+    }
+
+    #[test]
+    fn test_hexagonal() {
+        let lattice = Lattice::hexagonal(HOPG);
+        let (a, b) = lattice.lengths();
+        assert!((a - HOPG).abs() < 1e-12);
+        assert!((b - HOPG).abs() < 1e-12);
+        assert!((lattice.angle() - 120.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_from_reciprocal() {
+        let lattice = Lattice::square(0.5);
+        let reciprocal = Lattice::from_reciprocal([2.0, 0.0], [0.0, 2.0]).unwrap();
+        assert_close(reciprocal.a, lattice.a);
+        assert_close(reciprocal.b, lattice.b);
+        assert!(Lattice::from_reciprocal([1.0, 1.0], [2.0, 2.0]).is_none());
+    }
+
+    #[test]
+    fn test_correction_to() {
+        let reference = Lattice::hexagonal(AU_111);
+        // Rotated by 30 degrees, stretched along x and sheared
+        let (sin, cos) = 30f64.to_radians().sin_cos();
+        let rotation = [[cos, -sin], [sin, cos]];
+        let distortion = multiply([[1.1, 0.05], [0.0, 0.95]], rotation);
+        let observed = Lattice {
+            a: transform(distortion, reference.a),
+            b: transform(distortion, reference.b),
+        };
+        let m = observed.correction_to(&reference).unwrap();
+        let corrected = Lattice {
+            a: transform(m, observed.a),
+            b: transform(m, observed.b),
+        };
+        let (a, b) = corrected.lengths();
+        assert!((a - AU_111).abs() < 1e-12);
+        assert!((b - AU_111).abs() < 1e-12);
+        assert!((corrected.angle() - 120.0).abs() < 1e-9);
+        // The direction of a is kept
+        assert!(cross(corrected.a, observed.a).abs() < 1e-12);
+
+        // The same lattice needs no correction
+        let m = reference.correction_to(&reference).unwrap();
+        assert_close(m[0], [1.0, 0.0]);
+        assert_close(m[1], [0.0, 1.0]);
+    }
+}
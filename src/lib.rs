@@ -1,7 +1,14 @@
+pub mod error;
+pub mod fft;
 pub mod igor_ibw;
+pub mod lattice;
 pub mod mulfile;
-// pub mod omicron_matrix;
-// pub mod rhk_sm4;
+pub mod omicron_matrix;
+pub mod rhk_sm4;
 mod rocket;
+pub mod spm_file;
 pub mod spm_image;
 mod utils;
+
+pub use error::SpmError;
+pub use spm_file::{open, Registry, SpmChannel, SpmFile, SpmReader, SpmSpectrum};
@@ -9,13 +9,7 @@ use std::{
 use anyhow::Result;
 use clap::Parser;
 use notify::Watcher;
-// use spm_rs::igor_ibw::read_ibw;
-use spm_rs::{
-    mulfile::{read_mul, MulImage},
-    spm_image::SpmImage,
-};
-
-// use spm_rs::rhk_sm4::read_rhk_sm4;
+use spm_rs::{spm_image::SpmImage, Registry};
 
 use eframe::{
     egui::{self},
@@ -54,17 +48,19 @@ fn main() -> Result<(), eframe::Error> {
 struct GuiFile {
     filename: String,
     gui_images: Vec<GuiImage>,
+    /// Problems while preparing the images, shown below the file name
+    warnings: Vec<String>,
 }
 
 #[derive(Debug)]
 struct GuiImage {
-    img: MulImage,
+    img: SpmImage,
     png: Vec<u8>,
 }
 
 impl GuiImage {
-    pub fn new(img: MulImage) -> Self {
-        let png = img.img_data.to_png_bytes();
+    pub fn new(img: SpmImage) -> Self {
+        let png = img.to_png_bytes();
         Self { img, png }
     }
 
@@ -73,7 +69,7 @@ impl GuiImage {
     }
 
     pub fn img_data(&self) -> &SpmImage {
-        &self.img.img_data
+        &self.img
     }
 
     pub fn xres(&self) -> usize {
@@ -98,63 +94,32 @@ struct MyApp {
     tx: Sender<PathBuf>,
     rx: Receiver<PathBuf>,
     scale_factor: f32,
+    registry: Registry,
 }
 
 impl MyApp {
     fn new(_cc: &eframe::CreationContext<'_>) -> Self {
         let args = Args::parse();
-        let mut images = BTreeMap::new();
+        let images = BTreeMap::new();
 
         let (tx, rx) = std::sync::mpsc::channel::<PathBuf>();
 
-        match args.filename {
-            Some(filename) if filename.ends_with(".mul") || filename.ends_with(".flm") => {
-                let mulfile = read_mul(&filename).unwrap();
-
-                let active_images = mulfile
-                    .iter()
-                    .map(|img| (img.img_id.clone(), false))
-                    .collect();
-
-                let gui_images: Vec<GuiImage> = mulfile
-                    .into_iter()
-                    .map(|mut img| {
-                        img.img_data.correct_plane();
-                        img.img_data.correct_lines();
-                        GuiImage::new(img)
-                    })
-                    .collect();
-
-                images.insert(
-                    filename.clone(),
-                    GuiFile {
-                        filename,
-                        gui_images,
-                    },
-                );
-
-                Self {
-                    files: images,
-                    active_images,
-                    start_rect: egui::Pos2::default(),
-                    end_rect: egui::Pos2::default(),
-                    file_watcher: None,
-                    tx,
-                    rx,
-                    scale_factor: 1.0,
-                }
-            }
-            _ => Self {
-                files: images,
-                active_images: HashMap::new(),
-                start_rect: egui::Pos2::default(),
-                end_rect: egui::Pos2::default(),
-                file_watcher: None,
-                tx,
-                rx,
-                scale_factor: 1.0,
-            },
+        let mut app = Self {
+            files: images,
+            active_images: HashMap::new(),
+            start_rect: egui::Pos2::default(),
+            end_rect: egui::Pos2::default(),
+            file_watcher: None,
+            tx,
+            rx,
+            scale_factor: 1.0,
+            registry: Registry::default(),
+        };
+
+        if let Some(filename) = args.filename {
+            app.load_file(PathBuf::from(filename));
         }
+        app
     }
 
     fn main_window(&mut self, ctx: &egui::Context) {
@@ -193,7 +158,7 @@ impl MyApp {
         if let Some(path) = rfd::FileDialog::new().pick_file() {
             if let Some(parent) = path.parent() {
                 for f in std::fs::read_dir(parent)? {
-                    self.load_mulfile(f?.path());
+                    self.load_file(f?.path());
                 }
 
                 let tx_clone = self.tx.clone();
@@ -204,8 +169,9 @@ impl MyApp {
                         Ok(event) => match event.kind {
                             notify::EventKind::Modify(notify::event::ModifyKind::Any)
                             | notify::EventKind::Create(notify::event::CreateKind::Any) => {
+                                // the format is detected from the content when loading
                                 if let Some(path) = event.paths.first() {
-                                    if path.extension().is_some_and(|x| x == "mul") {
+                                    if path.is_file() {
                                         let _ = tx_clone.send(path.into());
                                         ctx_clone.request_repaint();
                                     }
@@ -227,7 +193,10 @@ impl MyApp {
 
     fn grid_view(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
         for (f_name, gui_file) in self.files.iter() {
-            ui.label(f_name);
+            ui.label(&gui_file.filename);
+            for warning in &gui_file.warnings {
+                ui.colored_label(Color32::YELLOW, warning);
+            }
             egui::Grid::new(f_name)
                 .spacing(egui::vec2(5.0, 5.0))
                 .show(ui, |ui| {
@@ -264,9 +233,9 @@ impl MyApp {
         for (_, gui_file) in self.files.iter_mut() {
             for img in gui_file.gui_images.iter_mut() {
                 if self.active_images.get(&img.img_id()).is_some_and(|&x| x) {
-                    let new_viewport_id = egui::ViewportId::from_hash_of(&img.img_id());
+                    let new_viewport_id = egui::ViewportId::from_hash_of(img.img_id());
                     let new_viewport = egui::ViewportBuilder::default()
-                        .with_title(&img.img_id())
+                        .with_title(img.img_id())
                         .with_inner_size(egui::Vec2 {
                             x: img.xres() as f32 * self.scale_factor,
                             y: img.yres() as f32 * self.scale_factor,
@@ -407,35 +376,52 @@ impl MyApp {
         }
     }
 
-    fn load_mulfile(&mut self, p: PathBuf) {
-        if p.extension().is_some_and(|x| x == "mul") {
-            let filename = p.file_name().unwrap();
-
-            let mulfile = read_mul(&p.to_string_lossy()).unwrap();
-
-            for mul_img in mulfile.iter() {
-                self.active_images
-                    .entry(mul_img.img_id.clone())
-                    .or_insert(false);
-            }
-
-            let gui_images: Vec<GuiImage> = mulfile
-                .into_iter()
-                .map(|mut img| {
-                    img.img_data.correct_plane();
-                    img.img_data.correct_lines();
-                    GuiImage::new(img)
-                })
-                .collect();
-
-            self.files.insert(
-                filename.to_string_lossy().to_string(),
-                GuiFile {
-                    filename: filename.to_string_lossy().to_string(),
-                    gui_images,
-                },
-            );
+    fn load_file(&mut self, p: PathBuf) {
+        let Ok(spm_file) = self.registry.open(&p) else {
+            return;
+        };
+        let filename = p
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+
+        for img in spm_file.images() {
+            self.active_images
+                .entry(img.img_id.clone())
+                .or_insert(false);
         }
+
+        let mut warnings = Vec::new();
+        let gui_images: Vec<GuiImage> = spm_file
+            .into_images()
+            .into_iter()
+            .map(|mut img| {
+                // Images that cannot be levelled, e.g. without finite pixels, are shown as read
+                if let Err(err) = img.correct_plane() {
+                    warnings.push(format!(
+                        "Cannot correct the plane of {}: {}",
+                        img.img_id, err
+                    ));
+                }
+                if let Err(err) = img.correct_lines() {
+                    warnings.push(format!(
+                        "Cannot correct the lines of {}: {}",
+                        img.img_id, err
+                    ));
+                }
+                GuiImage::new(img)
+            })
+            .collect();
+
+        self.files.insert(
+            filename.clone(),
+            GuiFile {
+                filename,
+                gui_images,
+                warnings,
+            },
+        );
     }
 }
 
@@ -444,7 +430,7 @@ impl eframe::App for MyApp {
         self.main_window(ctx);
         self.analysis_windows(ctx);
         if let Ok(p) = self.rx.try_recv() {
-            self.load_mulfile(p);
+            self.load_file(p);
         };
     }
 }
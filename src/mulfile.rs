@@ -1,16 +1,21 @@
+use std::collections::BTreeMap;
 use std::fs;
-use std::io::{Cursor, Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
 use std::str;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use chrono::prelude::*;
 use chrono::{DateTime, Utc};
 
+use crate::error::{SpmError, SpmResult};
+use crate::spm_file::{SpmChannel, SpmFile, SpmReader};
 use crate::spm_image::flip_img_data;
 use crate::spm_image::SpmImage;
 use crate::utils::{read_i16_le_bytes, Bytereading};
 
+const MUL_BLOCK: i32 = 128;
+
 #[derive(Debug)]
 pub struct MulImage {
     pub filepath: PathBuf,
@@ -23,12 +28,12 @@ pub struct MulImage {
     pub yres: usize,
     pub zres: i16,
     pub datetime: DateTime<Utc>,
-    /// Size in physical units
-    pub xsize: i16,
-    /// Size in pixels
-    pub ysize: i16,
-    pub xoffset: i16,
-    pub yoffset: i16,
+    /// Size in nm
+    pub xsize: f64,
+    pub ysize: f64,
+    /// Offset in nm
+    pub xoffset: f64,
+    pub yoffset: f64,
     pub zscale: i16,
     pub tilt: i16,
     pub speed: f64,
@@ -45,11 +50,95 @@ pub struct MulImage {
     pub unitnr: i16,
     pub version: i16,
     pub gain: i16,
+    // Header as stored in the file, only the spare words without a field are
+    // written back
+    header: Option<[i16; 64]>,
+    pub point_scans: Vec<MulPointScan>,
     pub img_data: SpmImage,
 }
 
+/// Spectroscopy curve recorded at a single tip position during the image
+#[derive(Debug)]
+pub struct MulPointScan {
+    /// Number of data points
+    pub size: i16,
+    pub ps_type: i16,
+    pub time4scan: i16,
+    /// Start of the voltage sweep in mV
+    pub min_voltage: f64,
+    /// End of the voltage sweep in mV
+    pub max_voltage: f64,
+    /// Tip position in nm in the frame of the image, origin is the first pixel of `img_data`
+    pub xpos: f64,
+    pub ypos: f64,
+    /// End position in nm for scans along a line
+    pub xpos_end: f64,
+    pub ypos_end: f64,
+    pub dz: i16,
+    pub delay: i16,
+    pub version: i16,
+    pub indendelay: i16,
+    pub vt_fw: i16,
+    pub it_fw: i16,
+    pub vt_bw: i16,
+    pub it_bw: i16,
+    pub lscan: i16,
+    pub data: Vec<f64>,
+}
+
+impl MulPointScan {
+    /// Voltage in mV for each data point, the sweep is linear between min and max
+    pub fn voltages(&self) -> Vec<f64> {
+        let n = self.data.len();
+        if n < 2 {
+            return vec![self.min_voltage; n];
+        }
+        let step = (self.max_voltage - self.min_voltage) / (n - 1) as f64;
+        (0..n).map(|i| self.min_voltage + i as f64 * step).collect()
+    }
+}
+
+// Resolution and size in nm of the image a point scan belongs to, the size
+// keeps the 0.1 nm resolution of the header
+struct ImageFrame {
+    xres: usize,
+    yres: usize,
+    xsize: f64,
+    ysize: f64,
+}
+
+impl ImageFrame {
+    // Positions are stored in pixels of the scan, counted in the order the lines are stored.
+    // The lines are flipped after reading, so scan line `ypos` is row `yres - 1 - ypos`.
+    fn to_nm(&self, xpos: i16, ypos: i16) -> (f64, f64) {
+        let x = f64::from(xpos) * self.xsize / self.xres as f64;
+        let y = (self.yres as f64 - 1.0 - f64::from(ypos)) * self.ysize / self.yres as f64;
+        (x, y)
+    }
+
+    fn to_pixels(&self, x: f64, y: f64) -> (i16, i16) {
+        let xpos = x * self.xres as f64 / self.xsize;
+        let ypos = self.yres as f64 - 1.0 - y * self.yres as f64 / self.ysize;
+        (xpos.round() as i16, ypos.round() as i16)
+    }
+}
+
+// Image data and point scans are padded to full blocks
+fn align_to_block(cursor: &mut Cursor<&[u8]>) {
+    let block = MUL_BLOCK as u64;
+    cursor.set_position(cursor.position().div_ceil(block) * block);
+}
+
+fn dac_to_mv(value: i16) -> f64 {
+    -f64::from(value) / 3.2768
+}
+
+fn mv_to_dac(value: f64) -> i16 {
+    (-value * 3.2768).round() as i16
+}
+
 // Always length 21
-fn read_mul_string(cursor: &mut Cursor<&[u8]>) -> String {
+fn read_mul_string(cursor: &mut Cursor<&[u8]>) -> SpmResult<String> {
     cursor.read_string(21)
 }
 
@@ -67,10 +156,13 @@ fn read_mul_pixels(buffer: &[u8], zscale: i32) -> Vec<f64> {
     pixels
 }
 
-fn read_mul_img_data(cursor: &mut Cursor<&[u8]>, num_pixels: usize, zscale: i32) -> Vec<f64> {
-    let mut buffer = vec![0; num_pixels * 2];
-    cursor.read_exact(&mut buffer).unwrap();
-    read_mul_pixels(&buffer, zscale)
+fn read_mul_img_data(
+    cursor: &mut Cursor<&[u8]>,
+    num_pixels: usize,
+    zscale: i32,
+) -> SpmResult<Vec<f64>> {
+    let buffer = cursor.read_bytes(num_pixels * 2)?;
+    Ok(read_mul_pixels(&buffer, zscale))
 }
 
 // Point Scan Data
@@ -85,125 +177,189 @@ fn read_data_points(buffer: &[u8]) -> Vec<f64> {
     data_points
 }
 
-fn read_point_scan(cursor: &mut Cursor<&[u8]>, num_data_points: i32) -> Vec<f64> {
-    let mut buffer = vec![0; (num_data_points * 2) as usize];
-    cursor.read_exact(&mut buffer).unwrap();
-    read_data_points(&buffer)
+fn read_point_scan(cursor: &mut Cursor<&[u8]>, num_data_points: usize) -> SpmResult<Vec<f64>> {
+    let buffer = cursor.read_bytes(num_data_points * 2)?;
+    Ok(read_data_points(&buffer))
+}
+
+// Header of 18 values padded to one block, followed by the data points
+fn read_point_scan_block(
+    cursor: &mut Cursor<&[u8]>,
+    frame: &ImageFrame,
+) -> SpmResult<MulPointScan> {
+    let size = cursor.read_i16_le()?;
+    let ps_type = cursor.read_i16_le()?;
+    let time4scan = cursor.read_i16_le()?;
+    let minv = cursor.read_i16_le()?;
+    let maxv = cursor.read_i16_le()?;
+    let xpos = cursor.read_i16_le()?;
+    let ypos = cursor.read_i16_le()?;
+    let dz = cursor.read_i16_le()?;
+    let delay = cursor.read_i16_le()?;
+    let version = cursor.read_i16_le()?;
+    let indendelay = cursor.read_i16_le()?;
+    let xposend = cursor.read_i16_le()?;
+    let yposend = cursor.read_i16_le()?;
+    let vt_fw = cursor.read_i16_le()?;
+    let it_fw = cursor.read_i16_le()?;
+    let vt_bw = cursor.read_i16_le()?;
+    let it_bw = cursor.read_i16_le()?;
+    let lscan = cursor.read_i16_le()?;
+
+    cursor.skip((MUL_BLOCK - 18 * 2) as u64)?;
+
+    let data = read_point_scan(cursor, size.max(0) as usize)?;
+    align_to_block(cursor);
+
+    let (xpos, ypos) = frame.to_nm(xpos, ypos);
+    let (xpos_end, ypos_end) = frame.to_nm(xposend, yposend);
+    Ok(MulPointScan {
+        size,
+        ps_type,
+        time4scan,
+        min_voltage: dac_to_mv(minv),
+        max_voltage: dac_to_mv(maxv),
+        xpos,
+        ypos,
+        xpos_end,
+        ypos_end,
+        dz,
+        delay,
+        version,
+        indendelay,
+        vt_fw,
+        it_fw,
+        vt_bw,
+        it_bw,
+        lscan,
+        data,
+    })
 }
 
 pub fn read_mul(filename: &str) -> Result<Vec<MulImage>> {
-    const MUL_BLOCK: i32 = 128;
+    let bytes = fs::read(filename)?;
+    read_mul_from_bytes(&bytes, filename)
+}
+
+/// Reads all data from `reader`, `filename` is only used to name the images
+pub fn read_mul_from_reader<R: Read>(mut reader: R, filename: &str) -> Result<Vec<MulImage>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    read_mul_from_bytes(&bytes, filename)
+}
+
+/// Parses the content of a mul or flm file, `filename` is only used to name the images
+pub fn read_mul_from_bytes(bytes: &[u8], filename: &str) -> Result<Vec<MulImage>> {
     let mut block_counter = 0;
     let mut mul: Vec<MulImage> = Vec::new();
 
-    let bytes = fs::read(filename)?;
     let file_len = bytes.len();
-    let mut cursor = Cursor::new(bytes.as_slice());
+    let mut cursor = Cursor::new(bytes);
 
-    let _nr = cursor.read_i16_le();
-    let adr = cursor.read_i32_le();
+    let _nr = cursor.read_i16_le()?;
+    let adr = cursor.read_i32_le()?;
 
     if adr == 3 {
-        cursor
-            .seek(SeekFrom::Start((adr * MUL_BLOCK) as u64))
-            .expect("seeking to first block failed");
+        cursor.set_position((adr * MUL_BLOCK) as u64);
         block_counter += adr;
     } else {
-        cursor
-            .seek(SeekFrom::Start(0))
-            .expect("seeking to start failed");
+        cursor.set_position(0);
     }
 
     while block_counter * MUL_BLOCK < file_len as i32 {
-        let img_num = cursor.read_i16_le();
-        let size = cursor.read_i16_le();
-
-        // we know these must be positive
-        let xres = cursor.read_i16_le() as usize;
-        let yres = cursor.read_i16_le() as usize;
-        let zres = cursor.read_i16_le();
-
-        let year = cursor.read_i16_le();
-        let month = cursor.read_i16_le();
-        let day = cursor.read_i16_le();
-        let hour = cursor.read_i16_le();
-        let minute = cursor.read_i16_le();
-        let second = cursor.read_i16_le();
-
-        let xsize = cursor.read_i16_le() / 10; // in nm
-        let ysize = cursor.read_i16_le() / 10; // in nm
-
-        let xoffset = cursor.read_i16_le() / 10; // in nm
-        let yoffset = cursor.read_i16_le() / 10; // in nm
-
-        let zscale = cursor.read_i16_le();
-        let tilt = cursor.read_i16_le();
-        let speed = cursor.read_i16_le() / 100; // in s
-
-        let bias = f64::from(cursor.read_i16_le());
-        let current = cursor.read_i16_le();
-
-        let sample = read_mul_string(&mut cursor);
-        let title = read_mul_string(&mut cursor);
-
-        let postpr = cursor.read_i16_le();
-        let postd1 = cursor.read_i16_le();
-        let mode = cursor.read_i16_le();
-        let currfac = cursor.read_i16_le();
-        let num_pointscans = cursor.read_i16_le();
-        let unitnr = cursor.read_i16_le();
-        let version = cursor.read_i16_le();
-
-        let _spare_48 = cursor.read_i16_le();
-        let _spare_49 = cursor.read_i16_le();
-        let _spare_50 = cursor.read_i16_le();
-        let _spare_51 = cursor.read_i16_le();
-        let _spare_52 = cursor.read_i16_le();
-        let _spare_53 = cursor.read_i16_le();
-        let _spare_54 = cursor.read_i16_le();
-        let _spare_54 = cursor.read_i16_le();
-        let _spare_56 = cursor.read_i16_le();
-        let _spare_57 = cursor.read_i16_le();
-        let _spare_58 = cursor.read_i16_le();
-        let _spare_59 = cursor.read_i16_le();
-
-        let gain = cursor.read_i16_le();
-
-        let _spare_61 = cursor.read_i16_le();
-        let _spare_62 = cursor.read_i16_le();
-        let _spare_63 = cursor.read_i16_le();
-
-        let img_data = read_mul_img_data(&mut cursor, xres * yres, zscale.into());
-
-        if num_pointscans > 0 {
-            for _ in 0..num_pointscans {
-                let _ps_size = cursor.read_i16_le();
-                let _ps_type = cursor.read_i16_le();
-                let _ps_time4scan = cursor.read_i16_le();
-                let _ps_minv = cursor.read_i16_le();
-                let _ps_maxv = cursor.read_i16_le();
-                let _ps_xpos = cursor.read_i16_le();
-                let _ps_ypos = cursor.read_i16_le();
-                let _ps_dz = cursor.read_i16_le();
-                let _ps_delay = cursor.read_i16_le();
-                let _ps_version = cursor.read_i16_le();
-                let _ps_indendelay = cursor.read_i16_le();
-                let _ps_xposend = cursor.read_i16_le();
-                let _ps_yposend = cursor.read_i16_le();
-                let _ps_vt_fw = cursor.read_i16_le();
-                let _ps_it_fw = cursor.read_i16_le();
-                let _ps_vt_bw = cursor.read_i16_le();
-                let _ps_it_bw = cursor.read_i16_le();
-                let _ps_lscan = cursor.read_i16_le();
-
-                let _ = cursor.seek(SeekFrom::Current((MUL_BLOCK - 18 * 2) as i64));
-
-                let _ps_data = read_point_scan(&mut cursor, _ps_size as i32);
+        let img_num = cursor.read_i16_le()?;
+        let size = cursor.read_i16_le()?;
+
+        let xres = cursor.read_i16_le()?;
+        let yres = cursor.read_i16_le()?;
+        let zres = cursor.read_i16_le()?;
+        let invalid = [("size", size, 1), ("xres", xres, 0), ("yres", yres, 0)]
+            .into_iter()
+            .find(|(_, value, min)| value < min);
+        if let Some((field, value, _)) = invalid {
+            return Err(SpmError::InvalidHeader {
+                format: "mul",
+                offset: (block_counter * MUL_BLOCK) as u64,
+                field,
+                value: value.into(),
             }
+            .into());
+        }
+        let xres = xres as usize;
+        let yres = yres as usize;
+
+        let year = cursor.read_i16_le()?;
+        let month = cursor.read_i16_le()?;
+        let day = cursor.read_i16_le()?;
+        let hour = cursor.read_i16_le()?;
+        let minute = cursor.read_i16_le()?;
+        let second = cursor.read_i16_le()?;
+
+        let xsize = f64::from(cursor.read_i16_le()?) / 10.0; // in nm
+        let ysize = f64::from(cursor.read_i16_le()?) / 10.0; // in nm
+
+        let xoffset = f64::from(cursor.read_i16_le()?) / 10.0; // in nm
+        let yoffset = f64::from(cursor.read_i16_le()?) / 10.0; // in nm
+
+        let zscale = cursor.read_i16_le()?;
+        let tilt = cursor.read_i16_le()?;
+        let speed = f64::from(cursor.read_i16_le()?) / 100.0; // in s
+
+        let bias = cursor.read_i16_le()?;
+        let current = cursor.read_i16_le()?;
+
+        let sample = read_mul_string(&mut cursor)?;
+        let title = read_mul_string(&mut cursor)?;
+
+        let postpr = cursor.read_i16_le()?;
+        let postd1 = cursor.read_i16_le()?;
+        let mode = cursor.read_i16_le()?;
+        let currfac = cursor.read_i16_le()?;
+        let num_pointscans = cursor.read_i16_le()?;
+        let unitnr = cursor.read_i16_le()?;
+        let version = cursor.read_i16_le()?;
+
+        let _spare_48 = cursor.read_i16_le()?;
+        let _spare_49 = cursor.read_i16_le()?;
+        let _spare_50 = cursor.read_i16_le()?;
+        let _spare_51 = cursor.read_i16_le()?;
+        let _spare_52 = cursor.read_i16_le()?;
+        let _spare_53 = cursor.read_i16_le()?;
+        let _spare_54 = cursor.read_i16_le()?;
+        let _spare_54 = cursor.read_i16_le()?;
+        let _spare_56 = cursor.read_i16_le()?;
+        let _spare_57 = cursor.read_i16_le()?;
+        let _spare_58 = cursor.read_i16_le()?;
+        let _spare_59 = cursor.read_i16_le()?;
+
+        let gain = cursor.read_i16_le()?;
+
+        let _spare_61 = cursor.read_i16_le()?;
+        let _spare_62 = cursor.read_i16_le()?;
+        let _spare_63 = cursor.read_i16_le()?;
+
+        let header_start = (block_counter * MUL_BLOCK) as usize;
+        let mut header = [0; 64];
+        for (x, b) in header.iter_mut().zip(bytes[header_start..].chunks_exact(2)) {
+            *x = read_i16_le_bytes(b);
+        }
+
+        let img_data = read_mul_img_data(&mut cursor, xres * yres, zscale.into())?;
+        align_to_block(&mut cursor);
+
+        let mut point_scans = Vec::with_capacity(num_pointscans.max(0) as usize);
+        for _ in 0..num_pointscans {
+            let frame = ImageFrame {
+                xres,
+                yres,
+                xsize,
+                ysize,
+            };
+            point_scans.push(read_point_scan_block(&mut cursor, &frame)?);
         }
 
-        let line_time = f64::from(speed) / (yres as f64) * 1000.0; // in ms
-        let bias = -bias / 3.2768; //  in mV
+        let line_time = speed / (yres as f64) * 1000.0; // in ms
+        let bias = dac_to_mv(bias); //  in mV
         let current = f64::from(current) * f64::from(currfac) * 0.01; // in nA
 
         let datetime = Utc
@@ -216,10 +372,16 @@ pub fn read_mul(filename: &str) -> Result<Vec<MulImage>> {
                 second as u32,
             )
             .single()
-            .expect("Parsing datetime failed");
+            .ok_or_else(|| SpmError::InvalidDate {
+                format: "mul",
+                date: format!("{}-{}-{} {}:{}:{}", year, month, day, hour, minute, second),
+            })?;
 
         let filepath = PathBuf::from(&filename);
-        let basename = filepath.file_stem().unwrap().to_str().unwrap();
+        let basename = filepath
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
         let img_id = format!("{}_{}", basename, img_num);
         let img_data = flip_img_data(img_data, xres as u32, yres as u32);
 
@@ -240,7 +402,7 @@ pub fn read_mul(filename: &str) -> Result<Vec<MulImage>> {
             yoffset,
             zscale,
             tilt,
-            speed: speed as f64,
+            speed,
             line_time,
             bias,
             current,
@@ -254,20 +416,291 @@ pub fn read_mul(filename: &str) -> Result<Vec<MulImage>> {
             unitnr,
             version,
             gain,
+            header: Some(header),
+            point_scans,
             img_data: SpmImage {
                 img_id,
                 xres,
                 yres,
-                xsize: xsize as f64,
-                ysize: ysize as f64,
+                xsize,
+                ysize,
                 img_data,
             },
         })
     }
-    assert_eq! {block_counter * MUL_BLOCK, bytes.len() as i32};
+    if block_counter * MUL_BLOCK != file_len as i32 {
+        return Err(SpmError::LengthMismatch {
+            format: "mul",
+            end: (block_counter * MUL_BLOCK) as u64,
+            len: file_len as u64,
+        }
+        .into());
+    }
     Ok(mul)
 }
 
+/// Writes images in the .mul layout with a file header of 3 blocks
+pub fn write_mul(filename: &str, images: &[MulImage]) -> Result<()> {
+    fs::write(filename, write_mul_to_bytes(images)?)?;
+    Ok(())
+}
+
+pub fn write_mul_to_writer<W: Write>(mut writer: W, images: &[MulImage]) -> Result<()> {
+    writer.write_all(&write_mul_to_bytes(images)?)?;
+    Ok(())
+}
+
+pub fn write_mul_to_bytes(images: &[MulImage]) -> Result<Vec<u8>> {
+    let adr: i32 = 3;
+    let mut buffer = Vec::new();
+    write_i16(&mut buffer, images.len().try_into()?);
+    buffer.extend(adr.to_le_bytes());
+    buffer.resize((adr * MUL_BLOCK) as usize, 0);
+
+    for img in images {
+        write_mul_image(&mut buffer, img)?;
+    }
+    Ok(buffer)
+}
+
+fn write_mul_image(buffer: &mut Vec<u8>, img: &MulImage) -> Result<()> {
+    let (xres, yres) = (img.img_data.xres, img.img_data.yres);
+    if img.img_data.img_data.len() != xres * yres {
+        bail!(
+            "Image {} has {} pixels, expected {}x{}",
+            img.img_id,
+            img.img_data.img_data.len(),
+            xres,
+            yres
+        );
+    }
+    let num_blocks = |num_bytes: usize| num_bytes.div_ceil(MUL_BLOCK as usize);
+    let size = 1
+        + num_blocks(xres * yres * 2)
+        + img
+            .point_scans
+            .iter()
+            .map(|ps| 1 + num_blocks(ps.data.len() * 2))
+            .sum::<usize>();
+    let current = if img.currfac == 0 {
+        0
+    } else {
+        (img.current / (f64::from(img.currfac) * 0.01)).round() as i16
+    };
+
+    let header = [
+        img.img_num,
+        size.try_into()?,
+        xres.try_into()?,
+        yres.try_into()?,
+        img.zres,
+        img.datetime.year().try_into()?,
+        img.datetime.month() as i16,
+        img.datetime.day() as i16,
+        img.datetime.hour() as i16,
+        img.datetime.minute() as i16,
+        img.datetime.second() as i16,
+        (img.xsize * 10.0).round() as i16,
+        (img.ysize * 10.0).round() as i16,
+        (img.xoffset * 10.0).round() as i16,
+        (img.yoffset * 10.0).round() as i16,
+        img.zscale,
+        img.tilt,
+        (img.speed * 100.0).round() as i16,
+        mv_to_dac(img.bias),
+        current,
+    ];
+    for value in header {
+        write_i16(buffer, value);
+    }
+    write_mul_string(buffer, &img.sample);
+    write_mul_string(buffer, &img.title);
+    let values = [
+        img.postpr,
+        img.postd1,
+        img.mode,
+        img.currfac,
+        img.point_scans.len().try_into()?,
+        img.unitnr,
+        img.version,
+    ];
+    for value in values {
+        write_i16(buffer, value);
+    }
+    // spare values around the gain, zero for new images
+    let spare = img.header.unwrap_or([0; 64]);
+    for value in &spare[48..60] {
+        write_i16(buffer, *value);
+    }
+    write_i16(buffer, img.gain);
+    for value in &spare[61..] {
+        write_i16(buffer, *value);
+    }
+    pad_to_block(buffer);
+
+    let img_data = flip_img_data(img.img_data.img_data.clone(), xres as u32, yres as u32);
+    for pixel in img_data {
+        write_i16(buffer, mul_pixel_to_raw(pixel, img.zscale.into()));
+    }
+    pad_to_block(buffer);
+
+    let frame = ImageFrame {
+        xres,
+        yres,
+        xsize: img.xsize,
+        ysize: img.ysize,
+    };
+    for ps in &img.point_scans {
+        write_point_scan_block(buffer, ps, &frame)?;
+    }
+    Ok(())
+}
+
+fn write_point_scan_block(
+    buffer: &mut Vec<u8>,
+    ps: &MulPointScan,
+    frame: &ImageFrame,
+) -> Result<()> {
+    let (xpos, ypos) = frame.to_pixels(ps.xpos, ps.ypos);
+    let (xposend, yposend) = frame.to_pixels(ps.xpos_end, ps.ypos_end);
+    let header = [
+        ps.data.len().try_into()?,
+        ps.ps_type,
+        ps.time4scan,
+        mv_to_dac(ps.min_voltage),
+        mv_to_dac(ps.max_voltage),
+        xpos,
+        ypos,
+        ps.dz,
+        ps.delay,
+        ps.version,
+        ps.indendelay,
+        xposend,
+        yposend,
+        ps.vt_fw,
+        ps.it_fw,
+        ps.vt_bw,
+        ps.it_bw,
+        ps.lscan,
+    ];
+    for value in header {
+        write_i16(buffer, value);
+    }
+    pad_to_block(buffer);
+    for x in &ps.data {
+        write_i16(buffer, x.round() as i16);
+    }
+    pad_to_block(buffer);
+    Ok(())
+}
+
+// Inverse of read_mul_pixels, values outside of the i16 range are clamped
+fn mul_pixel_to_raw(pixel: f64, zscale: i32) -> i16 {
+    if zscale == 0 {
+        return 0;
+    }
+    (pixel * 2000.0 / f64::from(zscale) * 1.36 / -0.1).round() as i16
+}
+
+// Strings are stored with a fixed length of 21 bytes
+fn write_mul_string(buffer: &mut Vec<u8>, s: &str) {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.resize(21, b' ');
+    buffer.extend(bytes);
+}
+
+fn write_i16(buffer: &mut Vec<u8>, value: i16) {
+    buffer.extend(value.to_le_bytes());
+}
+
+fn pad_to_block(buffer: &mut Vec<u8>) {
+    buffer.resize(
+        buffer.len().div_ceil(MUL_BLOCK as usize) * MUL_BLOCK as usize,
+        0,
+    );
+}
+
+pub struct MulReader;
+
+impl SpmReader for MulReader {
+    fn name(&self) -> &'static str {
+        "mul"
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &[".mul", ".flm"]
+    }
+
+    // Files consist of 128 byte blocks, .mul files start with a header of 3 blocks,
+    // .flm files directly with the first image
+    fn detect(&self, head: &[u8], file_len: u64) -> f32 {
+        if file_len == 0 || !file_len.is_multiple_of(MUL_BLOCK as u64) || head.len() < 6 {
+            return 0.0;
+        }
+        let adr = i32::from_le_bytes([head[2], head[3], head[4], head[5]]);
+        let start = if adr == 3 {
+            (adr * MUL_BLOCK) as usize
+        } else {
+            0
+        };
+        if head.len() < start + 22 {
+            return 0.0;
+        }
+        let fields: Vec<i32> = head[start..start + 22]
+            .chunks_exact(2)
+            .map(|b| read_i16_le_bytes(b).into())
+            .collect();
+        let [_img_num, size, xres, yres, _zres, _year, month, day, hour, minute, second] =
+            fields[..]
+        else {
+            return 0.0;
+        };
+        let plausible = size > 0
+            && xres > 0
+            && yres > 0
+            && xres * yres * 2 + MUL_BLOCK <= size * MUL_BLOCK
+            && (1..=12).contains(&month)
+            && (1..=31).contains(&day)
+            && (0..24).contains(&hour)
+            && (0..60).contains(&minute)
+            && (0..60).contains(&second);
+        if plausible {
+            0.8
+        } else {
+            0.0
+        }
+    }
+
+    fn read(&self, filename: &str) -> Result<SpmFile> {
+        self.read_bytes(filename, &fs::read(filename)?)
+    }
+
+    fn read_bytes(&self, filename: &str, bytes: &[u8]) -> Result<SpmFile> {
+        let mut spm_file = SpmFile::new(Path::new(filename), self.name());
+        for img in read_mul_from_bytes(bytes, filename)? {
+            let metadata = BTreeMap::from([
+                ("img_num".to_string(), img.img_num.to_string()),
+                ("datetime".to_string(), img.datetime.to_string()),
+                ("bias [mV]".to_string(), img.bias.to_string()),
+                ("current [nA]".to_string(), img.current.to_string()),
+                ("xoffset [nm]".to_string(), img.xoffset.to_string()),
+                ("yoffset [nm]".to_string(), img.yoffset.to_string()),
+                ("speed [s]".to_string(), img.speed.to_string()),
+                ("line_time [ms]".to_string(), img.line_time.to_string()),
+                ("gain".to_string(), img.gain.to_string()),
+                ("point_scans".to_string(), img.point_scans.len().to_string()),
+                ("sample".to_string(), img.sample.trim().to_string()),
+                ("title".to_string(), img.title.trim().to_string()),
+            ]);
+            spm_file.channels.push(SpmChannel {
+                metadata,
+                image: img.img_data,
+            });
+        }
+        Ok(spm_file)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -279,6 +712,174 @@ mod tests {
         assert_eq!(21, s.len());
         let buffer = s.as_bytes().to_vec();
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(read_mul_string(&mut cursor), s);
+        assert_eq!(read_mul_string(&mut cursor).unwrap(), s);
+    }
+
+    // Single 2x2 image as in a flm file, header block followed by one data block
+    fn synthetic_flm() -> Vec<u8> {
+        let header: [i16; 11] = [1, 2, 2, 2, 0, 2020, 11, 11, 12, 30, 0];
+        let mut bytes: Vec<u8> = header.iter().flat_map(|x| x.to_le_bytes()).collect();
+        bytes.resize(MUL_BLOCK as usize, 0);
+        for pixel in [0_i16, 1, 2, 3] {
+            bytes.extend(pixel.to_le_bytes());
+        }
+        bytes.resize(2 * MUL_BLOCK as usize, 0);
+        bytes
+    }
+
+    #[test]
+    fn test_read_mul_from_bytes() {
+        let mul = read_mul_from_bytes(&synthetic_flm(), "synthetic.flm").unwrap();
+        assert_eq!(mul.len(), 1);
+        assert_eq!(mul[0].img_id, "synthetic_1");
+        assert_eq!((mul[0].xres, mul[0].yres), (2, 2));
+        assert_eq!(mul[0].datetime.to_string(), "2020-11-11 12:30:00 UTC");
+    }
+
+    #[test]
+    fn test_read_mul_truncated() {
+        let bytes = synthetic_flm();
+        assert!(read_mul_from_bytes(&bytes[..MUL_BLOCK as usize + 4], "synthetic.flm").is_err());
+    }
+
+    #[test]
+    fn test_read_mul_corrupt_header() {
+        let error = |bytes: &[u8]| {
+            read_mul_from_bytes(bytes, "synthetic.flm")
+                .unwrap_err()
+                .downcast::<SpmError>()
+                .unwrap()
+        };
+        let mut bytes = synthetic_flm();
+        bytes[4..6].copy_from_slice(&(-2_i16).to_le_bytes());
+        assert_eq!(
+            error(&bytes),
+            SpmError::InvalidHeader {
+                format: "mul",
+                offset: 0,
+                field: "xres",
+                value: -2
+            }
+        );
+
+        let mut bytes = synthetic_flm();
+        bytes[12..14].copy_from_slice(&13_i16.to_le_bytes());
+        assert!(matches!(error(&bytes), SpmError::InvalidDate { .. }));
+
+        // 3 blocks in the header, but only 2 in the file
+        let mut bytes = synthetic_flm();
+        bytes[2..4].copy_from_slice(&3_i16.to_le_bytes());
+        assert_eq!(
+            error(&bytes),
+            SpmError::LengthMismatch {
+                format: "mul",
+                end: 384,
+                len: 256
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_point_scan() {
+        let mut bytes = synthetic_flm();
+        // image size in blocks and number of point scans
+        bytes[2..4].copy_from_slice(&4_i16.to_le_bytes());
+        bytes[90..92].copy_from_slice(&1_i16.to_le_bytes());
+        bytes[22..24].copy_from_slice(&10_i16.to_le_bytes()); // xsize 1 nm
+        bytes[24..26].copy_from_slice(&10_i16.to_le_bytes()); // ysize 1 nm
+        let ps_header: [i16; 7] = [4, 0, 0, -3277, 3277, 1, 0];
+        let mut ps: Vec<u8> = ps_header.iter().flat_map(|x| x.to_le_bytes()).collect();
+        ps.resize(MUL_BLOCK as usize, 0);
+        for x in [10_i16, 20, 30, 40] {
+            ps.extend(x.to_le_bytes());
+        }
+        ps.resize(2 * MUL_BLOCK as usize, 0);
+        bytes.extend(ps);
+
+        let mul = read_mul_from_bytes(&bytes, "synthetic.flm").unwrap();
+        let ps = &mul[0].point_scans;
+        assert_eq!(ps.len(), 1);
+        assert_eq!(ps[0].data, vec![10.0, 20.0, 30.0, 40.0]);
+        let voltages: Vec<_> = ps[0].voltages().iter().map(|v| v.round()).collect();
+        assert_eq!(voltages, vec![1000.0, 333.0, -333.0, -1000.0]);
+        assert_eq!((ps[0].xpos, ps[0].ypos), (0.5, 0.5));
+    }
+
+    #[test]
+    fn test_point_scan_on_image() {
+        // 4x4 image of 1 nm pixels with a single marked pixel at column 3 of scan line 1,
+        // the point scan was taken there
+        let mut bytes = synthetic_flm();
+        bytes.truncate(MUL_BLOCK as usize);
+        let header: [(usize, i16); 7] =
+            [(1, 4), (2, 4), (3, 4), (11, 40), (12, 40), (15, 1), (45, 1)];
+        for (word, value) in header {
+            bytes[2 * word..2 * word + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        let mut pixels = [0_i16; 16];
+        pixels[4 + 3] = -100;
+        bytes.extend(pixels.iter().flat_map(|x| x.to_le_bytes()));
+        bytes.resize(2 * MUL_BLOCK as usize, 0);
+        let ps_header: [i16; 7] = [0, 0, 0, 0, 0, 3, 1];
+        bytes.extend(ps_header.iter().flat_map(|x| x.to_le_bytes()));
+        bytes.resize(4 * MUL_BLOCK as usize, 0);
+
+        let mul = read_mul_from_bytes(&bytes, "synthetic.flm").unwrap();
+        let image = &mul[0].img_data;
+        let ps = &mul[0].point_scans[0];
+        assert_eq!((ps.xpos, ps.ypos), (3.0, 2.0));
+        let (col, row) = (ps.xpos as usize, ps.ypos as usize);
+        let marked = image.img_data.iter().position(|&z| z != 0.0).unwrap();
+        assert_eq!(marked, row * image.xres + col);
+    }
+
+    #[test]
+    fn test_point_scan_fractional_size() {
+        let frame = ImageFrame {
+            xres: 2,
+            yres: 2,
+            xsize: 1.5,
+            ysize: 2.5,
+        };
+        assert_eq!(frame.to_nm(1, 0), (0.75, 1.25));
+        assert_eq!(frame.to_pixels(0.75, 1.25), (1, 0));
+
+        // Sizes of 1.5 nm and 2.5 nm in the header
+        let mut bytes = synthetic_flm();
+        bytes[2..4].copy_from_slice(&3_i16.to_le_bytes());
+        bytes[90..92].copy_from_slice(&1_i16.to_le_bytes());
+        bytes[22..24].copy_from_slice(&15_i16.to_le_bytes());
+        bytes[24..26].copy_from_slice(&25_i16.to_le_bytes());
+        let ps_header: [i16; 7] = [0, 0, 0, 0, 0, 1, 0];
+        for x in ps_header {
+            bytes.extend(x.to_le_bytes());
+        }
+        bytes.resize(3 * MUL_BLOCK as usize, 0);
+        let mul = read_mul_from_bytes(&bytes, "synthetic.flm").unwrap();
+        assert_eq!((mul[0].xsize, mul[0].ysize), (1.5, 2.5));
+        let ps = &mul[0].point_scans[0];
+        assert_eq!((ps.xpos, ps.ypos), (0.75, 1.25));
+    }
+
+    #[test]
+    fn test_write_point_scan() {
+        let mut bytes = synthetic_flm();
+        bytes[2..4].copy_from_slice(&4_i16.to_le_bytes());
+        bytes[90..92].copy_from_slice(&1_i16.to_le_bytes());
+        bytes[22..24].copy_from_slice(&10_i16.to_le_bytes());
+        bytes[24..26].copy_from_slice(&10_i16.to_le_bytes());
+        bytes[30..32].copy_from_slice(&1_i16.to_le_bytes()); // zscale
+        let ps_header: [i16; 18] = [2, 1, 5, -3277, 3277, 1, 0, 3, 4, 1, 0, 0, 1, 1, 2, 3, 4, 1];
+        for x in ps_header {
+            bytes.extend(x.to_le_bytes());
+        }
+        bytes.resize(3 * MUL_BLOCK as usize, 0);
+        bytes.extend([7, 0, 9, 0]);
+        bytes.resize(4 * MUL_BLOCK as usize, 0);
+
+        let mul = read_mul_from_bytes(&bytes, "synthetic.flm").unwrap();
+        let written = write_mul_to_bytes(&mul).unwrap();
+        assert_eq!(&written[..6], &[1, 0, 3, 0, 0, 0]);
+        assert_eq!(&written[3 * MUL_BLOCK as usize..], &bytes[..]);
     }
 }
@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use crate::omicron_matrix::paramfile::{
+    read_ident_block, IdentBlock, MatrixType, TransferFunction,
+};
+use crate::utils::expect_magic;
+
+/// Everything the paramfile records about a session: the system, the
+/// instrument setup and the history of all parameter changes
+#[derive(Debug)]
+pub struct MatrixExperiment {
+    /// program_name, version, profile and user from META
+    pub system: BTreeMap<String, String>,
+    /// Components of the instrument from INST
+    pub components: Vec<MatrixComponent>,
+    /// Connections between the components from CNXS
+    pub connections: Vec<MatrixConnection>,
+    /// Channels by name from DICT and XFER, the latest definition wins
+    pub channels: BTreeMap<String, MatrixChannel>,
+    /// User marks and recording changes from MARK
+    pub marks: Vec<MatrixMark>,
+    /// Parameter values at the start of the session from EEPA
+    pub initial_params: HashMap<String, MatrixType>,
+    /// Parameter changes from PMOD in the order of the paramfile
+    pub changes: Vec<ParamChange>,
+    /// Data files from BREF in the order they were written
+    pub data_files: Vec<MatrixDataFile>,
+}
+
+/// An instrument component, e.g. the `XYScanner` of kind `XYScanner`
+#[derive(Debug, Clone)]
+pub struct MatrixComponent {
+    pub name: String,
+    pub kind: String,
+    pub library: String,
+    pub properties: BTreeMap<String, String>,
+}
+
+/// `signal` of `source` drives `slot` of `target`
+#[derive(Debug, Clone)]
+pub struct MatrixConnection {
+    pub source: String,
+    pub signal: String,
+    pub target: String,
+    pub slot: String,
+}
+
+/// A measured channel as listed in the DICT block
+#[derive(Debug, Clone)]
+pub struct MatrixChannel {
+    pub id: u32,
+    /// Unit of the converted values, from the XFER block if the channel has a
+    /// transfer function
+    pub unit: String,
+    pub transfer_function: TransferFunction,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatrixMark {
+    pub datetime: DateTime<Utc>,
+    pub text: String,
+}
+
+/// A single parameter change, keys are of the form "Regulator.Setpoint_1 [Ampere]"
+#[derive(Debug, Clone)]
+pub struct ParamChange {
+    pub datetime: DateTime<Utc>,
+    pub key: String,
+    pub value: MatrixType,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatrixDataFile {
+    pub name: String,
+    pub datetime: DateTime<Utc>,
+    /// Number of parameter changes made before the file was written
+    pub num_changes: usize,
+}
+
+impl MatrixExperiment {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(bytes);
+        expect_magic(&mut cursor, "ONTMATRX0101")?;
+
+        let mut experiment = MatrixExperiment {
+            system: BTreeMap::new(),
+            components: Vec::new(),
+            connections: Vec::new(),
+            channels: BTreeMap::new(),
+            marks: Vec::new(),
+            initial_params: HashMap::new(),
+            changes: Vec::new(),
+            data_files: Vec::new(),
+        };
+        let mut tffs: HashMap<u32, (TransferFunction, String)> = HashMap::new();
+        let mut dict: HashMap<String, u32> = HashMap::new();
+        while cursor.position() < bytes.len() as u64 {
+            match read_ident_block(&mut cursor)? {
+                IdentBlock::META(hm) => experiment.system.extend(hm),
+                IdentBlock::INST(components) => experiment.components.extend(components),
+                IdentBlock::CNXS(connections) => experiment.connections.extend(connections),
+                IdentBlock::EEPA(hm) => experiment.initial_params.extend(hm),
+                IdentBlock::PMOD(datetime, hm) => {
+                    experiment
+                        .changes
+                        .extend(hm.into_iter().map(|(key, value)| ParamChange {
+                            datetime,
+                            key,
+                            value,
+                        }));
+                }
+                IdentBlock::MARK(datetime, text) => {
+                    experiment.marks.push(MatrixMark { datetime, text })
+                }
+                IdentBlock::BREF(datetime, name) => experiment.data_files.push(MatrixDataFile {
+                    name,
+                    datetime,
+                    num_changes: experiment.changes.len(),
+                }),
+                IdentBlock::DICT(hm) => dict.extend(hm),
+                IdentBlock::XFER(hm) => tffs.extend(hm),
+                _ => {}
+            }
+        }
+
+        // DICT keys are of the form "I(V) [A]"
+        for (key, id) in dict {
+            let (name, unit) = key
+                .strip_suffix(']')
+                .and_then(|k| k.split_once(" ["))
+                .unwrap_or((&key, ""));
+            let (transfer_function, unit) = match tffs.get(&id) {
+                Some((tff, xfer_unit)) if !xfer_unit.is_empty() => {
+                    (tff.clone(), xfer_unit.as_str())
+                }
+                Some((tff, _)) => (tff.clone(), unit),
+                None => (TransferFunction::Identity, unit),
+            };
+            let channel = MatrixChannel {
+                id,
+                unit: unit.to_string(),
+                transfer_function,
+            };
+            experiment.channels.insert(name.to_string(), channel);
+        }
+        Ok(experiment)
+    }
+
+    /// The data file `name`, files referenced more than once are returned at their first reference
+    pub fn data_file(&self, name: &str) -> Option<&MatrixDataFile> {
+        self.data_files.iter().find(|f| f.name == name)
+    }
+
+    /// All parameter values when the data file `name` was written,
+    /// files that are not referenced get the final state of the session
+    pub fn params_at(&self, name: &str) -> HashMap<String, MatrixType> {
+        let end = self
+            .data_file(name)
+            .map_or(self.changes.len(), |f| f.num_changes);
+        let mut params = self.initial_params.clone();
+        for change in &self.changes[..end] {
+            params.insert(change.key.clone(), change.value.clone());
+        }
+        params
+    }
+
+    /// Value of the parameter `key` when the data file `name` was written
+    pub fn param_at(&self, name: &str, key: &str) -> Option<&MatrixType> {
+        let end = self
+            .data_file(name)
+            .map_or(self.changes.len(), |f| f.num_changes);
+        self.changes[..end]
+            .iter()
+            .rev()
+            .find(|change| change.key == key)
+            .map(|change| &change.value)
+            .or_else(|| self.initial_params.get(key))
+    }
+}
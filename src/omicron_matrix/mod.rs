@@ -1,6 +1,24 @@
+mod experiment;
+#[allow(clippy::module_inception)]
 mod omicron_matrix;
 mod paramfile;
 mod paraminfo;
 mod scanfile;
+mod spectroscopy;
 
-pub use omicron_matrix::read_omicron_matrix;
+pub use experiment::{
+    MatrixChannel, MatrixComponent, MatrixConnection, MatrixDataFile, MatrixExperiment, MatrixMark,
+    ParamChange,
+};
+pub use omicron_matrix::{
+    read_omicron_matrix, read_omicron_matrix_from_bytes, OmicronMatrix, OmicronMatrixReader,
+};
+pub use paramfile::{MatrixType, TransferFunction};
+pub use paraminfo::{
+    clear_experiment_cache, find_omicron_matrix_experiment, paramfile_path,
+    read_omicron_matrix_experiment,
+};
+pub use spectroscopy::{
+    channel_name, is_curve_channel, read_omicron_matrix_spectrum,
+    read_omicron_matrix_spectrum_from_bytes, MatrixCurve, MatrixSpectrum, SweepDirection,
+};
@@ -1,14 +1,24 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
 
-use crate::omicron_matrix::paraminfo::get_param_info;
-use crate::omicron_matrix::scanfile::read_omicron_matrix_scanfile;
-use crate::spm_image::flip_img_data;
+use crate::omicron_matrix::paramfile::TransferFunction;
+use crate::omicron_matrix::paraminfo::{get_param_info, get_param_info_from_bytes, ParamData};
+use crate::omicron_matrix::scanfile::{
+    read_omicron_matrix_scanfile, read_omicron_matrix_scanfile_from_bytes, ScanData,
+};
+use crate::omicron_matrix::spectroscopy::{
+    channel_name, is_curve_channel, read_omicron_matrix_spectrum, SweepDirection,
+};
+use crate::spm_file::{SpmChannel, SpmFile, SpmReader, SpmSpectrum};
 use crate::spm_image::SpmImage;
 
 #[derive(Debug)]
 pub struct OmicronMatrix {
+    pub datetime: DateTime<Utc>,
+    /// Setpoint current in nA
     pub current: f64,
     pub bias: f64,
     pub xsize: f64,
@@ -19,35 +29,122 @@ pub struct OmicronMatrix {
     pub raster_time: f64,
     pub xoffset: f64,
     pub yoffset: f64,
-    pub img_data_fw: SpmImage,
-    pub img_data_bw: SpmImage,
+    /// Number of points of the complete scan in all directions
+    pub num_points_set: u32,
+    /// Smaller than `num_points_set` for aborted scans
+    pub num_points_scanned: u32,
+    /// Channel name as in the file name, e.g. "Z"
+    pub channel: String,
+    /// Unit of the image data
+    pub unit: String,
+    /// Conversion of the raw data, already applied to the images
+    pub transfer_function: TransferFunction,
+    /// One image per scan direction, aborted scans only contain the completed lines
+    pub images: Vec<SpmImage>,
+}
+
+impl OmicronMatrix {
+    pub fn is_complete(&self) -> bool {
+        self.num_points_scanned >= self.num_points_set
+    }
 }
 
 pub fn read_omicron_matrix(filename: &str) -> Result<OmicronMatrix> {
     let paraminfo = get_param_info(filename)?;
-    let scandata = read_omicron_matrix_scanfile(filename);
-    // TODO: handle different number of images
-    let _num_imgs =
-        (if paraminfo.xretrace { 2 } else { 1 }) * (if paraminfo.yretrace { 2 } else { 1 });
-    let mut lines = scandata.img_data.chunks(paraminfo.xres as usize);
-
-    let mut forward_up = Vec::new();
-    let mut backward_up = Vec::new();
-
-    for _ in 0..lines.len() / 2 {
-        for x in lines.next().unwrap().iter() {
-            forward_up.push(tff_linear(f64::from(*x), &paraminfo.tffs))
-        }
+    let scandata = read_omicron_matrix_scanfile(filename)?;
+    build_omicron_matrix(paraminfo, scandata, filename)
+}
+
+/// Reads a data file together with its paramfile, both already in memory.
+/// `filename` is the name of the data file, which is looked up in the paramfile.
+pub fn read_omicron_matrix_from_bytes(
+    scanfile: &[u8],
+    paramfile: &[u8],
+    filename: &str,
+) -> Result<OmicronMatrix> {
+    let paraminfo = get_param_info_from_bytes(paramfile, filename)?;
+    let scandata = read_omicron_matrix_scanfile_from_bytes(scanfile)?;
+    build_omicron_matrix(paraminfo, scandata, filename)
+}
+
+/// Names of the images in the order of acquisition, a scan goes up first
+/// and with `yretrace` down again, each line forward and with `xretrace` backward
+const DIRECTIONS: [[&str; 2]; 2] = [
+    ["forward_up", "backward_up"],
+    ["forward_down", "backward_down"],
+];
 
-        for x in lines.next().unwrap().iter() {
-            backward_up.push(tff_linear(f64::from(*x), &paraminfo.tffs))
+fn build_omicron_matrix(
+    paraminfo: ParamData,
+    scandata: ScanData,
+    filename: &str,
+) -> Result<OmicronMatrix> {
+    let channel_name = channel_name(filename)
+        .with_context(|| format!("Cannot derive channel name from {}", filename))?;
+    let channel = paraminfo
+        .channel(channel_name)
+        .with_context(|| format!("Channel {} not found in paramfile", channel_name))?;
+    let xres = paraminfo.xres as usize;
+    let yres = paraminfo.yres as usize;
+    if xres == 0 || yres == 0 {
+        bail!("Invalid resolution {}x{}", xres, yres);
+    }
+    let num_xdirs = if paraminfo.xretrace { 2 } else { 1 };
+    let num_ydirs = if paraminfo.yretrace { 2 } else { 1 };
+
+    let num_points_set = scandata.desc.get("num_points_set").copied().unwrap_or(0);
+    let num_points_scanned = scandata
+        .desc
+        .get("num_points_scanned")
+        .copied()
+        .unwrap_or(0);
+    // Aborted scans only contain the points scanned so far, the last line may be incomplete
+    let num_points = (num_points_scanned as usize).min(scandata.img_data.len());
+
+    let mut lines: Vec<Vec<Vec<f64>>> = vec![Vec::new(); num_xdirs * num_ydirs];
+    for (i, raw_line) in scandata.img_data[..num_points]
+        .chunks_exact(xres)
+        .enumerate()
+    {
+        let ydir = i / (yres * num_xdirs);
+        if ydir >= num_ydirs {
+            break;
+        }
+        let xdir = i % num_xdirs;
+        let mut line: Vec<f64> = raw_line
+            .iter()
+            .map(|x| channel.transfer_function.apply(f64::from(*x)))
+            .collect();
+        // backward lines are recorded from right to left
+        if xdir == 1 {
+            line.reverse();
         }
+        lines[ydir * num_xdirs + xdir].push(line);
     }
 
-    let v_fw = flip_img_data(forward_up, paraminfo.xres, paraminfo.yres);
-    backward_up.reverse();
+    let mut images = Vec::new();
+    for (i, mut dir_lines) in lines.into_iter().enumerate() {
+        if dir_lines.is_empty() {
+            continue;
+        }
+        let (ydir, xdir) = (i / num_xdirs, i % num_xdirs);
+        // the first line of an upward scan is at the bottom of the image
+        if ydir == 0 {
+            dir_lines.reverse();
+        }
+        let num_lines = dir_lines.len();
+        images.push(SpmImage {
+            img_id: DIRECTIONS[ydir][xdir].to_string(),
+            xres,
+            yres: num_lines,
+            xsize: paraminfo.xsize * 1e9,
+            ysize: paraminfo.ysize * 1e9 * num_lines as f64 / yres as f64,
+            img_data: dir_lines.concat(),
+        });
+    }
 
     Ok(OmicronMatrix {
+        datetime: scandata.datetime,
         current: paraminfo.current * 1e9,
         bias: paraminfo.bias,
         xsize: paraminfo.xsize * 1e9,
@@ -58,37 +155,100 @@ pub fn read_omicron_matrix(filename: &str) -> Result<OmicronMatrix> {
         raster_time: paraminfo.raster_time * paraminfo.xres as f64 * paraminfo.yres as f64,
         xoffset: paraminfo.xoffset * 1e9,
         yoffset: paraminfo.yoffset * 1e9,
-        img_data_fw: SpmImage {
-            img_id: "forward_up".to_string(),
-            xres: paraminfo.xres,
-            yres: paraminfo.yres,
-            xsize: paraminfo.xsize,
-            ysize: paraminfo.ysize,
-            img_data: v_fw,
-        },
-        img_data_bw: SpmImage {
-            img_id: "backward_up".to_string(),
-            xres: paraminfo.xres,
-            yres: paraminfo.yres,
-            xsize: paraminfo.xsize,
-            ysize: paraminfo.ysize,
-            img_data: backward_up,
-        },
+        num_points_set,
+        num_points_scanned,
+        channel: channel_name.to_string(),
+        unit: channel.unit.clone(),
+        transfer_function: channel.transfer_function.clone(),
+        images,
     })
 }
 
-// TODO: datapoints seem to differ from gwyddion, there is also 'zoom' mentioned
-fn tff_linear(x: f64, tffs: &HashMap<String, f64>) -> f64 {
-    let offset = tffs["TFF_Linear1D.Offset [m]"];
-    let factor = tffs["TFF_Linear1D.Factor [m]"];
-    (x - offset) / factor
+pub struct OmicronMatrixReader;
+
+impl SpmReader for OmicronMatrixReader {
+    fn name(&self) -> &'static str {
+        "omicron_matrix"
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["_mtrx"]
+    }
+
+    // Data files continue with a BKLT block after the magic header,
+    // the paramfile with a META block
+    fn detect(&self, head: &[u8], _file_len: u64) -> f32 {
+        if head.starts_with(b"ONTMATRX0101TLKB") {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn read(&self, filename: &str) -> Result<SpmFile> {
+        if channel_name(filename).is_some_and(is_curve_channel) {
+            return read_spectrum_file(filename, self.name());
+        }
+        let mtrx = read_omicron_matrix(filename)?;
+        let mut spm_file = SpmFile::new(Path::new(filename), self.name());
+        spm_file.metadata = BTreeMap::from([
+            ("datetime".to_string(), mtrx.datetime.to_string()),
+            ("bias [V]".to_string(), mtrx.bias.to_string()),
+            ("current [nA]".to_string(), mtrx.current.to_string()),
+            ("xoffset [nm]".to_string(), mtrx.xoffset.to_string()),
+            ("yoffset [nm]".to_string(), mtrx.yoffset.to_string()),
+            ("rotation [deg]".to_string(), mtrx.rotation.to_string()),
+            ("raster_time [s]".to_string(), mtrx.raster_time.to_string()),
+            ("complete".to_string(), mtrx.is_complete().to_string()),
+        ]);
+        for image in mtrx.images {
+            spm_file.channels.push(SpmChannel {
+                metadata: BTreeMap::from([("unit".to_string(), mtrx.unit.clone())]),
+                image,
+            });
+        }
+        Ok(spm_file)
+    }
+
+    // The parameters of a data file are only in the paramfile of the session
+    fn read_bytes(&self, filename: &str, _bytes: &[u8]) -> Result<SpmFile> {
+        bail!(
+            "{} needs the paramfile of its session, read it with \
+             read_omicron_matrix_from_bytes or read_omicron_matrix_spectrum_from_bytes",
+            filename
+        )
+    }
 }
 
-fn _tff_multilinear(x: f64, tffs: &HashMap<String, f64>) -> f64 {
-    let raw_1 = tffs["TFF_MultiLinear1D.Raw_1 [A]"];
-    let preoffset = tffs["TFF_MultiLinear1D.PreOffset [A]"];
-    let offset = tffs["TFF_MultiLinear1D.Offset [A]"];
-    let neutralfactor = tffs["TFF_MultiLinear1D.NeutralFactor [A]"];
-    let prefactor = tffs["TFF_MultiLinear1D.PreFactor [A]"];
-    (raw_1 - preoffset) * (x - offset) / neutralfactor / prefactor
+fn read_spectrum_file(filename: &str, format: &'static str) -> Result<SpmFile> {
+    let spectrum = read_omicron_matrix_spectrum(filename)?;
+    let mut spm_file = SpmFile::new(Path::new(filename), format);
+    spm_file.metadata = BTreeMap::from([
+        ("datetime".to_string(), spectrum.datetime.to_string()),
+        ("bias [V]".to_string(), spectrum.bias.to_string()),
+        ("current [nA]".to_string(), spectrum.current.to_string()),
+        ("repetitions".to_string(), spectrum.repetitions.to_string()),
+        (
+            "ramp_reversal".to_string(),
+            spectrum.ramp_reversal.to_string(),
+        ),
+    ]);
+    for curve in spectrum.curves {
+        let direction = match curve.direction {
+            SweepDirection::Forward => "forward",
+            SweepDirection::Backward => "backward",
+        };
+        spm_file.spectra.push(SpmSpectrum {
+            name: format!("{}_{}_{}", spectrum.channel, direction, curve.repetition),
+            metadata: BTreeMap::from([
+                ("direction".to_string(), direction.to_string()),
+                ("repetition".to_string(), curve.repetition.to_string()),
+            ]),
+            x_unit: spectrum.x_unit.clone(),
+            y_unit: spectrum.y_unit.clone(),
+            x: curve.x,
+            y: curve.y,
+        });
+    }
+    Ok(spm_file)
 }
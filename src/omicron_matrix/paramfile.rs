@@ -1,10 +1,15 @@
-use std::collections::HashMap;
-use std::fs::read;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Cursor;
 use std::str;
 
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::omicron_matrix::experiment::{MatrixComponent, MatrixConnection};
 use crate::utils::Bytereading;
 
+// Variant names mirror the block identifiers in the file
+#[allow(clippy::upper_case_acronyms, dead_code)]
 #[derive(Debug)]
 pub enum IdentBlock {
     META(HashMap<String, String>),
@@ -14,22 +19,24 @@ pub enum IdentBlock {
     GENL(String),
     EEPA(HashMap<String, MatrixType>),
     INCI(String),
-    MARK(String),
+    MARK(DateTime<Utc>, String),
     VIEW(String),
     PROC(String),
-    PMOD(HashMap<String, MatrixType>),
+    PMOD(DateTime<Utc>, HashMap<String, MatrixType>),
     CCSY(String),
-    BREF(String),
+    BREF(DateTime<Utc>, String),
     EOED(bool),
-    INST(HashMap<String, String>),
-    CNXS(HashMap<String, String>),
+    INST(Vec<MatrixComponent>),
+    CNXS(Vec<MatrixConnection>),
     DICT(HashMap<String, u32>),
     CHCS(String),
-    XFER(HashMap<String, MatrixType>),
+    XFER(HashMap<u32, (TransferFunction, String)>),
     SCAN(String),
 }
 
-#[derive(Debug)]
+/// A parameter value as stored in the paramfile
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MatrixType {
     BOOL(u32),
     LONG(u32),
@@ -37,26 +44,71 @@ pub enum MatrixType {
     DOUB(f64),
 }
 
-// returns a Vec of all IdentBlock in the paramfile
-pub fn _read_omicron_matrix_paramfile_full(filename: &str) -> Vec<IdentBlock> {
-    let bytes = read(filename).unwrap();
-    let mut cursor = Cursor::new(bytes.as_slice());
-    let magic_header = cursor.read_magic_header();
-    assert_eq!(magic_header, "ONTMATRX0101");
-
-    let file_length = bytes.len();
-    let mut position = 0;
-    let mut v = Vec::new();
-    while position < file_length as u64 {
-        v.push(read_ident_block(&mut cursor));
-        position = cursor.position();
+/// Conversion from the raw integers in the data files to physical values,
+/// the formulas are the same as in Gwyddion
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferFunction {
+    /// Channels without transfer function contain physical values already
+    Identity,
+    /// `(raw - offset) / factor`
+    Linear1D { factor: f64, offset: f64 },
+    /// `(raw_1 - pre_offset) * (raw - offset) / (neutral_factor * pre_factor)`
+    MultiLinear1D {
+        neutral_factor: f64,
+        offset: f64,
+        pre_factor: f64,
+        pre_offset: f64,
+        raw_1: f64,
+    },
+    /// Transfer function of the given name that is not implemented or lacks
+    /// parameters, the raw values are kept
+    Unsupported(String),
+}
+
+impl TransferFunction {
+    pub fn apply(&self, raw: f64) -> f64 {
+        match *self {
+            TransferFunction::Identity | TransferFunction::Unsupported(_) => raw,
+            TransferFunction::Linear1D { factor, offset } => (raw - offset) / factor,
+            TransferFunction::MultiLinear1D {
+                neutral_factor,
+                offset,
+                pre_factor,
+                pre_offset,
+                raw_1,
+            } => (raw_1 - pre_offset) * (raw - offset) / (neutral_factor * pre_factor),
+        }
+    }
+
+    // `params` are the properties of a single XFER entry, entries with an
+    // unknown name or missing parameters keep the raw values
+    fn from_params(name: &str, params: &HashMap<String, f64>) -> Self {
+        Self::known(name, params).unwrap_or_else(|| TransferFunction::Unsupported(name.to_string()))
+    }
+
+    fn known(name: &str, params: &HashMap<String, f64>) -> Option<Self> {
+        let param = |key: &str| params.get(key).copied();
+        let tff = match name {
+            "TFF_Identity" => TransferFunction::Identity,
+            "TFF_Linear1D" => TransferFunction::Linear1D {
+                factor: param("Factor")?,
+                offset: param("Offset")?,
+            },
+            "TFF_MultiLinear1D" => TransferFunction::MultiLinear1D {
+                neutral_factor: param("NeutralFactor")?,
+                offset: param("Offset")?,
+                pre_factor: param("PreFactor")?,
+                pre_offset: param("PreOffset")?,
+                raw_1: param("Raw_1")?,
+            },
+            _ => return None,
+        };
+        Some(tff)
     }
-    assert_eq!(cursor.position(), file_length as u64);
-    v
 }
 
-pub fn read_ident_block(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let ident: String = cursor.read_matrix_type();
+pub fn read_ident_block(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let ident: String = cursor.read_matrix_type()?;
 
     match ident.as_str() {
         "META" => read_meta(cursor),
@@ -79,331 +131,360 @@ pub fn read_ident_block(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
         "CHCS" => read_chcs(cursor),
         "SCAN" => read_scan(cursor),
         "XFER" => read_xfer(cursor),
-        _ => unimplemented!(),
+        _ => bail!(
+            "Unknown block identifier {:?} at offset {}",
+            ident,
+            cursor.position() - 4
+        ),
     }
 }
 
+// Blocks with a timestamp store it as seconds since the epoch
+fn read_matrix_time(cursor: &mut Cursor<&[u8]>) -> Result<DateTime<Utc>> {
+    let time = cursor.read_u32_le()?;
+    Utc.timestamp_opt(time as i64, 0)
+        .single()
+        .with_context(|| format!("Invalid timestamp {}", time))
+}
+
+fn read_matrix_value(cursor: &mut Cursor<&[u8]>, matrix_type: &str) -> Result<MatrixType> {
+    let value = match matrix_type {
+        "BOOL" => MatrixType::BOOL(cursor.read_u32_le()?),
+        "LONG" => MatrixType::LONG(cursor.read_u32_le()?),
+        "STRG" => MatrixType::STRG(cursor.read_matrix_string()?),
+        "DOUB" => MatrixType::DOUB(cursor.read_f64_le()?),
+        _ => bail!(
+            "Unknown value type {:?} at offset {}",
+            matrix_type,
+            cursor.position() - 4
+        ),
+    };
+    Ok(value)
+}
+
 // META
-fn read_meta(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
+fn read_meta(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let _time = cursor.read_u32_le()?;
+    let _unused = cursor.read_u32_le()?;
 
     let mut hm: HashMap<String, String> = HashMap::new();
-    hm.insert("program_name".to_string(), cursor.read_matrix_string());
-    hm.insert("version".to_string(), cursor.read_matrix_string());
+    hm.insert("program_name".to_string(), cursor.read_matrix_string()?);
+    hm.insert("version".to_string(), cursor.read_matrix_string()?);
 
-    cursor.skip(4);
+    cursor.skip(4)?;
 
-    hm.insert("profile".to_string(), cursor.read_matrix_string());
-    hm.insert("user".to_string(), cursor.read_matrix_string());
+    hm.insert("profile".to_string(), cursor.read_matrix_string()?);
+    hm.insert("user".to_string(), cursor.read_matrix_string()?);
 
-    cursor.skip(4);
+    cursor.skip(4)?;
 
-    IdentBlock::META(hm)
+    Ok(IdentBlock::META(hm))
 }
 
 //EXPD
-fn read_expd(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
+fn read_expd(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let _time = cursor.read_u32_le()?;
+    let _unused = cursor.read_u32_le()?;
 
-    cursor.skip(4);
+    cursor.skip(4)?;
 
     let mut content = String::new();
     for _ in 0..7 {
-        let s = cursor.read_matrix_string();
+        let s = cursor.read_matrix_string()?;
         content += &format!("\n{}", s);
     }
-    IdentBlock::EXPD(content.trim().to_owned())
+    Ok(IdentBlock::EXPD(content.trim().to_owned()))
 }
 
 // FSEQ
-fn read_fseq(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
+fn read_fseq(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
     // let len = read_u32_le(cursor);
     // let time = read_u32_le(cursor);
-    cursor.skip(20);
-    IdentBlock::FSEQ("".to_string())
+    cursor.skip(20)?;
+    Ok(IdentBlock::FSEQ("".to_string()))
 }
 
 // EXPS contains INST and CNXS
 // therefore len of exps contains len of inst and CNXS
 // reading of those blocks is also handled by read_ident_block
-fn read_exps(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
-    cursor.skip(4);
+fn read_exps(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let _time = cursor.read_u32_le()?;
+    let _unused = cursor.read_u32_le()?;
+    cursor.skip(4)?;
 
-    IdentBlock::EXPS("".to_string())
+    Ok(IdentBlock::EXPS("".to_string()))
 }
 
 // GENL
 // this block is nested first in EXPS
-fn read_genl(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
+fn read_genl(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
 
-    let a = cursor.read_matrix_string();
-    let b = cursor.read_matrix_string();
-    let c = cursor.read_matrix_string();
-    IdentBlock::GENL(format!("{}; {}; {}", a, b, c))
+    let a = cursor.read_matrix_string()?;
+    let b = cursor.read_matrix_string()?;
+    let c = cursor.read_matrix_string()?;
+    Ok(IdentBlock::GENL(format!("{}; {}; {}", a, b, c)))
 }
 
 // INST
 // this block is nested second in EXPS
-fn read_inst(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let len = cursor.read_u32_le();
+// components of the experiment with their properties
+fn read_inst(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let len = cursor.read_u32_le()?;
 
     let mut position = cursor.position();
     let end = position + len as u64;
-    cursor.skip(4);
+    cursor.skip(4)?;
 
-    let mut hm: HashMap<String, String> = HashMap::new();
+    let mut components = Vec::new();
     while position < end {
-        let k1 = cursor.read_matrix_string();
-        let k2 = cursor.read_matrix_string();
-        let k3 = cursor.read_matrix_string();
-        let len_inner = cursor.read_u32_le();
+        let name = cursor.read_matrix_string()?;
+        let kind = cursor.read_matrix_string()?;
+        let library = cursor.read_matrix_string()?;
+        let len_inner = cursor.read_u32_le()?;
+        let mut properties = BTreeMap::new();
         for _ in 0..len_inner {
-            let k4 = cursor.read_matrix_string();
-            let v = cursor.read_matrix_string();
-            hm.insert(format!("{}::{}::{}.{}", k1, k2, k3, k4), v);
+            let prop = cursor.read_matrix_string()?;
+            let v = cursor.read_matrix_string()?;
+            properties.insert(prop, v);
         }
+        components.push(MatrixComponent {
+            name,
+            kind,
+            library,
+            properties,
+        });
         position = cursor.position();
     }
-    IdentBlock::INST(hm)
+    Ok(IdentBlock::INST(components))
 }
 
 // CNXS
 // this block is nested third in EXPS
-fn read_cnxs(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let len = cursor.read_u32_le();
+// connections from a signal of a component to slots of other components
+fn read_cnxs(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let len = cursor.read_u32_le()?;
 
     let mut position = cursor.position();
     let end = position + len as u64;
-    cursor.skip(4);
+    cursor.skip(4)?;
 
-    let mut hm: HashMap<String, String> = HashMap::new();
+    let mut connections = Vec::new();
     while position < end {
-        let k1 = cursor.read_matrix_string();
-        let k2 = cursor.read_matrix_string();
-        let len_inner = cursor.read_u32_le();
+        let source = cursor.read_matrix_string()?;
+        let signal = cursor.read_matrix_string()?;
+        let len_inner = cursor.read_u32_le()?;
         for _ in 0..len_inner {
-            let k3 = cursor.read_matrix_string();
-            let v = cursor.read_matrix_string();
-            hm.insert(format!("{}::{}.{}", k1, k2, k3), v);
+            let target = cursor.read_matrix_string()?;
+            let slot = cursor.read_matrix_string()?;
+            connections.push(MatrixConnection {
+                source: source.clone(),
+                signal: signal.clone(),
+                target,
+                slot,
+            });
         }
         position = cursor.position();
     }
-    IdentBlock::CNXS(hm)
+    Ok(IdentBlock::CNXS(connections))
 }
 
 // EEPA
-fn read_eepa(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
+fn read_eepa(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let _time = cursor.read_u32_le()?;
+    let _unused = cursor.read_u32_le()?;
 
-    cursor.skip(4);
+    cursor.skip(4)?;
 
-    let len_outer = cursor.read_u32_le();
+    let len_outer = cursor.read_u32_le()?;
 
     let mut hm: HashMap<String, MatrixType> = HashMap::new();
     for _ in 0..len_outer {
-        let inst = cursor.read_matrix_string();
-        let len_inner = cursor.read_u32_le();
+        let inst = cursor.read_matrix_string()?;
+        let len_inner = cursor.read_u32_le()?;
         for _ in 0..len_inner {
-            let prop = cursor.read_matrix_string();
-            let unit = cursor.read_matrix_string();
+            let prop = cursor.read_matrix_string()?;
+            let unit = cursor.read_matrix_string()?;
             // don't know if this is useful
-            let _empty = cursor.read_u32_le();
-            let matrix_type: String = cursor.read_matrix_type();
-            let value = match matrix_type.as_str() {
-                "BOOL" => MatrixType::BOOL(cursor.read_u32_le()),
-                "LONG" => MatrixType::LONG(cursor.read_u32_le()),
-                "STRG" => MatrixType::STRG(cursor.read_matrix_string()),
-                "DOUB" => MatrixType::DOUB(cursor.read_f64_le()),
-                _ => unimplemented!(),
-            };
+            let _empty = cursor.read_u32_le()?;
+            let matrix_type: String = cursor.read_matrix_type()?;
+            let value = read_matrix_value(cursor, &matrix_type)?;
             hm.insert(format!("{}.{} [{}]", inst, prop, unit), value);
         }
     }
-    IdentBlock::EEPA(hm)
+    Ok(IdentBlock::EEPA(hm))
 }
 
 // INCI
 // state of experiment
-fn read_inci(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
-
-    let _ = cursor.read_u32_le();
-    let _ = cursor.read_u32_le();
-    IdentBlock::INCI("".to_string())
+fn read_inci(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let _time = cursor.read_u32_le()?;
+    let _unused = cursor.read_u32_le()?;
+
+    let _ = cursor.read_u32_le()?;
+    let _ = cursor.read_u32_le()?;
+    Ok(IdentBlock::INCI("".to_string()))
 }
 
 // MARK
 // calibration of system
-fn read_mark(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
+fn read_mark(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let time = read_matrix_time(cursor)?;
+    let _unused = cursor.read_u32_le()?;
 
-    let content = cursor.read_matrix_string();
-    IdentBlock::MARK(content)
+    let content = cursor.read_matrix_string()?;
+    Ok(IdentBlock::MARK(time, content))
 }
 
 // VIEW
 // scanning windows settings
-fn read_view(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
+fn read_view(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let len = cursor.read_u32_le()?;
+    let _time = cursor.read_u32_le()?;
+    let _unused = cursor.read_u32_le()?;
 
-    let content = cursor.read_string(len as usize);
-    IdentBlock::VIEW(content)
+    let content = cursor.read_string(len as usize)?;
+    Ok(IdentBlock::VIEW(content))
 }
 
 // PROC
 // processors of scanning windows (plugins, e.g. CurveAverager, Despiker)
-fn read_proc(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
+fn read_proc(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let len = cursor.read_u32_le()?;
+    let _time = cursor.read_u32_le()?;
+    let _unused = cursor.read_u32_le()?;
 
-    let content = cursor.read_string(len as usize);
-    IdentBlock::PROC(content)
+    let content = cursor.read_string(len as usize)?;
+    Ok(IdentBlock::PROC(content))
 }
 
 // PMOD
-fn read_pmod(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
-    cursor.skip(4);
-
-    let category = cursor.read_matrix_string();
-    let prop = cursor.read_matrix_string();
-    let unit = cursor.read_matrix_string();
-
-    cursor.skip(4);
-    let matrix_type = cursor.read_matrix_type();
-
-    let value = match matrix_type.as_str() {
-        "BOOL" => MatrixType::BOOL(cursor.read_u32_le()),
-        "LONG" => MatrixType::LONG(cursor.read_u32_le()),
-        "STRG" => MatrixType::STRG(cursor.read_matrix_string()),
-        "DOUB" => MatrixType::DOUB(cursor.read_f64_le()),
-        _ => unreachable!(),
-    };
-    cursor.skip(4);
+fn read_pmod(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let time = read_matrix_time(cursor)?;
+    let _unused = cursor.read_u32_le()?;
+    cursor.skip(4)?;
+
+    let category = cursor.read_matrix_string()?;
+    let prop = cursor.read_matrix_string()?;
+    let unit = cursor.read_matrix_string()?;
+
+    cursor.skip(4)?;
+    let matrix_type = cursor.read_matrix_type()?;
+
+    let value = read_matrix_value(cursor, &matrix_type)?;
+    cursor.skip(4)?;
 
     let mut hm: HashMap<String, MatrixType> = HashMap::new();
     hm.insert(format!("{}.{} [{}]", category, prop, unit), value);
-    IdentBlock::PMOD(hm)
+    Ok(IdentBlock::PMOD(time, hm))
 }
 
 // CCSY
 // has nested blocks DICT, CHCS, SCAN, XFER
-fn read_ccsy(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unused = cursor.read_u32_le();
+fn read_ccsy(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let _time = cursor.read_u32_le()?;
+    let _unused = cursor.read_u32_le()?;
 
-    cursor.skip(4);
-    IdentBlock::CCSY("".to_string())
+    cursor.skip(4)?;
+    Ok(IdentBlock::CCSY("".to_string()))
 }
 
 // DICT
 // nested in CCSY
-fn read_dict(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _ = cursor.read_u32_le(); // no time in here
-    let _unused = cursor.read_u32_le();
+fn read_dict(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let _ = cursor.read_u32_le()?; // no time in here
+    let _unused = cursor.read_u32_le()?;
 
-    let n_first = cursor.read_u32_le();
+    let n_first = cursor.read_u32_le()?;
     for _ in 0..n_first {
-        cursor.skip(16); // could also be 4 different u32
-        let _s1 = cursor.read_matrix_string();
-        let _s2 = cursor.read_matrix_string();
+        cursor.skip(16)?; // could also be 4 different u32
+        let _s1 = cursor.read_matrix_string()?;
+        let _s2 = cursor.read_matrix_string()?;
     }
 
-    let n_second = cursor.read_u32_le();
+    let n_second = cursor.read_u32_le()?;
     let mut hm: HashMap<String, u32> = HashMap::new();
     for _ in 0..n_second {
-        cursor.skip(4);
+        cursor.skip(4)?;
         // This seems to be some info about channels
-        let channel_num = cursor.read_u32_le();
-        cursor.skip(8);
+        let channel_num = cursor.read_u32_le()?;
+        cursor.skip(8)?;
 
-        let channel = cursor.read_matrix_string();
-        let unit = cursor.read_matrix_string();
+        let channel = cursor.read_matrix_string()?;
+        let unit = cursor.read_matrix_string()?;
         hm.insert(format!("{} [{}]", channel, unit), channel_num);
     }
 
-    let n_third = cursor.read_u32_le();
+    let n_third = cursor.read_u32_le()?;
     for _ in 0..n_third {
-        cursor.skip(16); // could be 4 times u32
-        let _s3 = cursor.read_matrix_string();
+        cursor.skip(16)?; // could be 4 times u32
+        let _s3 = cursor.read_matrix_string()?;
     }
-    IdentBlock::DICT(hm)
+    Ok(IdentBlock::DICT(hm))
 }
 
 // CHCS
 // netsted in CCSY
-fn read_chcs(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
+fn read_chcs(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
     // println!("CHCS len: {}", _len);
 
-    let n_first = cursor.read_u32_le();
+    let n_first = cursor.read_u32_le()?;
     // println!("n_first: {}", n_first);
     for _ in 0..n_first {
-        let _a = cursor.read_u32_le();
+        let _a = cursor.read_u32_le()?;
         // println!("a: {}", a);
-        let _b = cursor.read_u32_le();
+        let _b = cursor.read_u32_le()?;
         // println!("b: {}", b);
-        let _c = cursor.read_u32_le();
+        let _c = cursor.read_u32_le()?;
         // println!("c: {}", c);
-        let _d = cursor.read_u32_le();
+        let _d = cursor.read_u32_le()?;
         // println!("d: {}", d);
-        let _e = cursor.read_u32_le();
+        let _e = cursor.read_u32_le()?;
         // println!("e: {}", e);
     }
 
-    let n_second = cursor.read_u32_le();
+    let n_second = cursor.read_u32_le()?;
     // println!("n_sec: {}", n_second);
     for _ in 0..n_second {
-        let _f = cursor.read_u32_le();
+        let _f = cursor.read_u32_le()?;
         // println!("f: {}", f);
-        let _g = cursor.read_u32_le();
+        let _g = cursor.read_u32_le()?;
         // println!("g: {}", g);
-        let _h = cursor.read_u32_le();
+        let _h = cursor.read_u32_le()?;
         // println!("h: {}", h);
-        let _i = cursor.read_u32_le();
+        let _i = cursor.read_u32_le()?;
         // println!("i: {}", i);
     }
 
-    let n_third = cursor.read_u32_le();
+    let n_third = cursor.read_u32_le()?;
     // println!("n_third: {}", n_third);
     for _ in 0..n_third {
-        let _j = cursor.read_u32_le();
+        let _j = cursor.read_u32_le()?;
         // println!("j: {}", j);
-        let _k = cursor.read_u32_le();
+        let _k = cursor.read_u32_le()?;
         // println!("k: {}", k);
-        let _l = cursor.read_u32_le();
+        let _l = cursor.read_u32_le()?;
         // println!("l: {}", l);
-        let _m = cursor.read_u32_le();
+        let _m = cursor.read_u32_le()?;
         // println!("m: {}", m);
     }
-    IdentBlock::CHCS("".to_string())
+    Ok(IdentBlock::CHCS("".to_string()))
 }
 
 // SCAN
 // netsted in CCSY
-fn read_scan(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
+fn read_scan(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
     // println!("SCAN len: {}", _len);
     //
     // let n = read_u32_le(cursor);  // no time in here
@@ -411,59 +492,60 @@ fn read_scan(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
     // for _ in 0..n {
     // }
 
-    cursor.skip(_len as u64);
-    IdentBlock::SCAN("".to_string())
+    cursor.skip(_len as u64)?;
+    Ok(IdentBlock::SCAN("".to_string()))
 }
 
 // XFER
 // netsted in CCSY
-fn read_xfer(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
+// one transfer function per channel, the channel ids are the ones from DICT
+fn read_xfer(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let len = cursor.read_u32_le()?;
 
     let mut position = cursor.position();
-    let end = position + _len as u64;
+    let end = position + len as u64;
 
-    let mut hm: HashMap<String, MatrixType> = HashMap::new();
+    let mut hm: HashMap<u32, (TransferFunction, String)> = HashMap::new();
     while position < end {
-        cursor.skip(4);
-        let _n = cursor.read_u32_le();
-        let name = cursor.read_matrix_string();
-        let unit = cursor.read_matrix_string();
+        cursor.skip(4)?;
+        let channel_num = cursor.read_u32_le()?;
+        let name = cursor.read_matrix_string()?;
+        let unit = cursor.read_matrix_string()?;
 
-        let len_inner = cursor.read_u32_le();
+        let len_inner = cursor.read_u32_le()?;
+        let mut params: HashMap<String, f64> = HashMap::new();
         for _ in 0..len_inner {
-            let prop = cursor.read_matrix_string();
-            let matrix_type = cursor.read_matrix_type();
-            let value = match matrix_type.as_str() {
-                "BOOL" => MatrixType::BOOL(cursor.read_u32_le()),
-                "LONG" => MatrixType::LONG(cursor.read_u32_le()),
-                "STRG" => MatrixType::STRG(cursor.read_matrix_string()),
-                "DOUB" => MatrixType::DOUB(cursor.read_f64_le()),
-                _ => unreachable!(),
-            };
-            hm.insert(format!("{}.{} [{}]", name, prop, unit), value);
+            let prop = cursor.read_matrix_string()?;
+            let matrix_type = cursor.read_matrix_type()?;
+            if let MatrixType::DOUB(x) = read_matrix_value(cursor, &matrix_type)? {
+                params.insert(prop, x);
+            }
         }
+        hm.insert(
+            channel_num,
+            (TransferFunction::from_params(&name, &params), unit),
+        );
         position = cursor.position();
     }
-    IdentBlock::XFER(hm)
+    Ok(IdentBlock::XFER(hm))
 }
 
 // BREF
-fn read_bref(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unbytes = cursor.read_u32_le();
+fn read_bref(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let time = read_matrix_time(cursor)?;
+    let _unbytes = cursor.read_u32_le()?;
 
-    cursor.skip(4);
+    cursor.skip(4)?;
 
-    let filename = cursor.read_matrix_string();
-    IdentBlock::BREF(filename)
+    let filename = cursor.read_matrix_string()?;
+    Ok(IdentBlock::BREF(time, filename))
 }
 
 // EOED
-fn read_eoed(cursor: &mut Cursor<&[u8]>) -> IdentBlock {
-    let _len = cursor.read_u32_le();
-    let _time = cursor.read_u32_le();
-    let _unbytes = cursor.read_u32_le();
-    IdentBlock::EOED(true)
+fn read_eoed(cursor: &mut Cursor<&[u8]>) -> Result<IdentBlock> {
+    let _len = cursor.read_u32_le()?;
+    let _time = cursor.read_u32_le()?;
+    let _unbytes = cursor.read_u32_le()?;
+    Ok(IdentBlock::EOED(true))
 }
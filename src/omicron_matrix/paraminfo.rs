@@ -1,12 +1,14 @@
-use std::collections::HashMap;
-use std::fs::read;
-use std::io::Cursor;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::SystemTime;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 
-use crate::omicron_matrix::paramfile::{read_ident_block, IdentBlock, MatrixType};
-use crate::utils::Bytereading;
+use crate::omicron_matrix::experiment::{MatrixChannel, MatrixExperiment};
+use crate::omicron_matrix::paramfile::MatrixType;
 
 #[derive(Debug)]
 pub struct ParamData {
@@ -22,7 +24,10 @@ pub struct ParamData {
     pub yoffset: f64,
     pub xretrace: bool,
     pub yretrace: bool,
-    pub tffs: HashMap<String, f64>,
+    /// Channels by name, e.g. "I(V)"
+    pub channels: BTreeMap<String, MatrixChannel>,
+    /// All parameter values valid for the data file
+    pub params: HashMap<String, MatrixType>,
 }
 
 static CURRENT: &str = "Regulator.Setpoint_1 [Ampere]";
@@ -39,150 +44,245 @@ static YOFFSET: &str = "XYScanner.Y_Offset [Meter]";
 static XRETRACE: &str = "XYScanner.X_Retrace [--]";
 static YRETRACE: &str = "XYScanner.Y_Retrace [--]";
 
-pub fn get_param_info(filename: &str) -> Result<ParamData> {
-    let basename = Path::new(filename).file_name().unwrap().to_str().unwrap();
-    let paramfile = format!("{}_0001.mtrx", filename.split_once("--").unwrap().0);
-    let bytes = read(paramfile).unwrap();
-    let mut cursor = Cursor::new(bytes.as_slice());
-    let magic_header = cursor.read_magic_header();
-    assert_eq!(magic_header, "ONTMATRX0101");
-
-    let file_length = bytes.len();
-
-    let mut current = 0.0;
-    let mut bias = 0.0;
-    let mut xsize = 0.0;
-    let mut ysize = 0.0;
-    let mut xres: u32 = 0;
-    let mut yres: u32 = 0;
-    let mut rotation: u32 = 0;
-    let mut raster_time = 0.0;
-    let mut xoffset = 0.0;
-    let mut yoffset = 0.0;
-    let mut xretrace = false;
-    let mut yretrace = false;
-    let mut tffs: HashMap<String, f64> = HashMap::new();
-
-    let mut position = 0;
-    while position < file_length as u64 {
-        // 1. read EEPA which gives initial values, in EEPA all keys should be in one Hashmap
-        // 2. change initial values if the PMOD with the key for this value appears
-        // 3. break if BREF with filename to look appears
-        // => therefore values are always the ones from last PMOD, which should be right
-
-        let block = read_ident_block(&mut cursor);
-        match block {
-            IdentBlock::EEPA(hm) => {
-                if let MatrixType::DOUB(x) = hm[CURRENT] {
-                    current = x;
-                }
-                if let MatrixType::DOUB(x) = hm[BIAS] {
-                    bias = x;
-                }
-                if let MatrixType::DOUB(x) = hm[XSIZE] {
-                    xsize = x;
-                }
-                if let MatrixType::DOUB(x) = hm[YSIZE] {
-                    ysize = x;
-                }
-                if let MatrixType::LONG(x) = hm[XRES] {
-                    xres = x;
-                }
-                if let MatrixType::LONG(x) = hm[YRES] {
-                    yres = x;
-                }
-                if let MatrixType::LONG(x) = hm[ROTATION] {
-                    rotation = x;
-                }
-                if let MatrixType::DOUB(x) = hm[RASTER_TIME] {
-                    raster_time = x;
-                }
-                if let MatrixType::DOUB(x) = hm[XOFFSET] {
-                    xoffset = x;
-                }
-                if let MatrixType::DOUB(x) = hm[YOFFSET] {
-                    yoffset = x;
-                }
-                if let MatrixType::BOOL(x) = hm[XRETRACE] {
-                    xretrace = x != 0;
-                }
-                if let MatrixType::BOOL(x) = hm[YRETRACE] {
-                    yretrace = x != 0;
-                }
-            }
-            IdentBlock::PMOD(hm) => {
-                if hm.contains_key(CURRENT) {
-                    if let MatrixType::DOUB(x) = hm[CURRENT] {
-                        current = x;
-                    }
-                } else if hm.contains_key(BIAS) {
-                    if let MatrixType::DOUB(x) = hm[BIAS] {
-                        bias = x;
-                    }
-                } else if hm.contains_key(XSIZE) {
-                    if let MatrixType::DOUB(x) = hm[XSIZE] {
-                        xsize = x;
-                    }
-                } else if hm.contains_key(YSIZE) {
-                    if let MatrixType::DOUB(x) = hm[YSIZE] {
-                        ysize = x;
-                    }
-                } else if hm.contains_key(ROTATION) {
-                    if let MatrixType::LONG(x) = hm[ROTATION] {
-                        rotation = x;
-                    }
-                } else if hm.contains_key(RASTER_TIME) {
-                    if let MatrixType::DOUB(x) = hm[RASTER_TIME] {
-                        raster_time = x;
-                    }
-                } else if hm.contains_key(XOFFSET) {
-                    if let MatrixType::DOUB(x) = hm[XOFFSET] {
-                        xoffset = x;
-                    }
-                } else if hm.contains_key(YOFFSET) {
-                    if let MatrixType::DOUB(x) = hm[YOFFSET] {
-                        yoffset = x;
-                    }
-                } else if hm.contains_key(XRETRACE) {
-                    if let MatrixType::BOOL(x) = hm[XRETRACE] {
-                        xretrace = x != 0;
-                    }
-                } else if hm.contains_key(YRETRACE) {
-                    if let MatrixType::BOOL(x) = hm[YRETRACE] {
-                        yretrace = x != 0;
-                    }
-                }
-            }
-            IdentBlock::XFER(hm) => {
-                for (key, value) in hm.iter() {
-                    if let MatrixType::DOUB(x) = value {
-                        tffs.insert(key.to_owned(), *x);
-                    }
-                }
-            }
-            IdentBlock::BREF(x) => {
-                if x == basename {
-                    break;
-                }
+// Replays the parameter changes up to the data file `basename`
+fn param_data(experiment: &MatrixExperiment, basename: &str) -> ParamData {
+    let params = experiment.params_at(basename);
+    ParamData {
+        current: get_doub(&params, CURRENT).unwrap_or(0.0),
+        bias: get_doub(&params, BIAS).unwrap_or(0.0),
+        xsize: get_doub(&params, XSIZE).unwrap_or(0.0),
+        ysize: get_doub(&params, YSIZE).unwrap_or(0.0),
+        xres: get_long(&params, XRES).unwrap_or(0),
+        yres: get_long(&params, YRES).unwrap_or(0),
+        rotation: get_long(&params, ROTATION).unwrap_or(0),
+        raster_time: get_doub(&params, RASTER_TIME).unwrap_or(0.0),
+        xoffset: get_doub(&params, XOFFSET).unwrap_or(0.0),
+        yoffset: get_doub(&params, YOFFSET).unwrap_or(0.0),
+        xretrace: get_bool(&params, XRETRACE).unwrap_or(false),
+        yretrace: get_bool(&params, YRETRACE).unwrap_or(false),
+        channels: experiment.channels.clone(),
+        params,
+    }
+}
+
+struct CachedExperiment {
+    modified: Option<SystemTime>,
+    len: u64,
+    last_used: u64,
+    experiment: Arc<MatrixExperiment>,
+}
+
+#[derive(Default)]
+struct ExperimentCache {
+    entries: HashMap<PathBuf, CachedExperiment>,
+    uses: u64,
+}
+
+// Number of parsed paramfiles kept, the least recently used one is dropped first
+const CACHE_SIZE: usize = 8;
+
+// Parsed paramfiles, a paramfile is parsed again only if it changed on disk,
+// e.g. because the session is still running
+static EXPERIMENTS: OnceLock<Mutex<ExperimentCache>> = OnceLock::new();
+
+// A panic while the cache was locked cannot leave it inconsistent, so a poisoned
+// lock is used as is
+fn experiment_cache() -> MutexGuard<'static, ExperimentCache> {
+    EXPERIMENTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Drops all parsed paramfiles, e.g. after a directory has been processed
+pub fn clear_experiment_cache() {
+    experiment_cache().entries.clear();
+}
+
+fn load_experiment(path: &Path) -> Result<Arc<MatrixExperiment>> {
+    let metadata = fs::metadata(path).with_context(|| format!("Cannot read {:?}", path))?;
+    let modified = metadata.modified().ok();
+    let len = metadata.len();
+
+    {
+        let mut cache = experiment_cache();
+        cache.uses += 1;
+        let uses = cache.uses;
+        if let Some(cached) = cache.entries.get_mut(path) {
+            if cached.modified == modified && cached.len == len {
+                cached.last_used = uses;
+                return Ok(cached.experiment.clone());
             }
-            _ => continue,
+        }
+    }
+
+    let bytes = fs::read(path).with_context(|| format!("Cannot read {:?}", path))?;
+    let experiment = Arc::new(
+        MatrixExperiment::from_bytes(&bytes).with_context(|| format!("Cannot parse {:?}", path))?,
+    );
+    let mut cache = experiment_cache();
+    if cache.entries.len() >= CACHE_SIZE && !cache.entries.contains_key(path) {
+        let oldest = cache
+            .entries
+            .iter()
+            .min_by_key(|(_, cached)| cached.last_used)
+            .map(|(path, _)| path.clone());
+        if let Some(oldest) = oldest {
+            cache.entries.remove(&oldest);
+        }
+    }
+    let last_used = cache.uses;
+    cache.entries.insert(
+        path.to_path_buf(),
+        CachedExperiment {
+            modified,
+            len,
+            last_used,
+            experiment: experiment.clone(),
+        },
+    );
+    Ok(experiment)
+}
+
+fn basename(filename: &str) -> Result<&str> {
+    Path::new(filename)
+        .file_name()
+        .and_then(|f| f.to_str())
+        .with_context(|| format!("Invalid file name {}", filename))
+}
+
+// Timestamp of the first block of a Matrix file, for a paramfile this is when the
+// session was started, for a data file when the measurement was started
+fn start_time(path: &Path) -> Option<u32> {
+    let mut header = [0; 24];
+    let mut file = fs::File::open(path).ok()?;
+    file.read_exact(&mut header).ok()?;
+    if &header[..12] != b"ONTMATRX0101" {
+        return None;
+    }
+    Some(u32::from_le_bytes(header[20..24].try_into().ok()?))
+}
+
+// Finds the paramfile with a BREF to the data file `filename` in the same directory.
+// Only paramfiles of sessions started before the data file are parsed, the most
+// recently started first, so usually only the paramfile of the session is read.
+fn resolve_paramfile(filename: &str) -> Result<(PathBuf, Arc<MatrixExperiment>)> {
+    let basename = basename(filename)?;
+    let dir = match Path::new(filename).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let recorded = start_time(Path::new(filename));
+
+    // Paramfiles that cannot be read, e.g. of other software, are skipped
+    let mut candidates: Vec<(u32, PathBuf)> = fs::read_dir(dir)
+        .with_context(|| format!("Cannot read directory {:?}", dir))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mtrx"))
+        .filter_map(|path| Some((start_time(&path)?, path)))
+        .filter(|(started, _)| recorded.is_none_or(|recorded| *started <= recorded))
+        .collect();
+    candidates.sort_by(|a, b| b.cmp(a));
+
+    for (_, path) in candidates {
+        let Ok(experiment) = load_experiment(&path) else {
+            continue;
         };
-        position = cursor.position();
+        if experiment.data_file(basename).is_some() {
+            return Ok((path, experiment));
+        }
+    }
+    bail!("No paramfile in {:?} references {}", dir, basename)
+}
+
+/// Path of the paramfile belonging to the data file `filename`
+pub fn paramfile_path(filename: &str) -> Result<String> {
+    let (path, _) = resolve_paramfile(filename)?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// The session of the data file `filename`, the paramfile is only parsed once
+pub fn find_omicron_matrix_experiment(filename: &str) -> Result<Arc<MatrixExperiment>> {
+    let (_, experiment) = resolve_paramfile(filename)?;
+    Ok(experiment)
+}
+
+/// Reads the paramfile `paramfile`, e.g. `20201111_0001.mtrx`
+pub fn read_omicron_matrix_experiment(paramfile: &str) -> Result<Arc<MatrixExperiment>> {
+    load_experiment(Path::new(paramfile))
+}
+
+pub fn get_param_info(filename: &str) -> Result<ParamData> {
+    let (_, experiment) = resolve_paramfile(filename)?;
+    Ok(param_data(&experiment, basename(filename)?))
+}
+
+/// Collects the parameters valid for the data file `filename` from the paramfile content
+pub fn get_param_info_from_bytes(bytes: &[u8], filename: &str) -> Result<ParamData> {
+    let experiment = MatrixExperiment::from_bytes(bytes)?;
+    Ok(param_data(&experiment, basename(filename)?))
+}
+
+impl ParamData {
+    pub fn channel(&self, name: &str) -> Option<&MatrixChannel> {
+        self.channels.get(name)
+    }
+
+    pub fn get_doub(&self, key: &str) -> Option<f64> {
+        get_doub(&self.params, key)
+    }
+
+    pub fn get_long(&self, key: &str) -> Option<u32> {
+        get_long(&self.params, key)
     }
 
-    Ok(ParamData {
-        current,
-        bias,
-        xsize,
-        ysize,
-        xres,
-        yres,
-        rotation,
-        raster_time,
-        xoffset,
-        yoffset,
-        xretrace,
-        yretrace,
-        tffs,
-    })
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        get_bool(&self.params, key)
+    }
+}
+
+fn get_doub(hm: &HashMap<String, MatrixType>, key: &str) -> Option<f64> {
+    match hm.get(key) {
+        Some(MatrixType::DOUB(x)) => Some(*x),
+        _ => None,
+    }
+}
+
+fn get_long(hm: &HashMap<String, MatrixType>, key: &str) -> Option<u32> {
+    match hm.get(key) {
+        Some(MatrixType::LONG(x)) => Some(*x),
+        _ => None,
+    }
+}
+
+fn get_bool(hm: &HashMap<String, MatrixType>, key: &str) -> Option<bool> {
+    match hm.get(key) {
+        Some(MatrixType::BOOL(x)) => Some(*x != 0),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_experiment_cached() {
+        let path = Path::new("tests/test_files/20201111_0001.mtrx");
+        let first = load_experiment(path).unwrap();
+        let second = load_experiment(path).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(first.data_file("20201111--4_1.Z_mtrx").is_some());
+    }
+
+    #[test]
+    fn test_param_replay() {
+        let bytes = fs::read("tests/test_files/20201111_0001.mtrx").unwrap();
+        let experiment = MatrixExperiment::from_bytes(&bytes).unwrap();
+        let bias = |f: &str| param_data(&experiment, f).bias;
+        assert_eq!(format!("{:.1}", bias("20201111--4_1.Z_mtrx")), "0.6");
+        assert_eq!(bias("20201111--9_1.Z_mtrx"), 1.0);
+        assert_eq!(bias("20201111--18_1.Z_mtrx"), -2.0);
+        assert_eq!(param_data(&experiment, "20201111--10_1.Z_mtrx").xsize, 2e-8);
+    }
 }
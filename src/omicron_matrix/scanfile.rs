@@ -4,10 +4,12 @@ use std::io::Cursor;
 use std::mem::size_of;
 use std::str;
 
+use anyhow::{bail, Context, Result};
 use chrono::prelude::*;
 use chrono::Utc;
 
-use crate::utils::Bytereading;
+use crate::error::{SpmError, SpmResult};
+use crate::utils::{expect_magic, Bytereading};
 
 #[derive(Debug)]
 pub struct ScanData {
@@ -16,13 +18,16 @@ pub struct ScanData {
     pub img_data: Vec<i32>,
 }
 
-pub fn read_omicron_matrix_scanfile(filename: &str) -> ScanData {
-    let bytes = read(filename).unwrap();
+pub fn read_omicron_matrix_scanfile(filename: &str) -> Result<ScanData> {
+    let bytes = read(filename).with_context(|| format!("Cannot read {}", filename))?;
+    read_omicron_matrix_scanfile_from_bytes(&bytes)
+}
+
+pub fn read_omicron_matrix_scanfile_from_bytes(bytes: &[u8]) -> Result<ScanData> {
     let file_length = bytes.len();
-    let mut cursor = Cursor::new(bytes.as_slice());
+    let mut cursor = Cursor::new(bytes);
 
-    let magic_header = cursor.read_magic_header();
-    assert_eq!(magic_header, "ONTMATRX0101");
+    expect_magic(&mut cursor, "ONTMATRX0101")?;
 
     // println!("file length: {}", file_length);
     // let mut position = 0;
@@ -31,13 +36,15 @@ pub fn read_omicron_matrix_scanfile(filename: &str) -> ScanData {
     // }
 
     let scandata = ScanData {
-        datetime: read_bklt(&mut cursor),
-        desc: read_desc(&mut cursor),
-        img_data: read_data(&mut cursor),
+        datetime: read_bklt(&mut cursor)?,
+        desc: read_desc(&mut cursor)?,
+        img_data: read_data(&mut cursor)?,
     };
 
-    assert_eq!(cursor.position(), file_length as u64);
-    scandata
+    if cursor.position() != file_length as u64 {
+        bail!("Unexpected data after position {}", cursor.position());
+    }
+    Ok(scandata)
 }
 
 // fn read_ident_block(cursor: &mut Cursor<&Vec<u8>>) -> IdentBlock {
@@ -52,65 +59,77 @@ pub fn read_omicron_matrix_scanfile(filename: &str) -> ScanData {
 //     }
 // }
 
-fn read_bklt(cursor: &mut Cursor<&[u8]>) -> DateTime<Utc> {
-    let ident: String = cursor.read_matrix_type();
-    assert_eq!(ident, "BKLT");
-    let _len = cursor.read_u32_le();
+/// Block identifiers are stored reversed, so they are compared after `read_matrix_type`
+fn expect_ident(cursor: &mut Cursor<&[u8]>, expected: &str) -> SpmResult<()> {
+    let ident = cursor.read_matrix_type()?;
+    if ident != expected {
+        return Err(SpmError::BadMagic {
+            expected: expected.to_string(),
+            found: ident,
+        });
+    }
+    Ok(())
+}
+
+fn read_bklt(cursor: &mut Cursor<&[u8]>) -> Result<DateTime<Utc>> {
+    expect_ident(cursor, "BKLT")?;
+    let _len = cursor.read_u32_le()?;
     // println!("BKLT len: {}", _len);
 
     // Time when image finished
-    let time = cursor.read_u32_le();
+    let time = cursor.read_u32_le()?;
     // println!("BKLT time: {}", time);
-    let _unused = cursor.read_u32_le();
+    let _unused = cursor.read_u32_le()?;
     // println!("BKLT un: {}", _unused);
 
-    cursor.skip(4);
+    cursor.skip(4)?;
 
-    Utc.timestamp(time as i64, 0)
+    Utc.timestamp_opt(time as i64, 0)
+        .single()
+        .with_context(|| format!("Invalid timestamp {}", time))
     // println!("Datetime: {}", t.with_timezone(&FixedOffset::east(1*3600)).to_string());
     // IdentBlock::BKLT(t)
 }
 
-fn read_desc(cursor: &mut Cursor<&[u8]>) -> HashMap<String, u32> {
-    let ident: String = cursor.read_matrix_type();
-    assert_eq!(ident, "DESC");
-    let _channel_hash = cursor.read_u64_le();
-    cursor.skip(16);
+fn read_desc(cursor: &mut Cursor<&[u8]>) -> SpmResult<HashMap<String, u32>> {
+    expect_ident(cursor, "DESC")?;
+    let _channel_hash = cursor.read_u64_le()?;
+    cursor.skip(16)?;
 
     let mut hm: HashMap<String, u32> = HashMap::new();
-    hm.insert("num_points_set".to_string(), cursor.read_u32_le());
-    hm.insert("num_points_scanned".to_string(), cursor.read_u32_le());
+    hm.insert("num_points_set".to_string(), cursor.read_u32_le()?);
+    hm.insert("num_points_scanned".to_string(), cursor.read_u32_le()?);
 
     // "SI32" don't know how this is useful
-    let _matrix_type = cursor.read_matrix_string();
+    let _matrix_type = cursor.read_matrix_string()?;
 
     // It seems also empty channels with no data listed here
-    hm.insert("num_img_channels".to_string(), cursor.read_u32_le());
+    hm.insert("num_img_channels".to_string(), cursor.read_u32_le()?);
 
-    cursor.skip(8);
+    cursor.skip(8)?;
 
-    hm.insert("num_points_set_alt".to_string(), cursor.read_u32_le());
+    hm.insert("num_points_set_alt".to_string(), cursor.read_u32_le()?);
 
-    hm
+    Ok(hm)
     // println!("DESC hm: {:#?}", hm);
     // IdentBlock::DESC(hm)
 }
 
 // TODO: num images
-fn read_data(cursor: &mut Cursor<&[u8]>) -> Vec<i32> {
-    let ident: String = cursor.read_matrix_type();
-    assert_eq!(ident, "DATA");
-    let len = cursor.read_u32_le();
+fn read_data(cursor: &mut Cursor<&[u8]>) -> SpmResult<Vec<i32>> {
+    expect_ident(cursor, "DATA")?;
+    let len = cursor.read_u32_le()?;
     // println!("DATA len: {}", len);
     let img_data_len = len / size_of::<u32>() as u32;
 
-    let mut img_data = Vec::with_capacity(img_data_len as usize);
+    let remaining = cursor.get_ref().len() / size_of::<u32>();
+    let mut img_data = Vec::with_capacity((img_data_len as usize).min(remaining));
     // TODO: this is the data for all channels
     // DESC shows 4 image channels but the data points here are 2 x 160_000 (2 400x400 pixel images)
     for _ in 0..img_data_len {
-        img_data.push(cursor.read_i32_le());
+        img_data.push(cursor.read_i32_le()?);
     }
-    img_data
+    Ok(img_data)
     // return all data here, then with info from paramfile split it for use in seperate images
     // IdentBlock::DATA(img_data)
 }
@@ -0,0 +1,178 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+
+use crate::omicron_matrix::paramfile::TransferFunction;
+use crate::omicron_matrix::paraminfo::{get_param_info, get_param_info_from_bytes, ParamData};
+use crate::omicron_matrix::scanfile::{
+    read_omicron_matrix_scanfile, read_omicron_matrix_scanfile_from_bytes, ScanData,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SweepDirection {
+    Forward,
+    /// Recorded on the way back when ramp reversal is enabled
+    Backward,
+}
+
+/// A single sweep of the spectroscopy device
+#[derive(Debug)]
+pub struct MatrixCurve {
+    pub direction: SweepDirection,
+    /// Starts at 0
+    pub repetition: u32,
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+}
+
+/// Content of a curve file like `*.I(V)_mtrx`, all values are in SI units
+#[derive(Debug)]
+pub struct MatrixSpectrum {
+    pub datetime: DateTime<Utc>,
+    /// Channel name as in the file name, e.g. "I(V)"
+    pub channel: String,
+    pub x_unit: String,
+    pub y_unit: String,
+    /// Conversion of the raw data, already applied to the curves
+    pub transfer_function: TransferFunction,
+    pub bias: f64,
+    /// Setpoint current in nA, as `OmicronMatrix::current`
+    pub current: f64,
+    pub repetitions: u32,
+    pub ramp_reversal: bool,
+    /// Curves in the order of acquisition, aborted measurements only contain complete curves
+    pub curves: Vec<MatrixCurve>,
+}
+
+pub fn read_omicron_matrix_spectrum(filename: &str) -> Result<MatrixSpectrum> {
+    let paraminfo = get_param_info(filename)?;
+    let scandata = read_omicron_matrix_scanfile(filename)?;
+    build_spectrum(paraminfo, scandata, filename)
+}
+
+/// Reads a curve file together with its paramfile, both already in memory.
+/// `filename` is the name of the curve file, which is looked up in the paramfile.
+pub fn read_omicron_matrix_spectrum_from_bytes(
+    curvefile: &[u8],
+    paramfile: &[u8],
+    filename: &str,
+) -> Result<MatrixSpectrum> {
+    let paraminfo = get_param_info_from_bytes(paramfile, filename)?;
+    let scandata = read_omicron_matrix_scanfile_from_bytes(curvefile)?;
+    build_spectrum(paraminfo, scandata, filename)
+}
+
+/// Channel name from a file name like `20201111--4_1.I(V)_mtrx`
+pub fn channel_name(filename: &str) -> Option<&str> {
+    let basename = Path::new(filename).file_name()?.to_str()?;
+    basename.rsplit_once('.')?.1.strip_suffix("_mtrx")
+}
+
+/// Curve channels have the swept quantity in parentheses, e.g. "I(V)"
+pub fn is_curve_channel(channel: &str) -> bool {
+    channel.ends_with(')')
+}
+
+fn build_spectrum(
+    paraminfo: ParamData,
+    scandata: ScanData,
+    filename: &str,
+) -> Result<MatrixSpectrum> {
+    let channel = channel_name(filename)
+        .filter(|c| is_curve_channel(c))
+        .with_context(|| format!("{} is not a Matrix curve file", filename))?;
+    let sweep = channel
+        .split_once('(')
+        .and_then(|(_, x)| x.strip_suffix(')'))
+        .unwrap_or_default();
+    // V sweeps use the first spectroscopy device, Z sweeps the second one
+    let (device, x_unit, param_unit) = match sweep {
+        "V" => (1, "V", "Volt"),
+        "Z" => (2, "m", "Meter"),
+        _ => bail!("Unsupported spectroscopy channel {}", channel),
+    };
+    let param =
+        |name: &str, unit: &str| format!("Spectroscopy.Device_{}_{} [{}]", device, name, unit);
+    let start = paraminfo
+        .get_doub(&param("Start", param_unit))
+        .with_context(|| format!("Missing start of device {}", device))?;
+    let end = paraminfo
+        .get_doub(&param("End", param_unit))
+        .with_context(|| format!("Missing end of device {}", device))?;
+    let num_points = paraminfo
+        .get_long(&param("Points", "Count"))
+        .with_context(|| format!("Missing number of points of device {}", device))?
+        as usize;
+    let repetitions = paraminfo
+        .get_long(&param("Repetitions", "Count"))
+        .unwrap_or(1);
+    let ramp_reversal = paraminfo
+        .get_bool(&format!(
+            "Spectroscopy.Enable_Device_{}_Ramp_Reversal [--]",
+            device
+        ))
+        .unwrap_or(false);
+    if num_points == 0 {
+        bail!("Spectroscopy device {} has no points", device);
+    }
+
+    let step = if num_points > 1 {
+        (end - start) / (num_points - 1) as f64
+    } else {
+        0.0
+    };
+    let x_forward: Vec<f64> = (0..num_points).map(|i| start + i as f64 * step).collect();
+
+    // Channels missing in the paramfile are stored without transfer function,
+    // their unit is unknown
+    let (y_unit, tff) = match paraminfo.channel(channel) {
+        Some(c) => (c.unit.as_str(), c.transfer_function.clone()),
+        None => ("", TransferFunction::Identity),
+    };
+    let num_points_scanned = scandata
+        .desc
+        .get("num_points_scanned")
+        .map_or(scandata.img_data.len(), |x| *x as usize)
+        .min(scandata.img_data.len());
+    let mut curves = Vec::new();
+    for (i, raw) in scandata.img_data[..num_points_scanned]
+        .chunks_exact(num_points)
+        .enumerate()
+    {
+        let (repetition, direction) = if ramp_reversal {
+            let direction = if i % 2 == 0 {
+                SweepDirection::Forward
+            } else {
+                SweepDirection::Backward
+            };
+            (i / 2, direction)
+        } else {
+            (i, SweepDirection::Forward)
+        };
+        let mut x = x_forward.clone();
+        if direction == SweepDirection::Backward {
+            x.reverse();
+        }
+        let y = raw.iter().map(|v| tff.apply(f64::from(*v))).collect();
+        curves.push(MatrixCurve {
+            direction,
+            repetition: repetition as u32,
+            x,
+            y,
+        });
+    }
+
+    Ok(MatrixSpectrum {
+        datetime: scandata.datetime,
+        channel: channel.to_string(),
+        x_unit: x_unit.to_string(),
+        y_unit: y_unit.to_string(),
+        transfer_function: tff,
+        bias: paraminfo.bias,
+        current: paraminfo.current * 1e9,
+        repetitions,
+        ramp_reversal,
+        curves,
+    })
+}
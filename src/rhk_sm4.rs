@@ -1,10 +1,29 @@
 use anyhow::Result;
-use std::{fs::read, io::Cursor};
-
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::read::ZlibDecoder;
+use ndarray::{s, Array2, Array4};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::read,
+    io::{Cursor, Read},
+    path::Path,
+};
+
+use crate::error::SpmResult;
+use crate::spm_file::{SpmChannel, SpmFile, SpmReader, SpmSpectrum};
+use crate::spm_image::SpmImage;
 use crate::utils::Bytereading;
 
-#[derive(Debug)]
-enum RhkDataType {
+/// Upper bound for preallocations sized by counts read from the file
+const MAX_PREALLOC: usize = 1 << 16;
+
+fn prealloc<T: Into<u64>>(count: T) -> usize {
+    count.into().min(MAX_PREALLOC as u64) as usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RhkDataType {
     DataImage,
     DataLine,
     DataXyData,
@@ -32,7 +51,7 @@ impl RhkDataType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum RhkObjectType {
     Undefined,           //= 0,
     PageIndexHeader,     //= 1,
@@ -66,8 +85,8 @@ enum RhkObjectType {
     AuxPiInfo,           //= 29,
     LowpassFilterR0Info, //= 30,
     LowpassFilterR1Info, //= 31,
-    _FileHeader,          //= -42,
-    _PageIndex,           //= -43,
+    _FileHeader,         //= -42,
+    _PageIndex,          //= -43,
     Unkwown,
 }
 
@@ -125,10 +144,10 @@ enum RhkSourceType {
 impl RhkSourceType {
     fn from_num(num: u32) -> Self {
         match num {
-            1 => Self::SourceRaw,        //= 0,
-            2 => Self::SourceProcessed,  //= 1,
-            3 => Self::SourceCalculated, //= 2,
-            4 => Self::SourceImported,   //= 3,
+            0 => Self::SourceRaw,
+            1 => Self::SourceProcessed,
+            2 => Self::SourceCalculated,
+            3 => Self::SourceImported,
             _ => Self::Unknown,
         }
     }
@@ -144,15 +163,16 @@ enum RhkImageType {
 impl RhkImageType {
     fn from_num(num: u32) -> Self {
         match num {
-            1 => Self::Normal,         //= 0,
-            2 => Self::Autocorrelated, //= 1,
+            0 => Self::Normal,
+            1 => Self::Autocorrelated,
             _ => Self::Unknown,
         }
     }
 }
 
-#[derive(Debug)]
-enum RhkPageType {
+/// What a page contains, e.g. a topography image or IV spectra
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RhkPageType {
     Undefined,                 //= 0,
     Topographic,               //= 1,
     Current,                   //= 2,
@@ -244,21 +264,22 @@ impl RhkPageType {
     }
 }
 
-#[derive(Debug)]
-enum RhkLineType {
+/// Kind of line data, `NotALine` for images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RhkLineType {
     NotALine,                     //= 0,
     Histogram,                    //= 1,
     CrossSection,                 //= 2,
     LineTest,                     //= 3,
     Oscilloscope,                 //= 4,
     NoisePowerSpectrum,           //= 6,
-    IvSpectrum,                  //= 7,
-    IzSpectrum,                  //= 8,
+    IvSpectrum,                   //= 7,
+    IzSpectrum,                   //= 8,
     ImageXAverage,                //= 9,
     ImageYAverage,                //= 10,
     NoiseAutocorrelationSpectrum, //= 11,
     MultichannelAnalyserData,     //= 12,
-    RenormalizedIv,              //= 13,
+    RenormalizedIv,               //= 13,
     ImageHistogramSpectra,        //= 14,
     ImageCrossSection,            //= 15,
     ImageAverage,                 //= 16,
@@ -278,21 +299,36 @@ enum RhkLineType {
 }
 
 impl RhkLineType {
+    /// Whether lines of this type are spectra recorded at a tip position
+    pub fn is_spectrum(&self) -> bool {
+        matches!(
+            self,
+            Self::IvSpectrum
+                | Self::IzSpectrum
+                | Self::RenormalizedIv
+                | Self::DatalogSpectrum
+                | Self::DiscreteSpectroscopy
+                | Self::TimeSpectroscopy
+                | Self::FrequencySweep
+        )
+    }
+
     fn from_num(num: u32) -> Self {
         match num {
-            1 => Self::NotALine,                      //= 0,
-            2 => Self::Histogram,                     //= 1,
-            3 => Self::CrossSection,                  //= 2,
-            4 => Self::LineTest,                      //= 3,
-            5 => Self::Oscilloscope,                  //= 4,
+            0 => Self::NotALine,     //= 0,
+            1 => Self::Histogram,    //= 1,
+            2 => Self::CrossSection, //= 2,
+            3 => Self::LineTest,     //= 3,
+            4 => Self::Oscilloscope, //= 4,
+            // 5 is reserved
             6 => Self::NoisePowerSpectrum,            //= 6,
-            7 => Self::IvSpectrum,                   //= 7,
-            8 => Self::IzSpectrum,                   //= 8,
+            7 => Self::IvSpectrum,                    //= 7,
+            8 => Self::IzSpectrum,                    //= 8,
             9 => Self::ImageXAverage,                 //= 9,
             10 => Self::ImageYAverage,                //= 10,
             11 => Self::NoiseAutocorrelationSpectrum, //= 11,
             12 => Self::MultichannelAnalyserData,     //= 12,
-            13 => Self::RenormalizedIv,              //= 13,
+            13 => Self::RenormalizedIv,               //= 13,
             14 => Self::ImageHistogramSpectra,        //= 14,
             15 => Self::ImageCrossSection,            //= 15,
             16 => Self::ImageAverage,                 //= 16,
@@ -313,8 +349,9 @@ impl RhkLineType {
     }
 }
 
-#[derive(Debug)]
-enum RhkScanType {
+/// Fast scan direction of an image page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RhkScanType {
     ScanRight, //= 0,
     ScanLeft,  //= 1,
     ScanUp,    //= 2,
@@ -325,94 +362,29 @@ enum RhkScanType {
 impl RhkScanType {
     fn from_num(num: u32) -> Self {
         match num {
-            1 => Self::ScanRight,
-            2 => Self::ScanLeft,
-            3 => Self::ScanUp,
-            4 => Self::ScanDown,
+            0 => Self::ScanRight,
+            1 => Self::ScanLeft,
+            2 => Self::ScanUp,
+            3 => Self::ScanDown,
             _ => Self::Unknown,
         }
     }
 }
 
-#[derive(Debug)]
-enum RhkStringType {
-    Label,
-    SystemText,
-    SessionText,
-    UserText,
-    Path,
-    Date,
-    Time,
-    XUnits,
-    YUnits,
-    ZUnits,
-    XLnits,
-    YLnits,
-    StatusChannelText,
-    CompletedLineCount,
-    OversamplingCount,
-    SlicedVoltage,
-    PllProStatus,
-    NString,
-    Unknown,
-}
-
-impl RhkStringType {
-    fn from_num(num: u32) -> Self {
-        match num {
-            1 => Self::Label,
-            2 => Self::SystemText,
-            3 => Self::SessionText,
-            4 => Self::UserText,
-            5 => Self::Path,
-            6 => Self::Date,
-            7 => Self::Time,
-            8 => Self::XUnits,
-            9 => Self::YUnits,
-            10 => Self::ZUnits,
-            11 => Self::XLnits,
-            12 => Self::YLnits,
-            13 => Self::StatusChannelText,
-            14 => Self::CompletedLineCount,
-            15 => Self::OversamplingCount,
-            16 => Self::SlicedVoltage,
-            17 => Self::PllProStatus,
-            18 => Self::NString,
-            _ => Self::Unknown,
-        }
-    }
-}
-
-#[derive(Debug)]
-enum RhkPiezoStringType {
-    TubeXUnit,
-    TubeYUnit,
-    TubeZUnit,
-    TubeZoffetUnit,
-    ScanXUnit,
-    ScanYUnit,
-    ScanZUnit,
-    ActuatorUnit,
-    TubeCalibration,
-    ScanCalibration,
-    AcutuatorCalibration,
-    NString,
-}
-
 #[derive(Debug)]
 enum RhkDriftOptionType {
-    Disabled,      //= 0,
-    Each_Spectra,  //= 1,
-    Each_Location, //= 2
+    Disabled,     //= 0,
+    EachSpectra,  //= 1,
+    EachLocation, //= 2
     Unknown,
 }
 
 impl RhkDriftOptionType {
     fn from_num(num: u32) -> Self {
         match num {
-            1 => Self::Disabled,      //= 0,
-            2 => Self::Each_Spectra,  //= 1,
-            3 => Self::Each_Location, //= 2
+            0 => Self::Disabled,
+            1 => Self::EachSpectra,
+            2 => Self::EachLocation,
             _ => Self::Unknown,
         }
     }
@@ -535,15 +507,19 @@ impl RhkDriftOptionType {
 
 #[derive(Debug)]
 struct Sm4Header {
+    #[allow(dead_code)]
     size: u16,
+    #[allow(dead_code)]
     signature: String,
+    #[allow(dead_code)]
     page_count: u32,
     object_list_count: u32,
+    #[allow(dead_code)]
     object_field_size: u32,
     object_list: Vec<Sm4Object>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Sm4Object {
     obj_type: RhkObjectType,
     offset: u32,
@@ -552,6 +528,7 @@ struct Sm4Object {
 
 #[derive(Debug)]
 struct PageIndexHeader {
+    #[allow(dead_code)]
     offset: u32,
     page_count: u32,
     object_count: u32,
@@ -560,10 +537,13 @@ struct PageIndexHeader {
 
 #[derive(Debug)]
 struct Sm4Page {
+    #[allow(dead_code)]
     page_id: u16,
     page_data_type: RhkDataType,
+    #[allow(dead_code)]
     page_source_type: RhkSourceType,
     object_list_count: u32,
+    #[allow(dead_code)]
     minor_version: u32,
     object_list: Vec<Sm4Object>,
 }
@@ -577,12 +557,23 @@ enum Sm4PageHeader {
 #[derive(Debug)]
 struct Sm4PageHeaderSequential {
     data_type: u32,
+    #[allow(dead_code)]
     data_length: u32,
     param_count: u32,
     object_list_count: u32,
+    #[allow(dead_code)]
     data_info_size: u32,
+    #[allow(dead_code)]
     data_info_string_count: u32,
     object_list: Vec<Sm4Object>,
+    params: Vec<SequentialParam>,
+}
+
+#[derive(Debug)]
+struct SequentialParam {
+    gain: f32,
+    label: String,
+    unit: String,
 }
 
 #[derive(Debug)]
@@ -590,24 +581,33 @@ struct Sm4PageHeaderDefault {
     string_count: u16,
     page_type: RhkPageType,
     // Channel: e.g. Topograhic, Current,...
+    #[allow(dead_code)]
     data_sub_source: u32,
     line_type: RhkLineType,
+    #[allow(dead_code)]
     x_corner: u32,
+    #[allow(dead_code)]
     y_corner: u32,
     // xres
     x_size: u32,
     // yres
     y_size: u32,
+    #[allow(dead_code)]
     image_type: RhkImageType,
     scan_type: RhkScanType,
+    #[allow(dead_code)]
     group_id: u32,
+    #[allow(dead_code)]
     page_data_size: u32,
+    #[allow(dead_code)]
     min_z_value: u32,
+    #[allow(dead_code)]
     max_z_value: u32,
     // x_scale * x_size gives physical dimensions
     x_scale: f32,
     y_scale: f32,
     z_scale: f32,
+    #[allow(dead_code)]
     xy_scale: f32,
     // offsets
     x_offset: f32,
@@ -620,6 +620,7 @@ struct Sm4PageHeaderDefault {
     current: f32,
     // rotation
     angle: f32,
+    #[allow(dead_code)]
     color_info_count: u32,
     grid_x_size: u32,
     grid_y_size: u32,
@@ -628,8 +629,19 @@ struct Sm4PageHeaderDefault {
     object_list: Vec<Sm4Object>,
 }
 
+/// A single page of the file, images have `xres` x `yres` values,
+/// line pages `yres` curves with `xres` points each
 #[derive(Debug)]
 pub struct Sm4Image {
+    pub data_type: RhkDataType,
+    pub page_type: RhkPageType,
+    pub line_type: RhkLineType,
+    pub scan_type: RhkScanType,
+    /// Channel name, e.g. "Topography" or "Current"
+    pub label: String,
+    pub x_units: String,
+    pub y_units: String,
+    pub z_units: String,
     pub current: f64,
     pub bias: f64,
     pub xsize: f64,
@@ -640,93 +652,544 @@ pub struct Sm4Image {
     pub raster_time: f64,
     pub xoffset: f64,
     pub yoffset: f64,
+    /// Step of the x axis, negative if the x axis is reversed
+    pub x_scale: f64,
+    /// Number of columns and rows of grid spectroscopy, 0 for other pages
+    pub grid_xres: u32,
+    pub grid_yres: u32,
+    /// Tip position of every curve of a line page in m, empty if the file has no drift data
+    pub positions: Vec<(f64, f64)>,
+    /// Cumulative drift when the image was recorded
+    pub image_drift: Option<Sm4Track>,
+    /// Cumulative drift for every curve of a line page
+    pub spec_drift: Option<Sm4Track>,
+    /// Trajectory of the tip while tracking a feature
+    pub tip_track: Option<Sm4Track>,
+    pub metadata: Sm4Metadata,
     pub data: Vec<f64>,
 }
 
+/// Time stamped positions, e.g. the recorded drift or the trajectory of the tip
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Sm4Track {
+    /// Start of the recording
+    pub start: Option<DateTime<Utc>>,
+    /// Seconds since `start`
+    pub time: Vec<f64>,
+    /// Positions in m
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+    /// Heights in m, empty if the file records only lateral positions
+    pub z: Vec<f64>,
+}
+
+impl Sm4Track {
+    /// The latest position
+    pub fn last(&self) -> Option<(f64, f64)> {
+        Some((*self.x.last()?, *self.y.last()?))
+    }
+}
+
+/// Auxiliary objects stored with a page, objects missing in the file are `None`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Sm4Metadata {
+    pub strings: StringData,
+    /// Text of the parameter file
+    pub prm: Option<String>,
+    pub api_info: Option<ApiInfo>,
+    pub piezo_sensitivity: Option<PiezoSensitivity>,
+    pub frequency_sweep: Option<FrequencySweepData>,
+    pub scan_processor: Option<ScanProcessorInfo>,
+    pub pll: Option<PllInfo>,
+    pub ch1_drive: Option<ChannelDriveInfo>,
+    pub ch2_drive: Option<ChannelDriveInfo>,
+    pub lockin0: Option<LockinInfo>,
+    pub lockin1: Option<LockinInfo>,
+    pub z_pi: Option<PiControllerInfo>,
+    pub k_pi: Option<PiControllerInfo>,
+    pub aux_pi: Option<PiControllerInfo>,
+    pub lowpass_filter_r0: Option<String>,
+    pub lowpass_filter_r1: Option<String>,
+}
+
+/// A table of values recorded one after another, e.g. a data log
+#[derive(Debug)]
+pub struct Sm4Sequential {
+    pub labels: Vec<String>,
+    pub units: Vec<String>,
+    /// One column per label, already scaled by the gain of the column
+    pub columns: Vec<Vec<f64>>,
+}
+
+/// All pages of a file
+#[derive(Debug)]
+pub struct Sm4File {
+    /// Image and line pages in the order of the file
+    pub pages: Vec<Sm4Image>,
+    pub sequential: Vec<Sm4Sequential>,
+}
+
+impl Sm4Image {
+    /// Name of the scan direction as used for the other formats
+    pub fn direction(&self) -> &'static str {
+        match self.scan_type {
+            RhkScanType::ScanRight => "forward",
+            RhkScanType::ScanLeft => "backward",
+            RhkScanType::ScanUp => "up",
+            RhkScanType::ScanDown => "down",
+            RhkScanType::Unknown => "unknown",
+        }
+    }
+
+    /// Whether the page holds spectra, e.g. I(V), dI/dV or Z(V) curves
+    pub fn is_spectrum(&self) -> bool {
+        self.data_type == RhkDataType::DataLine && self.line_type.is_spectrum()
+    }
+
+    /// x values shared by all curves of a line page
+    pub fn x_axis(&self) -> Vec<f64> {
+        (0..self.xres)
+            .map(|i| self.xoffset + i as f64 * self.x_scale)
+            .collect()
+    }
+
+    /// The `yres` curves of a line page
+    pub fn curves(&self) -> std::slice::Chunks<'_, f64> {
+        self.data.chunks(self.xres.max(1) as usize)
+    }
+
+    /// The spectra of a grid spectroscopy page as data cube, `None` for other pages.
+    /// Several curves at a grid point, e.g. forward and backward sweeps, are kept apart.
+    pub fn grid(&self) -> Option<Sm4Grid> {
+        let (nx, ny) = (self.grid_xres as usize, self.grid_yres as usize);
+        let num_points = nx * ny;
+        if !self.is_spectrum()
+            || num_points == 0
+            || !(self.yres as usize).is_multiple_of(num_points)
+        {
+            return None;
+        }
+        let per_point = self.yres as usize / num_points;
+        let xres = self.xres as usize;
+        let mut data = Array4::zeros((ny, nx, per_point, xres));
+        for (i, curve) in self.curves().enumerate() {
+            let point = i / per_point;
+            let mut spectrum = data.slice_mut(s![point / nx, point % nx, i % per_point, ..]);
+            for (value, v) in spectrum.iter_mut().zip(curve) {
+                *value = *v;
+            }
+        }
+        // Grid points are recorded row by row
+        let position = |point: usize| self.positions.get(point * per_point).copied();
+        let x = (0..nx).map(|i| position(i).map(|p| p.0)).collect();
+        let y = (0..ny).map(|j| position(j * nx).map(|p| p.1)).collect();
+        Some(Sm4Grid {
+            label: self.label.clone(),
+            bias_unit: self.x_units.clone(),
+            unit: self.z_units.clone(),
+            x,
+            y,
+            bias: self.x_axis(),
+            data,
+        })
+    }
+}
+
+/// Spectra recorded on a grid, e.g. current imaging tunnelling spectroscopy
+#[derive(Debug, Clone)]
+pub struct Sm4Grid {
+    pub label: String,
+    pub bias_unit: String,
+    /// Unit of the data, e.g. "A"
+    pub unit: String,
+    /// Positions of the columns in m, `None` if the file has no drift data
+    pub x: Option<Vec<f64>>,
+    /// Positions of the rows in m, `None` if the file has no drift data
+    pub y: Option<Vec<f64>>,
+    /// Values of the swept quantity, usually the bias
+    pub bias: Vec<f64>,
+    /// Indexed by row, column, curve at the grid point in the order of
+    /// recording, e.g. forward and backward sweep, and bias
+    pub data: Array4<f64>,
+}
+
+impl Sm4Grid {
+    /// The map of the curve `sweep` at the bias closest to `bias`, e.g. a
+    /// constant energy dI/dV map of the forward sweeps
+    pub fn map_at(&self, bias: f64, sweep: usize) -> Option<Array2<f64>> {
+        if sweep >= self.data.shape()[2] {
+            return None;
+        }
+        let index = self
+            .bias
+            .iter()
+            .enumerate()
+            .min_by(|a, b| (a.1 - bias).abs().total_cmp(&(b.1 - bias).abs()))?
+            .0;
+        Some(self.data.slice(s![.., .., sweep, index]).to_owned())
+    }
+}
+
+/// Image and line pages of the file `filename`
 pub fn read_rhk_sm4(filename: &str) -> Result<Vec<Sm4Image>> {
     let bytes = read(filename)?;
+    read_rhk_sm4_from_bytes(&bytes)
+}
+
+/// All pages of the file `filename` including sequential data
+pub fn read_rhk_sm4_file(filename: &str) -> Result<Sm4File> {
+    let bytes = read(filename)?;
+    read_rhk_sm4_file_from_bytes(&bytes)
+}
+
+pub fn read_rhk_sm4_from_reader<R: Read>(mut reader: R) -> Result<Vec<Sm4Image>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    read_rhk_sm4_from_bytes(&bytes)
+}
+
+pub fn read_rhk_sm4_from_bytes(bytes: &[u8]) -> Result<Vec<Sm4Image>> {
+    Ok(read_rhk_sm4_file_from_bytes(bytes)?.pages)
+}
+
+pub fn read_rhk_sm4_file_from_bytes(bytes: &[u8]) -> Result<Sm4File> {
     let _file_len = bytes.len();
-    let mut cursor = Cursor::new(bytes.as_slice());
+    let mut cursor = Cursor::new(bytes);
 
-    let mut header = read_header(&mut cursor);
+    let mut header = read_header(&mut cursor)?;
 
     for _ in 0..header.object_list_count {
-        header.object_list.push(read_sm4_object(&mut cursor))
+        header.object_list.push(read_sm4_object(&mut cursor)?)
     }
 
     let page_index_header = get_page_index_header(&mut cursor, &header.object_list)?;
 
-    let mut page_index_header_list = Vec::with_capacity(page_index_header.object_count as usize);
+    let mut page_index_header_list = Vec::with_capacity(prealloc(page_index_header.object_count));
     for _ in 0..page_index_header.object_count {
-        page_index_header_list.push(read_sm4_object(&mut cursor))
+        page_index_header_list.push(read_sm4_object(&mut cursor)?)
     }
 
     let page_index_array_offset = get_offset_page_index_array(&page_index_header_list)?;
     cursor.set_position(page_index_array_offset as u64);
 
-    let mut pages = Vec::with_capacity(page_index_header.page_count as usize);
+    let mut pages = Vec::with_capacity(prealloc(page_index_header.page_count));
     for _ in 0..page_index_header.page_count {
-        let mut page = read_sm4_page(&mut cursor);
+        let mut page = read_sm4_page(&mut cursor)?;
 
         for _ in 0..page.object_list_count {
-            page.object_list.push(read_sm4_object(&mut cursor));
+            page.object_list.push(read_sm4_object(&mut cursor)?);
         }
 
         pages.push(page);
     }
 
-    let mut page_objects = Vec::with_capacity(pages.len());
     let mut images = Vec::new();
+    let mut sequential = Vec::new();
     for page in &pages {
-        let mut page_header = read_page_header(&mut cursor, &page)?;
+        let mut page_header = read_page_header(&mut cursor, page)?;
         match page_header {
             Sm4PageHeader::Sequential(ref mut ph) => {
-                ph.object_list.push(read_sm4_object(&mut cursor));
-
-                let mut sequential_param_gain = Vec::with_capacity(ph.param_count as usize);
-                let mut sequential_param_label = Vec::with_capacity(ph.param_count as usize);
-                let mut sequential_param_unit = Vec::with_capacity(ph.param_count as usize);
+                for _ in 0..ph.object_list_count {
+                    ph.object_list.push(read_sm4_object(&mut cursor)?);
+                }
                 for _ in 0..ph.param_count {
-                    sequential_param_gain.push(cursor.read_f32_le());
-                    sequential_param_label.push(read_sm4_string(&mut cursor));
-                    sequential_param_unit.push(read_sm4_string(&mut cursor));
+                    ph.params.push(SequentialParam {
+                        gain: cursor.read_f32_le()?,
+                        label: read_sm4_string(&mut cursor)?,
+                        unit: read_sm4_string(&mut cursor)?,
+                    });
+                }
+            }
+            Sm4PageHeader::Default(ref mut ph) => {
+                for _ in 0..ph.object_list_count {
+                    ph.object_list.push(read_sm4_object(&mut cursor)?)
                 }
             }
-            Sm4PageHeader::Default(ref mut ph) => ph.object_list.push(read_sm4_object(&mut cursor)),
         }
+        // objects of the page index (header, data) and of the page header (strings, drift, ...)
+        let header_objects = match &page_header {
+            Sm4PageHeader::Default(ph) => ph.object_list.clone(),
+            Sm4PageHeader::Sequential(ph) => ph.object_list.clone(),
+        };
         let mut tiptrack_info_count = 0;
-        let mut read_objects = Vec::with_capacity(page.object_list.len());
-        for obj in &page.object_list {
+        let mut page_data = None;
+        let mut metadata = Sm4Metadata::default();
+        let mut positions = Vec::new();
+        let mut image_drift: Option<Sm4Track> = None;
+        let mut spec_drift: Option<Sm4Track> = None;
+        let mut tip_track: Option<Sm4Track> = None;
+        for obj in page.object_list.iter().chain(&header_objects) {
             if obj.offset != 0 && obj.size != 0 {
                 let read_obj =
                     read_object_content(obj, &page_header, &mut cursor, &mut tiptrack_info_count)?;
-                match (&read_obj, &page_header) {
-                    (ReadType::PageData(data), Sm4PageHeader::Default(ph)) => {
-                        if let RhkPageType::Topographic = ph.page_type {
-                            images.push(Sm4Image {
-                                current: ph.current as f64,
-                                bias: ph.bias as f64,
-                                xsize: (ph.x_scale as f64 * ph.x_size as f64).abs(),
-                                ysize: (ph.y_size as f64 * ph.y_scale as f64).abs(),
-                                xres: ph.x_size,
-                                yres: ph.y_size,
-                                rotation: ph.angle as f64,
-                                raster_time: ph.period as f64,
-                                xoffset: ph.x_offset as f64,
-                                yoffset: ph.y_offset as f64,
-                                data: data.clone(),
+                match read_obj {
+                    ReadType::PageData(data) => page_data = Some(data),
+                    ReadType::StringData(data) => metadata.strings = data,
+                    ReadType::Prm(data) => metadata.prm = Some(data),
+                    ReadType::ApiInfo(data) => metadata.api_info = Some(data),
+                    ReadType::PiezoSensitivity(data) => metadata.piezo_sensitivity = Some(data),
+                    ReadType::FrequencySweepData(data) => metadata.frequency_sweep = Some(data),
+                    ReadType::ScanprocessorInfo(data) => metadata.scan_processor = Some(data),
+                    ReadType::PllInfo(data) => metadata.pll = Some(data),
+                    ReadType::ChannelDriveInfo(data) => match obj.obj_type {
+                        RhkObjectType::Ch1DriveInfo => metadata.ch1_drive = Some(data),
+                        _ => metadata.ch2_drive = Some(data),
+                    },
+                    ReadType::LockinInfo(data) => match obj.obj_type {
+                        RhkObjectType::Lockin0Info => metadata.lockin0 = Some(data),
+                        _ => metadata.lockin1 = Some(data),
+                    },
+                    ReadType::PiControllerInfo(data) => match obj.obj_type {
+                        RhkObjectType::ZpiInfo => metadata.z_pi = Some(data),
+                        RhkObjectType::KpiInfo => metadata.k_pi = Some(data),
+                        _ => metadata.aux_pi = Some(data),
+                    },
+                    ReadType::LowpassFilterInfo(data) => match obj.obj_type {
+                        RhkObjectType::LowpassFilterR0Info => {
+                            metadata.lowpass_filter_r0 = Some(data)
+                        }
+                        _ => metadata.lowpass_filter_r1 = Some(data),
+                    },
+                    ReadType::SequentialData(columns) => {
+                        if let Sm4PageHeader::Sequential(ph) = &page_header {
+                            sequential.push(Sm4Sequential {
+                                labels: ph.params.iter().map(|p| p.label.clone()).collect(),
+                                units: ph.params.iter().map(|p| p.unit.clone()).collect(),
+                                columns,
                             });
                         }
                     }
+                    ReadType::ImageDriftHeader(header) => {
+                        image_drift.get_or_insert_with(Default::default).start =
+                            start_time(header.imagedrift_filetime);
+                    }
+                    ReadType::ImageDriftData(data) => {
+                        let track = image_drift.get_or_insert_with(Default::default);
+                        track.time = vec![data.imagedrift_time as f64];
+                        track.x = vec![data.imagedrift_cumulative_x as f64];
+                        track.y = vec![data.imagedrift_cumulative_y as f64];
+                    }
+                    ReadType::SpecDriftHeader(header) => {
+                        spec_drift.get_or_insert_with(Default::default).start =
+                            start_time(header.specdrift_filetime);
+                    }
+                    ReadType::SpecDriftData(data) => {
+                        positions = data
+                            .specdrift_x_coord
+                            .iter()
+                            .zip(&data.specdrift_y_coord)
+                            .map(|(x, y)| (*x as f64, *y as f64))
+                            .collect();
+                        let track = spec_drift.get_or_insert_with(Default::default);
+                        track.time = to_f64(&data.specdrift_time);
+                        track.x = to_f64(&data.specdrift_cumulative_x);
+                        track.y = to_f64(&data.specdrift_cumulative_y);
+                    }
+                    ReadType::TipTrackHeader(header) => {
+                        tip_track.get_or_insert_with(Default::default).start =
+                            start_time(header.tiptrack_filetime);
+                    }
+                    // The tip track stores the steps, the trajectory is their sum
+                    ReadType::TipTrackData(data) => {
+                        let track = tip_track.get_or_insert_with(Default::default);
+                        track.time = to_f64(&data.tiptrack_cumulative_time);
+                        track.x = cumulative_sum(&data.tiptrack_dx);
+                        track.y = cumulative_sum(&data.tiptrack_dy);
+                        track.z = cumulative_sum(&data.tiptrack_dz);
+                    }
                     _ => {}
                 };
-                read_objects.push(read_obj);
             }
         }
-        page_objects.push(read_objects)
+        if let (Sm4PageHeader::Default(ph), Some(data)) = (&page_header, page_data) {
+            images.push(Sm4Image {
+                data_type: page.page_data_type,
+                page_type: ph.page_type,
+                line_type: ph.line_type,
+                scan_type: ph.scan_type,
+                label: metadata.strings.label.trim().to_string(),
+                x_units: metadata.strings.x_units.clone(),
+                y_units: metadata.strings.y_units.clone(),
+                z_units: metadata.strings.z_units.clone(),
+                current: ph.current as f64,
+                bias: ph.bias as f64,
+                xsize: (ph.x_scale as f64 * ph.x_size as f64).abs(),
+                ysize: (ph.y_size as f64 * ph.y_scale as f64).abs(),
+                xres: ph.x_size,
+                yres: ph.y_size,
+                rotation: ph.angle as f64,
+                raster_time: ph.period as f64,
+                xoffset: ph.x_offset as f64,
+                yoffset: ph.y_offset as f64,
+                x_scale: ph.x_scale as f64,
+                grid_xres: ph.grid_x_size,
+                grid_yres: ph.grid_y_size,
+                positions,
+                image_drift,
+                spec_drift,
+                tip_track,
+                metadata,
+                data,
+            });
+        }
+    }
+
+    Ok(Sm4File {
+        pages: images,
+        sequential,
+    })
+}
+
+/// Shifts the offsets of the image pages by the cumulative drift recorded since
+/// the first image with drift data, so their positions refer to the same frame.
+/// Only `xoffset` and `yoffset` change, the data is not resampled. Pages without
+/// drift data are unchanged.
+pub fn correct_offsets_for_drift(pages: &mut [Sm4Image]) {
+    let mut reference = None;
+    for page in pages
+        .iter_mut()
+        .filter(|p| p.data_type == RhkDataType::DataImage)
+    {
+        let Some((dx, dy)) = page.image_drift.as_ref().and_then(|d| d.last()) else {
+            continue;
+        };
+        let (x0, y0) = *reference.get_or_insert((dx, dy));
+        page.xoffset -= dx - x0;
+        page.yoffset -= dy - y0;
     }
+}
+
+// Start times are stored as seconds since the unix epoch
+fn start_time(filetime: u64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(i64::try_from(filetime).ok()?, 0).single()
+}
+
+fn cumulative_sum(values: &[f32]) -> Vec<f64> {
+    values
+        .iter()
+        .scan(0.0, |sum, v| {
+            *sum += *v as f64;
+            Some(*sum)
+        })
+        .collect()
+}
 
-    Ok(images)
+fn to_f64(values: &[f32]) -> Vec<f64> {
+    values.iter().map(|v| *v as f64).collect()
+}
+
+pub struct Sm4Reader;
+
+impl SpmReader for Sm4Reader {
+    fn name(&self) -> &'static str {
+        "rhk_sm4"
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &[".sm4"]
+    }
+
+    // The header size is followed by the signature "STiMage 005.00x" in UTF-16
+    fn detect(&self, head: &[u8], _file_len: u64) -> f32 {
+        let signature: Vec<u8> = "STiMage".bytes().flat_map(|b| [b, 0]).collect();
+        if head.len() >= 2 + signature.len() && head[2..2 + signature.len()] == signature[..] {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn read(&self, filename: &str) -> Result<SpmFile> {
+        self.read_bytes(filename, &read(filename)?)
+    }
+
+    // Image pages become channels, every curve of a spectroscopy page and
+    // every column of sequential data a spectrum
+    fn read_bytes(&self, filename: &str, bytes: &[u8]) -> Result<SpmFile> {
+        let Sm4File { pages, sequential } = read_rhk_sm4_file_from_bytes(bytes)?;
+        let mut spm_file = SpmFile::new(Path::new(filename), self.name());
+        if let Some(page) = pages.first() {
+            spm_file.metadata = BTreeMap::from([
+                ("bias [V]".to_string(), page.bias.to_string()),
+                ("current [nA]".to_string(), (page.current * 1e9).to_string()),
+                ("xoffset [nm]".to_string(), (page.xoffset * 1e9).to_string()),
+                ("yoffset [nm]".to_string(), (page.yoffset * 1e9).to_string()),
+                ("rotation [deg]".to_string(), page.rotation.to_string()),
+                ("period [s]".to_string(), page.raster_time.to_string()),
+                ("date".to_string(), page.metadata.strings.date.clone()),
+                ("time".to_string(), page.metadata.strings.time.clone()),
+            ]);
+        }
+        // Several pages can share a label, e.g. forward and backward sweeps, so
+        // the names contain the index of the page
+        for (index, page) in pages.iter().enumerate().filter(|(_, p)| p.is_spectrum()) {
+            let x = page.x_axis();
+            for (i, curve) in page.curves().enumerate() {
+                let mut metadata = BTreeMap::from([
+                    ("page".to_string(), index.to_string()),
+                    ("direction".to_string(), page.direction().to_string()),
+                    ("line_type".to_string(), format!("{:?}", page.line_type)),
+                    ("bias [V]".to_string(), page.bias.to_string()),
+                ]);
+                if let Some((xpos, ypos)) = page.positions.get(i) {
+                    metadata.insert("x [nm]".to_string(), (xpos * 1e9).to_string());
+                    metadata.insert("y [nm]".to_string(), (ypos * 1e9).to_string());
+                }
+                spm_file.spectra.push(SpmSpectrum {
+                    name: format!("{}_{}_{}", page.label, index, i),
+                    metadata,
+                    x_unit: page.x_units.clone(),
+                    y_unit: page.z_units.clone(),
+                    x: x.clone(),
+                    y: curve.to_vec(),
+                });
+            }
+        }
+        // The first column of sequential data is the x axis of the others
+        for table in sequential {
+            let (Some(x), Some(x_unit)) = (table.columns.first(), table.units.first()) else {
+                continue;
+            };
+            for ((label, unit), y) in table
+                .labels
+                .iter()
+                .zip(&table.units)
+                .zip(&table.columns)
+                .skip(1)
+            {
+                spm_file.spectra.push(SpmSpectrum {
+                    name: label.clone(),
+                    metadata: BTreeMap::new(),
+                    x_unit: x_unit.clone(),
+                    y_unit: unit.clone(),
+                    x: x.clone(),
+                    y: y.clone(),
+                });
+            }
+        }
+        for page in pages {
+            if page.data_type != RhkDataType::DataImage {
+                continue;
+            }
+            let metadata = BTreeMap::from([
+                ("page_type".to_string(), format!("{:?}", page.page_type)),
+                ("direction".to_string(), page.direction().to_string()),
+                ("unit".to_string(), page.z_units.clone()),
+            ]);
+            spm_file.channels.push(SpmChannel {
+                metadata,
+                image: SpmImage {
+                    img_id: format!("{}_{}", page.label, page.direction()),
+                    xres: page.xres as usize,
+                    yres: page.yres as usize,
+                    xsize: page.xsize * 1e9,
+                    ysize: page.ysize * 1e9,
+                    img_data: page.data,
+                },
+            });
+        }
+        Ok(spm_file)
+    }
 }
 
 fn read_object_content(
@@ -736,19 +1199,20 @@ fn read_object_content(
     tiptrack_info_count: &mut u32,
 ) -> Result<ReadType> {
     let read_obj = match obj.obj_type {
-        RhkObjectType::PageData => {
-            if let Sm4PageHeader::Default(ph) = page_header {
-                read_page_data(cursor, obj.offset, obj.size, ph.z_scale, ph.z_offset)
-            } else {
-                ReadType::Unknown
+        RhkObjectType::PageData => match page_header {
+            Sm4PageHeader::Default(ph) => {
+                read_page_data(cursor, obj.offset, obj.size, ph.z_scale, ph.z_offset)?
             }
-        }
-        RhkObjectType::ImageDriftHeader => read_image_drift_header(cursor, obj.offset),
-        RhkObjectType::ImageDrift => read_image_drift(cursor, obj.offset),
-        RhkObjectType::SpecDriftHeader => read_spec_drift_header(cursor, obj.offset),
+            Sm4PageHeader::Sequential(ph) => {
+                read_sequential_data(cursor, obj.offset, obj.size, ph)?
+            }
+        },
+        RhkObjectType::ImageDriftHeader => read_image_drift_header(cursor, obj.offset)?,
+        RhkObjectType::ImageDrift => read_image_drift(cursor, obj.offset)?,
+        RhkObjectType::SpecDriftHeader => read_spec_drift_header(cursor, obj.offset)?,
         RhkObjectType::SpecDriftData => {
             if let Sm4PageHeader::Default(ph) = page_header {
-                read_spec_drift_data(cursor, obj.offset, ph.y_size)
+                read_spec_drift_data(cursor, obj.offset, ph.y_size)?
             } else {
                 ReadType::Unknown
             }
@@ -756,20 +1220,20 @@ fn read_object_content(
         RhkObjectType::ColorInfo => ReadType::Unknown,
         RhkObjectType::StringData => {
             if let Sm4PageHeader::Default(ph) = page_header {
-                read_string_data(cursor, obj.offset, ph.string_count)
+                read_string_data(cursor, obj.offset, ph.string_count)?
             } else {
                 ReadType::Unknown
             }
         }
         RhkObjectType::TipTrackHeader => {
-            let tiptrack_header = read_tip_track_header(cursor, obj.offset);
+            let tiptrack_header = read_tip_track_header(cursor, obj.offset)?;
             if let ReadType::TipTrackHeader(tth) = &tiptrack_header {
                 *tiptrack_info_count = tth.tiptrack_tiptrack_info_count;
             }
             tiptrack_header
         }
         RhkObjectType::TipTrackData => {
-            read_tip_track_data(cursor, obj.offset, *tiptrack_info_count)
+            read_tip_track_data(cursor, obj.offset, obj.size, *tiptrack_info_count)?
         }
         RhkObjectType::Prm => ReadType::Unknown,
         RhkObjectType::PrmHeader => {
@@ -779,201 +1243,34 @@ fn read_object_content(
                 ReadType::Unknown
             }
         }
-        RhkObjectType::ApiInfo => read_api_info(cursor, obj.offset),
-        RhkObjectType::HistoryInfo => read_history_info(cursor, obj.offset),
-        RhkObjectType::PiezoSensitivity => read_piezo_sensitivity(cursor, obj.offset),
-        RhkObjectType::FrequencySweepData => read_frequency_sweep_data(cursor, obj.offset),
-        RhkObjectType::ScanProcessorInfo => read_scan_processor_info(cursor, obj.offset),
-        RhkObjectType::PllInfo => read_pll_info(cursor, obj.offset),
+        RhkObjectType::ApiInfo => read_api_info(cursor, obj.offset)?,
+        RhkObjectType::HistoryInfo => read_history_info(cursor, obj.offset)?,
+        RhkObjectType::PiezoSensitivity => read_piezo_sensitivity(cursor, obj.offset)?,
+        RhkObjectType::FrequencySweepData => read_frequency_sweep_data(cursor, obj.offset)?,
+        RhkObjectType::ScanProcessorInfo => read_scan_processor_info(cursor, obj.offset)?,
+        RhkObjectType::PllInfo => read_pll_info(cursor, obj.offset)?,
 
-        RhkObjectType::Ch1DriveInfo => read_channel_drive_info(cursor, obj.offset),
-        RhkObjectType::Ch2DriveInfo => read_channel_drive_info(cursor, obj.offset),
+        RhkObjectType::Ch1DriveInfo => read_channel_drive_info(cursor, obj.offset)?,
+        RhkObjectType::Ch2DriveInfo => read_channel_drive_info(cursor, obj.offset)?,
 
-        RhkObjectType::Lockin0Info => read_lockin_info(cursor, obj.offset),
-        RhkObjectType::Lockin1Info => read_lockin_info(cursor, obj.offset),
+        RhkObjectType::Lockin0Info => read_lockin_info(cursor, obj.offset)?,
+        RhkObjectType::Lockin1Info => read_lockin_info(cursor, obj.offset)?,
 
-        RhkObjectType::ZpiInfo => read_pi_controller_info(cursor, obj.offset),
-        RhkObjectType::KpiInfo => read_pi_controller_info(cursor, obj.offset),
-        RhkObjectType::AuxPiInfo => read_pi_controller_info(cursor, obj.offset),
+        RhkObjectType::ZpiInfo => read_pi_controller_info(cursor, obj.offset)?,
+        RhkObjectType::KpiInfo => read_pi_controller_info(cursor, obj.offset)?,
+        RhkObjectType::AuxPiInfo => read_pi_controller_info(cursor, obj.offset)?,
 
-        RhkObjectType::LowpassFilterR0Info => read_lowpass_filter_info(cursor, obj.offset),
-        RhkObjectType::LowpassFilterR1Info => read_lowpass_filter_info(cursor, obj.offset),
+        RhkObjectType::LowpassFilterR0Info => read_lowpass_filter_info(cursor, obj.offset)?,
+        RhkObjectType::LowpassFilterR1Info => read_lowpass_filter_info(cursor, obj.offset)?,
         _ => ReadType::Unknown,
     };
     Ok(read_obj)
 }
 
-fn read_sm4_string(cursor: &mut Cursor<&[u8]>) -> String {
-    let length = cursor.read_u16_le();
-    cursor.read_string(length as usize)
-}
-
-fn get_object_type_name(object_type_id: u32) -> String {
-    let name = match object_type_id {
-        0 => "RHK_OBJECT_UNDEFINED",
-        1 => "RHK_OBJECT_PAGE_INDEX_HEADER",
-        2 => "RHK_OBJECT_PAGE_INDEX_ARRAY",
-        3 => "RHK_OBJECT_PAGE_HEADER",
-        4 => "RHK_OBJECT_PAGE_DATA",
-        5 => "RHK_OBJECT_IMAGE_DRIFT_HEADER",
-        6 => "RHK_OBJECT_IMAGE_DRIFT",
-        7 => "RHK_OBJECT_SPEC_DRIFT_HEADER",
-        8 => "RHK_OBJECT_SPEC_DRIFT_DATA",
-        9 => "RHK_OBJECT_COLOR_INFO",
-        10 => "RHK_OBJECT_STRING_DATA",
-        11 => "RHK_OBJECT_TIP_TRACK_HEADER",
-        12 => "RHK_OBJECT_TIP_TRACK_DATA",
-        13 => "RHK_OBJECT_PRM",
-        14 => "RHK_OBJECT_THUMBNAIL",
-        15 => "RHK_OBJECT_PRM_HEADER",
-        16 => "RHK_OBJECT_THUMBNAIL_HEADER",
-        17 => "RHK_OBJECT_API_INFO",
-        18 => "RHK_OBJECT_HISTORY_INFO",
-        19 => "RHK_OBJECT_PIEZO_SENSITIVITY",
-        20 => "RHK_OBJECT_FREQUENCY_SWEEP_DATA",
-        21 => "RHK_OBJECT_SCAN_PROCESSOR_INFO",
-        22 => "RHK_OBJECT_PLL_INFO",
-        23 => "RHK_OBJECT_CH1_DRIVE_INFO",
-        24 => "RHK_OBJECT_CH2_DRIVE_INFO",
-        25 => "RHK_OBJECT_LOCKIN0_INFO",
-        26 => "RHK_OBJECT_LOCKIN1_INFO",
-        27 => "RHK_OBJECT_ZPI_INFO",
-        28 => "RHK_OBJECT_KPI_INFO",
-        29 => "RHK_OBJECT_AUX_PI_INFO",
-        30 => "RHK_OBJECT_LOWPASS_FILTER0_INFO",
-        31 => "RHK_OBJECT_LOWPASS_FILTER1_INFO",
-        _ => "RHK_OBJECT_UNKNOWN",
-    };
-    name.to_string()
-}
-
-fn get_page_data_type_name(page_data_type: u32) -> String {
-    let page_data_type_name = match page_data_type {
-        0 => "RHK_DATA_IMAGE",
-        1 => "RHK_DATA_LINE",
-        2 => "RHK_DATA_XY_DATA",
-        3 => "RHK_DATA_ANNOTATED_LINE",
-        4 => "RHK_DATA_TEXT",
-        5 => "RHK_DATA_ANNOTATED_TEXT",
-        6 => "RHK_DATA_SEQUENTIAL",
-        7 => "RHK_DATA_MOVIE",
-        _ => "RHK_DATA_UNKOWN",
-    };
-    page_data_type_name.to_string()
-}
-
-fn get_page_source_type_name(page_source_type: u32) -> String {
-    let page_source_type_name = match page_source_type {
-        0 => "RHK_SOURCE_RAW",
-        1 => "RHK_SOURCE_PROCESSED",
-        2 => "RHK_SOURCE_CALCULATED",
-        3 => "RHK_SOURCE_IMPORTED",
-        _ => "RHK_SOURCE_UNKNOWN",
-    };
-    page_source_type_name.to_string()
-}
-
-fn get_page_type_name(page_type: u32) -> String {
-    let page_type_name = match page_type {
-        0 => "RHK_PAGE_UNDEFINED",
-        1 => "RHK_PAGE_TOPOGRAPHIC",
-        2 => "RHK_PAGE_CURRENT",
-        3 => "RHK_PAGE_AUX",
-        4 => "RHK_PAGE_FORCE",
-        5 => "RHK_PAGE_SIGNAL",
-        6 => "RHK_PAGE_FFT_TRANSFORM",
-        7 => "RHK_PAGE_NOISE_POWER_SPECTRUM",
-        8 => "RHK_PAGE_LINE_TEST",
-        9 => "RHK_PAGE_OSCILLOSCOPE",
-        10 => "RHK_PAGE_IV_SPECTRA",
-        11 => "RHK_PAGE_IV_4x4",
-        12 => "RHK_PAGE_IV_8x8",
-        13 => "RHK_PAGE_IV_16x16",
-        14 => "RHK_PAGE_IV_32x32",
-        15 => "RHK_PAGE_IV_CENTER",
-        16 => "RHK_PAGE_INTERACTIVE_SPECTRA",
-        17 => "RHK_PAGE_AUTOCORRELATION",
-        18 => "RHK_PAGE_IZ_SPECTRA",
-        19 => "RHK_PAGE_4_GAIN_TOPOGRAPHY",
-        20 => "RHK_PAGE_8_GAIN_TOPOGRAPHY",
-        21 => "RHK_PAGE_4_GAIN_CURRENT",
-        22 => "RHK_PAGE_8_GAIN_CURRENT",
-        23 => "RHK_PAGE_IV_64x64",
-        24 => "RHK_PAGE_AUTOCORRELATION_SPECTRUM",
-        25 => "RHK_PAGE_COUNTER",
-        26 => "RHK_PAGE_MULTICHANNEL_ANALYSER",
-        27 => "RHK_PAGE_AFM_100",
-        28 => "RHK_PAGE_CITS",
-        29 => "RHK_PAGE_GPIB",
-        30 => "RHK_PAGE_VIDEO_CHANNEL",
-        31 => "RHK_PAGE_IMAGE_OUT_SPECTRA",
-        32 => "RHK_PAGE_I_DATALOG",
-        33 => "RHK_PAGE_I_ECSET",
-        34 => "RHK_PAGE_I_ECDATA",
-        35 => "RHK_PAGE_I_DSP_AD",
-        36 => "RHK_PAGE_DISCRETE_SPECTROSCOPY_PP",
-        37 => "RHK_PAGE_IMAGE_DISCRETE_SPECTROSCOPY",
-        38 => "RHK_PAGE_RAMP_SPECTROSCOPY_RP",
-        39 => "RHK_PAGE_DISCRETE_SPECTROSCOPY_RP",
-        _ => "RHK_PAGE_TYPE_UNKWOWN",
-    };
-    page_type_name.to_string()
-}
-
-fn get_line_type_name(line_type: u32) -> String {
-    let line_type_name = match line_type {
-        0 => "RHK_LINE_NOT_A_LINE",
-        1 => "RHK_LINE_HISTOGRAM",
-        2 => "RHK_LINE_CROSS_SECTION",
-        3 => "RHK_LINE_LINE_TEST",
-        4 => "RHK_LINE_OSCILLOSCOPE",
-        5 => "RHK_LINE_RESERVED",
-        6 => "RHK_LINE_NOISE_POWER_SPECTRUM",
-        7 => "RHK_LINE_IV_SPECTRUM",
-        8 => "RHK_LINE_IZ_SPECTRUM",
-        9 => "RHK_LINE_IMAGE_X_AVERAGE",
-        10 => "RHK_LINE_IMAGE_Y_AVERAGE",
-        11 => "RHK_LINE_NOISE_AUTOCORRELATION_SPECTRUM",
-        12 => "RHK_LINE_MULTICHANNEL_ANALYSER_DATA",
-        13 => "RHK_LINE_RENORMALIZED_IV",
-        14 => "RHK_LINE_IMAGE_HISTOGRAM_SPECTRA",
-        15 => "RHK_LINE_IMAGE_CROSS_SECTION",
-        16 => "RHK_LINE_IMAGE_AVERAGE",
-        17 => "RHK_LINE_IMAGE_CROSS_SECTION_G",
-        18 => "RHK_LINE_IMAGE_OUT_SPECTRA",
-        19 => "RHK_LINE_DATALOG_SPECTRUM",
-        20 => "RHK_LINE_GXY",
-        21 => "RHK_LINE_ELECTROCHEMISTRY",
-        22 => "RHK_LINE_DISCRETE_SPECTROSCOPY",
-        23 => "RHK_LINE_DATA_LOGGER",
-        24 => "RHK_LINE_TIME_SPECTROSCOPY",
-        25 => "RHK_LINE_ZOOM_FFT",
-        26 => "RHK_LINE_FREQUENCY_SWEEP",
-        27 => "RHK_LINE_PHASE_ROTATE",
-        28 => "RHK_LINE_FIBER_SWEEP",
-        _ => "RHK_LINE_TYPE_UKNWOWN",
-    };
-    line_type_name.to_string()
-}
-
-fn get_image_type_name(image_type: u32) -> String {
-    let image_type_name = match image_type {
-        0 => "RHK_IMAGE_NORMAL",
-        1 => "RHK_IMAGE_AUTOCORRELATED",
-        _ => "RHK_IMAGE_TYPE_UNKNOWN",
-    };
-    image_type_name.to_string()
-}
-
-fn get_scan_type_name(scan_type: u32) -> String {
-    let scan_type_name = match scan_type {
-        0 => "RHK_SCAN_RIGHT",
-        1 => "RHK_SCAN_LEFT",
-        2 => "RHK_SCAN_UP",
-        3 => "RHK_SCAN_DOWN",
-        _ => "RHK_SCAN_TYPE_UNKWOWN",
-    };
-    scan_type_name.to_string()
+// Strings are stored as UTF-16 with the number of characters in front
+fn read_sm4_string(cursor: &mut Cursor<&[u8]>) -> SpmResult<String> {
+    let length = cursor.read_u16_le()?;
+    cursor.read_utf16_string(length as usize)
 }
 
 fn get_drift_option_type_name(drift_option_type: u32) -> String {
@@ -992,10 +1289,10 @@ fn get_page_index_header(
 ) -> Result<PageIndexHeader> {
     let offset = get_offset_page_index_header(object_list)?;
     cursor.set_position(offset as u64);
-    let page_count = cursor.read_u32_le();
-    let object_list_count = cursor.read_u32_le();
-    let _reserved_1 = cursor.read_u32_le();
-    let _reserved_2 = cursor.read_u32_le();
+    let page_count = cursor.read_u32_le()?;
+    let object_list_count = cursor.read_u32_le()?;
+    let _reserved_1 = cursor.read_u32_le()?;
+    let _reserved_2 = cursor.read_u32_le()?;
     Ok(PageIndexHeader {
         offset,
         page_count,
@@ -1021,57 +1318,57 @@ fn get_offset_page_index_array(object_list: &Vec<Sm4Object>) -> Result<u32> {
     Err(anyhow::anyhow!("No page index array"))
 }
 
-fn read_header(cursor: &mut Cursor<&[u8]>) -> Sm4Header {
-    let size = cursor.read_u16_le();
-    let signature = cursor.read_string(36);
-    let page_count = cursor.read_u32_le();
-    let object_list_count = cursor.read_u32_le();
-    let object_field_size = cursor.read_u32_le();
+fn read_header(cursor: &mut Cursor<&[u8]>) -> Result<Sm4Header> {
+    let size = cursor.read_u16_le()?;
+    let signature = cursor.read_string(36)?;
+    let page_count = cursor.read_u32_le()?;
+    let object_list_count = cursor.read_u32_le()?;
+    let object_field_size = cursor.read_u32_le()?;
 
-    let _reserved_1 = cursor.read_u32_le();
-    let _reserved_2 = cursor.read_u32_le();
+    let _reserved_1 = cursor.read_u32_le()?;
+    let _reserved_2 = cursor.read_u32_le()?;
 
-    Sm4Header {
+    Ok(Sm4Header {
         size,
         signature,
         page_count,
         object_list_count,
         object_field_size,
-        object_list: Vec::with_capacity(object_list_count as usize),
-    }
+        object_list: Vec::with_capacity(prealloc(object_list_count)),
+    })
 }
 
-fn read_sm4_object(cursor: &mut Cursor<&[u8]>) -> Sm4Object {
-    let object_type_id = RhkObjectType::from_num(cursor.read_u32_le());
-    let offset = cursor.read_u32_le();
-    let size = cursor.read_u32_le();
+fn read_sm4_object(cursor: &mut Cursor<&[u8]>) -> Result<Sm4Object> {
+    let object_type_id = RhkObjectType::from_num(cursor.read_u32_le()?);
+    let offset = cursor.read_u32_le()?;
+    let size = cursor.read_u32_le()?;
 
-    Sm4Object {
+    Ok(Sm4Object {
         obj_type: object_type_id,
         offset,
         size,
-    }
+    })
 }
 
-fn read_sm4_page(cursor: &mut Cursor<&[u8]>) -> Sm4Page {
-    let page_id = cursor.read_u16_le();
-    cursor.skip(14);
-    let page_data_type = RhkDataType::from_num(cursor.read_u32_le());
+fn read_sm4_page(cursor: &mut Cursor<&[u8]>) -> Result<Sm4Page> {
+    let page_id = cursor.read_u16_le()?;
+    cursor.skip(14)?;
+    let page_data_type = RhkDataType::from_num(cursor.read_u32_le()?);
 
-    let page_source_type = RhkSourceType::from_num(cursor.read_u32_le());
+    let page_source_type = RhkSourceType::from_num(cursor.read_u32_le()?);
 
-    let object_list_count = cursor.read_u32_le();
+    let object_list_count = cursor.read_u32_le()?;
 
-    let minor_version = cursor.read_u32_le();
+    let minor_version = cursor.read_u32_le()?;
 
-    Sm4Page {
+    Ok(Sm4Page {
         page_id,
         page_data_type,
         page_source_type,
         minor_version,
         object_list_count,
-        object_list: Vec::with_capacity(object_list_count as usize),
-    }
+        object_list: Vec::with_capacity(prealloc(object_list_count)),
+    })
 }
 
 fn read_page_header(cursor: &mut Cursor<&[u8]>, page: &Sm4Page) -> Result<Sm4PageHeader> {
@@ -1079,14 +1376,12 @@ fn read_page_header(cursor: &mut Cursor<&[u8]>, page: &Sm4Page) -> Result<Sm4Pag
     cursor.set_position(offset as u64);
     // Sequential data type
     if let RhkDataType::DataSequential = page.page_data_type {
-        return Ok(Sm4PageHeader::Sequential(read_sequential_type(
-            cursor, page,
-        )));
+        return Ok(Sm4PageHeader::Sequential(read_sequential_type(cursor)?));
     }
-    Ok(Sm4PageHeader::Default(read_default_type(cursor, page)))
+    Ok(Sm4PageHeader::Default(read_default_type(cursor)?))
 }
 
-fn get_offset_object_page_header(object_list: &Vec<Sm4Object>) -> Result<u32> {
+fn get_offset_object_page_header(object_list: &[Sm4Object]) -> Result<u32> {
     for obj in object_list {
         if let RhkObjectType::PageHeader = obj.obj_type {
             return Ok(obj.offset);
@@ -1095,73 +1390,74 @@ fn get_offset_object_page_header(object_list: &Vec<Sm4Object>) -> Result<u32> {
     Err(anyhow::anyhow!("No page header"))
 }
 
-fn read_sequential_type(cursor: &mut Cursor<&[u8]>, page: &Sm4Page) -> Sm4PageHeaderSequential {
-    let data_type = cursor.read_u32_le();
-    let data_length = cursor.read_u32_le();
-    let param_count = cursor.read_u32_le();
+fn read_sequential_type(cursor: &mut Cursor<&[u8]>) -> Result<Sm4PageHeaderSequential> {
+    let data_type = cursor.read_u32_le()?;
+    let data_length = cursor.read_u32_le()?;
+    let param_count = cursor.read_u32_le()?;
 
-    let object_list_count = cursor.read_u32_le();
+    let object_list_count = cursor.read_u32_le()?;
 
-    let data_info_size = cursor.read_u32_le();
-    let data_info_string_count = cursor.read_u32_le();
-    Sm4PageHeaderSequential {
+    let data_info_size = cursor.read_u32_le()?;
+    let data_info_string_count = cursor.read_u32_le()?;
+    Ok(Sm4PageHeaderSequential {
         data_type,
         data_length,
         param_count,
         object_list_count,
         data_info_size,
         data_info_string_count,
-        object_list: Vec::with_capacity(object_list_count as usize),
-    }
+        object_list: Vec::with_capacity(prealloc(object_list_count)),
+        params: Vec::with_capacity(prealloc(param_count)),
+    })
 }
 
-fn read_default_type(cursor: &mut Cursor<&[u8]>, page: &Sm4Page) -> Sm4PageHeaderDefault {
-    _ = cursor.read_u16_le();
-    let string_count = cursor.read_u16_le();
-    let page_type = RhkPageType::from_num(cursor.read_u32_le());
-    let data_sub_source = cursor.read_u32_le();
+fn read_default_type(cursor: &mut Cursor<&[u8]>) -> Result<Sm4PageHeaderDefault> {
+    _ = cursor.read_u16_le()?;
+    let string_count = cursor.read_u16_le()?;
+    let page_type = RhkPageType::from_num(cursor.read_u32_le()?);
+    let data_sub_source = cursor.read_u32_le()?;
 
-    let line_type = RhkLineType::from_num(cursor.read_u32_le());
+    let line_type = RhkLineType::from_num(cursor.read_u32_le()?);
 
-    let x_corner = cursor.read_u32_le();
-    let y_corner = cursor.read_u32_le();
+    let x_corner = cursor.read_u32_le()?;
+    let y_corner = cursor.read_u32_le()?;
     // xres
-    let x_size = cursor.read_u32_le();
-    let y_size = cursor.read_u32_le();
+    let x_size = cursor.read_u32_le()?;
+    let y_size = cursor.read_u32_le()?;
 
-    let image_type = RhkImageType::from_num(cursor.read_u32_le());
+    let image_type = RhkImageType::from_num(cursor.read_u32_le()?);
 
-    let scan_type = RhkScanType::from_num(cursor.read_u32_le());
+    let scan_type = RhkScanType::from_num(cursor.read_u32_le()?);
 
-    let group_id = cursor.read_u32_le();
-    let page_data_size = cursor.read_u32_le();
+    let group_id = cursor.read_u32_le()?;
+    let page_data_size = cursor.read_u32_le()?;
 
-    let min_z_value = cursor.read_u32_le();
-    let max_z_value = cursor.read_u32_le();
+    let min_z_value = cursor.read_u32_le()?;
+    let max_z_value = cursor.read_u32_le()?;
 
-    let x_scale = cursor.read_f32_le();
-    let y_scale = cursor.read_f32_le();
-    let z_scale = cursor.read_f32_le();
-    let xy_scale = cursor.read_f32_le();
-    let x_offset = cursor.read_f32_le();
-    let y_offset = cursor.read_f32_le();
-    let z_offset = cursor.read_f32_le();
-    let period = cursor.read_f32_le();
-    let bias = cursor.read_f32_le();
-    let current = cursor.read_f32_le();
-    let angle = cursor.read_f32_le();
+    let x_scale = cursor.read_f32_le()?;
+    let y_scale = cursor.read_f32_le()?;
+    let z_scale = cursor.read_f32_le()?;
+    let xy_scale = cursor.read_f32_le()?;
+    let x_offset = cursor.read_f32_le()?;
+    let y_offset = cursor.read_f32_le()?;
+    let z_offset = cursor.read_f32_le()?;
+    let period = cursor.read_f32_le()?;
+    let bias = cursor.read_f32_le()?;
+    let current = cursor.read_f32_le()?;
+    let angle = cursor.read_f32_le()?;
 
-    let color_info_count = cursor.read_u32_le();
-    let grid_x_size = cursor.read_u32_le();
-    let grid_y_size = cursor.read_u32_le();
+    let color_info_count = cursor.read_u32_le()?;
+    let grid_x_size = cursor.read_u32_le()?;
+    let grid_y_size = cursor.read_u32_le()?;
 
-    let object_list_count = cursor.read_u32_le();
-    let _32_bit_data_flag = cursor.read_u8_le();
+    let object_list_count = cursor.read_u32_le()?;
+    let _32_bit_data_flag = cursor.read_u8_le()?;
 
     //reserved
-    cursor.skip(63);
+    cursor.skip(63)?;
 
-    Sm4PageHeaderDefault {
+    Ok(Sm4PageHeaderDefault {
         string_count,
         page_type,
         data_sub_source,
@@ -1192,8 +1488,8 @@ fn read_default_type(cursor: &mut Cursor<&[u8]>, page: &Sm4Page) -> Sm4PageHeade
         grid_y_size,
         object_list_count,
         _32_bit_data_flag,
-        object_list: Vec::with_capacity(object_list_count as usize),
-    }
+        object_list: Vec::with_capacity(prealloc(object_list_count)),
+    })
 }
 
 fn read_page_data(
@@ -1202,19 +1498,45 @@ fn read_page_data(
     size: u32,
     z_scale: f32,
     z_offset: f32,
-) -> ReadType {
+) -> Result<ReadType> {
     cursor.set_position(offset as u64);
     let len = size / 4;
-    let mut page_data = Vec::with_capacity(len as usize);
+    let mut page_data = Vec::with_capacity(prealloc(len));
     for _ in 0..len {
-        page_data.push(cursor.read_i32_le() as f64 * (z_scale as f64) + (z_offset as f64));
+        page_data.push(cursor.read_i32_le()? as f64 * (z_scale as f64) + (z_offset as f64));
     }
-    ReadType::PageData(page_data)
+    Ok(ReadType::PageData(page_data))
+}
+
+// Sequential data is stored row by row. Only data type 0, 32 bit floats, is
+// known, other types are rejected instead of being decoded as garbage.
+fn read_sequential_data(
+    cursor: &mut Cursor<&[u8]>,
+    offset: u32,
+    size: u32,
+    ph: &Sm4PageHeaderSequential,
+) -> Result<ReadType> {
+    anyhow::ensure!(
+        ph.data_type == 0,
+        "Unsupported sequential data type {}",
+        ph.data_type
+    );
+    let params = &ph.params;
+    cursor.set_position(offset as u64);
+    let rows = (size / 4) as usize / params.len().max(1);
+    let mut columns = vec![Vec::with_capacity(prealloc(rows as u64)); params.len()];
+    for _ in 0..rows {
+        for (column, param) in columns.iter_mut().zip(params) {
+            column.push(cursor.read_f32_le()? as f64 * param.gain as f64);
+        }
+    }
+    Ok(ReadType::SequentialData(columns))
 }
 
 #[derive(Debug)]
 enum ReadType {
     PageData(Vec<f64>),
+    SequentialData(Vec<Vec<f64>>),
     ImageDriftHeader(ImageDriftHeader),
     ImageDriftData(ImageDriftData),
     SpecDriftHeader(SpecDriftHeader),
@@ -1222,7 +1544,7 @@ enum ReadType {
     StringData(StringData),
     TipTrackHeader(TipTrackHeader),
     TipTrackData(TipTrackData),
-    Prm(Prm),
+    Prm(String),
     ApiInfo(ApiInfo),
     PiezoSensitivity(PiezoSensitivity),
     FrequencySweepData(FrequencySweepData),
@@ -1231,7 +1553,7 @@ enum ReadType {
     ChannelDriveInfo(ChannelDriveInfo),
     LockinInfo(LockinInfo),
     PiControllerInfo(PiControllerInfo),
-    LowpassFilterInfo(LowpassFilterInfo),
+    LowpassFilterInfo(String),
     HistoryInfo,
     Unknown,
 }
@@ -1239,25 +1561,33 @@ enum ReadType {
 #[derive(Debug)]
 struct ImageDriftHeader {
     imagedrift_filetime: u64,
+    #[allow(dead_code)]
     imagedrift_drift_option_type: RhkDriftOptionType,
 }
 
 #[derive(Debug)]
 struct ImageDriftData {
-    imagedrift_time: u32,
-    imagedrift_dx: u32,
-    imagedrift_dy: u32,
-    imagedrift_cumulative_x: u32,
-    imagedrift_cumulative_y: u32,
-    imagedrift_vector_x: u32,
-    imagedrift_vector_y: u32,
+    imagedrift_time: f32,
+    #[allow(dead_code)]
+    imagedrift_dx: f32,
+    #[allow(dead_code)]
+    imagedrift_dy: f32,
+    imagedrift_cumulative_x: f32,
+    imagedrift_cumulative_y: f32,
+    #[allow(dead_code)]
+    imagedrift_vector_x: f32,
+    #[allow(dead_code)]
+    imagedrift_vector_y: f32,
 }
 
 #[derive(Debug)]
 struct SpecDriftHeader {
     specdrift_filetime: u64,
+    #[allow(dead_code)]
     specdrift_drift_option_type: u32,
+    #[allow(dead_code)]
     specdrift_drift_option_type_name: String,
+    #[allow(dead_code)]
     specdrift_channel: String,
 }
 
@@ -1266,33 +1596,35 @@ struct SpecDriftData {
     specdrift_time: Vec<f32>,
     specdrift_x_coord: Vec<f32>,
     specdrift_y_coord: Vec<f32>,
+    #[allow(dead_code)]
     specdrift_dx: Vec<f32>,
+    #[allow(dead_code)]
     specdrift_dy: Vec<f32>,
     specdrift_cumulative_x: Vec<f32>,
     specdrift_cumulative_y: Vec<f32>,
 }
 
-fn read_image_drift_header(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+fn read_image_drift_header(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
     // unix epoch
-    let imagedrift_filetime = cursor.read_u64_le();
-    let imagedrift_drift_option_type = RhkDriftOptionType::from_num(cursor.read_u32_le());
-    ReadType::ImageDriftHeader(ImageDriftHeader {
+    let imagedrift_filetime = cursor.read_u64_le()?;
+    let imagedrift_drift_option_type = RhkDriftOptionType::from_num(cursor.read_u32_le()?);
+    Ok(ReadType::ImageDriftHeader(ImageDriftHeader {
         imagedrift_filetime,
         imagedrift_drift_option_type,
-    })
+    }))
 }
 
-fn read_image_drift(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+fn read_image_drift(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let imagedrift_time = cursor.read_u32_le();
-    let imagedrift_dx = cursor.read_u32_le();
-    let imagedrift_dy = cursor.read_u32_le();
-    let imagedrift_cumulative_x = cursor.read_u32_le();
-    let imagedrift_cumulative_y = cursor.read_u32_le();
-    let imagedrift_vector_x = cursor.read_u32_le();
-    let imagedrift_vector_y = cursor.read_u32_le();
-    ReadType::ImageDriftData(ImageDriftData {
+    let imagedrift_time = cursor.read_f32_le()?;
+    let imagedrift_dx = cursor.read_f32_le()?;
+    let imagedrift_dy = cursor.read_f32_le()?;
+    let imagedrift_cumulative_x = cursor.read_f32_le()?;
+    let imagedrift_cumulative_y = cursor.read_f32_le()?;
+    let imagedrift_vector_x = cursor.read_f32_le()?;
+    let imagedrift_vector_y = cursor.read_f32_le()?;
+    Ok(ReadType::ImageDriftData(ImageDriftData {
         imagedrift_time,
         imagedrift_dx,
         imagedrift_dy,
@@ -1300,46 +1632,46 @@ fn read_image_drift(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
         imagedrift_cumulative_y,
         imagedrift_vector_x,
         imagedrift_vector_y,
-    })
+    }))
 }
 
-fn read_spec_drift_header(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+fn read_spec_drift_header(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
     // unix epoch
-    let specdrift_filetime = cursor.read_u64_le();
-    let specdrift_drift_option_type = cursor.read_u32_le();
+    let specdrift_filetime = cursor.read_u64_le()?;
+    let specdrift_drift_option_type = cursor.read_u32_le()?;
     let specdrift_drift_option_type_name = get_drift_option_type_name(specdrift_drift_option_type);
-    _ = cursor.read_u32_le();
-    let specdrift_channel = read_sm4_string(cursor);
+    _ = cursor.read_u32_le()?;
+    let specdrift_channel = read_sm4_string(cursor)?;
 
-    ReadType::SpecDriftHeader(SpecDriftHeader {
+    Ok(ReadType::SpecDriftHeader(SpecDriftHeader {
         specdrift_filetime,
         specdrift_drift_option_type,
         specdrift_drift_option_type_name,
         specdrift_channel,
-    })
+    }))
 }
 
-fn read_spec_drift_data(cursor: &mut Cursor<&[u8]>, offset: u32, y_size: u32) -> ReadType {
+fn read_spec_drift_data(cursor: &mut Cursor<&[u8]>, offset: u32, y_size: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let mut specdrift_time = Vec::with_capacity(y_size as usize);
-    let mut specdrift_x_coord = Vec::with_capacity(y_size as usize);
-    let mut specdrift_y_coord = Vec::with_capacity(y_size as usize);
-    let mut specdrift_dx = Vec::with_capacity(y_size as usize);
-    let mut specdrift_dy = Vec::with_capacity(y_size as usize);
-    let mut specdrift_cumulative_x = Vec::with_capacity(y_size as usize);
-    let mut specdrift_cumulative_y = Vec::with_capacity(y_size as usize);
+    let mut specdrift_time = Vec::with_capacity(prealloc(y_size));
+    let mut specdrift_x_coord = Vec::with_capacity(prealloc(y_size));
+    let mut specdrift_y_coord = Vec::with_capacity(prealloc(y_size));
+    let mut specdrift_dx = Vec::with_capacity(prealloc(y_size));
+    let mut specdrift_dy = Vec::with_capacity(prealloc(y_size));
+    let mut specdrift_cumulative_x = Vec::with_capacity(prealloc(y_size));
+    let mut specdrift_cumulative_y = Vec::with_capacity(prealloc(y_size));
 
     for _ in 0..y_size {
-        specdrift_time.push(cursor.read_f32_le());
-        specdrift_x_coord.push(cursor.read_f32_le());
-        specdrift_y_coord.push(cursor.read_f32_le());
-        specdrift_dx.push(cursor.read_f32_le());
-        specdrift_dy.push(cursor.read_f32_le());
-        specdrift_cumulative_x.push(cursor.read_f32_le());
-        specdrift_cumulative_y.push(cursor.read_f32_le());
+        specdrift_time.push(cursor.read_f32_le()?);
+        specdrift_x_coord.push(cursor.read_f32_le()?);
+        specdrift_y_coord.push(cursor.read_f32_le()?);
+        specdrift_dx.push(cursor.read_f32_le()?);
+        specdrift_dy.push(cursor.read_f32_le()?);
+        specdrift_cumulative_x.push(cursor.read_f32_le()?);
+        specdrift_cumulative_y.push(cursor.read_f32_le()?);
     }
-    ReadType::SpecDriftData(SpecDriftData {
+    Ok(ReadType::SpecDriftData(SpecDriftData {
         specdrift_time,
         specdrift_x_coord,
         specdrift_y_coord,
@@ -1347,102 +1679,102 @@ fn read_spec_drift_data(cursor: &mut Cursor<&[u8]>, offset: u32, y_size: u32) ->
         specdrift_dy,
         specdrift_cumulative_x,
         specdrift_cumulative_y,
-    })
+    }))
 }
 
-#[derive(Debug)]
-struct StringData {
-    label: String,
-    system_text: String,
-    session_text: String,
-    user_text: String,
-    filename: String,
-    date: String,
-    time: String,
-    x_units: String,
-    y_units: String,
-    z_units: String,
-    x_label: String,
-    y_label: String,
-    status_channel_text: String,
-    completed_line_count: String,
-    oversampling_count: String,
-    sliced_voltage: String,
-    pll_pro_status: String,
-    setpoint_unit: String,
-    channel_list: String,
-}
-
-fn read_string_data(cursor: &mut Cursor<&[u8]>, offset: u32, string_count: u16) -> ReadType {
+/// Texts stored with a page, strings missing in older files are empty
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StringData {
+    pub label: String,
+    pub system_text: String,
+    pub session_text: String,
+    pub user_text: String,
+    pub filename: String,
+    pub date: String,
+    pub time: String,
+    pub x_units: String,
+    pub y_units: String,
+    pub z_units: String,
+    pub x_label: String,
+    pub y_label: String,
+    pub status_channel_text: String,
+    pub completed_line_count: String,
+    pub oversampling_count: String,
+    pub sliced_voltage: String,
+    pub pll_pro_status: String,
+    pub setpoint_unit: String,
+    pub channel_list: String,
+}
+
+fn read_string_data(
+    cursor: &mut Cursor<&[u8]>,
+    offset: u32,
+    string_count: u16,
+) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let label = read_sm4_string(cursor);
-    let system_text = read_sm4_string(cursor);
-    let session_text = read_sm4_string(cursor);
-    let user_text = read_sm4_string(cursor);
-    let filename = read_sm4_string(cursor);
-    let date = read_sm4_string(cursor);
-    let time = read_sm4_string(cursor);
-    let x_units = read_sm4_string(cursor);
-    let y_units = read_sm4_string(cursor);
-    let z_units = read_sm4_string(cursor);
-    let x_label = read_sm4_string(cursor);
-    let y_label = read_sm4_string(cursor);
-    let status_channel_text = read_sm4_string(cursor);
-    let completed_line_count = read_sm4_string(cursor);
-    let oversampling_count = read_sm4_string(cursor);
-    let sliced_voltage = read_sm4_string(cursor);
-    let pll_pro_status = read_sm4_string(cursor);
-    let setpoint_unit = read_sm4_string(cursor);
-    let channel_list = read_sm4_string(cursor);
-    ReadType::StringData(StringData {
-        label,
-        system_text,
-        session_text,
-        user_text,
-        filename,
-        date,
-        time,
-        x_units,
-        y_units,
-        z_units,
-        x_label,
-        y_label,
-        status_channel_text,
-        completed_line_count,
-        oversampling_count,
-        sliced_voltage,
-        pll_pro_status,
-        setpoint_unit,
-        channel_list,
-    })
+    // older files contain fewer strings, the missing ones stay empty
+    let mut strings = Vec::with_capacity(string_count as usize);
+    for _ in 0..string_count {
+        strings.push(read_sm4_string(cursor)?);
+    }
+    strings.resize(19, String::new());
+    let mut strings = strings.into_iter();
+    let mut next = || strings.next().unwrap_or_default();
+    Ok(ReadType::StringData(StringData {
+        label: next(),
+        system_text: next(),
+        session_text: next(),
+        user_text: next(),
+        filename: next(),
+        date: next(),
+        time: next(),
+        x_units: next(),
+        y_units: next(),
+        z_units: next(),
+        x_label: next(),
+        y_label: next(),
+        status_channel_text: next(),
+        completed_line_count: next(),
+        oversampling_count: next(),
+        sliced_voltage: next(),
+        pll_pro_status: next(),
+        setpoint_unit: next(),
+        channel_list: next(),
+    }))
 }
 
 #[derive(Debug)]
 struct TipTrackHeader {
     tiptrack_filetime: u64,
+    #[allow(dead_code)]
     tiptrack_feature_height: f32,
+    #[allow(dead_code)]
     tiptrack_feature_width: f32,
+    #[allow(dead_code)]
     tiptrack_time_constant: f32,
+    #[allow(dead_code)]
     tiptrack_cycle_rate: f32,
+    #[allow(dead_code)]
     tiptrack_phase_lag: f32,
     tiptrack_tiptrack_info_count: u32,
+    #[allow(dead_code)]
     tiptrack_channel: String,
 }
 
-fn read_tip_track_header(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+fn read_tip_track_header(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
     // epoch time
-    let tiptrack_filetime = cursor.read_u64_le();
-    let tiptrack_feature_height = cursor.read_f32_le();
-    let tiptrack_feature_width = cursor.read_f32_le();
-
-    let tiptrack_time_constant = cursor.read_f32_le();
-    let tiptrack_cycle_rate = cursor.read_f32_le();
-    let tiptrack_phase_lag = cursor.read_f32_le();
-    _ = cursor.read_u32_le();
-    let tiptrack_tiptrack_info_count = cursor.read_u32_le();
-    let tiptrack_channel = read_sm4_string(cursor);
-    ReadType::TipTrackHeader(TipTrackHeader {
+    let tiptrack_filetime = cursor.read_u64_le()?;
+    let tiptrack_feature_height = cursor.read_f32_le()?;
+    let tiptrack_feature_width = cursor.read_f32_le()?;
+
+    let tiptrack_time_constant = cursor.read_f32_le()?;
+    let tiptrack_cycle_rate = cursor.read_f32_le()?;
+    let tiptrack_phase_lag = cursor.read_f32_le()?;
+    _ = cursor.read_u32_le()?;
+    let tiptrack_tiptrack_info_count = cursor.read_u32_le()?;
+    let tiptrack_channel = read_sm4_string(cursor)?;
+    Ok(ReadType::TipTrackHeader(TipTrackHeader {
         tiptrack_filetime,
         tiptrack_feature_height,
         tiptrack_feature_width,
@@ -1451,129 +1783,139 @@ fn read_tip_track_header(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
         tiptrack_phase_lag,
         tiptrack_tiptrack_info_count,
         tiptrack_channel,
-    })
+    }))
 }
 
 #[derive(Debug)]
 struct TipTrackData {
     tiptrack_cumulative_time: Vec<f32>,
+    #[allow(dead_code)]
     tiptrack_time: Vec<f32>,
     tiptrack_dx: Vec<f32>,
     tiptrack_dy: Vec<f32>,
+    tiptrack_dz: Vec<f32>,
 }
 
+// Every record holds the times and the x and y steps, records of 20 bytes or
+// more also the z step
 fn read_tip_track_data(
     cursor: &mut Cursor<&[u8]>,
     offset: u32,
+    size: u32,
     tiptrack_info_count: u32,
-) -> ReadType {
+) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let mut tiptrack_cumulative_time = Vec::with_capacity(tiptrack_info_count as usize);
-    let mut tiptrack_time = Vec::with_capacity(tiptrack_info_count as usize);
-    let mut tiptrack_dx = Vec::with_capacity(tiptrack_info_count as usize);
-    let mut tiptrack_dy = Vec::with_capacity(tiptrack_info_count as usize);
+    let record_size = size.checked_div(tiptrack_info_count).unwrap_or(0) as u64;
+    let mut tiptrack_cumulative_time = Vec::with_capacity(prealloc(tiptrack_info_count));
+    let mut tiptrack_time = Vec::with_capacity(prealloc(tiptrack_info_count));
+    let mut tiptrack_dx = Vec::with_capacity(prealloc(tiptrack_info_count));
+    let mut tiptrack_dy = Vec::with_capacity(prealloc(tiptrack_info_count));
+    let mut tiptrack_dz = Vec::new();
     for _ in 0..tiptrack_info_count {
-        tiptrack_cumulative_time.push(cursor.read_f32_le());
-        tiptrack_time.push(cursor.read_f32_le());
-        tiptrack_dx.push(cursor.read_f32_le());
-        tiptrack_dy.push(cursor.read_f32_le());
+        tiptrack_cumulative_time.push(cursor.read_f32_le()?);
+        tiptrack_time.push(cursor.read_f32_le()?);
+        tiptrack_dx.push(cursor.read_f32_le()?);
+        tiptrack_dy.push(cursor.read_f32_le()?);
+        if record_size >= 20 {
+            tiptrack_dz.push(cursor.read_f32_le()?);
+            cursor.skip(record_size - 20)?;
+        }
     }
-    ReadType::TipTrackData(TipTrackData {
+    Ok(ReadType::TipTrackData(TipTrackData {
         tiptrack_cumulative_time,
         tiptrack_time,
         tiptrack_dx,
         tiptrack_dy,
-    })
-}
-
-#[derive(Debug)]
-struct Prm {
-    prm_compression_flag: u32,
-    prm_data_size: u32,
-    prm_compression_size: u32,
-    prm_data: Vec<u32>,
+        tiptrack_dz,
+    }))
 }
 
 fn read_prm_header(
     cursor: &mut Cursor<&[u8]>,
     offset: u32,
-    object_list: &Vec<Sm4Object>,
+    object_list: &[Sm4Object],
 ) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let prm_compression_flag = cursor.read_u32_le();
-    let prm_data_size = cursor.read_u32_le();
-    let prm_compression_size = cursor.read_u32_le();
+    let prm_compression_flag = cursor.read_u32_le()?;
+    let prm_data_size = cursor.read_u32_le()?;
+    let prm_compression_size = cursor.read_u32_le()?;
 
     let prm_data_offset = get_offset_object_prm(object_list)?;
-    let prm_data = read_prm_data(cursor, prm_data_offset, prm_data_size, prm_compression_flag)?;
-    Ok(ReadType::Prm(Prm {
-        prm_compression_flag,
+    let prm_data = read_prm_data(
+        cursor,
+        prm_data_offset,
         prm_data_size,
         prm_compression_size,
-        prm_data,
-    }))
+        prm_compression_flag,
+    )?;
+    Ok(ReadType::Prm(prm_data))
 }
 
-fn get_offset_object_prm(object_list: &Vec<Sm4Object>) -> Result<u32> {
+fn get_offset_object_prm(object_list: &[Sm4Object]) -> Result<u32> {
     for obj in object_list {
         if let RhkObjectType::Prm = obj.obj_type {
             return Ok(obj.offset);
         }
     }
-    Err(anyhow::anyhow!("No page index array"))
+    Err(anyhow::anyhow!("No PRM data"))
 }
 
+// The PRM data is the text of the parameter file, zlib compressed if the flag is set
 fn read_prm_data(
     cursor: &mut Cursor<&[u8]>,
     offset: u32,
     prm_data_size: u32,
+    prm_compression_size: u32,
     prm_compression_flag: u32,
-) -> Result<Vec<u32>> {
+) -> Result<String> {
     cursor.set_position(offset as u64);
-    let mut prm_data = Vec::with_capacity(prm_data_size as usize);
-    if prm_compression_flag == 0 {
-        for _ in 0..prm_data_size {
-            prm_data.push(cursor.read_u32_le())
-        }
+    let prm_data = if prm_compression_flag == 0 {
+        cursor.read_bytes(prm_data_size as usize)?
     } else {
-        return Err(anyhow::anyhow!("Compressed Data not supported"));
-    }
-    Ok(prm_data)
-}
-
-#[derive(Debug)]
-struct ApiInfo {
-    voltage_high: f32,
-    voltage_low: f32,
-    gain: f32,
-    api_offset: f32,
-    ramp_type: u32,
-    step: u32,
-    image_count: u32,
-    dac: u32,
-    mux: u32,
-    bias: u32,
-}
-
-fn read_api_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+        let compressed = cursor.read_bytes(prm_compression_size as usize)?;
+        let mut prm_data = Vec::with_capacity(prealloc(prm_data_size));
+        ZlibDecoder::new(&compressed[..]).read_to_end(&mut prm_data)?;
+        prm_data
+    };
+    Ok(String::from_utf8_lossy(&prm_data)
+        .trim_end_matches('\0')
+        .to_string())
+}
+
+/// Settings of the analog interface
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApiInfo {
+    pub voltage_high: f32,
+    pub voltage_low: f32,
+    pub gain: f32,
+    pub api_offset: f32,
+    pub ramp_type: u32,
+    pub step: u32,
+    pub image_count: u32,
+    pub dac: u32,
+    pub mux: u32,
+    pub bias: u32,
+}
+
+fn read_api_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let voltage_high = cursor.read_f32_le();
-    let voltage_low = cursor.read_f32_le();
-    let gain = cursor.read_f32_le();
-    let api_offset = cursor.read_f32_le();
-
-    let ramp_mode = cursor.read_u32_le();
-    let ramp_type = cursor.read_u32_le();
-    let step = cursor.read_u32_le();
-    let image_count = cursor.read_u32_le();
-    let dac = cursor.read_u32_le();
-    let mux = cursor.read_u32_le();
-    let bias = cursor.read_u32_le();
-
-    _ = cursor.read_u32_le();
-    let units = read_sm4_string(cursor);
-
-    ReadType::ApiInfo(ApiInfo {
+    let voltage_high = cursor.read_f32_le()?;
+    let voltage_low = cursor.read_f32_le()?;
+    let gain = cursor.read_f32_le()?;
+    let api_offset = cursor.read_f32_le()?;
+
+    let _ramp_mode = cursor.read_u32_le()?;
+    let ramp_type = cursor.read_u32_le()?;
+    let step = cursor.read_u32_le()?;
+    let image_count = cursor.read_u32_le()?;
+    let dac = cursor.read_u32_le()?;
+    let mux = cursor.read_u32_le()?;
+    let bias = cursor.read_u32_le()?;
+
+    _ = cursor.read_u32_le()?;
+    let _units = read_sm4_string(cursor)?;
+
+    Ok(ReadType::ApiInfo(ApiInfo {
         voltage_high,
         voltage_low,
         gain,
@@ -1584,63 +1926,64 @@ fn read_api_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
         dac,
         mux,
         bias,
-    })
+    }))
 }
 
-fn read_history_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+fn read_history_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    _ = cursor.read_u32_le();
-    _ = read_sm4_string(cursor);
-    _ = read_sm4_string(cursor);
-    ReadType::HistoryInfo
-}
-
-#[derive(Debug)]
-struct PiezoSensitivity {
-    tube_x: f64,
-    tube_y: f64,
-    tube_z: f64,
-    tube_z_offset: f64,
-    scan_x: f64,
-    scan_y: f64,
-    scan_z: f64,
-    actuator: f64,
-    tube_z_unit: String,
-    tube_z_unit_offset: String,
-    scan_x_unit: String,
-    scan_y_unit: String,
-    scan_z_unit: String,
-    actuator_unit: String,
-    tube_calibration: String,
-    scan_calibration: String,
-    actuator_calibration: String,
-}
-
-fn read_piezo_sensitivity(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+    _ = cursor.read_u32_le()?;
+    _ = read_sm4_string(cursor)?;
+    _ = read_sm4_string(cursor)?;
+    Ok(ReadType::HistoryInfo)
+}
+
+/// Piezo sensitivities of the tube and the scanner, needed for calibration
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PiezoSensitivity {
+    pub tube_x: f64,
+    pub tube_y: f64,
+    pub tube_z: f64,
+    pub tube_z_offset: f64,
+    pub scan_x: f64,
+    pub scan_y: f64,
+    pub scan_z: f64,
+    pub actuator: f64,
+    pub tube_z_unit: String,
+    pub tube_z_unit_offset: String,
+    pub scan_x_unit: String,
+    pub scan_y_unit: String,
+    pub scan_z_unit: String,
+    pub actuator_unit: String,
+    pub tube_calibration: String,
+    pub scan_calibration: String,
+    pub actuator_calibration: String,
+}
+
+fn read_piezo_sensitivity(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let tube_x = cursor.read_f64_le();
-    let tube_y = cursor.read_f64_le();
-    let tube_z = cursor.read_f64_le();
-    let tube_z_offset = cursor.read_f64_le();
-    let scan_x = cursor.read_f64_le();
-    let scan_y = cursor.read_f64_le();
-    let scan_z = cursor.read_f64_le();
-    let actuator = cursor.read_f64_le();
-
-    _ = cursor.read_u32_le();
-
-    let tube_x_unit = read_sm4_string(cursor);
-    let tube_y_unit = read_sm4_string(cursor);
-    let tube_z_unit = read_sm4_string(cursor);
-    let tube_z_unit_offset = read_sm4_string(cursor);
-    let scan_x_unit = read_sm4_string(cursor);
-    let scan_y_unit = read_sm4_string(cursor);
-    let scan_z_unit = read_sm4_string(cursor);
-    let actuator_unit = read_sm4_string(cursor);
-    let tube_calibration = read_sm4_string(cursor);
-    let scan_calibration = read_sm4_string(cursor);
-    let actuator_calibration = read_sm4_string(cursor);
-    ReadType::PiezoSensitivity(PiezoSensitivity {
+    let tube_x = cursor.read_f64_le()?;
+    let tube_y = cursor.read_f64_le()?;
+    let tube_z = cursor.read_f64_le()?;
+    let tube_z_offset = cursor.read_f64_le()?;
+    let scan_x = cursor.read_f64_le()?;
+    let scan_y = cursor.read_f64_le()?;
+    let scan_z = cursor.read_f64_le()?;
+    let actuator = cursor.read_f64_le()?;
+
+    _ = cursor.read_u32_le()?;
+
+    let _tube_x_unit = read_sm4_string(cursor)?;
+    let _tube_y_unit = read_sm4_string(cursor)?;
+    let tube_z_unit = read_sm4_string(cursor)?;
+    let tube_z_unit_offset = read_sm4_string(cursor)?;
+    let scan_x_unit = read_sm4_string(cursor)?;
+    let scan_y_unit = read_sm4_string(cursor)?;
+    let scan_z_unit = read_sm4_string(cursor)?;
+    let actuator_unit = read_sm4_string(cursor)?;
+    let tube_calibration = read_sm4_string(cursor)?;
+    let scan_calibration = read_sm4_string(cursor)?;
+    let actuator_calibration = read_sm4_string(cursor)?;
+    Ok(ReadType::PiezoSensitivity(PiezoSensitivity {
         tube_x,
         tube_y,
         tube_z,
@@ -1658,45 +2001,46 @@ fn read_piezo_sensitivity(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
         tube_calibration,
         scan_calibration,
         actuator_calibration,
-    })
+    }))
 }
 
-#[derive(Debug)]
-struct FrequencySweepData {
-    psd_total_signal: f64,
-    peak_frequency: f64,
-    peak_amplitude: f64,
-    drive_aplitude: f64,
-    signal_to_drive_ratio: f64,
-    q_factor: f64,
-    total_signal_unit: String,
-    peak_frequency_unit: String,
-    peak_amplitude_unit: String,
-    drive_amplitude_unit: String,
-    signal_to_drive_ratio_unit: String,
-    q_factor_unit: String,
-}
-
-fn read_frequency_sweep_data(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+/// Result of a frequency sweep of the oscillator
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FrequencySweepData {
+    pub psd_total_signal: f64,
+    pub peak_frequency: f64,
+    pub peak_amplitude: f64,
+    pub drive_amplitude: f64,
+    pub signal_to_drive_ratio: f64,
+    pub q_factor: f64,
+    pub total_signal_unit: String,
+    pub peak_frequency_unit: String,
+    pub peak_amplitude_unit: String,
+    pub drive_amplitude_unit: String,
+    pub signal_to_drive_ratio_unit: String,
+    pub q_factor_unit: String,
+}
+
+fn read_frequency_sweep_data(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let psd_total_signal = cursor.read_f64_le();
-    let peak_frequency = cursor.read_f64_le();
-    let peak_amplitude = cursor.read_f64_le();
-    let drive_aplitude = cursor.read_f64_le();
-    let signal_to_drive_ratio = cursor.read_f64_le();
-    let q_factor = cursor.read_f64_le();
-    _ = cursor.read_u32_le();
-    let total_signal_unit = read_sm4_string(cursor);
-    let peak_frequency_unit = read_sm4_string(cursor);
-    let peak_amplitude_unit = read_sm4_string(cursor);
-    let drive_amplitude_unit = read_sm4_string(cursor);
-    let signal_to_drive_ratio_unit = read_sm4_string(cursor);
-    let q_factor_unit = read_sm4_string(cursor);
-    ReadType::FrequencySweepData(FrequencySweepData {
+    let psd_total_signal = cursor.read_f64_le()?;
+    let peak_frequency = cursor.read_f64_le()?;
+    let peak_amplitude = cursor.read_f64_le()?;
+    let drive_amplitude = cursor.read_f64_le()?;
+    let signal_to_drive_ratio = cursor.read_f64_le()?;
+    let q_factor = cursor.read_f64_le()?;
+    _ = cursor.read_u32_le()?;
+    let total_signal_unit = read_sm4_string(cursor)?;
+    let peak_frequency_unit = read_sm4_string(cursor)?;
+    let peak_amplitude_unit = read_sm4_string(cursor)?;
+    let drive_amplitude_unit = read_sm4_string(cursor)?;
+    let signal_to_drive_ratio_unit = read_sm4_string(cursor)?;
+    let q_factor_unit = read_sm4_string(cursor)?;
+    Ok(ReadType::FrequencySweepData(FrequencySweepData {
         psd_total_signal,
         peak_frequency,
         peak_amplitude,
-        drive_aplitude,
+        drive_amplitude,
         signal_to_drive_ratio,
         q_factor,
         total_signal_unit,
@@ -1705,99 +2049,101 @@ fn read_frequency_sweep_data(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadTyp
         drive_amplitude_unit,
         signal_to_drive_ratio_unit,
         q_factor_unit,
-    })
+    }))
 }
 
-#[derive(Debug)]
-struct ScanProcessorInfo {
-    x_slope_compensation: f64,
-    y_slope_compensation: f64,
-    x_slope_compensation_unit: String,
-    y_slope_compensation_unit: String,
+/// Slope compensation of the scan processor
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanProcessorInfo {
+    pub x_slope_compensation: f64,
+    pub y_slope_compensation: f64,
+    pub x_slope_compensation_unit: String,
+    pub y_slope_compensation_unit: String,
 }
 
-fn read_scan_processor_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+fn read_scan_processor_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let x_slope_compensation = cursor.read_f64_le();
-    let y_slope_compensation = cursor.read_f64_le();
-    _ = cursor.read_u32_le();
-    let x_slope_compensation_unit = read_sm4_string(cursor);
-    let y_slope_compensation_unit = read_sm4_string(cursor);
-    ReadType::ScanprocessorInfo(ScanProcessorInfo {
+    let x_slope_compensation = cursor.read_f64_le()?;
+    let y_slope_compensation = cursor.read_f64_le()?;
+    _ = cursor.read_u32_le()?;
+    let x_slope_compensation_unit = read_sm4_string(cursor)?;
+    let y_slope_compensation_unit = read_sm4_string(cursor)?;
+    Ok(ReadType::ScanprocessorInfo(ScanProcessorInfo {
         x_slope_compensation,
         y_slope_compensation,
         x_slope_compensation_unit,
         y_slope_compensation_unit,
-    })
+    }))
 }
 
-#[derive(Debug)]
-struct PllInfo {
-    amplitude_control: u32,
-    drive_amplitude: f64,
-    drive_ref_frequency: f64,
-    lockin_freq_offset: f64,
-    lockin_harmonic_factor: f64,
-    lockin_phase_offset: f64,
-    pi_gain: f64,
-    pi_int_cutoff_frequency: f64,
-    pi_lower_bound: f64,
-    pi_upper_bound: f64,
-    diss_pi_gain: f64,
-    diss_pi_int_cutoff_frequency: f64,
-    diss_pi_lower_bound: f64,
-    diss_pi_upper_bound: f64,
-
-    lockin_filter_cutoff_frequency: String,
-
-    drive_amplitude_unit: String,
-    drive_ref_frequency_unit: String,
-    lockin_freq_offset_unit: String,
-    lockin_harmonic_factor_unit: String,
-    lockin_phase_offset_unit: String,
-    pi_gain_unit: String,
-    pi_int_cutoff_frequency_unit: String,
-    pi_lower_bound_unit: String,
-    pi_upper_bound_unit: String,
-    diss_pi_gain_unit: String,
-    diss_pi_int_cutoff_frequency_unit: String,
-    diss_pi_lower_bound_unit: String,
-    diss_pi_upper_bound_unit: String,
-}
-
-fn read_pll_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+/// Settings of the phase locked loop
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PllInfo {
+    pub amplitude_control: u32,
+    pub drive_amplitude: f64,
+    pub drive_ref_frequency: f64,
+    pub lockin_freq_offset: f64,
+    pub lockin_harmonic_factor: f64,
+    pub lockin_phase_offset: f64,
+    pub pi_gain: f64,
+    pub pi_int_cutoff_frequency: f64,
+    pub pi_lower_bound: f64,
+    pub pi_upper_bound: f64,
+    pub diss_pi_gain: f64,
+    pub diss_pi_int_cutoff_frequency: f64,
+    pub diss_pi_lower_bound: f64,
+    pub diss_pi_upper_bound: f64,
+
+    pub lockin_filter_cutoff_frequency: String,
+
+    pub drive_amplitude_unit: String,
+    pub drive_ref_frequency_unit: String,
+    pub lockin_freq_offset_unit: String,
+    pub lockin_harmonic_factor_unit: String,
+    pub lockin_phase_offset_unit: String,
+    pub pi_gain_unit: String,
+    pub pi_int_cutoff_frequency_unit: String,
+    pub pi_lower_bound_unit: String,
+    pub pi_upper_bound_unit: String,
+    pub diss_pi_gain_unit: String,
+    pub diss_pi_int_cutoff_frequency_unit: String,
+    pub diss_pi_lower_bound_unit: String,
+    pub diss_pi_upper_bound_unit: String,
+}
+
+fn read_pll_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let amplitude_control = cursor.read_u32_le();
-    let drive_amplitude = cursor.read_f64_le();
-    let drive_ref_frequency = cursor.read_f64_le();
-    let lockin_freq_offset = cursor.read_f64_le();
-    let lockin_harmonic_factor = cursor.read_f64_le();
-    let lockin_phase_offset = cursor.read_f64_le();
-    let pi_gain = cursor.read_f64_le();
-    let pi_int_cutoff_frequency = cursor.read_f64_le();
-    let pi_lower_bound = cursor.read_f64_le();
-    let pi_upper_bound = cursor.read_f64_le();
-    let diss_pi_gain = cursor.read_f64_le();
-    let diss_pi_int_cutoff_frequency = cursor.read_f64_le();
-    let diss_pi_lower_bound = cursor.read_f64_le();
-    let diss_pi_upper_bound = cursor.read_f64_le();
-
-    let lockin_filter_cutoff_frequency = read_sm4_string(cursor);
-
-    let drive_amplitude_unit = read_sm4_string(cursor);
-    let drive_ref_frequency_unit = read_sm4_string(cursor);
-    let lockin_freq_offset_unit = read_sm4_string(cursor);
-    let lockin_harmonic_factor_unit = read_sm4_string(cursor);
-    let lockin_phase_offset_unit = read_sm4_string(cursor);
-    let pi_gain_unit = read_sm4_string(cursor);
-    let pi_int_cutoff_frequency_unit = read_sm4_string(cursor);
-    let pi_lower_bound_unit = read_sm4_string(cursor);
-    let pi_upper_bound_unit = read_sm4_string(cursor);
-    let diss_pi_gain_unit = read_sm4_string(cursor);
-    let diss_pi_int_cutoff_frequency_unit = read_sm4_string(cursor);
-    let diss_pi_lower_bound_unit = read_sm4_string(cursor);
-    let diss_pi_upper_bound_unit = read_sm4_string(cursor);
-    ReadType::PllInfo(PllInfo {
+    let amplitude_control = cursor.read_u32_le()?;
+    let drive_amplitude = cursor.read_f64_le()?;
+    let drive_ref_frequency = cursor.read_f64_le()?;
+    let lockin_freq_offset = cursor.read_f64_le()?;
+    let lockin_harmonic_factor = cursor.read_f64_le()?;
+    let lockin_phase_offset = cursor.read_f64_le()?;
+    let pi_gain = cursor.read_f64_le()?;
+    let pi_int_cutoff_frequency = cursor.read_f64_le()?;
+    let pi_lower_bound = cursor.read_f64_le()?;
+    let pi_upper_bound = cursor.read_f64_le()?;
+    let diss_pi_gain = cursor.read_f64_le()?;
+    let diss_pi_int_cutoff_frequency = cursor.read_f64_le()?;
+    let diss_pi_lower_bound = cursor.read_f64_le()?;
+    let diss_pi_upper_bound = cursor.read_f64_le()?;
+
+    let lockin_filter_cutoff_frequency = read_sm4_string(cursor)?;
+
+    let drive_amplitude_unit = read_sm4_string(cursor)?;
+    let drive_ref_frequency_unit = read_sm4_string(cursor)?;
+    let lockin_freq_offset_unit = read_sm4_string(cursor)?;
+    let lockin_harmonic_factor_unit = read_sm4_string(cursor)?;
+    let lockin_phase_offset_unit = read_sm4_string(cursor)?;
+    let pi_gain_unit = read_sm4_string(cursor)?;
+    let pi_int_cutoff_frequency_unit = read_sm4_string(cursor)?;
+    let pi_lower_bound_unit = read_sm4_string(cursor)?;
+    let pi_upper_bound_unit = read_sm4_string(cursor)?;
+    let diss_pi_gain_unit = read_sm4_string(cursor)?;
+    let diss_pi_int_cutoff_frequency_unit = read_sm4_string(cursor)?;
+    let diss_pi_lower_bound_unit = read_sm4_string(cursor)?;
+    let diss_pi_upper_bound_unit = read_sm4_string(cursor)?;
+    Ok(ReadType::PllInfo(PllInfo {
         amplitude_control,
         drive_amplitude,
         drive_ref_frequency,
@@ -1826,38 +2172,39 @@ fn read_pll_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
         diss_pi_int_cutoff_frequency_unit,
         diss_pi_lower_bound_unit,
         diss_pi_upper_bound_unit,
-    })
+    }))
 }
 
-#[derive(Debug)]
-struct ChannelDriveInfo {
-    master_osciallator: u32,
-    amplitude: f64,
-    frequency: f64,
-    phase_offset: f64,
-    harmonic_factor: f64,
-    amplitude_unit: String,
-    frequency_unit: String,
-    phase_offset_unit: String,
-    harmonic_factor_unit: String,
-}
-
-fn read_channel_drive_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+/// Oscillator driving an output channel
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChannelDriveInfo {
+    pub master_oscillator: u32,
+    pub amplitude: f64,
+    pub frequency: f64,
+    pub phase_offset: f64,
+    pub harmonic_factor: f64,
+    pub amplitude_unit: String,
+    pub frequency_unit: String,
+    pub phase_offset_unit: String,
+    pub harmonic_factor_unit: String,
+}
+
+fn read_channel_drive_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    _ = cursor.read_u32_le();
-    let master_osciallator = cursor.read_u32_le();
-
-    let amplitude = cursor.read_f64_le();
-    let frequency = cursor.read_f64_le();
-    let phase_offset = cursor.read_f64_le();
-    let harmonic_factor = cursor.read_f64_le();
-
-    let amplitude_unit = read_sm4_string(cursor);
-    let frequency_unit = read_sm4_string(cursor);
-    let phase_offset_unit = read_sm4_string(cursor);
-    let harmonic_factor_unit = read_sm4_string(cursor);
-    ReadType::ChannelDriveInfo(ChannelDriveInfo {
-        master_osciallator,
+    _ = cursor.read_u32_le()?;
+    let master_oscillator = cursor.read_u32_le()?;
+
+    let amplitude = cursor.read_f64_le()?;
+    let frequency = cursor.read_f64_le()?;
+    let phase_offset = cursor.read_f64_le()?;
+    let harmonic_factor = cursor.read_f64_le()?;
+
+    let amplitude_unit = read_sm4_string(cursor)?;
+    let frequency_unit = read_sm4_string(cursor)?;
+    let phase_offset_unit = read_sm4_string(cursor)?;
+    let harmonic_factor_unit = read_sm4_string(cursor)?;
+    Ok(ReadType::ChannelDriveInfo(ChannelDriveInfo {
+        master_oscillator,
         amplitude,
         frequency,
         phase_offset,
@@ -1866,35 +2213,36 @@ fn read_channel_drive_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType
         frequency_unit,
         phase_offset_unit,
         harmonic_factor_unit,
-    })
+    }))
 }
 
-#[derive(Debug)]
-struct LockinInfo {
-    num_strings: u32,
-    non_master_oscillator: u32,
-    frequency: f64,
-    harmonic_factor: f64,
-    phase_offset: f64,
+/// Lock-in settings, e.g. to normalize dI/dV maps
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LockinInfo {
+    pub num_strings: u32,
+    pub non_master_oscillator: u32,
+    pub frequency: f64,
+    pub harmonic_factor: f64,
+    pub phase_offset: f64,
     // these might be not included
-    filter_cutoff_frequency: String,
-    frequency_unit: String,
-    phase_unit: String,
+    pub filter_cutoff_frequency: String,
+    pub frequency_unit: String,
+    pub phase_unit: String,
 }
 
-fn read_lockin_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+fn read_lockin_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let num_strings = cursor.read_u32_le();
+    let num_strings = cursor.read_u32_le()?;
 
-    let non_master_oscillator = cursor.read_u32_le();
-    let frequency = cursor.read_f64_le();
-    let harmonic_factor = cursor.read_f64_le();
-    let phase_offset = cursor.read_f64_le();
+    let non_master_oscillator = cursor.read_u32_le()?;
+    let frequency = cursor.read_f64_le()?;
+    let harmonic_factor = cursor.read_f64_le()?;
+    let phase_offset = cursor.read_f64_le()?;
     // these might be not included
-    let filter_cutoff_frequency = read_sm4_string(cursor);
-    let frequency_unit = read_sm4_string(cursor);
-    let phase_unit = read_sm4_string(cursor);
-    ReadType::LockinInfo(LockinInfo {
+    let filter_cutoff_frequency = read_sm4_string(cursor)?;
+    let frequency_unit = read_sm4_string(cursor)?;
+    let phase_unit = read_sm4_string(cursor)?;
+    Ok(ReadType::LockinInfo(LockinInfo {
         num_strings,
         non_master_oscillator,
         frequency,
@@ -1903,37 +2251,38 @@ fn read_lockin_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
         filter_cutoff_frequency,
         frequency_unit,
         phase_unit,
-    })
+    }))
 }
 
-#[derive(Debug)]
-struct PiControllerInfo {
-    setpoint: f64,
-    proportional_gain: f64,
-    integral_gain: f64,
-    lower_bound: f64,
-    upper_bound: f64,
-    feedback_unit: String,
-    setpoint_unit: String,
-    proportional_gain_unit: String,
-    integral_gain_unit: String,
-    output_unit: String,
-}
-
-fn read_pi_controller_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+/// Settings of a PI controller, e.g. the Z feedback
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PiControllerInfo {
+    pub setpoint: f64,
+    pub proportional_gain: f64,
+    pub integral_gain: f64,
+    pub lower_bound: f64,
+    pub upper_bound: f64,
+    pub feedback_unit: String,
+    pub setpoint_unit: String,
+    pub proportional_gain_unit: String,
+    pub integral_gain_unit: String,
+    pub output_unit: String,
+}
+
+fn read_pi_controller_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    let setpoint = cursor.read_f64_le();
-    let proportional_gain = cursor.read_f64_le();
-    let integral_gain = cursor.read_f64_le();
-    let lower_bound = cursor.read_f64_le();
-    let upper_bound = cursor.read_f64_le();
-    _ = cursor.read_u32_le();
-    let feedback_unit = read_sm4_string(cursor);
-    let setpoint_unit = read_sm4_string(cursor);
-    let proportional_gain_unit = read_sm4_string(cursor);
-    let integral_gain_unit = read_sm4_string(cursor);
-    let output_unit = read_sm4_string(cursor);
-    ReadType::PiControllerInfo(PiControllerInfo {
+    let setpoint = cursor.read_f64_le()?;
+    let proportional_gain = cursor.read_f64_le()?;
+    let integral_gain = cursor.read_f64_le()?;
+    let lower_bound = cursor.read_f64_le()?;
+    let upper_bound = cursor.read_f64_le()?;
+    _ = cursor.read_u32_le()?;
+    let feedback_unit = read_sm4_string(cursor)?;
+    let setpoint_unit = read_sm4_string(cursor)?;
+    let proportional_gain_unit = read_sm4_string(cursor)?;
+    let integral_gain_unit = read_sm4_string(cursor)?;
+    let output_unit = read_sm4_string(cursor)?;
+    Ok(ReadType::PiControllerInfo(PiControllerInfo {
         setpoint,
         proportional_gain,
         integral_gain,
@@ -1944,19 +2293,455 @@ fn read_pi_controller_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType
         proportional_gain_unit,
         integral_gain_unit,
         output_unit,
-    })
-}
-
-#[derive(Debug)]
-struct LowpassFilterInfo {
-    info: String,
+    }))
 }
 
-fn read_lowpass_filter_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> ReadType {
+fn read_lowpass_filter_info(cursor: &mut Cursor<&[u8]>, offset: u32) -> Result<ReadType> {
     cursor.set_position(offset as u64);
-    _ = cursor.read_u32_le();
-    let lowpass_filter_info = read_sm4_string(cursor);
-    ReadType::LowpassFilterInfo(LowpassFilterInfo {
-        info: lowpass_filter_info,
-    })
+    _ = cursor.read_u32_le()?;
+    Ok(ReadType::LowpassFilterInfo(read_sm4_string(cursor)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// Gain, label and unit of a column of sequential data
+    type TestParam = (f32, &'static str, &'static str);
+
+    /// A page written by `write_sm4`
+    #[derive(Default)]
+    struct TestPage {
+        data_type: u32,
+        page_type: u32,
+        line_type: u32,
+        scan_type: u32,
+        xres: u32,
+        yres: u32,
+        /// x_scale, y_scale, x_offset and y_offset
+        scale: [f32; 4],
+        grid: (u32, u32),
+        strings: Vec<&'static str>,
+        data: Vec<u8>,
+        /// Further objects of the page header as type and content
+        objects: Vec<(u32, Vec<u8>)>,
+        /// Data type and columns of a sequential page
+        sequential: Option<(u32, Vec<TestParam>)>,
+    }
+
+    fn bytes_of<T: Copy, const N: usize>(values: &[T], to_le: fn(T) -> [u8; N]) -> Vec<u8> {
+        values.iter().flat_map(|v| to_le(*v)).collect()
+    }
+
+    fn sm4_string(text: &str) -> Vec<u8> {
+        let units: Vec<u16> = text.encode_utf16().collect();
+        let mut bytes = (units.len() as u16).to_le_bytes().to_vec();
+        bytes.extend(bytes_of(&units, u16::to_le_bytes));
+        bytes
+    }
+
+    // Object list entries are written empty and patched once the content is placed
+    fn object(out: &mut Vec<u8>, obj_type: u32) -> usize {
+        out.extend(bytes_of(&[obj_type, 0, 0], u32::to_le_bytes));
+        out.len() - 12
+    }
+
+    fn place(out: &mut Vec<u8>, entry: usize, content: &[u8]) {
+        let offset = out.len() as u32;
+        out[entry + 4..entry + 8].copy_from_slice(&offset.to_le_bytes());
+        out[entry + 8..entry + 12].copy_from_slice(&(content.len() as u32).to_le_bytes());
+        out.extend(content);
+    }
+
+    /// A complete SM4 file with `pages`
+    fn write_sm4(pages: &[TestPage]) -> Vec<u8> {
+        let mut out = 56_u16.to_le_bytes().to_vec();
+        let mut signature: Vec<u16> = "STiMage 005.006 1".encode_utf16().collect();
+        signature.resize(18, 0);
+        out.extend(bytes_of(&signature, u16::to_le_bytes));
+        out.extend(bytes_of(
+            &[pages.len() as u32, 1, 12, 0, 0],
+            u32::to_le_bytes,
+        ));
+        let index_header = object(&mut out, 1);
+        let mut content = bytes_of(&[pages.len() as u32, 1, 0, 0], u32::to_le_bytes);
+        // The page index array follows directly after its object list entry
+        content.extend(bytes_of(&[2, out.len() as u32 + 28, 0], u32::to_le_bytes));
+        place(&mut out, index_header, &content);
+
+        let mut page_objects = Vec::new();
+        for (id, page) in pages.iter().enumerate() {
+            out.extend((id as u16).to_le_bytes());
+            out.extend([0; 14]);
+            out.extend(bytes_of(&[page.data_type, 0, 2, 6], u32::to_le_bytes));
+            page_objects.push((object(&mut out, 3), object(&mut out, 4)));
+        }
+
+        for (page, (header_entry, data_entry)) in pages.iter().zip(page_objects) {
+            let mut header = Vec::new();
+            let mut objects: Vec<(u32, Vec<u8>)> = Vec::new();
+            if let Some((data_type, params)) = &page.sequential {
+                header.extend(bytes_of(
+                    &[
+                        *data_type,
+                        0,
+                        params.len() as u32,
+                        page.objects.len() as u32,
+                        0,
+                        0,
+                    ],
+                    u32::to_le_bytes,
+                ));
+            } else {
+                let strings: Vec<u8> = page.strings.iter().flat_map(|s| sm4_string(s)).collect();
+                objects.push((10, strings));
+                header.extend(bytes_of(&[0, page.strings.len() as u16], u16::to_le_bytes));
+                header.extend(bytes_of(
+                    &[
+                        page.page_type,
+                        0,
+                        page.line_type,
+                        0,
+                        0,
+                        page.xres,
+                        page.yres,
+                        0,
+                        page.scan_type,
+                        0,
+                        page.data.len() as u32,
+                        0,
+                        0,
+                    ],
+                    u32::to_le_bytes,
+                ));
+                let [x_scale, y_scale, x_offset, y_offset] = page.scale;
+                header.extend(bytes_of(
+                    &[
+                        x_scale, y_scale, 1.0, 1.0, x_offset, y_offset, 0.0, 0.0, 0.0, 0.0, 0.0,
+                    ],
+                    f32::to_le_bytes,
+                ));
+                header.extend(bytes_of(&[0, page.grid.0, page.grid.1], u32::to_le_bytes));
+                header.extend(((page.objects.len() + 1) as u32).to_le_bytes());
+                header.extend([0; 64]);
+            }
+            objects.extend(page.objects.iter().cloned());
+
+            let header_start = out.len();
+            out.extend(&header);
+            let entries: Vec<_> = objects
+                .iter()
+                .map(|(obj_type, _)| object(&mut out, *obj_type))
+                .collect();
+            if let Some((_, params)) = &page.sequential {
+                for (gain, label, unit) in params {
+                    out.extend(gain.to_le_bytes());
+                    out.extend(sm4_string(label));
+                    out.extend(sm4_string(unit));
+                }
+            }
+            let header_size = (out.len() - header_start) as u32;
+            out[header_entry + 4..header_entry + 8]
+                .copy_from_slice(&(header_start as u32).to_le_bytes());
+            out[header_entry + 8..header_entry + 12].copy_from_slice(&header_size.to_le_bytes());
+            for (entry, (_, content)) in entries.into_iter().zip(&objects) {
+                place(&mut out, entry, content);
+            }
+            place(&mut out, data_entry, &page.data);
+        }
+        out
+    }
+
+    // The test file with its first page turned into an I(V) page of 512 curves
+    // recorded on a grid of `grid` points
+    fn iv_file(grid: (u32, u32)) -> Vec<u8> {
+        let mut bytes = std::fs::read("tests/test_files/stm-rhk-sm4.SM4").unwrap();
+        let mut cursor = Cursor::new(&bytes[..]);
+        let mut header = read_header(&mut cursor).unwrap();
+        for _ in 0..header.object_list_count {
+            header
+                .object_list
+                .push(read_sm4_object(&mut cursor).unwrap());
+        }
+        let index_header = get_page_index_header(&mut cursor, &header.object_list).unwrap();
+        let index_objects: Vec<_> = (0..index_header.object_count)
+            .map(|_| read_sm4_object(&mut cursor).unwrap())
+            .collect();
+        let page_offset = get_offset_page_index_array(&index_objects).unwrap() as usize;
+        cursor.set_position(page_offset as u64);
+        let mut page = read_sm4_page(&mut cursor).unwrap();
+        for _ in 0..page.object_list_count {
+            page.object_list.push(read_sm4_object(&mut cursor).unwrap());
+        }
+        let header_offset = get_offset_object_page_header(&page.object_list).unwrap() as usize;
+
+        bytes[page_offset + 16..page_offset + 20].copy_from_slice(&1_u32.to_le_bytes());
+        bytes[header_offset + 12..header_offset + 16].copy_from_slice(&7_u32.to_le_bytes());
+        bytes[header_offset + 104..header_offset + 108].copy_from_slice(&grid.0.to_le_bytes());
+        bytes[header_offset + 108..header_offset + 112].copy_from_slice(&grid.1.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_prm_compressed() {
+        let text = b"Parameter file\0";
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        std::io::Write::write_all(&mut encoder, text).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut cursor = Cursor::new(&compressed[..]);
+        let prm = read_prm_data(&mut cursor, 0, 15, compressed.len() as u32, 1).unwrap();
+        assert_eq!(prm, "Parameter file");
+    }
+
+    #[test]
+    fn test_spectrum_page() {
+        let sm4 = read_rhk_sm4_file_from_bytes(&iv_file((0, 0))).unwrap();
+        let page = &sm4.pages[0];
+        assert!(page.is_spectrum());
+        assert_eq!(page.line_type, RhkLineType::IvSpectrum);
+        assert_eq!(page.curves().count(), 512);
+        let x = page.x_axis();
+        assert_eq!(x[0], page.xoffset);
+        assert_eq!(x[1] - x[0], page.x_scale);
+        assert!(!sm4.pages[1].is_spectrum());
+        assert!(sm4.sequential.is_empty());
+    }
+
+    #[test]
+    fn test_spectrum_page_spm_file() {
+        let spm_file = Sm4Reader.read_bytes("iv.sm4", &iv_file((0, 0))).unwrap();
+        assert_eq!(spm_file.channels.len(), 9);
+        assert_eq!(spm_file.spectra.len(), 512);
+        assert_eq!(spm_file.spectra[3].name, "VEC_0_3");
+        assert_eq!(spm_file.spectra[3].metadata["page"], "0");
+        assert_eq!(spm_file.spectra[3].x.len(), 512);
+        assert_eq!(spm_file.spectra[3].y_unit, "V");
+    }
+
+    #[test]
+    fn test_grid() {
+        let sm4 = read_rhk_sm4_from_bytes(&iv_file((16, 16))).unwrap();
+        assert!(sm4[1].grid().is_none());
+        let page = &sm4[0];
+        let grid = page.grid().unwrap();
+        assert_eq!(grid.data.shape(), [16, 16, 2, 512]);
+        // The test file has no drift data
+        assert!(grid.x.is_none());
+        assert!(grid.y.is_none());
+        assert_eq!(grid.bias, page.x_axis());
+        // Two curves at every grid point
+        let curves: Vec<_> = page.curves().collect();
+        assert_eq!(grid.data[[1, 1, 0, 5]], curves[2 * 17][5]);
+        assert_eq!(grid.data[[1, 1, 1, 5]], curves[2 * 17 + 1][5]);
+        let map = grid.map_at(grid.bias[5], 1).unwrap();
+        assert_eq!(map[[1, 1]], grid.data[[1, 1, 1, 5]]);
+        assert!(grid.map_at(grid.bias[5], 2).is_none());
+
+        let sm4 = read_rhk_sm4_from_bytes(&iv_file((10, 10))).unwrap();
+        assert!(sm4[0].grid().is_none());
+    }
+
+    // A 3x2 grid with forward and backward sweeps of 4 bias values, the spec
+    // drift data holds the tip position of every curve
+    fn grid_page() -> TestPage {
+        let mut drift_header = 1_600_000_000_u64.to_le_bytes().to_vec();
+        drift_header.extend(bytes_of(&[2_u32, 0], u32::to_le_bytes));
+        drift_header.extend(sm4_string("Topography"));
+        let mut drift_data = Vec::new();
+        for curve in 0..12 {
+            let point = curve / 2;
+            let (x, y) = (1e-9 * (point % 3) as f32, -2e-9 * (point / 3) as f32);
+            drift_data.extend(bytes_of(
+                &[curve as f32, x, y, 0.0, 0.0, 0.0, 0.0],
+                f32::to_le_bytes,
+            ));
+        }
+        // Forward sweeps rise, backward sweeps fall
+        let data: Vec<i32> = (0..12)
+            .flat_map(|curve| {
+                (0..4).map(move |i| 100 * (curve / 2) + if curve % 2 == 0 { i } else { -i })
+            })
+            .collect();
+        TestPage {
+            data_type: 1,
+            page_type: 16,
+            line_type: 7,
+            xres: 4,
+            yres: 12,
+            scale: [0.5, 1.0, -1.0, 0.0],
+            grid: (3, 2),
+            strings: vec!["dIdV", "", "", "", "", "", "", "V", "", "A"],
+            data: bytes_of(&data, i32::to_le_bytes),
+            objects: vec![(7, drift_header), (8, drift_data)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_grid_positions() {
+        let pages = read_rhk_sm4_from_bytes(&write_sm4(&[grid_page()])).unwrap();
+        let grid = pages[0].grid().unwrap();
+        assert_eq!(grid.label, "dIdV");
+        assert_eq!((grid.bias_unit.as_str(), grid.unit.as_str()), ("V", "A"));
+        assert_eq!(grid.bias, [-1.0, -0.5, 0.0, 0.5]);
+        assert_eq!(grid.data.shape(), [2, 3, 2, 4]);
+        let x = grid.x.clone().unwrap();
+        let y = grid.y.clone().unwrap();
+        assert_eq!(x, [0.0, 1e-9_f32 as f64, 2e-9_f32 as f64]);
+        assert_eq!(y, [0.0, -2e-9_f32 as f64]);
+        // Point (row 1, column 2) is the sixth point
+        assert_eq!(grid.data[[1, 2, 0, 3]], 503.0);
+        assert_eq!(grid.data[[1, 2, 1, 3]], 497.0);
+        let forward = grid.map_at(0.4, 0).unwrap();
+        let backward = grid.map_at(0.4, 1).unwrap();
+        assert_eq!(forward[[0, 1]], 103.0);
+        assert_eq!(backward[[0, 1]], 97.0);
+    }
+
+    fn sequential_page(data_type: u32) -> TestPage {
+        TestPage {
+            data_type: 6,
+            data: bytes_of(&[0.0_f32, 1.5, 0.5, 2.5, 1.0, 3.5], f32::to_le_bytes),
+            sequential: Some((data_type, vec![(1.0, "Time", "s"), (2.0, "Current", "A")])),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sequential_page() {
+        let bytes = write_sm4(&[sequential_page(0)]);
+        let sm4 = read_rhk_sm4_file_from_bytes(&bytes).unwrap();
+        assert!(sm4.pages.is_empty());
+        let table = &sm4.sequential[0];
+        assert_eq!(table.labels, ["Time", "Current"]);
+        assert_eq!(table.units, ["s", "A"]);
+        assert_eq!(table.columns, [vec![0.0, 0.5, 1.0], vec![3.0, 5.0, 7.0]]);
+
+        let spm_file = Sm4Reader.read_bytes("log.sm4", &bytes).unwrap();
+        assert_eq!(spm_file.spectra.len(), 1);
+        assert_eq!(spm_file.spectra[0].name, "Current");
+        assert_eq!(spm_file.spectra[0].x_unit, "s");
+        assert_eq!(spm_file.spectra[0].y, [3.0, 5.0, 7.0]);
+
+        let error = read_rhk_sm4_file_from_bytes(&write_sm4(&[sequential_page(3)])).unwrap_err();
+        assert_eq!(error.to_string(), "Unsupported sequential data type 3");
+    }
+
+    // An image page with drift and tip-track objects as written during atom tracking
+    fn tracked_page(drift: (f32, f32), scan_type: u32) -> TestPage {
+        let mut drift_header = 1_600_000_000_u64.to_le_bytes().to_vec();
+        drift_header.extend(1_u32.to_le_bytes());
+        let drift_data = bytes_of(
+            &[12.0, 0.0, 0.0, drift.0, drift.1, 0.0, 0.0],
+            f32::to_le_bytes,
+        );
+        let mut track_header = 1_600_000_010_u64.to_le_bytes().to_vec();
+        track_header.extend(bytes_of(
+            &[1e-10_f32, 5e-10, 0.1, 10.0, 0.0],
+            f32::to_le_bytes,
+        ));
+        track_header.extend(bytes_of(&[0_u32, 2], u32::to_le_bytes));
+        track_header.extend(sm4_string("Topography"));
+        let track_data = bytes_of(
+            &[
+                1.0_f32, 1.0, 1e-10, 2e-10, 5e-11, 2.0, 1.0, 1e-10, -1e-10, 5e-11,
+            ],
+            f32::to_le_bytes,
+        );
+        let mut api_info = bytes_of(&[10.0_f32, -10.0, 2.0, 0.5], f32::to_le_bytes);
+        api_info.extend(bytes_of(&[0_u32, 1, 16, 1, 2, 3, 4, 0], u32::to_le_bytes));
+        api_info.extend(sm4_string("V"));
+        let mut lockin = bytes_of(&[3_u32, 0], u32::to_le_bytes);
+        lockin.extend(bytes_of(&[973.0, 1.0, 90.0], f64::to_le_bytes));
+        for text in ["100 Hz", "Hz", "deg"] {
+            lockin.extend(sm4_string(text));
+        }
+        TestPage {
+            scan_type,
+            xres: 2,
+            yres: 2,
+            scale: [1e-9, 1e-9, 5e-9, -5e-9],
+            strings: vec![
+                "Topography",
+                "",
+                "",
+                "",
+                "",
+                "09/14/20",
+                "10:00:00",
+                "m",
+                "m",
+                "m",
+            ],
+            data: bytes_of(&[1, 2, 3, 4], i32::to_le_bytes),
+            objects: vec![
+                (5, drift_header),
+                (6, drift_data),
+                (11, track_header),
+                (12, track_data),
+                (17, api_info),
+                (25, lockin),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_drift_and_tip_track() {
+        let bytes = write_sm4(&[tracked_page((0.0, 0.0), 0), tracked_page((2e-9, -1e-9), 1)]);
+        let mut pages = read_rhk_sm4_from_bytes(&bytes).unwrap();
+        let drift = pages[1].image_drift.as_ref().unwrap();
+        assert_eq!(drift.start, Utc.timestamp_opt(1_600_000_000, 0).single());
+        assert_eq!(drift.time, [12.0]);
+        assert_eq!(drift.last(), Some((2e-9_f32 as f64, -1e-9_f32 as f64)));
+        assert!(drift.z.is_empty());
+
+        let track = pages[0].tip_track.as_ref().unwrap();
+        assert_eq!(track.start, Utc.timestamp_opt(1_600_000_010, 0).single());
+        assert_eq!(track.time, [1.0, 2.0]);
+        let close = |a: &[f64], b: &[f64]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-16);
+        assert!(close(&track.x, &[1e-10, 2e-10]));
+        assert!(close(&track.y, &[2e-10, 1e-10]));
+        assert!(close(&track.z, &[5e-11, 1e-10]));
+
+        let offsets: Vec<_> = pages.iter().map(|p| (p.xoffset, p.yoffset)).collect();
+        let data = pages[1].data.clone();
+        correct_offsets_for_drift(&mut pages);
+        assert_eq!((pages[0].xoffset, pages[0].yoffset), offsets[0]);
+        assert!((pages[1].xoffset - (offsets[1].0 - 2e-9)).abs() < 1e-15);
+        assert!((pages[1].yoffset - (offsets[1].1 + 1e-9)).abs() < 1e-15);
+        assert_eq!(pages[1].data, data);
+    }
+
+    #[test]
+    fn test_serialize_metadata() {
+        let pages = read_rhk_sm4("tests/test_files/stm-rhk-sm4.SM4").unwrap();
+        let metadata = serde_json::to_value(&pages[8].metadata).unwrap();
+        let strings = &metadata["strings"];
+        assert_eq!(strings["label"], json!("Topography"));
+        assert_eq!(strings["date"], json!("01/08/20"));
+        assert_eq!(strings["z_units"], json!("m"));
+        assert_eq!(metadata["prm"], Value::Null);
+        assert_eq!(metadata["lockin0"], Value::Null);
+
+        let bytes = write_sm4(&[tracked_page((2e-9, -1e-9), 0)]);
+        let page = &read_rhk_sm4_from_bytes(&bytes).unwrap()[0];
+        let metadata = serde_json::to_value(&page.metadata).unwrap();
+        let api_info = &metadata["api_info"];
+        assert_eq!(api_info["gain"], json!(2.0));
+        assert_eq!(api_info["step"], json!(16));
+        assert_eq!(api_info["bias"], json!(4));
+        let lockin = &metadata["lockin0"];
+        assert_eq!(lockin["frequency"], json!(973.0));
+        assert_eq!(lockin["filter_cutoff_frequency"], json!("100 Hz"));
+        assert_eq!(metadata["lockin1"], Value::Null);
+
+        let track = serde_json::to_value(page.tip_track.as_ref().unwrap()).unwrap();
+        assert_eq!(track["start"], json!("2020-09-13T12:26:50Z"));
+        assert_eq!(track["time"], json!([1.0, 2.0]));
+        assert_eq!(track["z"].as_array().unwrap().len(), 2);
+        let drift = serde_json::to_value(page.image_drift.as_ref().unwrap()).unwrap();
+        assert_eq!(drift["z"], json!([]));
+    }
 }
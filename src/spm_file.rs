@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+use crate::igor_ibw::IbwReader;
+use crate::mulfile::MulReader;
+use crate::omicron_matrix::OmicronMatrixReader;
+use crate::rhk_sm4::Sm4Reader;
+use crate::spm_image::SpmImage;
+
+/// Number of bytes from the start of a file which are passed to `SpmReader::detect`
+pub const DETECT_LEN: usize = 1024;
+
+/// Confidence added when the file name ending matches one of the reader's extensions
+const EXTENSION_CONFIDENCE: f32 = 0.2;
+
+/// A measurement file in a format independent representation
+#[derive(Debug)]
+pub struct SpmFile {
+    pub filepath: PathBuf,
+    /// Name of the reader which opened the file, e.g. "mul"
+    pub format: &'static str,
+    /// Metadata valid for the whole file
+    pub metadata: BTreeMap<String, String>,
+    pub channels: Vec<SpmChannel>,
+    pub spectra: Vec<SpmSpectrum>,
+}
+
+/// A single image of a file together with the metadata describing it
+#[derive(Debug)]
+pub struct SpmChannel {
+    pub metadata: BTreeMap<String, String>,
+    pub image: SpmImage,
+}
+
+/// A single spectroscopy curve, `x` and `y` have the same length
+#[derive(Debug)]
+pub struct SpmSpectrum {
+    pub name: String,
+    pub metadata: BTreeMap<String, String>,
+    pub x_unit: String,
+    pub y_unit: String,
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+}
+
+impl SpmChannel {
+    pub fn name(&self) -> &str {
+        &self.image.img_id
+    }
+}
+
+impl SpmFile {
+    pub fn new(filepath: &Path, format: &'static str) -> Self {
+        Self {
+            filepath: filepath.to_path_buf(),
+            format,
+            metadata: BTreeMap::new(),
+            channels: Vec::new(),
+            spectra: Vec::new(),
+        }
+    }
+
+    pub fn images(&self) -> impl Iterator<Item = &SpmImage> {
+        self.channels.iter().map(|c| &c.image)
+    }
+
+    pub fn into_images(self) -> Vec<SpmImage> {
+        self.channels.into_iter().map(|c| c.image).collect()
+    }
+}
+
+/// Common interface of all file format readers
+pub trait SpmReader {
+    /// Short name of the format
+    fn name(&self) -> &'static str;
+
+    /// File name endings handled by this reader, compared case insensitively
+    fn extensions(&self) -> &[&'static str];
+
+    fn read(&self, filename: &str) -> Result<SpmFile>;
+
+    /// Reads a file which is already in memory, e.g. extracted from an archive.
+    /// `filename` is only used for naming and does not need to exist. Formats
+    /// that keep data in companion files fail, e.g. Omicron Matrix data files,
+    /// whose parameters are in the paramfile of the session.
+    fn read_bytes(&self, filename: &str, _bytes: &[u8]) -> Result<SpmFile> {
+        bail!(
+            "{} reader cannot read {} from memory",
+            self.name(),
+            filename
+        )
+    }
+
+    /// Confidence between 0 and 1 that the content belongs to this format.
+    /// `head` holds at most `DETECT_LEN` bytes from the start of the file.
+    fn detect(&self, _head: &[u8], _file_len: u64) -> f32 {
+        0.0
+    }
+
+    fn accepts(&self, path: &Path) -> bool {
+        let filename = path
+            .file_name()
+            .map(|f| f.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        self.extensions()
+            .iter()
+            .any(|ext| filename.ends_with(&ext.to_lowercase()))
+    }
+}
+
+/// Reader chosen for a file and how sure the detection is about it
+pub struct Detection<'a> {
+    pub reader: &'a dyn SpmReader,
+    pub confidence: f32,
+}
+
+/// Collection of readers which are tried when opening a file
+pub struct Registry {
+    readers: Vec<Box<dyn SpmReader>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            readers: vec![
+                Box::new(MulReader),
+                Box::new(IbwReader),
+                Box::new(OmicronMatrixReader),
+                Box::new(Sm4Reader),
+            ],
+        }
+    }
+}
+
+impl Registry {
+    /// Registry without any readers
+    pub fn empty() -> Self {
+        Self {
+            readers: Vec::new(),
+        }
+    }
+
+    /// Adds a reader, on equal confidence it wins over the ones registered before
+    pub fn register(&mut self, reader: Box<dyn SpmReader>) {
+        self.readers.push(reader);
+    }
+
+    pub fn readers(&self) -> impl Iterator<Item = &dyn SpmReader> {
+        self.readers.iter().map(|r| r.as_ref())
+    }
+
+    /// Returns the reader with the highest confidence for the given content
+    pub fn detect(&self, path: &Path, head: &[u8], file_len: u64) -> Option<Detection<'_>> {
+        let mut best: Option<Detection> = None;
+        for reader in self.readers() {
+            let mut confidence = reader.detect(head, file_len).clamp(0.0, 1.0);
+            if reader.accepts(path) {
+                confidence = (confidence + EXTENSION_CONFIDENCE).min(1.0);
+            }
+            if confidence > 0.0 && best.as_ref().is_none_or(|b| confidence >= b.confidence) {
+                best = Some(Detection { reader, confidence });
+            }
+        }
+        best
+    }
+
+    pub fn detect_file(&self, path: &Path) -> Result<Option<Detection<'_>>> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut head = Vec::with_capacity(DETECT_LEN);
+        file.take(DETECT_LEN as u64).read_to_end(&mut head)?;
+        Ok(self.detect(path, &head, file_len))
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<SpmFile> {
+        let path = path.as_ref();
+        let detection = self
+            .detect_file(path)?
+            .ok_or_else(|| anyhow::anyhow!("Unsupported file format: {}", path.display()))?;
+        detection.reader.read(&path.to_string_lossy())
+    }
+
+    /// Like `open` for a file which is already in memory, `filename` is used for detection.
+    /// Fails for formats that need companion files, see `SpmReader::read_bytes`.
+    pub fn open_bytes(&self, filename: &str, bytes: &[u8]) -> Result<SpmFile> {
+        let head = &bytes[..bytes.len().min(DETECT_LEN)];
+        let detection = self
+            .detect(Path::new(filename), head, bytes.len() as u64)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported file format: {}", filename))?;
+        detection.reader.read_bytes(filename, bytes)
+    }
+
+    /// Like `open_bytes` for any source, which is read to the end
+    pub fn open_reader<R: Read>(&self, filename: &str, mut reader: R) -> Result<SpmFile> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        self.open_bytes(filename, &bytes)
+    }
+}
+
+/// Opens a file in any of the built-in formats
+pub fn open<P: AsRef<Path>>(path: P) -> Result<SpmFile> {
+    Registry::default().open(path)
+}
@@ -3,14 +3,20 @@ use std::io::Cursor;
 use anyhow::Context;
 use image::{ImageBuffer, Luma};
 use linfa_linalg::qr::LeastSquaresQr;
-use ndarray::{Array, Array2, ArrayView, Axis, s};
+use ndarray::{Array2, ArrayView, s};
+use num_complex::Complex;
+
+use crate::fft::{self, FourierFilter, RadialPsd, Window};
+use crate::lattice::{self, BraggPeak, Lattice, Matrix2};
 
 use crate::rocket::ROCKET;
 
 #[derive(Debug)]
 pub struct SpmImage {
     pub img_id: String,
+    /// Size in nm
     pub xsize: f64,
+    /// Size in nm
     pub ysize: f64,
     /// Resolution in x-axis, corresponds to number of pixels per scan lines
     pub xres: usize,
@@ -95,50 +101,617 @@ impl SpmImage {
         .ok();
     }
 
-    pub fn correct_plane(&mut self) -> &Self {
+    /// Fits a plane to the image and subtracts it
+    pub fn correct_plane(&mut self) -> anyhow::Result<&Self> {
+        self.subtract_fit(&[(0, 0), (1, 0), (0, 1)], None)?;
+        Ok(self)
+    }
+
+    /// Fits a polynomial with the terms x^i * y^j for i <= `x_order` and
+    /// j <= `y_order` and subtracts it from the image. Pixels where `mask` is
+    /// true, e.g. adsorbates, are excluded from the fit but still corrected.
+    /// An order must be below the resolution along its axis, e.g. `y_order`
+    /// is 0 for a single scan line.
+    pub fn subtract_polynomial(
+        &mut self,
+        x_order: usize,
+        y_order: usize,
+        mask: Option<&[bool]>,
+    ) -> anyhow::Result<&Self> {
+        let terms: Vec<(usize, usize)> = (0..=y_order)
+            .flat_map(|j| (0..=x_order).map(move |i| (i, j)))
+            .collect();
+        self.subtract_fit(&terms, mask)?;
+        Ok(self)
+    }
+
+    /// Checks that the image data and an optional exclusion mask match the
+    /// resolution
+    fn check_mask(&self, mask: Option<&[bool]>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.img_data.len() == self.xres * self.yres,
+            "Image has {} pixels instead of {}x{}",
+            self.img_data.len(),
+            self.xres,
+            self.yres
+        );
+        if let Some(mask) = mask {
+            anyhow::ensure!(
+                mask.len() == self.img_data.len(),
+                "Mask has {} pixels, the image {}",
+                mask.len(),
+                self.img_data.len()
+            );
+        }
+        Ok(())
+    }
+
+    fn subtract_fit(
+        &mut self,
+        terms: &[(usize, usize)],
+        mask: Option<&[bool]>,
+    ) -> anyhow::Result<()> {
+        self.check_mask(mask)?;
+        subtract_fit(&mut self.img_data, self.xres, self.yres, terms, mask)
+    }
+
+    /// Subtracts the mean of each scan line
+    pub fn correct_lines(&mut self) -> anyhow::Result<&Self> {
+        self.align_lines(LineAlignment::Mean, None)
+    }
+
+    /// Aligns the scan lines with `method`. Pixels where `mask` is true, e.g.
+    /// tall clusters, are ignored when determining the line offsets. Fails
+    /// without changing the image if a polynomial cannot be fitted to a line.
+    pub fn align_lines(
+        &mut self,
+        method: LineAlignment,
+        mask: Option<&[bool]>,
+    ) -> anyhow::Result<&Self> {
+        self.check_mask(mask)?;
+        if let LineAlignment::TrimmedMean(trim) = method {
+            anyhow::ensure!(
+                (0.0..0.5).contains(&trim),
+                "Trimmed fraction {} is not in [0, 0.5)",
+                trim
+            );
+        }
         let xres = self.xres;
-        let yres = self.yres;
+        if let LineAlignment::Polynomial(order) = method {
+            let terms: Vec<_> = (0..=order).map(|i| (i, 0)).collect();
+            let mut aligned = self.img_data.clone();
+            for (r, line) in aligned.chunks_exact_mut(xres).enumerate() {
+                let line_mask = mask.map(|m| &m[r * xres..(r + 1) * xres]);
+                subtract_fit(line, xres, 1, &terms, line_mask)
+                    .with_context(|| format!("Cannot align line {}", r))?;
+            }
+            self.img_data = aligned;
+            return Ok(self);
+        }
+        let unmasked = |r: usize, c: usize| !mask.is_some_and(|m| m[r * xres + c]);
 
-        let img_data = Array::from_vec(self.img_data.clone())
-            .into_shape((xres, yres))
-            .unwrap();
-        let img_data_flat = Array::from_vec(self.img_data.clone())
-            .into_shape((xres * yres, 1))
-            .unwrap();
-        let ones: Array2<f64> = Array::ones((xres, yres));
+        for r in 0..self.yres {
+            let line = r * xres..(r + 1) * xres;
+            let values = || {
+                self.img_data[line.clone()]
+                    .iter()
+                    .enumerate()
+                    .filter(|(c, z)| unmasked(r, *c) && z.is_finite())
+                    .map(|(_, z)| *z)
+            };
+            let offset = match method {
+                LineAlignment::Mean => trimmed_mean(values().collect(), 0.0),
+                LineAlignment::Median => median(values().collect()),
+                LineAlignment::TrimmedMean(trim) => trimmed_mean(values().collect(), trim),
+                LineAlignment::Polynomial(_) => unreachable!("aligned as a whole above"),
+                LineAlignment::MedianOfDifferences if r > 0 => {
+                    // The previous line is already shifted, so only the
+                    // remaining difference is subtracted
+                    let previous = (r - 1) * xres;
+                    let diffs: Vec<f64> = (0..xres)
+                        .filter(|c| unmasked(r, *c) && unmasked(r - 1, *c))
+                        .map(|c| self.img_data[line.start + c] - self.img_data[previous + c])
+                        .filter(|d| d.is_finite())
+                        .collect();
+                    median(diffs)
+                }
+                LineAlignment::Matching if r > 0 => {
+                    let previous = (r - 1) * xres;
+                    let usable = |c: usize| unmasked(r, c) && unmasked(r - 1, c);
+                    let pairs: Vec<(f64, f64)> = (1..xres)
+                        .filter(|c| usable(c - 1) && usable(*c))
+                        .map(|c| {
+                            let a = &self.img_data[previous + c - 1..=previous + c];
+                            let b = &self.img_data[line.start + c - 1..=line.start + c];
+                            let diff = (b[0] - a[0] + b[1] - a[1]) / 2.0;
+                            (diff, (b[1] - b[0]) - (a[1] - a[0]))
+                        })
+                        .filter(|(diff, mismatch)| diff.is_finite() && mismatch.is_finite())
+                        .collect();
+                    matching_offset(&pairs)
+                }
+                LineAlignment::MedianOfDifferences | LineAlignment::Matching => continue,
+            };
+            if offset.is_finite() {
+                self.img_data[line].iter_mut().for_each(|z| *z -= offset);
+            }
+        }
+        Ok(self)
+    }
 
-        let mut coeffs: Array2<f64> = Array::ones((xres * yres, 1));
-        let x_coords = Array::from_shape_fn((xres, yres), |(_, j)| j as f64);
-        let y_coords = Array::from_shape_fn((xres, yres), |(i, _)| i as f64);
+    /// Replaces scars, segments of at least `min_length` pixels of a scan line
+    /// that stick out from both neighbouring lines by more than `threshold`
+    /// times the RMS difference between neighbouring lines, by interpolating
+    /// the neighbours. Returns the indices of the modified lines.
+    pub fn remove_scars(
+        &mut self,
+        threshold: f64,
+        min_length: usize,
+        polarity: ScarPolarity,
+    ) -> anyhow::Result<Vec<usize>> {
+        self.check_mask(None)?;
+        let (xres, yres) = (self.xres, self.yres);
+        if yres < 2 {
+            return Ok(Vec::new());
+        }
+        let data = &self.img_data;
+        let diffs = (xres..data.len()).map(|k| data[k] - data[k - xres]);
+        let rms = (diffs.map(|d| d * d).sum::<f64>() / (data.len() - xres) as f64).sqrt();
+        let limit = threshold * rms;
 
-        let x_view = ArrayView::from(&x_coords);
-        let x_coords_flat = x_view.into_shape(xres * yres).unwrap();
-        coeffs.push_column(x_coords_flat).unwrap();
-        coeffs
-            .push_column(ArrayView::from(&y_coords).into_shape(xres * yres).unwrap())
-            .unwrap();
-        let res = coeffs.least_squares(&img_data_flat).unwrap();
-        // let qr = coeffs.qr().unwrap();
-        // let res = qr.solve(&img_data_flat);
+        let mut scars = Vec::new();
+        for r in 0..yres {
+            let neighbours: Vec<usize> = [r.checked_sub(1), Some(r + 1).filter(|n| *n < yres)]
+                .into_iter()
+                .flatten()
+                .collect();
+            let is_scar = |c: usize| {
+                let z = data[r * xres + c];
+                let (low, high) = neighbours
+                    .iter()
+                    .map(|n| data[n * xres + c])
+                    .fold((f64::INFINITY, f64::NEG_INFINITY), |(l, h), v| {
+                        (l.min(v), h.max(v))
+                    });
+                let positive = z - high > limit;
+                let negative = low - z > limit;
+                match polarity {
+                    ScarPolarity::Positive => positive,
+                    ScarPolarity::Negative => negative,
+                    ScarPolarity::Both => positive || negative,
+                }
+            };
+            let mut c = 0;
+            while c < xres {
+                let start = c;
+                while c < xres && is_scar(c) {
+                    c += 1;
+                }
+                if c - start >= min_length.max(1) {
+                    scars.push((r, start..c, neighbours.clone()));
+                }
+                c = c.max(start + 1);
+            }
+        }
+
+        // Scars are replaced only after all of them were detected, so the
+        // detection is not affected by the interpolated values
+        let mut lines = Vec::new();
+        for (r, columns, neighbours) in scars {
+            for c in columns {
+                self.img_data[r * xres + c] = neighbours
+                    .iter()
+                    .map(|n| self.img_data[n * xres + c])
+                    .sum::<f64>()
+                    / neighbours.len() as f64;
+            }
+            if lines.last() != Some(&r) {
+                lines.push(r);
+            }
+        }
+        Ok(lines)
+    }
 
-        let correction = ones * res[[0, 0]] + x_coords * res[[1, 0]] + y_coords * res[[2, 0]];
-        let s = img_data - correction;
+    /// Fourier transform of the image, `inverse_fft` restores it. The result
+    /// has the shape (yres, xres), the frequencies of the axes are given by
+    /// `fft::frequencies`.
+    pub fn fft(&self) -> anyhow::Result<Array2<Complex<f64>>> {
+        self.check_mask(None)?;
+        let mut spectrum = Array2::from_shape_fn((self.yres, self.xres), |(r, c)| {
+            Complex::new(self.img_data[r * self.xres + c], 0.0)
+        });
+        fft::fft2(&mut spectrum, false);
+        Ok(spectrum)
+    }
 
-        self.img_data = s.into_raw_vec();
-        self
+    /// Fourier transform of the image after subtracting its mean and applying
+    /// `window` along both axes, for spectra of the image content. Unlike
+    /// `fft` it cannot be inverted.
+    pub fn windowed_fft(&self, window: Window) -> anyhow::Result<Array2<Complex<f64>>> {
+        self.check_mask(None)?;
+        let mean = self.img_data.iter().sum::<f64>() / self.img_data.len() as f64;
+        let wx = window.coefficients(self.xres);
+        let wy = window.coefficients(self.yres);
+        let mut spectrum = Array2::from_shape_fn((self.yres, self.xres), |(r, c)| {
+            let z = self.img_data[r * self.xres + c] - mean;
+            Complex::new(z * wx[c] * wy[r], 0.0)
+        });
+        fft::fft2(&mut spectrum, false);
+        Ok(spectrum)
     }
 
-    pub fn correct_lines(&mut self) -> &Self {
-        let xres = self.xres;
-        let yres = self.yres;
+    /// Replaces the image by the real part of the inverse transform of
+    /// `spectrum`
+    pub fn inverse_fft(&mut self, mut spectrum: Array2<Complex<f64>>) -> anyhow::Result<&Self> {
+        anyhow::ensure!(
+            spectrum.dim() == (self.yres, self.xres),
+            "Spectrum of shape {:?} does not match the image {}x{}",
+            spectrum.dim(),
+            self.yres,
+            self.xres
+        );
+        fft::fft2(&mut spectrum, true);
+        self.img_data = spectrum.iter().map(|z| z.re).collect();
+        Ok(self)
+    }
 
-        let img_data = Array::from_vec(self.img_data.clone())
-            .into_shape((xres, yres))
-            .unwrap();
-        let means = img_data.mean_axis(Axis(1)).unwrap();
-        let corrected = img_data - means.broadcast((yres, xres)).unwrap().t();
-        self.img_data = corrected.into_raw_vec();
-        self
+    /// Radially averaged power spectral density of the image after subtracting
+    /// its mean and applying `window`, in bins of the coarser frequency resolution of both axes up
+    /// to the lower Nyquist frequency
+    pub fn radial_psd(&self, window: Window) -> anyhow::Result<RadialPsd> {
+        let spectrum = self.windowed_fft(window)?;
+        let (dx, dy) = (self.xsize / self.xres as f64, self.ysize / self.yres as f64);
+        let power = |w: Vec<f64>| w.iter().map(|c| c * c).sum::<f64>() / w.len() as f64;
+        let norm = dx * dy
+            / (self.xres * self.yres) as f64
+            / (power(window.coefficients(self.xres)) * power(window.coefficients(self.yres)));
+
+        let fx = fft::frequencies(self.xres, self.xsize);
+        let fy = fft::frequencies(self.yres, self.ysize);
+        let step = (1.0 / self.xsize).max(1.0 / self.ysize);
+        let nyquist = (0.5 / dx).min(0.5 / dy);
+        let bins = (nyquist / step).floor() as usize + 1;
+        let mut sums = vec![0.0; bins];
+        let mut counts = vec![0usize; bins];
+        for ((r, c), z) in spectrum.indexed_iter() {
+            let bin = (fx[c].hypot(fy[r]) / step).round() as usize;
+            if bin < bins {
+                sums[bin] += z.norm_sqr() * norm;
+                counts[bin] += 1;
+            }
+        }
+        Ok(RadialPsd {
+            frequency: (0..bins).map(|i| i as f64 * step).collect(),
+            psd: sums
+                .iter()
+                .zip(&counts)
+                .map(|(s, n)| if *n > 0 { s / *n as f64 } else { 0.0 })
+                .collect(),
+        })
+    }
+
+    /// Applies `filter` to the Fourier transform of the image
+    pub fn fourier_filter(&mut self, filter: FourierFilter) -> anyhow::Result<&Self> {
+        let mut spectrum = self.fft()?;
+        let fx = fft::frequencies(self.xres, self.xsize);
+        let fy = fft::frequencies(self.yres, self.ysize);
+        for ((r, c), z) in spectrum.indexed_iter_mut() {
+            if !filter.passes(fx[c], fy[r]) {
+                *z = Complex::new(0.0, 0.0);
+            }
+        }
+        self.inverse_fft(spectrum)
+    }
+
+    /// Local maxima of the power spectrum of the image without its mean and
+    /// with a Hann window, strongest first. Positions are refined by parabolic interpolation and
+    /// the bins next to zero frequency, which contain the leaked background,
+    /// are skipped.
+    pub fn bragg_peaks(&self) -> anyhow::Result<Vec<BraggPeak>> {
+        let magnitude = self.windowed_fft(Window::Hann)?.mapv(|z| z.norm());
+        let (yres, xres) = magnitude.dim();
+        let signed = |k: usize, n: usize| {
+            if k < n.div_ceil(2) {
+                k as f64
+            } else {
+                k as f64 - n as f64
+            }
+        };
+        let at = |r: usize, c: usize, dr: isize, dc: isize| {
+            let r = (r as isize + dr).rem_euclid(yres as isize) as usize;
+            let c = (c as isize + dc).rem_euclid(xres as isize) as usize;
+            magnitude[[r, c]]
+        };
+        // Vertex of the parabola through the neighbours
+        let offset = |minus: f64, centre: f64, plus: f64| {
+            let curvature = minus - 2.0 * centre + plus;
+            if curvature < 0.0 {
+                0.5 * (minus - plus) / curvature
+            } else {
+                0.0
+            }
+        };
+
+        let mut peaks = Vec::new();
+        for ((r, c), m) in magnitude.indexed_iter() {
+            let (kx, ky) = (signed(c, xres), signed(r, yres));
+            if kx.hypot(ky) <= 2.0 {
+                continue;
+            }
+            if is_local_max(&magnitude, r, c) {
+                peaks.push(BraggPeak {
+                    fx: (kx + offset(at(r, c, 0, -1), *m, at(r, c, 0, 1))) / self.xsize,
+                    fy: (ky + offset(at(r, c, -1, 0), *m, at(r, c, 1, 0))) / self.ysize,
+                    intensity: m * m,
+                });
+            }
+        }
+        peaks.sort_by(|a, b| b.intensity.total_cmp(&a.intensity));
+        Ok(peaks)
+    }
+
+    /// Estimates the lattice from the two shortest independent reciprocal
+    /// vectors among the Bragg peaks with at least a tenth of the intensity
+    /// of the strongest one, so that harmonics are not mistaken for the
+    /// lattice.
+    pub fn find_lattice(&self) -> anyhow::Result<Lattice> {
+        let peaks = self.bragg_peaks()?;
+        let strongest = peaks
+            .first()
+            .context("No peaks in the power spectrum")?
+            .intensity;
+        let candidates: Vec<[f64; 2]> = peaks
+            .iter()
+            .filter(|p| p.intensity >= 0.1 * strongest)
+            .map(|p| [p.fx, p.fy])
+            .collect();
+        let length = |g: &[f64; 2]| g[0].hypot(g[1]);
+        let g1 = *candidates
+            .iter()
+            .min_by(|a, b| length(a).total_cmp(&length(b)))
+            .context("No peaks in the power spectrum")?;
+        let g2 = *candidates
+            .iter()
+            .filter(|g| (lattice::cross(g1, **g) / (length(&g1) * length(g))).abs() > 0.2)
+            .min_by(|a, b| length(a).total_cmp(&length(b)))
+            .context("Only one lattice direction in the power spectrum")?;
+        // Reciprocal vectors at less than 90 degrees give the conventional
+        // real space vectors at more than 90 degrees
+        let g2 = if g1[0] * g2[0] + g1[1] * g2[1] < 0.0 {
+            [-g2[0], -g2[1]]
+        } else {
+            g2
+        };
+        Lattice::from_reciprocal(g1, g2).context("Degenerate lattice")
+    }
+
+    /// Resamples the image with the affine transform `m` about its centre,
+    /// using bilinear interpolation. Pixels mapped from outside the image
+    /// take the value of the nearest edge.
+    pub fn apply_affine(&mut self, m: Matrix2) -> anyhow::Result<&Self> {
+        self.check_mask(None)?;
+        anyhow::ensure!(!self.img_data.is_empty(), "Image is empty");
+        let inverse = lattice::invert(m).context("Affine transform is singular")?;
+        let (xres, yres) = (self.xres, self.yres);
+        let (dx, dy) = (self.xsize / xres as f64, self.ysize / yres as f64);
+        let (cx, cy) = ((xres - 1) as f64 / 2.0, (yres - 1) as f64 / 2.0);
+        let data = &self.img_data;
+        let sample = |x: f64, y: f64| {
+            let x = x.clamp(0.0, (xres - 1) as f64);
+            let y = y.clamp(0.0, (yres - 1) as f64);
+            let (c, r) = (x.floor() as usize, y.floor() as usize);
+            let (c1, r1) = ((c + 1).min(xres - 1), (r + 1).min(yres - 1));
+            let (tx, ty) = (x - c as f64, y - r as f64);
+            let top = data[r * xres + c] * (1.0 - tx) + data[r * xres + c1] * tx;
+            let bottom = data[r1 * xres + c] * (1.0 - tx) + data[r1 * xres + c1] * tx;
+            top * (1.0 - ty) + bottom * ty
+        };
+        let resampled = (0..xres * yres)
+            .map(|k| {
+                let p = [((k % xres) as f64 - cx) * dx, ((k / xres) as f64 - cy) * dy];
+                let source = lattice::transform(inverse, p);
+                sample(source[0] / dx + cx, source[1] / dy + cy)
+            })
+            .collect();
+        self.img_data = resampled;
+        Ok(self)
+    }
+
+    /// Detects the lattice and corrects scaling and shear of the image so it
+    /// matches `reference`, e.g. `Lattice::hexagonal(lattice::AU_111)`.
+    /// Returns the applied transform.
+    pub fn calibrate_lattice(&mut self, reference: &Lattice) -> anyhow::Result<Matrix2> {
+        let m = self
+            .find_lattice()?
+            .correction_to(reference)
+            .context("Degenerate lattice")?;
+        self.apply_affine(m)?;
+        Ok(m)
+    }
+}
+
+/// Which scars `SpmImage::remove_scars` removes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScarPolarity {
+    /// Scars higher than their neighbours
+    Positive,
+    /// Scars lower than their neighbours
+    Negative,
+    Both,
+}
+
+/// Methods to align scan lines, similar to Gwyddion's "Align Rows"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineAlignment {
+    /// Subtract the mean of each line
+    Mean,
+    /// Subtract the median of each line, robust against steps covering less
+    /// than half of a line
+    Median,
+    /// Shift each line by the median of the differences to the previous line
+    MedianOfDifferences,
+    /// Subtract a polynomial of the given order fitted to each line
+    Polynomial(usize),
+    /// Subtract the mean of each line after discarding the given fraction of
+    /// the lowest and highest values
+    TrimmedMean(f64),
+    /// Shift each line by the mean difference to the previous line, weighting
+    /// each pair of neighbouring columns by how well the local slopes of both
+    /// lines match, so that features crossing between the lines, e.g. step
+    /// edges, hardly contribute
+    Matching,
+}
+
+/// Least squares fit of the polynomial terms x^i * y^j in `terms` to the
+/// unmasked pixels of `data` with `xres` columns. The terms are expressed as
+/// products of Legendre polynomials P_i(x) * P_j(y) with coordinates scaled to
+/// [-1, 1], which are nearly orthogonal on the pixel grid, so the normal
+/// equations stay well conditioned for high orders and no copy of the data is
+/// needed.
+fn subtract_fit(
+    data: &mut [f64],
+    xres: usize,
+    yres: usize,
+    terms: &[(usize, usize)],
+    mask: Option<&[bool]>,
+) -> anyhow::Result<()> {
+    // Orders that cannot be resolved along an axis, e.g. any y term for a
+    // single scan line
+    if let Some((i, j)) = terms.iter().find(|(i, j)| *i >= xres || *j >= yres) {
+        anyhow::bail!(
+            "Term x^{} * y^{} cannot be fitted to {}x{} pixels",
+            i,
+            j,
+            xres,
+            yres
+        );
+    }
+    let max_i = terms.iter().map(|t| t.0).max().unwrap_or(0);
+    let max_j = terms.iter().map(|t| t.1).max().unwrap_or(0);
+    let (px, py) = (&legendre_table(xres, max_i), &legendre_table(yres, max_j));
+    let basis = |k: usize| {
+        let (c, r) = (k % xres, k / xres);
+        terms.iter().map(move |(i, j)| px[[c, *i]] * py[[r, *j]])
+    };
+
+    let n_terms = terms.len();
+    let mut ata: Array2<f64> = Array2::zeros((n_terms, n_terms));
+    let mut atb: Array2<f64> = Array2::zeros((n_terms, 1));
+    let mut n_fitted = 0;
+    let mut row = vec![0.0; n_terms];
+    for (k, z) in data.iter().enumerate() {
+        if mask.is_some_and(|m| m[k]) || !z.is_finite() {
+            continue;
+        }
+        for (r, b) in row.iter_mut().zip(basis(k)) {
+            *r = b;
+        }
+        for a in 0..n_terms {
+            atb[[a, 0]] += row[a] * z;
+            for b in 0..n_terms {
+                ata[[a, b]] += row[a] * row[b];
+            }
+        }
+        n_fitted += 1;
+    }
+    anyhow::ensure!(
+        n_fitted >= n_terms,
+        "{} unmasked pixels are not enough to fit {} coefficients",
+        n_fitted,
+        n_terms
+    );
+    let coeffs = ata.least_squares(&atb)?;
+
+    for (k, z) in data.iter_mut().enumerate() {
+        *z -= basis(k)
+            .zip(coeffs.column(0))
+            .map(|(b, c)| b * c)
+            .sum::<f64>();
+    }
+    Ok(())
+}
+
+/// Legendre polynomials P_0 to P_`order` at `n` points spread evenly over
+/// [-1, 1], with shape (n, order + 1)
+fn legendre_table(n: usize, order: usize) -> Array2<f64> {
+    let mut table = Array2::zeros((n, order + 1));
+    for k in 0..n {
+        let x = if n > 1 {
+            2.0 * k as f64 / (n - 1) as f64 - 1.0
+        } else {
+            0.0
+        };
+        table[[k, 0]] = 1.0;
+        if order > 0 {
+            table[[k, 1]] = x;
+        }
+        // (m + 1) P_m+1 = (2m + 1) x P_m - m P_m-1
+        for m in 1..order {
+            let m_f = m as f64;
+            table[[k, m + 1]] =
+                ((2.0 * m_f + 1.0) * x * table[[k, m]] - m_f * table[[k, m - 1]]) / (m_f + 1.0);
+        }
+    }
+    table
+}
+
+/// Mean of `values` without the `trim` fraction of lowest and highest values,
+/// NaN for no values
+fn trimmed_mean(mut values: Vec<f64>, trim: f64) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let cut = (values.len() as f64 * trim) as usize;
+    let kept = &values[cut..values.len() - cut];
+    kept.iter().sum::<f64>() / kept.len() as f64
+}
+
+/// Whether `values[[r, c]]` is a maximum among its 8 neighbours, with periodic
+/// boundaries as for a spectrum. Of neighbours with the same value, e.g. a
+/// peak exactly between two frequency bins, only the first in row-major order
+/// counts, and at least one neighbour must be lower.
+fn is_local_max(values: &Array2<f64>, r: usize, c: usize) -> bool {
+    let (yres, xres) = values.dim();
+    let centre = values[[r, c]];
+    let mut lower = false;
+    for (dr, dc) in (-1..=1).flat_map(|dr| (-1..=1).map(move |dc| (dr, dc))) {
+        let nr = (r as isize + dr).rem_euclid(yres as isize) as usize;
+        let nc = (c as isize + dc).rem_euclid(xres as isize) as usize;
+        if (nr, nc) == (r, c) {
+            continue;
+        }
+        let value = values[[nr, nc]];
+        if value > centre || (value == centre && (nr, nc) < (r, c)) {
+            return false;
+        }
+        lower |= value < centre;
+    }
+    lower
+}
+
+/// Mean of the differences between two lines at pairs of neighbouring
+/// columns, weighted by exp(-(m / rms)^2) with the difference m of the slopes
+/// of both lines and its RMS over all pairs. NaN for no pairs.
+fn matching_offset(pairs: &[(f64, f64)]) -> f64 {
+    let rms = (pairs.iter().map(|(_, m)| m * m).sum::<f64>() / pairs.len() as f64).sqrt();
+    let weight = |m: f64| {
+        if rms > 0.0 {
+            (-(m / rms).powi(2)).exp()
+        } else {
+            1.0
+        }
+    };
+    let (sum, weights) = pairs.iter().fold((0.0, 0.0), |(sum, weights), (diff, m)| {
+        (sum + weight(*m) * diff, weights + weight(*m))
+    });
+    sum / weights
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let n = values.len();
+    match n {
+        0 => f64::NAN,
+        _ if n.is_multiple_of(2) => (values[n / 2 - 1] + values[n / 2]) / 2.0,
+        _ => values[n / 2],
     }
 }
 
@@ -150,3 +723,22 @@ pub fn flip_img_data(img_data: Vec<f64>, xres: u32, yres: u32) -> Vec<f64> {
     }
     flipped
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plateau_max() {
+        let mut values = Array2::zeros((5, 6));
+        values[[2, 2]] = 1.0;
+        values[[2, 3]] = 1.0;
+        values[[4, 5]] = 0.5;
+        let maxima: Vec<_> = values
+            .indexed_iter()
+            .filter(|((r, c), _)| is_local_max(&values, *r, *c))
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(maxima, [(2, 2), (4, 5)]);
+    }
+}
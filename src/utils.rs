@@ -1,119 +1,148 @@
 use std::io::{Cursor, Read};
 use std::str;
 
+use crate::error::{SpmError, SpmResult};
+
 pub trait Bytereading {
-    fn skip(&mut self, num_bytes: u64);
-    fn read_matrix_type(&mut self) -> String;
-    fn read_matrix_string(&mut self) -> String;
-    fn read_utf16_string(&mut self, length: usize) -> String;
-    fn read_string(&mut self, length: usize) -> String;
-    fn read_magic_header(&mut self) -> String;
-    fn read_i8_le(&mut self) -> i8;
-    fn read_u8_le(&mut self) -> u8;
-    fn read_i16_le(&mut self) -> i16;
-    fn read_u16_le(&mut self) -> u16;
-    fn read_i32_le(&mut self) -> i32;
-    fn read_u32_le(&mut self) -> u32;
-    fn read_u64_le(&mut self) -> u64;
-    fn read_f32_le(&mut self) -> f32;
-    fn read_f64_le(&mut self) -> f64;
+    fn skip(&mut self, num_bytes: u64) -> SpmResult<()>;
+    fn read_bytes(&mut self, length: usize) -> SpmResult<Vec<u8>>;
+    fn read_matrix_type(&mut self) -> SpmResult<String>;
+    fn read_matrix_string(&mut self) -> SpmResult<String>;
+    fn read_utf16_string(&mut self, length: usize) -> SpmResult<String>;
+    fn read_string(&mut self, length: usize) -> SpmResult<String>;
+    fn read_i8_le(&mut self) -> SpmResult<i8>;
+    fn read_u8_le(&mut self) -> SpmResult<u8>;
+    fn read_i16_le(&mut self) -> SpmResult<i16>;
+    fn read_u16_le(&mut self) -> SpmResult<u16>;
+    fn read_i32_le(&mut self) -> SpmResult<i32>;
+    fn read_u32_le(&mut self) -> SpmResult<u32>;
+    fn read_u64_le(&mut self) -> SpmResult<u64>;
+    fn read_f32_le(&mut self) -> SpmResult<f32>;
+    fn read_f64_le(&mut self) -> SpmResult<f64>;
 }
 
 impl Bytereading for Cursor<&[u8]> {
-    fn skip(&mut self, num_bytes: u64) {
+    fn skip(&mut self, num_bytes: u64) -> SpmResult<()> {
+        let remaining = (self.get_ref().len() as u64).saturating_sub(self.position());
+        if num_bytes > remaining {
+            return Err(SpmError::UnexpectedEof {
+                offset: self.position(),
+                expected: num_bytes as usize,
+            });
+        }
         self.set_position(self.position() + num_bytes);
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, length: usize) -> SpmResult<Vec<u8>> {
+        let offset = self.position();
+        let remaining = (self.get_ref().len() as u64).saturating_sub(offset);
+        // checked before allocating, a corrupt length could be huge
+        if length as u64 > remaining {
+            return Err(SpmError::UnexpectedEof {
+                offset,
+                expected: length,
+            });
+        }
+        let mut buffer = vec![0; length];
+        self.read_exact(&mut buffer)
+            .map_err(|_| SpmError::UnexpectedEof {
+                offset,
+                expected: length,
+            })?;
+        Ok(buffer)
     }
 
-    fn read_matrix_type(&mut self) -> String {
-        self.read_string(4).chars().rev().collect()
+    fn read_matrix_type(&mut self) -> SpmResult<String> {
+        Ok(self.read_string(4)?.chars().rev().collect())
     }
 
-    fn read_matrix_string(&mut self) -> String {
-        let string_length = self.read_u32_le();
+    fn read_matrix_string(&mut self) -> SpmResult<String> {
+        let string_length = self.read_u32_le()?;
         self.read_utf16_string(string_length as usize)
     }
 
-    fn read_utf16_string(&mut self, length: usize) -> String {
-        let mut buffer = vec![0; length * 2];
-        self.read_exact(&mut buffer).expect("to read");
-        read_utf16_bytes(&buffer)
+    fn read_utf16_string(&mut self, length: usize) -> SpmResult<String> {
+        let offset = self.position();
+        let buffer = self.read_bytes(length * 2)?;
+        read_utf16_bytes(&buffer).ok_or(SpmError::InvalidUtf16 { offset })
     }
 
-    fn read_string(&mut self, length: usize) -> String {
-        let mut buffer = vec![0; length];
-        self.read_exact(&mut buffer).expect("to read");
-        read_str(&buffer).to_owned()
+    fn read_string(&mut self, length: usize) -> SpmResult<String> {
+        let offset = self.position();
+        let buffer = self.read_bytes(length)?;
+        read_str(&buffer)
+            .map(|s| s.to_owned())
+            .ok_or(SpmError::InvalidUtf8 { offset })
     }
 
-    fn read_magic_header(&mut self) -> String {
-        self.read_string(12)
+    fn read_i8_le(&mut self) -> SpmResult<i8> {
+        let buffer = self.read_bytes(1)?;
+        Ok(read_i8_le_bytes(&buffer))
     }
 
-    fn read_i8_le(&mut self) -> i8 {
-        let mut buffer = [0; 1];
-        self.read_exact(&mut buffer).unwrap();
-        read_i8_le_bytes(&buffer)
+    fn read_u8_le(&mut self) -> SpmResult<u8> {
+        let buffer = self.read_bytes(1)?;
+        Ok(read_u8_le_bytes(&buffer))
     }
 
-    fn read_u8_le(&mut self) -> u8 {
-        let mut buffer = [0; 1];
-        self.read_exact(&mut buffer).unwrap();
-        read_u8_le_bytes(&buffer)
+    fn read_i16_le(&mut self) -> SpmResult<i16> {
+        let buffer = self.read_bytes(2)?;
+        Ok(read_i16_le_bytes(&buffer))
     }
 
-    fn read_i16_le(&mut self) -> i16 {
-        let mut buffer = [0; 2];
-        self.read_exact(&mut buffer).unwrap();
-        read_i16_le_bytes(&buffer)
+    fn read_u16_le(&mut self) -> SpmResult<u16> {
+        let buffer = self.read_bytes(2)?;
+        Ok(read_u16_le_bytes(&buffer))
     }
 
-    fn read_u16_le(&mut self) -> u16 {
-        let mut buffer = [0; 2];
-        self.read_exact(&mut buffer).unwrap();
-        read_u16_le_bytes(&buffer)
+    fn read_i32_le(&mut self) -> SpmResult<i32> {
+        let buffer = self.read_bytes(4)?;
+        Ok(read_i32_le_bytes(&buffer))
     }
 
-    fn read_i32_le(&mut self) -> i32 {
-        let mut buffer = [0; 4];
-        self.read_exact(&mut buffer).unwrap();
-        read_i32_le_bytes(&buffer)
+    fn read_u32_le(&mut self) -> SpmResult<u32> {
+        let buffer = self.read_bytes(4)?;
+        Ok(read_u32_le_bytes(&buffer))
     }
 
-    fn read_u32_le(&mut self) -> u32 {
-        let mut buffer = [0; 4];
-        self.read_exact(&mut buffer).unwrap();
-        read_u32_le_bytes(&buffer)
+    fn read_u64_le(&mut self) -> SpmResult<u64> {
+        let buffer = self.read_bytes(8)?;
+        Ok(read_u64_le_bytes(&buffer))
     }
 
-    fn read_u64_le(&mut self) -> u64 {
-        let mut buffer = [0; 8];
-        self.read_exact(&mut buffer).unwrap();
-        read_u64_le_bytes(&buffer)
+    fn read_f32_le(&mut self) -> SpmResult<f32> {
+        let buffer = self.read_bytes(4)?;
+        Ok(read_f32_le_bytes(&buffer))
     }
 
-    fn read_f32_le(&mut self) -> f32 {
-        let mut buffer = [0; 4];
-        self.read_exact(&mut buffer).unwrap();
-        read_f32_le_bytes(&buffer)
+    fn read_f64_le(&mut self) -> SpmResult<f64> {
+        let buffer = self.read_bytes(8)?;
+        Ok(read_f64_le_bytes(&buffer))
     }
+}
 
-    fn read_f64_le(&mut self) -> f64 {
-        let mut buffer = [0; 8];
-        self.read_exact(&mut buffer).unwrap();
-        read_f64_le_bytes(&buffer)
+/// Checks the magic bytes at the current position
+pub fn expect_magic(cursor: &mut Cursor<&[u8]>, expected: &str) -> SpmResult<()> {
+    let found = cursor.read_bytes(expected.len())?;
+    if found != expected.as_bytes() {
+        return Err(SpmError::BadMagic {
+            expected: expected.to_string(),
+            found: String::from_utf8_lossy(&found).to_string(),
+        });
     }
+    Ok(())
 }
 
-pub fn read_utf16_bytes(slice: &[u8]) -> String {
+pub fn read_utf16_bytes(slice: &[u8]) -> Option<String> {
     let iter = (0..(slice.len() / 2)).map(|i| u16::from_le_bytes([slice[2 * i], slice[2 * i + 1]]));
-    let result = std::char::decode_utf16(iter)
+    std::char::decode_utf16(iter)
         .collect::<Result<String, _>>()
-        .unwrap();
-    result
+        .ok()
 }
 
-fn read_str(buffer: &[u8]) -> &str {
-    str::from_utf8(buffer).expect("to read_str")
+fn read_str(buffer: &[u8]) -> Option<&str> {
+    str::from_utf8(buffer).ok()
 }
 
 // i8
@@ -180,9 +209,9 @@ mod tests {
         buffer.append(&mut b.to_le_bytes().to_vec());
         buffer.append(&mut c.to_le_bytes().to_vec());
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_i8_le(), a);
-        assert_eq!(cursor.read_i8_le(), b);
-        assert_eq!(cursor.read_i8_le(), c);
+        assert_eq!(cursor.read_i8_le().unwrap(), a);
+        assert_eq!(cursor.read_i8_le().unwrap(), b);
+        assert_eq!(cursor.read_i8_le().unwrap(), c);
     }
 
     #[test]
@@ -199,9 +228,9 @@ mod tests {
         buffer.append(&mut b.to_le_bytes().to_vec());
         buffer.append(&mut c.to_le_bytes().to_vec());
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_u8_le(), a);
-        assert_eq!(cursor.read_u8_le(), b);
-        assert_eq!(cursor.read_u8_le(), c);
+        assert_eq!(cursor.read_u8_le().unwrap(), a);
+        assert_eq!(cursor.read_u8_le().unwrap(), b);
+        assert_eq!(cursor.read_u8_le().unwrap(), c);
     }
 
     #[test]
@@ -218,9 +247,9 @@ mod tests {
         buffer.append(&mut b.to_le_bytes().to_vec());
         buffer.append(&mut c.to_le_bytes().to_vec());
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_i16_le(), a);
-        assert_eq!(cursor.read_i16_le(), b);
-        assert_eq!(cursor.read_i16_le(), c);
+        assert_eq!(cursor.read_i16_le().unwrap(), a);
+        assert_eq!(cursor.read_i16_le().unwrap(), b);
+        assert_eq!(cursor.read_i16_le().unwrap(), c);
     }
 
     #[test]
@@ -237,9 +266,9 @@ mod tests {
         buffer.append(&mut b.to_le_bytes().to_vec());
         buffer.append(&mut c.to_le_bytes().to_vec());
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_u16_le(), a);
-        assert_eq!(cursor.read_u16_le(), b);
-        assert_eq!(cursor.read_u16_le(), c);
+        assert_eq!(cursor.read_u16_le().unwrap(), a);
+        assert_eq!(cursor.read_u16_le().unwrap(), b);
+        assert_eq!(cursor.read_u16_le().unwrap(), c);
     }
 
     #[test]
@@ -256,9 +285,9 @@ mod tests {
         buffer.append(&mut b.to_le_bytes().to_vec());
         buffer.append(&mut c.to_le_bytes().to_vec());
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_i32_le(), a);
-        assert_eq!(cursor.read_i32_le(), b);
-        assert_eq!(cursor.read_i32_le(), c);
+        assert_eq!(cursor.read_i32_le().unwrap(), a);
+        assert_eq!(cursor.read_i32_le().unwrap(), b);
+        assert_eq!(cursor.read_i32_le().unwrap(), c);
     }
 
     #[test]
@@ -275,9 +304,9 @@ mod tests {
         buffer.append(&mut b.to_le_bytes().to_vec());
         buffer.append(&mut c.to_le_bytes().to_vec());
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_u32_le(), a);
-        assert_eq!(cursor.read_u32_le(), b);
-        assert_eq!(cursor.read_u32_le(), c);
+        assert_eq!(cursor.read_u32_le().unwrap(), a);
+        assert_eq!(cursor.read_u32_le().unwrap(), b);
+        assert_eq!(cursor.read_u32_le().unwrap(), c);
     }
 
     #[test]
@@ -294,9 +323,9 @@ mod tests {
         buffer.append(&mut b.to_le_bytes().to_vec());
         buffer.append(&mut c.to_le_bytes().to_vec());
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_u64_le(), a);
-        assert_eq!(cursor.read_u64_le(), b);
-        assert_eq!(cursor.read_u64_le(), c);
+        assert_eq!(cursor.read_u64_le().unwrap(), a);
+        assert_eq!(cursor.read_u64_le().unwrap(), b);
+        assert_eq!(cursor.read_u64_le().unwrap(), c);
     }
 
     #[test]
@@ -313,9 +342,9 @@ mod tests {
         buffer.append(&mut b.to_le_bytes().to_vec());
         buffer.append(&mut c.to_le_bytes().to_vec());
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_f32_le(), a);
-        assert_eq!(cursor.read_f32_le(), b);
-        assert_eq!(cursor.read_f32_le(), c);
+        assert_eq!(cursor.read_f32_le().unwrap(), a);
+        assert_eq!(cursor.read_f32_le().unwrap(), b);
+        assert_eq!(cursor.read_f32_le().unwrap(), c);
     }
 
     #[test]
@@ -332,9 +361,9 @@ mod tests {
         buffer.append(&mut b.to_le_bytes().to_vec());
         buffer.append(&mut c.to_le_bytes().to_vec());
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_f64_le(), a);
-        assert_eq!(cursor.read_f64_le(), b);
-        assert_eq!(cursor.read_f64_le(), c);
+        assert_eq!(cursor.read_f64_le().unwrap(), a);
+        assert_eq!(cursor.read_f64_le().unwrap(), b);
+        assert_eq!(cursor.read_f64_le().unwrap(), c);
     }
 
     #[test]
@@ -342,14 +371,15 @@ mod tests {
         let h = "MAGICXHEADER";
         let buffer = h.as_bytes().to_vec();
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_magic_header(), h);
+        assert!(expect_magic(&mut cursor, h).is_ok());
+        assert_eq!(cursor.position(), 12);
     }
 
     #[test]
     fn test_read_str() {
         let s = "Test string";
         let sb = s.as_bytes();
-        assert_eq!(read_str(sb), s);
+        assert_eq!(read_str(sb), Some(s));
     }
 
     #[test]
@@ -357,6 +387,43 @@ mod tests {
         let s = "Test string";
         let buffer = s.as_bytes().to_vec();
         let mut cursor = Cursor::new(buffer.as_slice());
-        assert_eq!(cursor.read_string(4), "Test");
+        assert_eq!(cursor.read_string(4).unwrap(), "Test");
+    }
+
+    #[test]
+    fn test_read_past_end() {
+        let buffer = [1_u8, 2, 3];
+        let mut cursor = Cursor::new(&buffer[..]);
+        assert_eq!(cursor.read_u16_le().unwrap(), 0x0201);
+        assert_eq!(
+            cursor.read_u32_le(),
+            Err(SpmError::UnexpectedEof {
+                offset: 2,
+                expected: 4
+            })
+        );
+        assert!(cursor.skip(2).is_err());
+    }
+
+    #[test]
+    fn test_read_invalid_utf8() {
+        let buffer = [b'a', 0xff];
+        let mut cursor = Cursor::new(&buffer[..]);
+        assert_eq!(
+            cursor.read_string(2),
+            Err(SpmError::InvalidUtf8 { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn test_expect_magic() {
+        let buffer = b"ONTMATRX0101";
+        let mut cursor = Cursor::new(&buffer[..]);
+        assert!(expect_magic(&mut cursor, "ONTMATRX0101").is_ok());
+        cursor.set_position(0);
+        assert!(matches!(
+            expect_magic(&mut cursor, "STiMage"),
+            Err(SpmError::BadMagic { .. })
+        ));
     }
 }
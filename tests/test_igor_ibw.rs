@@ -1,5 +1,7 @@
-use spm_rs::igor_ibw::read_ibw;
+use num_complex::Complex;
 use spm_rs::igor_ibw::NumericData;
+use spm_rs::igor_ibw::{checksum, read_ibw, Ibw, IbwReader, NoteValue};
+use spm_rs::{SpmError, SpmReader};
 
 const IBW_MATRIX: &str = "tests/test_files/test_matrix.ibw";
 
@@ -63,7 +65,7 @@ fn test_dim_e_units() {
     assert_eq!(
         ibw.dim_e_units,
         Some(
-            vec!["row_units", "col_units", "", ""]
+            ["row_units", "col_units", "", ""]
                 .iter()
                 .map(|it| it.to_string())
                 .collect()
@@ -76,11 +78,298 @@ fn test_dim_labels() {
     let ibw = read_ibw(IBW_MATRIX).unwrap();
     assert_eq!(
         ibw.dim_labels,
-        Some(
-            vec!["", "", "", ""]
-                .iter()
-                .map(|it| it.to_string())
-                .collect()
-        )
+        Some(["", "", "", ""].iter().map(|it| it.to_string()).collect())
+    );
+}
+
+#[test]
+fn test_from_reader() {
+    let file = std::fs::File::open(IBW_MATRIX).unwrap();
+    let ibw = Ibw::from_reader(file).unwrap();
+    assert_eq!(ibw.bname, "test_matrix");
+    assert_eq!(ibw.n_dim, [4, 4, 0, 0]);
+}
+
+/// Content of a synthetic version 5 file
+struct WaveV5<'a> {
+    type_: i16,
+    name: &'a [u8],
+    /// Points along each dimension
+    dims: &'a [i32],
+    /// Step and single letter unit of the first dimensions
+    steps: &'a [(f64, u8)],
+    data: &'a [u8],
+    note: &'a [u8],
+    /// Labels of the third dimension, 32 bytes each
+    layer_labels: &'a [u8],
+    s_indices: &'a [i32],
+}
+
+impl Default for WaveV5<'_> {
+    fn default() -> Self {
+        WaveV5 {
+            type_: 2,
+            name: b"wave",
+            dims: &[],
+            steps: &[(1.0, 0)],
+            data: &[],
+            note: &[],
+            layer_labels: &[],
+            s_indices: &[],
+        }
+    }
+}
+
+// A version 5 file with a valid checksum
+fn ibw_v5(wave: &WaveV5) -> Vec<u8> {
+    let mut bytes = vec![0_u8; 64 + 320];
+    bytes[0..2].copy_from_slice(&5_i16.to_le_bytes());
+    bytes[4..8].copy_from_slice(&(320 + wave.data.len() as i32).to_le_bytes());
+    bytes[12..16].copy_from_slice(&(wave.note.len() as i32).to_le_bytes());
+    bytes[44..48].copy_from_slice(&(wave.layer_labels.len() as i32).to_le_bytes());
+    bytes[52..56].copy_from_slice(&(4 * wave.s_indices.len() as i32).to_le_bytes());
+    let npnts: i32 = wave.dims.iter().product();
+    bytes[76..80].copy_from_slice(&npnts.to_le_bytes());
+    bytes[80..82].copy_from_slice(&wave.type_.to_le_bytes());
+    bytes[92..92 + wave.name.len()].copy_from_slice(wave.name);
+    for (dim, n) in wave.dims.iter().enumerate() {
+        bytes[132 + 4 * dim..136 + 4 * dim].copy_from_slice(&n.to_le_bytes());
+    }
+    for (dim, (step, unit)) in wave.steps.iter().enumerate() {
+        bytes[148 + 8 * dim..156 + 8 * dim].copy_from_slice(&step.to_le_bytes());
+        bytes[216 + 4 * dim] = *unit;
+    }
+    let sum = checksum(&bytes);
+    bytes[2..4].copy_from_slice(&sum.wrapping_neg().to_le_bytes());
+    bytes.extend(wave.data);
+    bytes.extend(wave.note);
+    bytes.extend(wave.layer_labels);
+    for i in wave.s_indices {
+        bytes.extend(i.to_le_bytes());
+    }
+    bytes
+}
+
+#[test]
+fn test_complex() {
+    let data: Vec<u8> = [1_f32, 2., 3., -4.]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    let wave = WaveV5 {
+        type_: 3,
+        dims: &[2],
+        data: &data,
+        ..Default::default()
+    };
+    let ibw = Ibw::from_bytes(&ibw_v5(&wave)).unwrap();
+    assert_eq!(ibw.bname, "wave");
+    let NumericData::Complex64(d) = ibw.data else {
+        panic!("{:?}", ibw.data);
+    };
+    assert_eq!(d, [Complex::new(1., 2.), Complex::new(3., -4.)]);
+
+    let wave = WaveV5 {
+        type_: 0x51,
+        dims: &[2],
+        data: &[1, 0, 2, 0, 3, 0, 4, 0],
+        ..Default::default()
+    };
+    let ibw = Ibw::from_bytes(&ibw_v5(&wave)).unwrap();
+    let NumericData::ComplexUint16(d) = ibw.data else {
+        panic!("{:?}", ibw.data);
+    };
+    assert_eq!(d, [Complex::new(1, 2), Complex::new(3, 4)]);
+}
+
+#[test]
+fn test_text() {
+    let wave = WaveV5 {
+        type_: 0,
+        dims: &[3],
+        data: b"HeightPhase",
+        s_indices: &[6, 6, 11],
+        ..Default::default()
+    };
+    let ibw = Ibw::from_bytes(&ibw_v5(&wave)).unwrap();
+    let NumericData::Text(d) = ibw.data else {
+        panic!("{:?}", ibw.data);
+    };
+    assert_eq!(d, ["Height", "", "Phase"]);
+}
+
+// A file of version 1, 2 or 3 with a float wave, the note and formula follow the data
+fn ibw_old(version: i16, values: &[f32], note: &[u8], formula: &[u8]) -> Vec<u8> {
+    let bin_size = match version {
+        1 => 8,
+        2 => 16,
+        _ => 20,
+    };
+    let mut bytes = vec![0_u8; bin_size + 110];
+    bytes[0..2].copy_from_slice(&version.to_le_bytes());
+    let wfm_size = 110 + 4 * values.len() as i32 + 16;
+    bytes[2..6].copy_from_slice(&wfm_size.to_le_bytes());
+    if version > 1 {
+        bytes[6..10].copy_from_slice(&(note.len() as i32).to_le_bytes());
+    }
+    if version == 3 {
+        bytes[10..14].copy_from_slice(&(formula.len() as i32).to_le_bytes());
+    }
+    let wave_header = &mut bytes[bin_size..];
+    wave_header[0..2].copy_from_slice(&2_i16.to_le_bytes());
+    wave_header[6..10].copy_from_slice(b"wave");
+    wave_header[42..46].copy_from_slice(&(values.len() as i32).to_le_bytes());
+    wave_header[48..56].copy_from_slice(&0.5_f64.to_le_bytes());
+    let sum = checksum(&bytes);
+    bytes[bin_size - 2..bin_size].copy_from_slice(&sum.wrapping_neg().to_le_bytes());
+    for v in values {
+        bytes.extend(v.to_le_bytes());
+    }
+    bytes.extend([0; 16]);
+    bytes.extend(note);
+    bytes.extend(formula);
+    bytes
+}
+
+#[test]
+fn test_versions() {
+    for version in [1, 2, 3] {
+        let ibw = Ibw::from_bytes(&ibw_old(version, &[1., 2., 3.], b"note", b"x*2\0")).unwrap();
+        assert_eq!(ibw.bname, "wave");
+        assert_eq!(ibw.npnts, 3);
+        assert_eq!(ibw.x_step[0], 0.5);
+        let NumericData::Float32(d) = ibw.data else {
+            panic!("{:?}", ibw.data);
+        };
+        assert_eq!(d, [1., 2., 3.]);
+        let note = if version == 1 { "" } else { "note" };
+        assert_eq!(ibw.note, note);
+        let formula = (version == 3).then(|| "x*2".to_string());
+        assert_eq!(ibw.formula, formula);
+    }
+}
+
+#[test]
+fn test_bad_checksum() {
+    let mut bytes = std::fs::read(IBW_MATRIX).unwrap();
+    bytes[100] ^= 1;
+    let err = Ibw::from_bytes(&bytes).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<SpmError>(),
+        Some(SpmError::BadChecksum { format: "ibw", .. })
+    ));
+
+    let mut bytes = ibw_old(3, &[1.], b"", b"");
+    bytes[30] ^= 1;
+    assert!(Ibw::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn test_to_array() {
+    let ibw = read_ibw(IBW_MATRIX).unwrap();
+    let array = ibw.to_array().unwrap();
+    assert_eq!(array.shape(), [4, 4]);
+    // Igor shows the rows 1 2 3 4, 5 6 7 8, ...
+    assert_eq!(array[[0, 1]], 2.);
+    assert_eq!(array[[1, 0]], 5.);
+
+    let ibw = read_ibw("tests/test_files/test_3d_wave.ibw").unwrap();
+    let array = ibw.to_array().unwrap();
+    assert_eq!(array.shape(), [3, 3, 3]);
+    assert_eq!(array[[0, 1, 0]], 2.);
+    assert_eq!(array[[0, 0, 1]], 10.);
+
+    let ibw = read_ibw("tests/test_files/test_4d_wave.ibw").unwrap();
+    let array = ibw.to_array().unwrap();
+    assert_eq!(array.shape(), [2, 2, 2, 2]);
+    assert_eq!(array[[1, 1, 1, 1]], 16.);
+}
+
+#[test]
+fn test_axes() {
+    let ibw = read_ibw(IBW_MATRIX).unwrap();
+    let axes = ibw.axes();
+    assert_eq!(axes.len(), 2);
+    assert_eq!(axes[0].unit, "row_units");
+    assert_eq!(axes[1].unit, "col_units");
+    assert_eq!(axes[1].values, [0., 1., 2., 3.]);
+    assert_eq!(axes[0].label, "");
+}
+
+#[test]
+fn test_to_spm_image() {
+    let ibw = read_ibw(IBW_MATRIX).unwrap();
+    let image = ibw.to_spm_image().unwrap();
+    assert_eq!((image.xres, image.yres), (4, 4));
+    assert_eq!(image.xsize, 4.);
+    assert_eq!(image.img_data[..3], [1., 5., 9.]);
+    assert!(read_ibw("tests/test_files/test_3d_wave.ibw")
+        .unwrap()
+        .to_spm_image()
+        .is_none());
+}
+
+// A 2x2 image with the layers HeightRetrace and PhaseRetrace as written by Asylum Research
+fn asylum_ibw() -> Vec<u8> {
+    let note = b"ScanSize: 5e-06\rScanRate: 1.00\rImagingMode: AC Mode\rno value line";
+    let mut labels = vec![0_u8; 3 * 32];
+    labels[32..45].copy_from_slice(b"HeightRetrace");
+    labels[64..76].copy_from_slice(b"PhaseRetrace");
+    let data: Vec<u8> = [1_f32, 2., 3., 4., 10., 20., 30., 40.]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    ibw_v5(&WaveV5 {
+        name: b"image",
+        dims: &[2, 2, 2],
+        steps: &[(2.5e-6, b'm'), (2.5e-6, b'm')],
+        data: &data,
+        note,
+        layer_labels: &labels,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn test_note_params() {
+    let ibw = Ibw::from_bytes(&asylum_ibw()).unwrap();
+    let params = ibw.note_params();
+    assert_eq!(params.len(), 3);
+    assert_eq!(params["ScanSize"], NoteValue::Number(5e-6));
+    assert_eq!(params["ScanRate"].as_f64(), Some(1.0));
+    assert_eq!(
+        params["ImagingMode"],
+        NoteValue::Text("AC Mode".to_string())
     );
 }
+
+#[test]
+fn test_asylum_channels() {
+    let ibw = Ibw::from_bytes(&asylum_ibw()).unwrap();
+    assert_eq!(ibw.dim_labels(2), ["", "HeightRetrace", "PhaseRetrace"]);
+    let channels = ibw.to_spm_channels();
+    assert_eq!(channels.len(), 2);
+    assert_eq!(channels[0].name(), "HeightRetrace");
+    assert_eq!(channels[0].metadata["unit"], "m");
+    assert_eq!(channels[0].image.img_data, [1., 2., 3., 4.]);
+    assert_eq!(channels[0].image.xsize, 5000.);
+    assert_eq!(channels[1].name(), "PhaseRetrace");
+    assert_eq!(channels[1].metadata["unit"], "deg");
+    assert_eq!(channels[1].image.img_data, [10., 20., 30., 40.]);
+
+    let spm_file = IbwReader.read_bytes("image.ibw", &asylum_ibw()).unwrap();
+    assert_eq!(spm_file.channels.len(), 2);
+    assert_eq!(spm_file.metadata["note.ScanSize"], "5e-06");
+    assert_eq!(spm_file.metadata["note.ScanRate"], "1.00");
+
+    // Note entries do not replace the values of the reader
+    let bytes = ibw_v5(&WaveV5 {
+        dims: &[1],
+        data: &1f32.to_le_bytes(),
+        note: b"npnts: 99",
+        ..Default::default()
+    });
+    let spm_file = IbwReader.read_bytes("wave.ibw", &bytes).unwrap();
+    assert_eq!(spm_file.metadata["npnts"], "1");
+    assert_eq!(spm_file.metadata["note.npnts"], "99");
+}
@@ -1,4 +1,4 @@
-use spm_rs::mulfile::read_mul;
+use spm_rs::mulfile::{read_mul, read_mul_from_reader, write_mul, write_mul_to_bytes};
 
 const MULFILE: &str = "tests/test_files/stm-aarhus-mul-a.mul";
 
@@ -26,13 +26,102 @@ fn test_gain() {
 #[test]
 fn test_scan_duration() {
     let mulfile = read_mul(MULFILE).unwrap();
-    let scan_durations: Vec<_> = mulfile.iter().map(|x| x.speed.round()).collect();
-    assert_eq!(scan_durations, vec![77.0, 82.0, 123.0, 129.0]);
+    let scan_durations: Vec<_> = mulfile.iter().map(|x| x.speed).collect();
+    assert_eq!(scan_durations, vec![77.32, 82.82, 123.12, 129.34]);
 }
 
 #[test]
 fn test_lines_time() {
     let mulfile = read_mul(MULFILE).unwrap();
     let line_times: Vec<_> = mulfile.iter().map(|x| x.line_time.round()).collect();
-    assert_eq!(line_times, vec![150.0, 160.0, 240.0, 252.0]);
+    assert_eq!(line_times, vec![151.0, 162.0, 240.0, 253.0]);
+}
+
+#[test]
+fn test_offsets() {
+    let mulfile = read_mul(MULFILE).unwrap();
+    let offsets: Vec<_> = mulfile.iter().map(|x| (x.xoffset, x.yoffset)).collect();
+    assert_eq!(
+        offsets,
+        vec![(0.0, 0.0), (28.2, 636.7), (-74.3, 803.8), (-673.2, 580.3)]
+    );
+}
+
+#[test]
+fn test_read_from_reader() {
+    let from_file = read_mul(MULFILE).unwrap();
+    let file = std::fs::File::open(MULFILE).unwrap();
+    let from_reader = read_mul_from_reader(file, MULFILE).unwrap();
+    assert_eq!(from_reader.len(), from_file.len());
+    for (a, b) in from_reader.iter().zip(&from_file) {
+        assert_eq!(a.img_id, b.img_id);
+        assert_eq!(a.img_data.img_data, b.img_data.img_data);
+    }
+}
+
+#[test]
+fn test_no_point_scans() {
+    let mulfile = read_mul(MULFILE).unwrap();
+    assert!(mulfile.iter().all(|x| x.point_scans.is_empty()));
+}
+
+#[test]
+fn test_write_roundtrip() {
+    let original = std::fs::read(MULFILE).unwrap();
+    let mulfile = read_mul(MULFILE).unwrap();
+    let bytes = write_mul_to_bytes(&mulfile).unwrap();
+    assert_eq!(bytes.len(), original.len());
+    let diff = bytes[384..]
+        .iter()
+        .zip(&original[384..])
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+        .map(|(i, _)| i + 384)
+        .take(20)
+        .collect::<Vec<_>>();
+    assert_eq!(diff, Vec::<usize>::new());
+
+    let path = std::env::temp_dir().join("test_write_roundtrip.mul");
+    write_mul(&path.to_string_lossy(), &mulfile).unwrap();
+    let reread = read_mul(&path.to_string_lossy()).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(reread.len(), mulfile.len());
+    for (a, b) in reread.iter().zip(&mulfile) {
+        assert_eq!(a.img_data.img_data, b.img_data.img_data);
+        assert_eq!((a.xres, a.yres, a.zres), (b.xres, b.yres, b.zres));
+        assert_eq!(
+            (a.img_data.xsize, a.img_data.ysize),
+            (b.img_data.xsize, b.img_data.ysize)
+        );
+        assert_eq!(a.datetime, b.datetime);
+        assert_eq!((a.xsize, a.ysize), (b.xsize, b.ysize));
+        assert_eq!((a.xoffset, a.yoffset), (b.xoffset, b.yoffset));
+        assert_eq!((a.zscale, a.tilt), (b.zscale, b.tilt));
+        assert_eq!((a.speed, a.line_time), (b.speed, b.line_time));
+        assert_eq!((a.bias, a.current), (b.bias, b.current));
+        assert_eq!((&a.sample, &a.title), (&b.sample, &b.title));
+        assert_eq!(
+            (a.postpr, a.postd1, a.mode, a.currfac),
+            (b.postpr, b.postd1, b.mode, b.currfac)
+        );
+        assert_eq!(
+            (a.num_pointscans, a.unitnr, a.version, a.gain),
+            (b.num_pointscans, b.unitnr, b.version, b.gain)
+        );
+    }
+}
+
+#[test]
+fn test_write_edited() {
+    // Sizes with a fraction of a nm and a changed speed are kept
+    let mut mulfile = read_mul(MULFILE).unwrap();
+    mulfile[0].xsize = 12.3;
+    mulfile[0].yoffset = -4.5;
+    mulfile[0].speed = 80.25;
+    let bytes = write_mul_to_bytes(&mulfile).unwrap();
+    let reread = read_mul_from_reader(bytes.as_slice(), MULFILE).unwrap();
+    assert_eq!(reread[0].xsize, 12.3);
+    assert_eq!(reread[0].yoffset, -4.5);
+    assert_eq!(reread[0].speed, 80.25);
+    assert_eq!(reread[1].speed, mulfile[1].speed);
 }
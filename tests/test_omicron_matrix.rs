@@ -1,4 +1,8 @@
-use spm_rs::omicron_matrix::read_omicron_matrix;
+use spm_rs::omicron_matrix::{
+    channel_name, clear_experiment_cache, find_omicron_matrix_experiment, is_curve_channel,
+    paramfile_path, read_omicron_matrix, read_omicron_matrix_from_bytes,
+    read_omicron_matrix_spectrum_from_bytes, MatrixType, SweepDirection, TransferFunction,
+};
 
 const MTRX_FILE: &str = "tests/test_files/20201111--4_1.Z_mtrx";
 
@@ -33,3 +37,343 @@ fn test_rotation() {
     let mtrx = read_omicron_matrix(MTRX_FILE).unwrap();
     assert_eq!(mtrx.rotation, 0);
 }
+
+#[test]
+fn test_from_bytes() {
+    let scanfile = std::fs::read(MTRX_FILE).unwrap();
+    let paramfile = std::fs::read(paramfile_path(MTRX_FILE).unwrap()).unwrap();
+    let mtrx =
+        read_omicron_matrix_from_bytes(&scanfile, &paramfile, "20201111--4_1.Z_mtrx").unwrap();
+    assert_eq!(mtrx.xres, 400);
+    assert_eq!(mtrx.current, 0.3);
+    assert_eq!(
+        mtrx.images[0].img_data,
+        read_omicron_matrix(MTRX_FILE).unwrap().images[0].img_data
+    );
+}
+
+#[test]
+fn test_directions() {
+    let mtrx = read_omicron_matrix(MTRX_FILE).unwrap();
+    let names: Vec<_> = mtrx.images.iter().map(|x| x.img_id.as_str()).collect();
+    assert_eq!(names, ["forward_up", "backward_up"]);
+    assert!(mtrx.is_complete());
+}
+
+// Cut the data file after `num_points` as if the scan had been aborted
+fn aborted_scanfile(num_points: usize) -> Vec<u8> {
+    let mut bytes = std::fs::read(MTRX_FILE).unwrap();
+    bytes[64..68].copy_from_slice(&(num_points as u32).to_le_bytes());
+    let data_pos = bytes.windows(4).position(|w| w == b"ATAD").unwrap();
+    bytes[data_pos + 4..data_pos + 8].copy_from_slice(&(num_points as u32 * 4).to_le_bytes());
+    bytes.truncate(data_pos + 8 + num_points * 4);
+    bytes
+}
+
+#[test]
+fn test_aborted_scan() {
+    let paramfile = std::fs::read(paramfile_path(MTRX_FILE).unwrap()).unwrap();
+    let complete = read_omicron_matrix(MTRX_FILE).unwrap();
+    let scanfile = aborted_scanfile(400 * 2 * 10 + 400 + 100);
+    let mtrx =
+        read_omicron_matrix_from_bytes(&scanfile, &paramfile, "20201111--4_1.Z_mtrx").unwrap();
+    assert!(!mtrx.is_complete());
+    let yres: Vec<_> = mtrx.images.iter().map(|x| x.yres).collect();
+    assert_eq!(yres, [11, 10]);
+    assert_eq!(mtrx.images[0].ysize, 100.0 * 11.0 / 400.0);
+    // Lines of an upward scan start at the bottom
+    let n = 400 * 11;
+    assert_eq!(
+        mtrx.images[0].img_data[..],
+        complete.images[0].img_data[160_000 - n..]
+    );
+}
+
+// Replace the data of the image file with a curve of the given raw values
+fn synthetic_curvefile(data: &[i32]) -> Vec<u8> {
+    let mut bytes = aborted_scanfile(0);
+    let n = data.len() as u32;
+    bytes[60..64].copy_from_slice(&n.to_le_bytes());
+    bytes[64..68].copy_from_slice(&n.to_le_bytes());
+    let len = bytes.len();
+    bytes[len - 4..].copy_from_slice(&(n * 4).to_le_bytes());
+    for x in data {
+        bytes.extend(x.to_le_bytes());
+    }
+    bytes
+}
+
+#[test]
+fn test_spectrum() {
+    let paramfile = std::fs::read(paramfile_path(MTRX_FILE).unwrap()).unwrap();
+    // 101 points from -1 V to 1 V with ramp reversal
+    let data: Vec<i32> = (0..202).map(|x| x * 1000).collect();
+    let spectrum = read_omicron_matrix_spectrum_from_bytes(
+        &synthetic_curvefile(&data),
+        &paramfile,
+        "20201111--5_1.I(V)_mtrx",
+    )
+    .unwrap();
+    assert_eq!(spectrum.channel, "I(V)");
+    assert_eq!(spectrum.current, 0.3);
+    assert_eq!(
+        (spectrum.x_unit.as_str(), spectrum.y_unit.as_str()),
+        ("V", "A")
+    );
+    assert_eq!(spectrum.curves.len(), 2);
+    let forward = &spectrum.curves[0];
+    let backward = &spectrum.curves[1];
+    assert_eq!(forward.direction, SweepDirection::Forward);
+    assert_eq!(backward.direction, SweepDirection::Backward);
+    assert_eq!((forward.x[0], forward.x[100]), (-1.0, 1.0));
+    assert_eq!((backward.x[0], backward.x[100]), (1.0, -1.0));
+    assert!((forward.y[1] - 1000.0 / 6.4489e15).abs() < 1e-20);
+}
+
+#[test]
+fn test_spectrum_aborted() {
+    let paramfile = std::fs::read(paramfile_path(MTRX_FILE).unwrap()).unwrap();
+    let mut bytes = synthetic_curvefile(&[0; 150]);
+    bytes[60..64].copy_from_slice(&202_u32.to_le_bytes());
+    let spectrum =
+        read_omicron_matrix_spectrum_from_bytes(&bytes, &paramfile, "20201111--5_1.Z(V)_mtrx")
+            .unwrap();
+    // Z(V) is not a channel of the paramfile, so its unit is unknown
+    assert_eq!(spectrum.y_unit, "");
+    assert_eq!(spectrum.curves.len(), 1);
+}
+
+#[test]
+fn test_channel_name() {
+    assert_eq!(channel_name(MTRX_FILE), Some("Z"));
+    assert_eq!(channel_name("a/20201111--5_1.I(Z)_mtrx"), Some("I(Z)"));
+    assert!(is_curve_channel("I(Z)"));
+    assert!(!is_curve_channel("Z"));
+}
+
+#[test]
+fn test_transfer_functions() {
+    let mtrx = read_omicron_matrix(MTRX_FILE).unwrap();
+    assert_eq!(mtrx.unit, "m");
+    assert_eq!(
+        mtrx.transfer_function,
+        TransferFunction::Linear1D {
+            factor: 2.4e15,
+            offset: 0.0
+        }
+    );
+    let tff = TransferFunction::MultiLinear1D {
+        neutral_factor: 6.4489e15,
+        offset: 0.0,
+        pre_factor: 1.01,
+        pre_offset: -0.01,
+        raw_1: 1.0,
+    };
+    assert!((tff.apply(6.4489e6) - 1e-9).abs() < 1e-20);
+    assert_eq!(TransferFunction::Identity.apply(42.0), 42.0);
+}
+
+#[test]
+fn test_converted_values() {
+    // The first recorded point has the raw value -1231369839, the reference
+    // values follow from Gwyddion's TFF_Linear1D and TFF_MultiLinear1D formulas
+    let paramfile = std::fs::read(paramfile_path(MTRX_FILE).unwrap()).unwrap();
+    let scanfile = std::fs::read(MTRX_FILE).unwrap();
+    let first_point = |filename: &str| {
+        let mtrx = read_omicron_matrix_from_bytes(&scanfile, &paramfile, filename).unwrap();
+        let image = &mtrx.images[0];
+        // The first line of the upward scan is the bottom one
+        image.img_data[(image.yres - 1) * image.xres]
+    };
+    assert!((first_point("20201111--4_1.Z_mtrx") - -5.1307076625e-7).abs() < 1e-20);
+    assert!((first_point("20201111--4_1.I_mtrx") - -1.909426164152026e-7).abs() < 1e-20);
+}
+
+#[test]
+fn test_unsupported_transfer_function() {
+    // Only the channel with the unknown transfer function keeps its raw values
+    let paramfile = std::fs::read(paramfile_path(MTRX_FILE).unwrap()).unwrap();
+    let mut patched = paramfile.clone();
+    patch(&mut patched, &utf16("TFF_Linear1D"), &utf16("TFF_Spline1D"));
+    let scanfile = std::fs::read(MTRX_FILE).unwrap();
+    let z = read_omicron_matrix_from_bytes(&scanfile, &patched, "20201111--4_1.Z_mtrx").unwrap();
+    assert_eq!(
+        z.transfer_function,
+        TransferFunction::Unsupported("TFF_Spline1D".to_string())
+    );
+    assert_eq!(
+        z.images[0].img_data[(z.images[0].yres - 1) * z.xres as usize],
+        -1231369839.0
+    );
+    let i = read_omicron_matrix_from_bytes(&scanfile, &patched, "20201111--4_1.I_mtrx").unwrap();
+    assert!(matches!(
+        i.transfer_function,
+        TransferFunction::MultiLinear1D { .. }
+    ));
+}
+
+/// Replaces every occurrence of `old` in `bytes` by `new` of the same length
+fn patch(bytes: &mut [u8], old: &[u8], new: &[u8]) {
+    for i in 0..bytes.len() - old.len() {
+        if bytes[i..i + old.len()] == old[..] {
+            bytes[i..i + new.len()].copy_from_slice(new);
+        }
+    }
+}
+
+#[test]
+fn test_transfer_function_missing_parameter() {
+    // Only the channels whose transfer function lacks Raw_1 keep their raw values
+    let mut paramfile = std::fs::read(paramfile_path(MTRX_FILE).unwrap()).unwrap();
+    patch(&mut paramfile, &utf16("Raw_1"), &utf16("Raw_9"));
+    let scanfile = std::fs::read(MTRX_FILE).unwrap();
+    let i = read_omicron_matrix_from_bytes(&scanfile, &paramfile, "20201111--4_1.I_mtrx").unwrap();
+    assert_eq!(
+        i.transfer_function,
+        TransferFunction::Unsupported("TFF_MultiLinear1D".to_string())
+    );
+    let z = read_omicron_matrix_from_bytes(&scanfile, &paramfile, "20201111--4_1.Z_mtrx").unwrap();
+    assert!(matches!(
+        z.transfer_function,
+        TransferFunction::Linear1D { .. }
+    ));
+}
+
+#[test]
+fn test_transfer_function_unit() {
+    // The unit of the converted values is taken from the XFER block
+    let mut paramfile = std::fs::read(paramfile_path(MTRX_FILE).unwrap()).unwrap();
+    let mut old = utf16("TFF_MultiLinear1D");
+    old.extend(1_u32.to_le_bytes());
+    let mut new = old.clone();
+    old.extend(utf16("A"));
+    new.extend(utf16("X"));
+    patch(&mut paramfile, &old, &new);
+    let scanfile = std::fs::read(MTRX_FILE).unwrap();
+    let i = read_omicron_matrix_from_bytes(&scanfile, &paramfile, "20201111--4_1.I_mtrx").unwrap();
+    assert_eq!(i.unit, "X");
+}
+
+#[test]
+fn test_current_channel() {
+    // The current image uses the transfer function of the I channel instead of the Z one
+    let paramfile = std::fs::read(paramfile_path(MTRX_FILE).unwrap()).unwrap();
+    let scanfile = std::fs::read(MTRX_FILE).unwrap();
+    let z = read_omicron_matrix_from_bytes(&scanfile, &paramfile, "20201111--4_1.Z_mtrx").unwrap();
+    let i = read_omicron_matrix_from_bytes(&scanfile, &paramfile, "20201111--4_1.I_mtrx").unwrap();
+    assert_eq!(i.unit, "A");
+    let (z0, i0) = (z.images[0].img_data[0], i.images[0].img_data[0]);
+    assert!((i0 - z0 * 2.4e15 / 6.4489e15).abs() < 1e-20);
+}
+
+fn utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+#[test]
+fn test_paramfile_resolution() {
+    // A session restarted once, in a directory whose name contains "--"
+    let dir = std::env::temp_dir().join("test_paramfile--resolution");
+    std::fs::create_dir_all(&dir).unwrap();
+    let paramfile = std::fs::read("tests/test_files/20201111_0001.mtrx").unwrap();
+    let (old, new) = (utf16("20201111--4_1."), utf16("20201111--7_1."));
+    let mut restarted = paramfile.clone();
+    for i in 0..restarted.len() - old.len() {
+        if restarted[i..i + old.len()] == old[..] {
+            restarted[i..i + new.len()].copy_from_slice(&new);
+        }
+    }
+    std::fs::write(dir.join("20201111_0001.mtrx"), &paramfile).unwrap();
+    std::fs::write(dir.join("20201111_0002.mtrx"), &restarted).unwrap();
+    let first = dir.join("20201111--4_1.Z_mtrx");
+    let second = dir.join("20201111--7_1.Z_mtrx");
+    std::fs::copy(MTRX_FILE, &first).unwrap();
+    std::fs::copy(MTRX_FILE, &second).unwrap();
+
+    let paramfile_of = |path: &std::path::Path| paramfile_path(path.to_str().unwrap()).unwrap();
+    let mtrx = read_omicron_matrix(second.to_str().unwrap());
+    let (first_param, second_param) = (paramfile_of(&first), paramfile_of(&second));
+    std::fs::remove_dir_all(dir).unwrap();
+    assert!(first_param.ends_with("20201111_0001.mtrx"));
+    assert!(second_param.ends_with("20201111_0002.mtrx"));
+    assert_eq!(mtrx.unwrap().xres, 400);
+}
+
+#[test]
+fn test_paramfile_resolution_skips_broken() {
+    // Unreadable paramfiles before and after the one of the session
+    let dir = std::env::temp_dir().join("test_paramfile_broken");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy(
+        "tests/test_files/20201111_0001.mtrx",
+        dir.join("20201111_0001.mtrx"),
+    )
+    .unwrap();
+    std::fs::write(dir.join("20201111_0000.mtrx"), b"ONTMATRX0101garbage").unwrap();
+    std::fs::write(dir.join("20201111_0002.mtrx"), b"").unwrap();
+    std::fs::write(dir.join("other.mtrx"), [0xff; 64]).unwrap();
+    let scanfile = dir.join("20201111--4_1.Z_mtrx");
+    let unknown = dir.join("20201111--99_1.Z_mtrx");
+    std::fs::copy(MTRX_FILE, &scanfile).unwrap();
+
+    let mtrx = read_omicron_matrix(scanfile.to_str().unwrap());
+    let param = paramfile_path(scanfile.to_str().unwrap());
+    let unreferenced = paramfile_path(unknown.to_str().unwrap());
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(mtrx.unwrap().xres, 400);
+    assert!(param.unwrap().ends_with("20201111_0001.mtrx"));
+    assert!(unreferenced.is_err());
+}
+
+#[test]
+fn test_paramfile_resolution_later_session() {
+    // A session started after the data file was recorded is not considered, even if
+    // its paramfile claims the data file
+    let dir = std::env::temp_dir().join("test_paramfile_later_session");
+    std::fs::create_dir_all(&dir).unwrap();
+    let paramfile = std::fs::read("tests/test_files/20201111_0001.mtrx").unwrap();
+    let mut later = paramfile.clone();
+    later[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(dir.join("a_0001.mtrx"), &paramfile).unwrap();
+    std::fs::write(dir.join("z_0001.mtrx"), &later).unwrap();
+    let scanfile = dir.join("20201111--4_1.Z_mtrx");
+    std::fs::copy(MTRX_FILE, &scanfile).unwrap();
+
+    let param = paramfile_path(scanfile.to_str().unwrap());
+    clear_experiment_cache();
+    std::fs::remove_dir_all(dir).unwrap();
+    assert!(param.unwrap().ends_with("a_0001.mtrx"));
+}
+
+#[test]
+fn test_experiment() {
+    let experiment = find_omicron_matrix_experiment(MTRX_FILE).unwrap();
+    assert_eq!(experiment.system["program_name"], "MATRIX");
+    assert_eq!(experiment.system["user"], "VT-SPM");
+    let scanner = experiment
+        .components
+        .iter()
+        .find(|c| c.name == "XYScanner")
+        .unwrap();
+    assert_eq!(scanner.kind, "XYScanner");
+    assert!(experiment.connections.iter().any(|c| c.source == "Clock1"
+        && c.signal == "Active"
+        && c.target == "Z_t"
+        && c.slot == "Lock"));
+    assert_eq!(experiment.channels["I"].unit, "A");
+    assert_eq!(experiment.marks[0].text, "MTRX$DISABLE_RECORDING-Aux1");
+    assert_eq!(
+        experiment.data_files[0].name,
+        "20201111--4_1.I_mtrx".to_string()
+    );
+
+    // Feedback loop gain when image 18 was taken
+    assert_eq!(
+        experiment.param_at("20201111--18_1.Z_mtrx", "Regulator.Loop_Gain_1_I [Percent]"),
+        Some(&MatrixType::DOUB(1.3))
+    );
+    assert_eq!(
+        experiment.param_at("20201111--18_1.Z_mtrx", "GapVoltageControl.Voltage [Volt]"),
+        Some(&MatrixType::DOUB(-2.0))
+    );
+}
@@ -1,45 +1,127 @@
-// use spm_rs::rhk_sm4::read_rhk_sm4;
-//
-// const SM4_FILE: &str = "tests/test_files/stm-rhk-sm4.SM4";
-//
-// #[test]
-// fn test_current() {
-//     let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
-//     for i in sm4 {
-//         assert_eq!(i.current, 1.9969940978636913 * 1e-10);
-//     }
-// }
-//
-// #[test]
-// fn test_bias() {
-//     let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
-//     for i in sm4 {
-//         assert_eq!(i.bias, -0.17124176025390625);
-//     }
-// }
-//
-// #[test]
-// fn test_sizes() {
-//     let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
-//     for i in sm4 {
-//         assert_eq!(i.xsize, 299.99998218954715 * 1e-9);
-//         assert_eq!(i.ysize, 299.99998218954715 * 1e-9);
-//     }
-// }
-//
-// #[test]
-// fn test_resolutions() {
-//     let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
-//     for i in sm4 {
-//         assert_eq!(i.xres, 512);
-//         assert_eq!(i.yres, 512);
-//     }
-// }
-//
-// #[test]
-// fn test_rotation() {
-//     let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
-//     for i in sm4 {
-//         assert_eq!(i.rotation, 116.0);
-//     }
-// }
+use spm_rs::rhk_sm4::{
+    correct_offsets_for_drift, read_rhk_sm4, RhkDataType, RhkPageType, RhkScanType, Sm4Track,
+};
+
+const SM4_FILE: &str = "tests/test_files/stm-rhk-sm4.SM4";
+
+#[test]
+fn test_current() {
+    let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
+    for i in sm4 {
+        assert_eq!(i.current, 1.9969940978636913 * 1e-10);
+    }
+}
+
+#[test]
+fn test_bias() {
+    let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
+    for i in sm4 {
+        assert_eq!(i.bias, -0.17124176025390625);
+    }
+}
+
+#[test]
+fn test_sizes() {
+    let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
+    for i in sm4 {
+        assert_eq!(i.xsize, 299.99998218954715 * 1e-9);
+        assert_eq!(i.ysize, 299.99998218954715 * 1e-9);
+    }
+}
+
+#[test]
+fn test_resolutions() {
+    let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
+    for i in sm4 {
+        assert_eq!(i.xres, 512);
+        assert_eq!(i.yres, 512);
+        assert_eq!(i.data.len(), 512 * 512);
+    }
+}
+
+#[test]
+fn test_rotation() {
+    let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
+    for i in sm4 {
+        assert_eq!(i.rotation, 116.0);
+    }
+}
+
+#[test]
+fn test_pages() {
+    let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
+    assert_eq!(sm4.len(), 10);
+    let labels: Vec<_> = sm4.iter().map(|i| i.label.as_str()).collect();
+    assert_eq!(
+        labels,
+        [
+            "VEC",
+            "VEC",
+            "IEC",
+            "IEC",
+            "Utun",
+            "Utun",
+            "Current",
+            "Current",
+            "Topography",
+            "Topography"
+        ]
+    );
+    for (n, i) in sm4.iter().enumerate() {
+        assert_eq!(i.data_type, RhkDataType::DataImage);
+        let scan_type = if n % 2 == 0 {
+            RhkScanType::ScanRight
+        } else {
+            RhkScanType::ScanLeft
+        };
+        assert_eq!(i.scan_type, scan_type);
+        assert_eq!(i.x_units, "m");
+        assert_eq!(i.y_units, "m");
+    }
+    assert_eq!(sm4[0].page_type, RhkPageType::Aux);
+    assert_eq!(sm4[0].z_units, "V");
+    assert_eq!(sm4[6].page_type, RhkPageType::Current);
+    assert_eq!(sm4[6].z_units, "A");
+    assert_eq!(sm4[8].page_type, RhkPageType::Topographic);
+    assert_eq!(sm4[8].z_units, "m");
+}
+
+#[test]
+fn test_metadata() {
+    let sm4 = read_rhk_sm4(SM4_FILE).unwrap();
+    let metadata = &sm4[0].metadata;
+    assert_eq!(metadata.strings.date, "01/08/20");
+    assert_eq!(metadata.strings.time, "14:13:11");
+    assert_eq!(metadata.strings.completed_line_count, "0512");
+    // The file was written before the auxiliary objects were introduced
+    assert!(metadata.prm.is_none());
+    assert!(metadata.lockin0.is_none());
+    assert!(metadata.piezo_sensitivity.is_none());
+}
+
+#[test]
+fn test_correct_offsets_for_drift() {
+    let mut sm4 = read_rhk_sm4(SM4_FILE).unwrap();
+    // Drift compensation was disabled while recording
+    for i in &sm4 {
+        assert_eq!(i.image_drift.as_ref().unwrap().last(), Some((0.0, 0.0)));
+        assert!(i.tip_track.is_none());
+    }
+    let drift = |x: f64, y: f64| Sm4Track {
+        start: None,
+        time: vec![0.0],
+        x: vec![x],
+        y: vec![y],
+        z: vec![],
+    };
+    sm4[2].image_drift = Some(drift(1e-9, -1e-9));
+    sm4[4].image_drift = Some(drift(3e-9, 2e-9));
+    let offsets: Vec<_> = sm4.iter().map(|i| (i.xoffset, i.yoffset)).collect();
+    correct_offsets_for_drift(&mut sm4);
+    assert_eq!((sm4[0].xoffset, sm4[0].yoffset), offsets[0]);
+    assert_eq!((sm4[1].xoffset, sm4[1].yoffset), offsets[1]);
+    assert!((sm4[2].xoffset - (offsets[2].0 - 1e-9)).abs() < 1e-15);
+    assert!((sm4[2].yoffset - (offsets[2].1 + 1e-9)).abs() < 1e-15);
+    assert!((sm4[4].xoffset - (offsets[4].0 - 3e-9)).abs() < 1e-15);
+    assert!((sm4[4].yoffset - (offsets[4].1 - 2e-9)).abs() < 1e-15);
+}
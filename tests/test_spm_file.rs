@@ -0,0 +1,212 @@
+use std::path::Path;
+
+use spm_rs::spm_file::DETECT_LEN;
+use spm_rs::{open, Registry, SpmError, SpmFile, SpmReader};
+
+const MULFILE: &str = "tests/test_files/stm-aarhus-mul-a.mul";
+const FLMFILE: &str = "tests/test_files/stm-aarhus-flm.flm";
+const IBW_MATRIX: &str = "tests/test_files/test_matrix.ibw";
+const MTRX_FILE: &str = "tests/test_files/20201111--4_1.Z_mtrx";
+const MTRX_PARAMFILE: &str = "tests/test_files/20201111_0001.mtrx";
+const SM4_FILE: &str = "tests/test_files/stm-rhk-sm4.SM4";
+
+#[test]
+fn test_open_mul() {
+    let spm_file = open(MULFILE).unwrap();
+    assert_eq!(spm_file.format, "mul");
+    assert_eq!(spm_file.channels.len(), 4);
+    assert_eq!(spm_file.channels[0].metadata["current [nA]"], "0.23");
+}
+
+#[test]
+fn test_open_ibw() {
+    let spm_file = open(IBW_MATRIX).unwrap();
+    assert_eq!(spm_file.format, "ibw");
+    assert_eq!(spm_file.metadata["bname"], "test_matrix");
+    assert_eq!(spm_file.channels.len(), 1);
+    assert_eq!(spm_file.channels[0].name(), "test_matrix");
+}
+
+#[test]
+fn test_open_omicron_matrix() {
+    let spm_file = open(MTRX_FILE).unwrap();
+    assert_eq!(spm_file.format, "omicron_matrix");
+    let names: Vec<_> = spm_file.channels.iter().map(|c| c.name()).collect();
+    assert_eq!(names, vec!["forward_up", "backward_up"]);
+    for img in spm_file.images() {
+        assert_eq!(img.xres, 400);
+        assert_eq!(img.xsize, 100.0);
+    }
+}
+
+#[test]
+fn test_open_rhk_sm4() {
+    let spm_file = open(SM4_FILE).unwrap();
+    assert_eq!(spm_file.format, "rhk_sm4");
+    assert_eq!(spm_file.channels.len(), 10);
+    assert_eq!(spm_file.channels[8].name(), "Topography_forward");
+    assert_eq!(spm_file.channels[9].name(), "Topography_backward");
+    assert_eq!(spm_file.channels[8].metadata["unit"], "m");
+    assert_eq!(spm_file.channels[8].image.xres, 512);
+}
+
+#[test]
+fn test_open_unsupported() {
+    assert!(open("tests/test_files/unknown.xyz").is_err());
+}
+
+fn detect_renamed(filename: &str) -> Option<(&'static str, f32)> {
+    let bytes = std::fs::read(filename).unwrap();
+    let head = &bytes[..bytes.len().min(DETECT_LEN)];
+    Registry::default()
+        .detect(Path::new("renamed"), head, bytes.len() as u64)
+        .map(|d| (d.reader.name(), d.confidence))
+}
+
+#[test]
+fn test_detect_without_extension() {
+    assert_eq!(detect_renamed(MULFILE).unwrap().0, "mul");
+    assert_eq!(detect_renamed(FLMFILE).unwrap().0, "mul");
+    assert_eq!(detect_renamed(IBW_MATRIX).unwrap().0, "ibw");
+    assert_eq!(detect_renamed(MTRX_FILE).unwrap().0, "omicron_matrix");
+    assert_eq!(detect_renamed(SM4_FILE).unwrap().0, "rhk_sm4");
+}
+
+#[test]
+fn test_detect_matrix_paramfile() {
+    assert!(detect_renamed(MTRX_PARAMFILE).is_none());
+}
+
+#[test]
+fn test_detect_extension_confidence() {
+    let registry = Registry::default();
+    let detection = registry.detect_file(Path::new(MULFILE)).unwrap().unwrap();
+    assert!(detection.confidence > detect_renamed(MULFILE).unwrap().1);
+}
+
+struct TextReader;
+
+impl SpmReader for TextReader {
+    fn name(&self) -> &'static str {
+        "text"
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &[".txt"]
+    }
+
+    fn read(&self, filename: &str) -> anyhow::Result<SpmFile> {
+        Ok(SpmFile::new(Path::new(filename), self.name()))
+    }
+
+    fn detect(&self, head: &[u8], _file_len: u64) -> f32 {
+        if head.starts_with(b"TEXT") {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+#[test]
+fn test_register() {
+    let mut registry = Registry::default();
+    registry.register(Box::new(TextReader));
+    let detection = registry.detect(Path::new("a"), b"TEXT file", 9).unwrap();
+    assert_eq!(detection.reader.name(), "text");
+    assert!(registry.detect(Path::new("a"), b"other", 5).is_none());
+}
+
+fn truncated_copy(filename: &str, len: usize) -> std::path::PathBuf {
+    let bytes = std::fs::read(filename).unwrap();
+    let name = Path::new(filename).file_name().unwrap();
+    let path = std::env::temp_dir().join(format!("truncated_{}_{}", len, name.to_string_lossy()));
+    std::fs::write(&path, &bytes[..len]).unwrap();
+    path
+}
+
+#[test]
+fn test_open_truncated() {
+    let registry = Registry::default();
+    for (filename, len) in [(MULFILE, 128 * 3 + 100), (MULFILE, 5000), (IBW_MATRIX, 400)] {
+        let path = truncated_copy(filename, len);
+        let reader = registry.readers().find(|r| r.accepts(&path)).unwrap();
+        let err = reader.read(&path.to_string_lossy()).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<SpmError>(),
+                Some(SpmError::UnexpectedEof { .. })
+            ),
+            "{}: {}",
+            len,
+            err
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn test_open_bytes() {
+    let bytes = std::fs::read(MULFILE).unwrap();
+    let spm_file = Registry::default()
+        .open_bytes("archive/renamed", &bytes)
+        .unwrap();
+    assert_eq!(spm_file.format, "mul");
+    assert_eq!(spm_file.channels.len(), 4);
+    assert_eq!(spm_file.channels[0].name(), "renamed_1");
+}
+
+#[test]
+fn test_open_reader() {
+    let file = std::fs::File::open(IBW_MATRIX).unwrap();
+    let spm_file = Registry::default()
+        .open_reader("test_matrix.ibw", file)
+        .unwrap();
+    assert_eq!(spm_file.format, "ibw");
+    assert_eq!(spm_file.metadata["bname"], "test_matrix");
+}
+
+#[test]
+fn test_open_bytes_unsupported() {
+    let bytes = std::fs::read(MTRX_FILE).unwrap();
+    let err = Registry::default()
+        .open_bytes("20201111--4_1.Z_mtrx", &bytes)
+        .unwrap_err();
+    assert!(err.to_string().contains("paramfile"), "{}", err);
+}
+
+#[test]
+fn test_open_matrix_spectrum() {
+    // Curve file with 2 sweeps of 101 points next to a copy of the paramfile, whose
+    // BREF to an Aux2 image is changed to reference the curve file
+    let dir = std::env::temp_dir().join("test_open_matrix_spectrum");
+    std::fs::create_dir_all(&dir).unwrap();
+    let utf16 = |s: &str| -> Vec<u8> { s.encode_utf16().flat_map(u16::to_le_bytes).collect() };
+    let (aux2, curve) = (utf16("--18_1.Aux2_mtrx"), utf16("--18_1.I(V)_mtrx"));
+    let mut paramfile = std::fs::read(MTRX_PARAMFILE).unwrap();
+    let pos = paramfile
+        .windows(aux2.len())
+        .position(|w| w == aux2)
+        .unwrap();
+    paramfile[pos..pos + curve.len()].copy_from_slice(&curve);
+    std::fs::write(dir.join("20201111_0001.mtrx"), paramfile).unwrap();
+    let mut bytes = std::fs::read(MTRX_FILE).unwrap();
+    let data_pos = bytes.windows(4).position(|w| w == b"ATAD").unwrap();
+    bytes.truncate(data_pos + 4);
+    bytes.extend(808_u32.to_le_bytes());
+    bytes.extend([0; 808]);
+    bytes[60..64].copy_from_slice(&202_u32.to_le_bytes());
+    bytes[64..68].copy_from_slice(&202_u32.to_le_bytes());
+    let path = dir.join("20201111--18_1.I(V)_mtrx");
+    std::fs::write(&path, bytes).unwrap();
+
+    let spm_file = open(&path).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(spm_file.format, "omicron_matrix");
+    assert!(spm_file.channels.is_empty());
+    let names: Vec<_> = spm_file.spectra.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, ["I(V)_forward_0", "I(V)_backward_0"]);
+    assert_eq!(spm_file.spectra[0].x.len(), 101);
+    assert_eq!(spm_file.spectra[0].x_unit, "V");
+    assert_eq!(spm_file.metadata["current [nA]"], "0.3");
+}
@@ -0,0 +1,437 @@
+use std::f64::consts::PI;
+
+use num_complex::Complex;
+use spm_rs::fft::{FourierFilter, Window};
+use spm_rs::lattice::{self, Lattice};
+use spm_rs::spm_image::{LineAlignment, ScarPolarity, SpmImage};
+
+fn image(xres: usize, yres: usize, f: impl Fn(f64, f64) -> f64) -> SpmImage {
+    let img_data = (0..xres * yres)
+        .map(|k| f((k % xres) as f64, (k / xres) as f64))
+        .collect();
+    SpmImage {
+        img_id: "test".to_string(),
+        xsize: xres as f64,
+        ysize: yres as f64,
+        xres,
+        yres,
+        img_data,
+    }
+}
+
+fn max_abs(img: &SpmImage) -> f64 {
+    img.img_data.iter().fold(0.0, |m, z| m.max(z.abs()))
+}
+
+#[test]
+fn test_correct_plane_non_square() {
+    let mut img = image(7, 3, |x, y| 2.0 + 0.5 * x - 3.0 * y);
+    img.correct_plane().unwrap();
+    assert!(max_abs(&img) < 1e-9);
+}
+
+#[test]
+fn test_subtract_polynomial() {
+    let bow = |x: f64, y: f64| 1.0 + 0.1 * x * x - 0.02 * x * x * x + 0.3 * x * y - 0.2 * y * y;
+    let mut img = image(12, 5, bow);
+    img.subtract_polynomial(3, 2, None).unwrap();
+    assert!(max_abs(&img) < 1e-9);
+
+    // A plane cannot remove the bow
+    let mut img = image(12, 5, bow);
+    img.subtract_polynomial(1, 1, None).unwrap();
+    assert!(max_abs(&img) > 0.1);
+}
+
+#[test]
+fn test_subtract_polynomial_high_order() {
+    // Order 7 along both axes, where the normal equations of plain monomials
+    // are ill-conditioned
+    let surface = |x: f64, y: f64| {
+        let (u, v) = (x / 63.0, y / 63.0);
+        (1..=7)
+            .map(|k| (u.powi(k) - 0.5 * v.powi(k)) * 3.0 / k as f64)
+            .sum::<f64>()
+            + u * v
+    };
+    let mut img = image(64, 64, surface);
+    img.subtract_polynomial(7, 7, None).unwrap();
+    assert!(max_abs(&img) < 1e-12);
+}
+
+#[test]
+fn test_correct_plane_errors() {
+    let mut img = image(4, 4, |_, _| f64::NAN);
+    assert!(img.correct_plane().is_err());
+    img.img_data.pop();
+    assert!(img.correct_lines().is_err());
+}
+
+#[test]
+fn test_subtract_polynomial_mask() {
+    let plane = |x: f64, y: f64| 0.5 * x + 0.25 * y;
+    let mut img = image(8, 6, plane);
+    let mut mask = vec![false; 8 * 6];
+    for k in [9, 10, 17, 18] {
+        img.img_data[k] += 10.0;
+        mask[k] = true;
+    }
+    img.subtract_polynomial(1, 1, Some(&mask)).unwrap();
+    for (k, z) in img.img_data.iter().enumerate() {
+        let expected = if mask[k] { 10.0 } else { 0.0 };
+        assert!((z - expected).abs() < 1e-9);
+    }
+
+    assert!(img.subtract_polynomial(1, 1, Some(&mask[1..])).is_err());
+    assert!(img.subtract_polynomial(1, 1, Some(&[true; 48])).is_err());
+}
+
+#[test]
+fn test_subtract_polynomial_single_line() {
+    let mut img = image(10, 1, |x, _| 1.0 + 2.0 * x);
+    assert!(img.subtract_polynomial(1, 3, None).is_err());
+    assert_eq!(img.img_data[9], 19.0);
+    img.subtract_polynomial(1, 0, None).unwrap();
+    assert!(max_abs(&img) < 1e-9);
+}
+
+/// Lines shifted by different offsets
+fn offset(y: f64) -> f64 {
+    [0.0, 3.0, -2.0, 5.0, 1.0, -4.0][y as usize]
+}
+
+#[test]
+fn test_correct_lines_non_square() {
+    let mut img = image(8, 3, |x, y| offset(y) + 0.1 * (x - 3.5));
+    img.correct_lines().unwrap();
+    for line in img.img_data.chunks(8) {
+        assert!(line.iter().sum::<f64>().abs() < 1e-9);
+    }
+}
+
+#[test]
+fn test_align_lines_median_step() {
+    // A step edge covering a third of every line
+    let step = |x: f64, y: f64| offset(y) + if x >= 6.0 { 1.0 } else { 0.0 };
+    let mut img = image(9, 6, step);
+    img.align_lines(LineAlignment::Median, None).unwrap();
+    for (k, z) in img.img_data.iter().enumerate() {
+        let expected = if k % 9 >= 6 { 1.0 } else { 0.0 };
+        assert!((z - expected).abs() < 1e-9);
+    }
+
+    // The mean is pulled towards the step
+    let mut img = image(9, 6, step);
+    img.align_lines(LineAlignment::Mean, None).unwrap();
+    assert!((img.img_data[0] + 1.0 / 3.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_align_lines_differences() {
+    // Terrace position moving from line to line
+    let terrain = |x: f64, y: f64| offset(y) + if x >= 2.0 + y { 2.0 } else { 0.0 };
+    for method in [LineAlignment::MedianOfDifferences, LineAlignment::Matching] {
+        let mut img = image(12, 6, terrain);
+        img.align_lines(method, None).unwrap();
+        assert_eq!(img.img_data[0], 0.0);
+    }
+    let mut img = image(12, 6, terrain);
+    img.align_lines(LineAlignment::MedianOfDifferences, None)
+        .unwrap();
+    for (k, z) in img.img_data.iter().enumerate() {
+        let (x, y) = (k % 12, k / 12);
+        let expected = if x >= 2 + y { 2.0 } else { 0.0 };
+        assert!((z - expected).abs() < 1e-9);
+    }
+
+    // The columns where the step edge crosses between two lines have
+    // mismatching slopes and are weighted down, the plain mean of the
+    // differences would be off by 1/6 per line
+    let mut img = image(12, 6, terrain);
+    img.align_lines(LineAlignment::Matching, None).unwrap();
+    for (k, z) in img.img_data.iter().enumerate() {
+        let (x, y) = (k % 12, k / 12);
+        let expected = if x >= 2 + y { 2.0 } else { 0.0 };
+        assert!((z - expected).abs() < 0.01, "{} {}", k, z);
+    }
+}
+
+#[test]
+fn test_align_lines_polynomial() {
+    let mut img = image(10, 6, |x, y| offset(y) + y * x - 0.1 * x * x);
+    img.align_lines(LineAlignment::Polynomial(2), None).unwrap();
+    assert!(max_abs(&img) < 1e-9);
+
+    // Line 2 has only 2 unmasked pixels for 3 coefficients
+    let mut img = image(10, 6, |x, y| offset(y) + x);
+    let mask: Vec<bool> = (0..60).map(|k| k / 10 == 2 && k % 10 > 1).collect();
+    assert!(img
+        .align_lines(LineAlignment::Polynomial(2), Some(&mask))
+        .is_err());
+    assert_eq!(img.img_data[13], offset(1.0) + 3.0);
+}
+
+#[test]
+fn test_align_lines_trimmed_mean() {
+    let mut img = image(10, 6, |x, y| offset(y) + if x == 4.0 { 50.0 } else { 0.0 });
+    img.align_lines(LineAlignment::TrimmedMean(0.2), None)
+        .unwrap();
+    for (k, z) in img.img_data.iter().enumerate() {
+        let expected = if k % 10 == 4 { 50.0 } else { 0.0 };
+        assert!((z - expected).abs() < 1e-9);
+    }
+    assert!(img
+        .align_lines(LineAlignment::TrimmedMean(0.5), None)
+        .is_err());
+}
+
+#[test]
+fn test_align_lines_mask() {
+    // A tall cluster excluded by the mask
+    let cluster = |x: f64, y: f64| (2.0..5.0).contains(&x) && (1.0..4.0).contains(&y);
+    let mut img = image(8, 6, |x, y| {
+        offset(y) + if cluster(x, y) { 20.0 } else { 0.0 }
+    });
+    let mask: Vec<bool> = (0..48)
+        .map(|k| cluster((k % 8) as f64, (k / 8) as f64))
+        .collect();
+    for method in [
+        LineAlignment::Mean,
+        LineAlignment::Median,
+        LineAlignment::Polynomial(1),
+        LineAlignment::TrimmedMean(0.1),
+        LineAlignment::MedianOfDifferences,
+        LineAlignment::Matching,
+    ] {
+        let mut aligned = image(8, 6, |x, y| {
+            offset(y) + if cluster(x, y) { 20.0 } else { 0.0 }
+        });
+        aligned.align_lines(method, Some(&mask)).unwrap();
+        for (k, z) in aligned.img_data.iter().enumerate() {
+            let expected = if mask[k] { 20.0 } else { 0.0 };
+            assert!((z - expected).abs() < 1e-9, "{:?}", method);
+        }
+    }
+    img.align_lines(LineAlignment::Mean, None).unwrap();
+    assert!(img.img_data[8].abs() > 1.0);
+    assert!(img
+        .align_lines(LineAlignment::Mean, Some(&mask[1..]))
+        .is_err());
+}
+
+fn scarred(height: f64) -> SpmImage {
+    let mut img = image(20, 10, |x, y| 0.2 * y + x.sin());
+    for c in 2..10 {
+        img.img_data[3 * 20 + c] += height;
+    }
+    // Too short to count as a scar
+    for c in 14..16 {
+        img.img_data[6 * 20 + c] += height;
+    }
+    img
+}
+
+#[test]
+fn test_remove_scars() {
+    let mut img = scarred(5.0);
+    let lines = img.remove_scars(2.0, 4, ScarPolarity::Positive).unwrap();
+    assert_eq!(lines, [3]);
+    let clean = image(20, 10, |x, y| 0.2 * y + x.sin());
+    for (k, (z, expected)) in img.img_data.iter().zip(&clean.img_data).enumerate() {
+        if k / 20 == 6 && (14..16).contains(&(k % 20)) {
+            assert!((z - expected - 5.0).abs() < 1e-9);
+        } else {
+            assert!((z - expected).abs() < 1e-9);
+        }
+    }
+
+    let mut img = scarred(5.0);
+    let lines = img.remove_scars(2.0, 2, ScarPolarity::Both).unwrap();
+    assert_eq!(lines, [3, 6]);
+}
+
+#[test]
+fn test_remove_scars_polarity() {
+    let mut img = scarred(-5.0);
+    let original = img.img_data.clone();
+    let lines = img.remove_scars(2.0, 4, ScarPolarity::Positive).unwrap();
+    assert!(lines.is_empty());
+    assert_eq!(img.img_data, original);
+
+    let lines = img.remove_scars(2.0, 4, ScarPolarity::Negative).unwrap();
+    assert_eq!(lines, [3]);
+
+    // A high threshold keeps the scar
+    let mut img = scarred(5.0);
+    assert!(img
+        .remove_scars(10.0, 4, ScarPolarity::Both)
+        .unwrap()
+        .is_empty());
+}
+
+/// A slow wave along y and a fast wave with a period of 4 pixels along x
+fn waves(x: f64, y: f64) -> f64 {
+    (2.0 * PI * y / 20.0).sin() + 0.5 * (2.0 * PI * x / 4.0).cos()
+}
+
+#[test]
+fn test_fft_round_trip() {
+    let mut img = image(30, 20, |x, y| 3.0 + waves(x, y) + 0.01 * x * y);
+    let original = img.img_data.clone();
+    let spectrum = img.fft().unwrap();
+    assert_eq!(spectrum.dim(), (20, 30));
+    // The zero frequency component is the sum of the image
+    let sum = original.iter().sum::<f64>();
+    assert!((spectrum[[0, 0]].re - sum).abs() < 1e-9);
+    img.inverse_fft(spectrum).unwrap();
+    for (z, o) in img.img_data.iter().zip(&original) {
+        assert!((z - o).abs() < 1e-9);
+    }
+    assert!(img.inverse_fft(ndarray::Array2::zeros((30, 20))).is_err());
+}
+
+#[test]
+fn test_windowed_fft() {
+    // Ramp along x, after removing the mean of 2.5 the Hann window of 4 points
+    // [0, 0.75, 0.75, 0] leaves -0.28125 and 0.28125 in the two inner rows
+    let img = image(4, 4, |x, _| 1.0 + x);
+    let spectrum = img.windowed_fft(Window::Hann).unwrap();
+    assert!(spectrum[[0, 0]].norm() < 1e-12);
+    assert!((spectrum[[0, 1]] - Complex::new(-0.5625, 0.5625)).norm() < 1e-12);
+    let plain = img.windowed_fft(Window::Rectangular).unwrap();
+    assert!((plain[[0, 1]] - Complex::new(-8.0, 8.0)).norm() < 1e-12);
+}
+
+#[test]
+fn test_radial_psd() {
+    let mut img = image(32, 32, |x, _| (2.0 * PI * x / 4.0).sin());
+    // Physical units, 0.5 nm per pixel
+    img.xsize = 16.0;
+    img.ysize = 16.0;
+    for window in [Window::Rectangular, Window::Hann, Window::Blackman] {
+        let psd = img.radial_psd(window).unwrap();
+        assert_eq!(psd.frequency.len(), psd.psd.len());
+        assert_eq!(*psd.frequency.last().unwrap(), 1.0);
+        let peak = (0..psd.psd.len())
+            .max_by(|a, b| psd.psd[*a].total_cmp(&psd.psd[*b]))
+            .unwrap();
+        assert_eq!(psd.frequency[peak], 0.5);
+    }
+
+    // The mean is removed before the transform
+    img.img_data.iter_mut().for_each(|z| *z += 3.0);
+    let psd = img.radial_psd(Window::Rectangular).unwrap();
+    assert!(psd.psd[0] < 1e-20);
+}
+
+#[test]
+fn test_fourier_filter() {
+    let slow = |_: f64, y: f64| (2.0 * PI * y / 20.0).sin();
+    let fast = |x: f64, _: f64| 0.5 * (2.0 * PI * x / 4.0).cos();
+
+    let mut img = image(32, 20, waves);
+    img.fourier_filter(FourierFilter::LowPass(0.1)).unwrap();
+    let expected = image(32, 20, slow);
+    for (z, e) in img.img_data.iter().zip(&expected.img_data) {
+        assert!((z - e).abs() < 1e-9);
+    }
+
+    let mut img = image(32, 20, |x, y| 2.0 + waves(x, y));
+    img.fourier_filter(FourierFilter::BandPass(0.2, f64::INFINITY))
+        .unwrap();
+    let expected = image(32, 20, fast);
+    for (z, e) in img.img_data.iter().zip(&expected.img_data) {
+        assert!((z - e).abs() < 1e-9);
+    }
+
+    let mut img = image(32, 20, waves);
+    img.fourier_filter(FourierFilter::Notch {
+        fx: 0.25,
+        fy: 0.0,
+        radius: 0.01,
+    })
+    .unwrap();
+    let expected = image(32, 20, slow);
+    for (z, e) in img.img_data.iter().zip(&expected.img_data) {
+        assert!((z - e).abs() < 1e-9);
+    }
+}
+
+/// Hexagonal lattice of `constant` nm on a 16 nm image, with the sample
+/// coordinates distorted by `m` before sampling
+fn hexagonal(constant: f64, m: lattice::Matrix2) -> SpmImage {
+    let reciprocal = Lattice::hexagonal(constant);
+    // Reciprocal vectors of the hexagonal lattice
+    let inv = lattice::invert([
+        [reciprocal.a[0], reciprocal.a[1]],
+        [reciprocal.b[0], reciprocal.b[1]],
+    ])
+    .unwrap();
+    let g1 = [inv[0][0], inv[1][0]];
+    let g2 = [inv[0][1], inv[1][1]];
+    let g3 = [g1[0] - g2[0], g1[1] - g2[1]];
+    let mut img = image(128, 128, |x, y| {
+        let p = lattice::transform(m, [(x - 63.5) / 8.0, (y - 63.5) / 8.0]);
+        [g1, g2, g3]
+            .iter()
+            .map(|g| (2.0 * PI * (g[0] * p[0] + g[1] * p[1])).cos())
+            .sum()
+    });
+    img.xsize = 16.0;
+    img.ysize = 16.0;
+    img
+}
+
+const IDENTITY: lattice::Matrix2 = [[1.0, 0.0], [0.0, 1.0]];
+
+#[test]
+fn test_find_lattice() {
+    let img = hexagonal(0.5, IDENTITY);
+    let peaks = img.bragg_peaks().unwrap();
+    for p in &peaks[..6] {
+        let f = p.fx.hypot(p.fy);
+        assert!((f - 2.0 / (3f64.sqrt() * 0.5)).abs() < 0.02, "{:?}", p);
+    }
+    let found = img.find_lattice().unwrap();
+    let (a, b) = found.lengths();
+    assert!((a - 0.5).abs() < 0.005, "{:?}", found);
+    assert!((b - 0.5).abs() < 0.005, "{:?}", found);
+    assert!((found.angle() - 120.0).abs() < 1.0, "{:?}", found);
+
+    let flat = image(16, 16, |_, _| 1.0);
+    assert!(flat.find_lattice().is_err());
+}
+
+#[test]
+fn test_calibrate_lattice() {
+    // Sampled at stretched coordinates, the observed lattice is compressed
+    // along x and sheared
+    let mut img = hexagonal(0.5, [[1.1, 0.08], [0.0, 1.0]]);
+    let distorted = img.find_lattice().unwrap();
+    assert!((distorted.angle() - 120.0).abs() > 2.0);
+
+    let reference = Lattice::hexagonal(0.5);
+    img.calibrate_lattice(&reference).unwrap();
+    let corrected = img.find_lattice().unwrap();
+    let (a, b) = corrected.lengths();
+    assert!((a - 0.5).abs() < 0.01, "{:?}", corrected);
+    assert!((b - 0.5).abs() < 0.01, "{:?}", corrected);
+    assert!((corrected.angle() - 120.0).abs() < 1.5, "{:?}", corrected);
+}
+
+#[test]
+fn test_apply_affine() {
+    let mut img = image(9, 7, |x, y| x + 2.0 * y);
+    let original = img.img_data.clone();
+    img.apply_affine(IDENTITY).unwrap();
+    for (z, o) in img.img_data.iter().zip(&original) {
+        assert!((z - o).abs() < 1e-9);
+    }
+    // Point reflection at the centre
+    img.apply_affine([[-1.0, 0.0], [0.0, -1.0]]).unwrap();
+    for (z, o) in img.img_data.iter().zip(original.iter().rev()) {
+        assert!((z - o).abs() < 1e-9);
+    }
+    assert!(img.apply_affine([[1.0, 2.0], [2.0, 4.0]]).is_err());
+    assert!(image(0, 0, |x, _| x).apply_affine(IDENTITY).is_err());
+}